@@ -0,0 +1,51 @@
+//! Compares the cost of setting up, and resizing, the input buffer the way rotee used to (zeroing
+//! it by hand) with `ReadBuf`. Run with `cargo bench --bench read_buf`.
+
+use rotee::read_buf::ReadBuf;
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+const ROUNDS: u32 = 20;
+
+/// The mean time taken by `f` over `ROUNDS` calls.
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let before = Instant::now();
+    for _ in 0..ROUNDS {
+        f();
+    }
+    before.elapsed() / ROUNDS
+}
+
+fn main() {
+    for len in [1 << 20, 64 << 20, 1 << 30] {
+        println!("-b {}M:", len >> 20);
+        // This is what's being compared against.
+        #[allow(clippy::slow_vector_initialization)]
+        let zeroed = time(|| {
+            let mut buf = Vec::<u8>::with_capacity(len);
+            buf.resize(len, 0);
+            black_box(&buf);
+        });
+        let read_buf = time(|| {
+            black_box(&ReadBuf::new(len));
+        });
+        println!("  setup:  resize {zeroed:>12?}  ReadBuf {read_buf:>12?}");
+
+        // Toggling between a small and the full size, as --alt-buf-size does.
+        let mut buf = vec![0u8; len];
+        let zeroed = time(|| {
+            buf.truncate(4096);
+            buf.resize(len, 0);
+            black_box(&buf);
+        });
+        let mut buf = ReadBuf::new(len);
+        let read_buf = time(|| {
+            buf.resize(4096);
+            buf.resize(len);
+            black_box(&*buf);
+        });
+        println!("  toggle: resize {zeroed:>12?}  ReadBuf {read_buf:>12?}");
+    }
+}
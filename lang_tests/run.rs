@@ -19,9 +19,13 @@ fn run(block_size: &'static str) {
     LangTester::new()
         .test_dir("lang_tests/tests")
         .test_file_filter(|p| p.extension().unwrap().to_str().unwrap() == "in")
-        .test_extract(|p| {
-            let mut p = p.to_owned();
-            p.set_extension("expect");
+        .test_extract(move |p| {
+            // A test whose output depends on the block size gives what it expects with a block
+            // size of `N` in `<test>.expect.N`, if it differs from `<test>.expect`.
+            let p = match p.with_extension(format!("expect.{}", block_size)) {
+                e if e.exists() => e,
+                _ => p.with_extension("expect"),
+            };
             if !p.exists() {
                 panic!(
                     "expected output file doesn't exist: {}",
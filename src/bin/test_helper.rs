@@ -1,12 +1,17 @@
 //! Test helper that runs rotee and collects the output files (including stdout and stderr) onto
 //! stdout so that we can use lang_tester to match the output.
+//!
+//! rotee's arguments are taken from a first line of the test input of the form
+//! `# rotee-args: -s 5 -n 2` (which is not fed to rotee) or, failing that, from the `ROTEE_ARGS`
+//! environment variable.
 
 use std::{
     env, fs,
+    io::{Seek, Write},
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     process::{exit, Command, Output},
 };
-use tempfile;
 
 fn bin() -> PathBuf {
     let md = env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -18,63 +23,245 @@ fn bin() -> PathBuf {
     [&md, "target", kind, "rotee"].iter().collect::<PathBuf>()
 }
 
-fn run_rotee(infile: &str) -> Output {
+/// The header line with which a test input file can give its own arguments to rotee.
+const ARGS_HEADER: &str = "# rotee-args:";
+
+/// Split `s` into arguments at unquoted spaces. Single quotes preserve everything up to the
+/// closing quote; within double quotes, and outside quotes, a backslash escapes the next character.
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut cur = None::<String>;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => args.extend(cur.take()),
+            '\\' => cur
+                .get_or_insert_with(String::new)
+                .push(chars.next().expect("trailing backslash")),
+            '\'' => {
+                let cur = cur.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().expect("unterminated '") {
+                        '\'' => break,
+                        c => cur.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let cur = cur.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().expect("unterminated \"") {
+                        '"' => break,
+                        '\\' => cur.push(chars.next().expect("trailing backslash")),
+                        c => cur.push(c),
+                    }
+                }
+            }
+            c => cur.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(cur);
+    args
+}
+
+/// Read a test input file, returning the arguments from its header line (if it has one) and the
+/// input to feed to rotee (without the header).
+fn read_input(infile: &Path) -> (Option<Vec<String>>, Vec<u8>) {
+    let data = fs::read(infile).unwrap();
+    if let Some(rest) = data.strip_prefix(ARGS_HEADER.as_bytes()) {
+        let eol = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+        let header = std::str::from_utf8(&rest[..eol]).expect("header must be UTF-8");
+        let input = rest.get(eol + 1..).unwrap_or_default().to_vec();
+        (Some(split_args(header.trim())), input)
+    } else {
+        (None, data)
+    }
+}
+
+/// Run rotee in `dir` with `infile` as its stdin.
+fn run_rotee(infile: &str, dir: &Path) -> Output {
     let infile = PathBuf::from(infile);
     if !infile.is_absolute() {
         panic!("path to test input must be absolute");
     }
+    let (header_args, input) = read_input(&infile);
 
     let mut cmd = Command::new(bin());
-    cmd.stdin(fs::File::open(infile).unwrap());
+    cmd.current_dir(dir);
+    // The input (less any header) goes via an anonymous file, so that it doesn't appear in `dir`.
+    let mut stdin = tempfile::tempfile().unwrap();
+    stdin.write_all(&input).unwrap();
+    stdin.rewind().unwrap();
+    cmd.stdin(stdin);
+    // Don't let the user's own configuration file influence the tests.
+    cmd.env("HOME", dir);
+    // Make the permissions of created files predictable.
+    unsafe { libc::umask(0o022) };
+
+    // Arguments in the test's header take precedence over `ROTEE_ARGS`.
+    let mut args = match header_args {
+        Some(a) => a,
+        None => env::var("ROTEE_ARGS")
+            .map(|a| split_args(&a))
+            .unwrap_or_default(),
+    };
 
-    if let Ok(args) = env::var("ROTEE_ARGS") {
-        for arg in args.split(" ") {
-            cmd.arg(arg);
+    // The lang test runner re-runs each test with a range of block sizes so that we exercise
+    // different chunkings of the input. Tests that set their own `-b` take precedence. Arguments
+    // after `--` belong to the command that rotee runs, so `-b` must come before them.
+    if let Ok(bs) = env::var("ROTEE_BLOCKSIZE") {
+        let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+        if !args[..end].iter().any(|a| a == "-b" || a == "--buf-size") {
+            args.splice(end..end, [String::from("-b"), bs]);
         }
     }
+    cmd.args(args);
 
     cmd.output().unwrap()
 }
 
-fn emit(dir: &Path, output: &Output) {
-    let mut paths = fs::read_dir(dir)
-        .unwrap()
-        .map(|r| r.unwrap().path())
-        .collect::<Vec<_>>();
-    paths.sort();
-
-    let stdout = std::str::from_utf8(&output.stdout).unwrap();
-    if !stdout.is_empty() {
-        println!(">>> stdout");
-        print!("{}", stdout);
-        if !stdout.ends_with('\n') {
-            println!("<no-eol>");
+/// Is `bytes` safe to print verbatim? That is, is it UTF-8 free of control characters (other than
+/// newline and tab) that would be invisible or ambiguous in an `.expect` file?
+fn is_plain(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => !s.chars().any(|c| c.is_control() && c != '\n' && c != '\t'),
+        Err(_) => false,
+    }
+}
+
+/// Escape `bytes` so that they can be matched in an `.expect` file. Newlines are kept as-is (so
+/// that output remains line-oriented), a backslash becomes `\\`, and any other byte that isn't
+/// printable ASCII becomes `\xNN`. The encoding is reversible.
+fn escape(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'\n' => s.push('\n'),
+            b'\\' => s.push_str("\\\\"),
+            0x20..=0x7e => s.push(char::from(b)),
+            _ => s.push_str(&format!("\\x{:02x}", b)),
         }
     }
+    s
+}
+
+/// What to report about each path: set with `ROTEE_EMIT` to a comma separated list of `meta`
+/// (type, size, permission bits and symlink target), `size` (just the size of each file, for
+/// output too large or too binary to compare as content) and/or `content` (the default).
+struct Emit {
+    meta: bool,
+    size: bool,
+    content: bool,
+}
 
-    let stderr = std::str::from_utf8(&output.stderr).unwrap();
-    if !stderr.is_empty() {
-        println!(">>> stderr");
-        print!("{}", stderr);
-        if !stderr.ends_with('\n') {
-            println!("<no-eol>");
+impl Emit {
+    fn from_env() -> Self {
+        let mut e = Emit {
+            meta: false,
+            size: false,
+            content: false,
+        };
+        for what in env::var("ROTEE_EMIT")
+            .unwrap_or_else(|_| String::from("content"))
+            .split(',')
+        {
+            match what {
+                "meta" => e.meta = true,
+                "size" => e.size = true,
+                "content" => e.content = true,
+                _ => panic!("unknown ROTEE_EMIT value: {}", what),
+            }
         }
+        e
+    }
+}
+
+/// Print the header of a section of output called `name`, followed by `meta` (if any).
+fn emit_header(name: &str, escaped: bool, meta: Option<&str>) {
+    if escaped {
+        println!(">>> {} <escaped>", name);
+    } else {
+        println!(">>> {}", name);
+    }
+    if let Some(m) = meta {
+        println!("<{}>", m);
+    }
+}
+
+/// Print a section of output called `name`. Content that can't be printed verbatim (see
+/// `is_plain()`) is escaped (see `escape()`) and the section header is marked `<escaped>`.
+fn emit_section(name: &str, bytes: &[u8], meta: Option<&str>) {
+    let content = if is_plain(bytes) {
+        emit_header(name, false, meta);
+        String::from_utf8(bytes.to_vec()).unwrap()
+    } else {
+        emit_header(name, true, meta);
+        escape(bytes)
+    };
+    print!("{}", content);
+    if !content.ends_with('\n') {
+        println!("<no-eol>");
+    }
+}
+
+/// Collect all the paths under `dir`, recursively. Symlinks are not followed.
+fn paths(dir: &Path, paths: &mut Vec<PathBuf>) {
+    for ent in fs::read_dir(dir).unwrap() {
+        let ent = ent.unwrap();
+        paths.push(ent.path());
+        if ent.file_type().unwrap().is_dir() {
+            self::paths(&ent.path(), paths);
+        }
+    }
+}
+
+fn emit(dir: &Path, output: &Output) {
+    let what = Emit::from_env();
+    let mut all = Vec::new();
+    paths(dir, &mut all);
+    all.sort();
+
+    if !output.stdout.is_empty() {
+        emit_section("stdout", &output.stdout, None);
+    }
+
+    if !output.stderr.is_empty() {
+        emit_section("stderr", &output.stderr, None);
     }
 
-    for path in paths {
-        println!(">>> {}", path.file_name().unwrap().to_str().unwrap());
-        let fc = fs::read_to_string(&path).unwrap();
-        print!("{}", fc);
-        if !fc.ends_with('\n') {
-            println!("<no-eol>");
+    for path in all {
+        let name = path.strip_prefix(dir).unwrap().to_str().unwrap();
+        let md = fs::symlink_metadata(&path).unwrap();
+        let mode = md.permissions().mode() & 0o7777;
+        if md.is_dir() {
+            if what.meta {
+                emit_header(name, false, Some(&format!("dir mode={:04o}", mode)));
+            }
+        } else if md.file_type().is_symlink() {
+            if what.meta {
+                let target = fs::read_link(&path).unwrap();
+                let meta = format!("symlink -> {}", target.display());
+                emit_header(name, false, Some(&meta));
+            }
+        } else {
+            let meta = if what.meta {
+                Some(format!("file size={} mode={:04o}", md.len(), mode))
+            } else {
+                what.size.then(|| format!("size={}", md.len()))
+            };
+            let meta = meta.as_deref();
+            if what.content {
+                emit_section(name, &fs::read(&path).unwrap(), meta);
+            } else {
+                emit_header(name, false, meta);
+            }
         }
     }
 }
 
 fn main() {
     let tempdir = tempfile::tempdir().unwrap();
-    env::set_current_dir(tempdir.path()).unwrap();
-    let output = run_rotee(&env::args().skip(1).next().unwrap());
+    let output = run_rotee(&env::args().nth(1).unwrap(), tempdir.path());
     emit(tempdir.path(), &output);
     exit(output.status.code().unwrap_or(1));
 }
@@ -0,0 +1,75 @@
+//! Checksumming what is written to the output files (`--verify`).
+
+/// The table for the reflected CRC-32 polynomial (as used by zlib, PNG, and so on), computed at
+/// compile time.
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// A running CRC-32 of a stream, however it is split into chunks.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self { crc: !0 }
+    }
+}
+
+impl Crc32 {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.crc = TABLE[usize::from(self.crc as u8 ^ b)] ^ (self.crc >> 8);
+        }
+    }
+
+    /// The CRC-32 of everything fed so far.
+    pub fn digest(&self) -> u32 {
+        !self.crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crc32;
+
+    fn crc(bytes: &[u8]) -> u32 {
+        let mut c = Crc32::default();
+        c.update(bytes);
+        c.digest()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc(b""), 0);
+        assert_eq!(crc(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+        // However the input is split.
+        let mut c = Crc32::default();
+        for b in b"123456789".chunks(2) {
+            c.update(b);
+        }
+        assert_eq!(c.digest(), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,266 @@
+//! The arithmetic that decides how input is split between output files.
+
+use std::ops::Range;
+
+/// Tracks how full the active output file is and decides how much of each input chunk can be
+/// written to it before it must be rotated.
+pub struct Chunker {
+    /// The size (in bytes) at which files are rotated (`None` if files are only rotated by other
+    /// triggers).
+    file_size: Option<usize>,
+    /// The number of bytes written to the active file so far.
+    cur_size: usize,
+}
+
+impl Chunker {
+    pub fn new(file_size: usize) -> Self {
+        assert!(file_size > 0);
+        Self {
+            file_size: Some(file_size),
+            cur_size: 0,
+        }
+    }
+
+    /// A chunker that never asks for a rotation: input is written in full to the active file.
+    pub fn unlimited() -> Self {
+        Self {
+            file_size: None,
+            cur_size: 0,
+        }
+    }
+
+    /// Change the size at which files are rotated. If the active file already holds `file_size`
+    /// bytes, it is due to be rotated after the next write.
+    pub fn set_file_size(&mut self, file_size: usize) {
+        assert!(file_size > 0);
+        self.file_size = Some(file_size);
+    }
+
+    /// The number of bytes written to the active file so far.
+    pub fn cur_size(&self) -> usize {
+        self.cur_size
+    }
+
+    /// How many of the next `avail` bytes of input should be written to the active file. If a
+    /// due rotation has been held back (i.e. `rotated()` wasn't called when `advance()` said it
+    /// should have been), the active file is allowed to exceed `file_size`.
+    pub fn write_size(&self, avail: usize) -> usize {
+        match self.file_size {
+            Some(f) if self.cur_size < f => usize::min(avail, f - self.cur_size),
+            _ => avail,
+        }
+    }
+
+    /// Record that `n` bytes were written to the active file, returning `true` if the file is now
+    /// due to be rotated.
+    pub fn advance(&mut self, n: usize) -> bool {
+        self.cur_size = self.cur_size.saturating_add(n);
+        self.file_size.is_some_and(|f| self.cur_size >= f)
+    }
+
+    /// Record that the active file was rotated.
+    pub fn rotated(&mut self) {
+        self.cur_size = 0;
+    }
+
+    /// Record that the active file holds `cur_size` bytes after all (e.g. because something else
+    /// truncated it), returning `true` if the file is due to be rotated.
+    pub fn set_cur_size(&mut self, cur_size: usize) -> bool {
+        self.cur_size = cur_size;
+        self.file_size.is_some_and(|f| self.cur_size >= f)
+    }
+
+    /// Split a chunk of `len` bytes into the ranges to write to successive output files, assuming
+    /// that every due rotation is performed. Each range is paired with a flag saying whether the
+    /// file must be rotated after the range is written.
+    pub fn split(&mut self, len: usize) -> Split<'_> {
+        Split {
+            chunker: self,
+            idx: 0,
+            len,
+        }
+    }
+}
+
+/// An iterator over the `(range, rotate_after)` pairs of a chunk. See `Chunker::split()`.
+pub struct Split<'a> {
+    chunker: &'a mut Chunker,
+    idx: usize,
+    len: usize,
+}
+
+impl Iterator for Split<'_> {
+    type Item = (Range<usize>, bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx == self.len {
+            return None;
+        }
+        let n = self.chunker.write_size(self.len - self.idx);
+        let range = self.idx..self.idx + n;
+        self.idx += n;
+        let rotate = self.chunker.advance(n);
+        if rotate {
+            self.chunker.rotated();
+        }
+        Some((range, rotate))
+    }
+}
+
+/// Decode `data` into a file size and a sequence of chunk lengths, run them through a `Chunker`
+/// and panic if any invariant is violated. This is the body of the `chunker` fuzz target.
+#[doc(hidden)]
+pub fn fuzz_check(data: &[u8]) {
+    if data.len() < 2 {
+        return;
+    }
+    let file_size = usize::from(u16::from_le_bytes([data[0], data[1]])) + 1;
+    let chunks = data[2..]
+        .chunks(2)
+        .map(|c| usize::from(c[0]) | (usize::from(*c.get(1).unwrap_or(&0)) << 8))
+        .collect::<Vec<_>>();
+    check_invariants(file_size, &chunks);
+}
+
+/// Feed chunks of the given lengths through a `Chunker` and check that: every input byte is
+/// written exactly once and in order; no range exceeds the remaining budget of the active file;
+/// and a rotation happens exactly when the budget reaches zero.
+fn check_invariants(file_size: usize, chunks: &[usize]) {
+    let mut chunker = Chunker::new(file_size);
+    let mut total_in = 0;
+    let mut total_out = 0;
+    for &len in chunks {
+        total_in += len;
+        let mut expect_start = 0;
+        let splits = chunker.split(len).collect::<Vec<_>>();
+        for (range, rotate) in splits {
+            let before = file_size - (total_out % file_size);
+            assert_eq!(range.start, expect_start);
+            assert!(!range.is_empty());
+            assert!(range.len() <= before);
+            expect_start = range.end;
+            total_out += range.len();
+            assert_eq!(rotate, range.len() == before);
+        }
+        assert_eq!(expect_start, len);
+        assert_eq!(chunker.cur_size(), total_out % file_size);
+    }
+    assert_eq!(total_in, total_out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_invariants, fuzz_check, Chunker};
+    use proptest::prelude::*;
+    use rand::Rng;
+    use std::{env, fs, path::PathBuf};
+
+    #[test]
+    fn test_split() {
+        let mut c = Chunker::new(4);
+        assert_eq!(c.split(3).collect::<Vec<_>>(), vec![(0..3, false)]);
+        assert_eq!(
+            c.split(10).collect::<Vec<_>>(),
+            vec![(0..1, true), (1..5, true), (5..9, true), (9..10, false)]
+        );
+        assert_eq!(c.split(0).collect::<Vec<_>>(), vec![]);
+        assert_eq!(c.cur_size(), 1);
+    }
+
+    #[test]
+    fn test_held_back_rotation() {
+        let mut c = Chunker::new(2);
+        assert_eq!(c.write_size(5), 2);
+        assert!(c.advance(2));
+        // The caller decided not to rotate, so the file may grow beyond `file_size`.
+        assert_eq!(c.write_size(5), 5);
+        assert!(c.advance(5));
+        c.rotated();
+        assert_eq!(c.write_size(5), 2);
+    }
+
+    #[test]
+    fn test_set_cur_size() {
+        let mut c = Chunker::new(4);
+        c.advance(3);
+        assert!(!c.set_cur_size(1));
+        assert_eq!(c.write_size(5), 3);
+        assert!(c.set_cur_size(6));
+        assert_eq!(c.write_size(5), 5);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut c = Chunker::unlimited();
+        assert_eq!(
+            c.split(usize::MAX).collect::<Vec<_>>(),
+            vec![(0..usize::MAX, false)]
+        );
+        assert_eq!(c.write_size(5), 5);
+        assert!(!c.advance(5));
+        assert_eq!(c.cur_size(), usize::MAX);
+        c.rotated();
+        assert_eq!(c.cur_size(), 0);
+    }
+
+    /// Replay the fuzzer's regression corpus.
+    #[test]
+    fn test_fuzz_corpus() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = [&md, "fuzz", "corpus", "chunker"]
+            .iter()
+            .collect::<PathBuf>();
+        for ent in fs::read_dir(dir).unwrap() {
+            fuzz_check(&fs::read(ent.unwrap().path()).unwrap());
+        }
+    }
+
+    /// A bounded run of random inputs, so that the invariants get some coverage without
+    /// cargo-fuzz.
+    #[test]
+    fn test_random() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let file_size = rng.gen_range(1..64);
+            let chunks = (0..rng.gen_range(0..32))
+                .map(|_| rng.gen_range(0..256))
+                .collect::<Vec<_>>();
+            check_invariants(file_size, &chunks);
+        }
+    }
+
+    proptest! {
+        /// Split random data, read in random sized pieces, between files of a random size. The
+        /// files, concatenated in rotation order, must reproduce the input exactly, and every
+        /// file but the newest must be exactly `file_size` bytes.
+        #[test]
+        fn test_no_bytes_lost(
+            data in prop::collection::vec(any::<u8>(), 0..4096),
+            read_sizes in prop::collection::vec(1usize..512, 1..16),
+            file_size in 1usize..256,
+        ) {
+            let mut chunker = Chunker::new(file_size);
+            let mut files = vec![Vec::new()];
+            let mut idx = 0;
+            for &rs in read_sizes.iter().cycle() {
+                if idx == data.len() {
+                    break;
+                }
+                let chunk = &data[idx..usize::min(idx + rs, data.len())];
+                idx += chunk.len();
+                for (range, rotate) in chunker.split(chunk.len()) {
+                    files.last_mut().unwrap().extend_from_slice(&chunk[range]);
+                    if rotate {
+                        files.push(Vec::new());
+                    }
+                }
+            }
+            let (newest, sealed) = files.split_last().unwrap();
+            for f in sealed {
+                prop_assert_eq!(f.len(), file_size);
+            }
+            prop_assert!(newest.len() < file_size);
+            prop_assert_eq!(files.concat(), data);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Address ranges in CIDR notation (`--listen-allow`).
+
+use std::{net::IpAddr, str::FromStr};
+
+/// A range of addresses, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address is a range of just that
+/// address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    /// The number of leading bits of `addr` that an address in the range must share.
+    bits: u32,
+}
+
+impl Cidr {
+    /// Is `addr` in the range? An IPv4 address mapped into IPv6 (`::ffff:a.b.c.d`), as peers are
+    /// seen by a socket listening on an IPv6 address, is treated as the IPv4 address.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => prefix_eq(net.to_bits(), a.to_bits(), self.bits),
+            (IpAddr::V6(net), IpAddr::V6(a)) => prefix_eq(net.to_bits(), a.to_bits(), self.bits),
+            _ => false,
+        }
+    }
+}
+
+/// Are the first `bits` bits of `x` and `y` the same?
+fn prefix_eq<T>(x: T, y: T, bits: u32) -> bool
+where
+    T: Into<u128>,
+{
+    let (x, y, width) = (x.into(), y.into(), 8 * std::mem::size_of::<T>() as u32);
+    bits == 0 || (x ^ y) >> (width - bits) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid address range '{}'", s);
+        let (addr, bits) = match s.split_once('/') {
+            Some((a, b)) => (a, Some(b)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let bits = match bits {
+            Some(b) => match b.parse::<u32>() {
+                Ok(n) if n <= max && b.bytes().all(|c| c.is_ascii_digit()) => n,
+                _ => return Err(invalid()),
+            },
+            None => max,
+        };
+        Ok(Self { addr, bits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+    use std::net::IpAddr;
+
+    fn contains(range: &str, addr: &str) -> bool {
+        range
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(addr.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn test_contains() {
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.7", "192.168.1.7"));
+        assert!(!contains("192.168.1.7", "192.168.1.8"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("::/0", "::1"));
+        // Mapped IPv4 addresses are IPv4, not IPv6.
+        assert!(contains("127.0.0.0/8", "::ffff:127.0.0.1"));
+        assert!(!contains("::/0", "::ffff:127.0.0.1"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+    }
+
+    #[test]
+    fn test_parse() {
+        for s in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "10.0.0.0/+8",
+            "host/8",
+            "",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{}", s);
+        }
+    }
+}
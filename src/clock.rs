@@ -0,0 +1,494 @@
+//! Time, abstracted so that time-driven behaviour can be tested deterministically.
+//!
+//! The wall clock can jump (e.g. NTP corrections, or a fake clock in tests), so nothing here
+//! assumes that time is monotonic.
+
+use libc::{c_char, gmtime_r, localtime_r, poll, pollfd, strftime, time_t, timegm, tm, POLLIN};
+use std::{
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{self, Read},
+    mem::MaybeUninit,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub trait Clock {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+
+    /// Block until the time is at least `t`.
+    fn sleep_until(&self, t: SystemTime);
+
+    /// If the time only changes when a file descriptor becomes readable (rather than with the
+    /// passing of real time), that file descriptor, so that waits for a time can poll it.
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// The real system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, t: SystemTime) {
+        if let Ok(d) = t.duration_since(self.now()) {
+            std::thread::sleep(d);
+        }
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct FakeClock {
+    now: Cell<SystemTime>,
+}
+
+impl FakeClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Cell::new(now),
+        }
+    }
+
+    /// Set the time (which may be earlier than the current time).
+    pub fn set(&self, now: SystemTime) {
+        self.now.set(now);
+    }
+
+    /// Move the time forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        self.now.set(self.now.get() + d);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        self.now.get()
+    }
+
+    /// Sleeping on a fake clock moves time forward to `t` immediately.
+    fn sleep_until(&self, t: SystemTime) {
+        if t > self.now.get() {
+            self.now.set(t);
+        }
+    }
+}
+
+/// A clock whose time is read from a file descriptor as newline-separated seconds since the epoch,
+/// the most recent of which is the current time. This allows an external process (e.g. a test) to
+/// step time. Until the first time is read, the time is the epoch.
+pub struct FdClock {
+    file: File,
+    /// Bytes of an incomplete line read from `file`.
+    partial: RefCell<Vec<u8>>,
+    now: Cell<SystemTime>,
+    /// Is `file` still open for writing at the other end?
+    open: Cell<bool>,
+}
+
+impl FdClock {
+    /// Create a clock reading from `fd`, of which the clock takes ownership.
+    pub fn new(fd: RawFd) -> Result<Self, io::Error> {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1
+            || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1
+            || unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            file: unsafe { File::from_raw_fd(fd) },
+            partial: RefCell::new(Vec::new()),
+            now: Cell::new(UNIX_EPOCH),
+            open: Cell::new(true),
+        })
+    }
+
+    /// Read whatever times are available without blocking, returning `false` at EOF.
+    fn drain(&self) -> bool {
+        let mut buf = [0; 256];
+        let mut partial = self.partial.borrow_mut();
+        let open = loop {
+            match (&self.file).read(&mut buf) {
+                Ok(0) => break false,
+                Ok(n) => partial.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => break true,
+            }
+        };
+        while let Some(i) = partial.iter().position(|b| *b == b'\n') {
+            let line = partial.drain(..=i).collect::<Vec<_>>();
+            if let Some(secs) = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+            {
+                self.now.set(UNIX_EPOCH + Duration::from_secs(secs));
+            }
+        }
+        self.open.set(open);
+        open
+    }
+}
+
+impl Clock for FdClock {
+    fn now(&self) -> SystemTime {
+        self.drain();
+        self.now.get()
+    }
+
+    fn sleep_until(&self, t: SystemTime) {
+        while self.drain() && self.now.get() < t {
+            let mut pfd = pollfd {
+                fd: self.file.as_raw_fd(),
+                events: POLLIN,
+                revents: 0,
+            };
+            unsafe { poll(&mut pfd, 1, -1) };
+        }
+    }
+
+    /// Once the writer has gone, the time can't change (and the file would always be readable).
+    fn fd(&self) -> Option<RawFd> {
+        self.open.get().then(|| self.file.as_raw_fd())
+    }
+}
+
+/// The local date of `t` in `YYYY-MM-DD` form.
+pub fn local_date(t: SystemTime) -> String {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => time_t::try_from(d.as_secs()).unwrap_or(time_t::MAX),
+        Err(e) => -time_t::try_from(e.duration().as_secs()).unwrap_or(time_t::MAX),
+    };
+    let mut t = MaybeUninit::<tm>::uninit();
+    if unsafe { localtime_r(&secs, t.as_mut_ptr()) }.is_null() {
+        panic!("localtime_r failed");
+    }
+    let mut buf = [0u8; 16];
+    let n = unsafe {
+        strftime(
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            c"%Y-%m-%d".as_ptr(),
+            t.as_ptr(),
+        )
+    };
+    String::from_utf8_lossy(&buf[..n]).into_owned()
+}
+
+/// The RFC 3339 UTC timestamp of `t`, with millisecond precision (e.g.
+/// `2024-06-01T12:00:00.123Z`) if `millis` is true, or to the second (`2024-06-01T12:00:00Z`)
+/// otherwise.
+pub fn utc_timestamp(t: SystemTime, millis: bool) -> String {
+    let (secs, ms) = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => (
+            time_t::try_from(d.as_secs()).unwrap_or(time_t::MAX),
+            d.subsec_millis(),
+        ),
+        Err(e) => (
+            -time_t::try_from(e.duration().as_secs()).unwrap_or(time_t::MAX),
+            0,
+        ),
+    };
+    let mut t = MaybeUninit::<tm>::uninit();
+    if unsafe { gmtime_r(&secs, t.as_mut_ptr()) }.is_null() {
+        panic!("gmtime_r failed");
+    }
+    let mut buf = [0u8; 32];
+    let n = unsafe {
+        strftime(
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            c"%Y-%m-%dT%H:%M:%S".as_ptr(),
+            t.as_ptr(),
+        )
+    };
+    let date = String::from_utf8_lossy(&buf[..n]);
+    match millis {
+        true => format!("{}.{:03}Z", date, ms),
+        false => format!("{}Z", date),
+    }
+}
+
+/// Parse an RFC 3339 timestamp (e.g. `2024-06-01T12:00:00Z` or `2024-06-01T14:00:00.5+02:00`),
+/// as printed by `utc_timestamp()`.
+pub fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    fn num(s: &str) -> Option<i32> {
+        s.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| s.parse().ok())?
+    }
+    let b = s.as_bytes();
+    if b.len() < 20
+        || !s.is_ascii()
+        || b[4] != b'-'
+        || b[7] != b'-'
+        || !matches!(b[10], b'T' | b't' | b' ')
+        || b[13] != b':'
+        || b[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (num(&s[..4])?, num(&s[5..7])?, num(&s[8..10])?);
+    let (hour, min, sec) = (num(&s[11..13])?, num(&s[14..16])?, num(&s[17..19])?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+    if let Some(r) = rest.strip_prefix('.') {
+        let n = r.bytes().take_while(u8::is_ascii_digit).count();
+        if n == 0 {
+            return None;
+        }
+        nanos = format!("{:0<9}", &r[..n.min(9)]).parse::<u32>().ok()?;
+        rest = &r[n..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let (h, m) = (num(&rest[1..3])?, num(&rest[4..6])?);
+            if h > 23 || m > 59 {
+                return None;
+            }
+            match rest.as_bytes()[0] {
+                b'+' => h * 3600 + m * 60,
+                b'-' => -(h * 3600 + m * 60),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let mut t = unsafe { MaybeUninit::<tm>::zeroed().assume_init() };
+    t.tm_year = year - 1900;
+    t.tm_mon = month - 1;
+    t.tm_mday = day;
+    t.tm_hour = hour;
+    t.tm_min = min;
+    t.tm_sec = sec;
+    let secs = unsafe { timegm(&mut t) } - time_t::from(offset);
+    let t = match u64::try_from(secs) {
+        Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+        Err(_) => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+    };
+    Some(t + Duration::from_nanos(u64::from(nanos)))
+}
+
+/// Enforces a minimum interval between events.
+pub struct Interval {
+    interval: Duration,
+    last: SystemTime,
+}
+
+impl Interval {
+    /// Create an interval whose first period starts at `now`.
+    pub fn new(interval: Duration, now: SystemTime) -> Self {
+        Self {
+            interval,
+            last: now,
+        }
+    }
+
+    /// Has at least `interval` passed since the last `reset()`? If time has gone backwards, the
+    /// current period is restarted from `now`, so that a large backwards step can't stall events
+    /// indefinitely.
+    pub fn elapsed(&mut self, now: SystemTime) -> bool {
+        match now.duration_since(self.last) {
+            Ok(d) => d >= self.interval,
+            Err(_) => {
+                self.last = now;
+                false
+            }
+        }
+    }
+
+    /// Start a new period at `now`.
+    pub fn reset(&mut self, now: SystemTime) {
+        self.last = now;
+    }
+
+    /// When the current period ends.
+    pub fn due(&self) -> SystemTime {
+        self.last + self.interval
+    }
+}
+
+/// Tracks the local date, reporting when a new day starts.
+pub struct DayTracker {
+    date: String,
+}
+
+impl DayTracker {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            date: local_date(now),
+        }
+    }
+
+    /// The date currently being tracked.
+    pub fn date(&self) -> &str {
+        &self.date
+    }
+
+    /// If `now` is on a later date than the one being tracked, start tracking it and return
+    /// `true`. Time going backwards (even across midnight) never starts a new day.
+    pub fn new_day(&mut self, now: SystemTime) -> bool {
+        let date = local_date(now);
+        // `YYYY-MM-DD` dates compare chronologically.
+        if date > self.date {
+            self.date = date;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        local_date, parse_timestamp, utc_timestamp, Clock, DayTracker, FakeClock, FdClock, Interval,
+    };
+    use std::{
+        fs::File,
+        io::Write,
+        os::unix::io::FromRawFd,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    /// 2024-03-09T12:00:00Z: a day or so either side of the US DST change.
+    fn start() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_709_985_600)
+    }
+
+    #[test]
+    fn test_fake_clock() {
+        let c = FakeClock::new(start());
+        c.advance(HOUR);
+        assert_eq!(c.now(), start() + HOUR);
+        c.sleep_until(start());
+        assert_eq!(c.now(), start() + HOUR);
+        c.sleep_until(start() + 2 * HOUR);
+        assert_eq!(c.now(), start() + 2 * HOUR);
+        c.set(start());
+        assert_eq!(c.now(), start());
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(start(), true), "2024-03-09T12:00:00.000Z");
+        let t = start() + Duration::from_micros(123_999);
+        assert_eq!(utc_timestamp(t, true), "2024-03-09T12:00:00.123Z");
+        assert_eq!(utc_timestamp(t, false), "2024-03-09T12:00:00Z");
+        assert_eq!(utc_timestamp(UNIX_EPOCH, true), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("2024-03-09T12:00:00Z"), Some(start()));
+        assert_eq!(parse_timestamp("2024-03-09t13:30:00+01:30"), Some(start()));
+        assert_eq!(parse_timestamp("2024-03-09 07:00:00-05:00"), Some(start()));
+        assert_eq!(
+            parse_timestamp("2024-03-09T12:00:00.123Z"),
+            Some(start() + Duration::from_millis(123))
+        );
+        assert_eq!(
+            parse_timestamp(&utc_timestamp(UNIX_EPOCH, true)),
+            Some(UNIX_EPOCH)
+        );
+        for s in [
+            "2024-03-09T12:00:00",
+            "2024-03-09T12:00:00.Z",
+            "2024-13-09T12:00:00Z",
+            "2024-03-09T12:00:00+0100",
+            "2024-03-09T1:00:00Z",
+            "yesterday",
+        ] {
+            assert_eq!(parse_timestamp(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_interval() {
+        let c = FakeClock::new(start());
+        let mut i = Interval::new(Duration::from_secs(10), c.now());
+        c.advance(Duration::from_secs(9));
+        assert!(!i.elapsed(c.now()));
+        c.advance(Duration::from_secs(1));
+        assert!(i.elapsed(c.now()));
+        i.reset(c.now());
+        assert!(!i.elapsed(c.now()));
+
+        // A backwards step restarts the period rather than panicking or stalling.
+        c.set(start() - HOUR);
+        assert!(!i.elapsed(c.now()));
+        c.advance(Duration::from_secs(10));
+        assert!(i.elapsed(c.now()));
+    }
+
+    /// Step through a few days (across a DST change in time zones that have one) and check that
+    /// exactly one new day is reported per change of date.
+    #[test]
+    fn test_day_tracker() {
+        let c = FakeClock::new(start());
+        let mut d = DayTracker::new(c.now());
+        let mut dates = vec![d.date().to_owned()];
+        for _ in 0..(4 * 24 * 6) {
+            c.advance(Duration::from_secs(10 * 60));
+            let expect = local_date(c.now()) != *dates.last().unwrap();
+            assert_eq!(d.new_day(c.now()), expect);
+            if expect {
+                dates.push(d.date().to_owned());
+            }
+        }
+        assert!(dates.len() == 4 || dates.len() == 5);
+        let mut sorted = dates.clone();
+        sorted.dedup();
+        assert_eq!(sorted, dates);
+    }
+
+    /// Time going backwards across midnight, and then forwards again, must not cause extra days.
+    #[test]
+    fn test_day_tracker_backwards() {
+        let c = FakeClock::new(start());
+        let mut d = DayTracker::new(c.now());
+        // Find the next change of date.
+        while !d.new_day(c.now()) {
+            c.advance(Duration::from_secs(60));
+        }
+        let date = d.date().to_owned();
+        c.set(c.now() - HOUR);
+        assert!(!d.new_day(c.now()));
+        assert_eq!(d.date(), date);
+        c.advance(2 * HOUR);
+        assert!(!d.new_day(c.now()));
+        assert_eq!(d.date(), date);
+    }
+
+    #[test]
+    fn test_fd_clock() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut w = unsafe { File::from_raw_fd(fds[1]) };
+        let c = FdClock::new(fds[0]).unwrap();
+        assert_eq!(c.now(), UNIX_EPOCH);
+        assert_eq!(c.fd(), Some(fds[0]));
+        w.write_all(b"100\n20").unwrap();
+        assert_eq!(c.now(), UNIX_EPOCH + Duration::from_secs(100));
+        w.write_all(b"0\n").unwrap();
+        assert_eq!(c.now(), UNIX_EPOCH + Duration::from_secs(200));
+        // Backwards is fine.
+        w.write_all(b"50\n").unwrap();
+        assert_eq!(c.now(), UNIX_EPOCH + Duration::from_secs(50));
+        drop(w);
+        // At EOF, sleeping returns rather than blocking forever.
+        c.sleep_until(UNIX_EPOCH + Duration::from_secs(1000));
+        assert_eq!(c.now(), UNIX_EPOCH + Duration::from_secs(50));
+        assert_eq!(c.fd(), None);
+    }
+}
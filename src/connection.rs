@@ -0,0 +1,108 @@
+//! The input of the connections accepted with `--listen-unix`.
+
+/// Reassembles the input of one connection into lines, so that lines from different connections
+/// are never interleaved. At most `cap` bytes are buffered: if that much arrives without a newline,
+/// it is output as is, and the rest of the line follows later (possibly after other connections'
+/// lines). With tagging, each line is prefixed with the connection's id, as `[ID] `.
+pub struct Connection {
+    id: u64,
+    cap: usize,
+    tag: bool,
+    /// The incomplete line read so far.
+    buf: Vec<u8>,
+    /// Is the next byte output the start of a line?
+    line_start: bool,
+}
+
+impl Connection {
+    pub fn new(id: u64, cap: usize, tag: bool) -> Self {
+        assert!(cap > 0);
+        Self {
+            id,
+            cap,
+            tag,
+            buf: Vec::new(),
+            line_start: true,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// How many bytes can be fed next. Reading no more than this from the connection keeps the
+    /// buffer bounded: what isn't read stays in the socket, and so eventually holds the sender up.
+    pub fn room(&self) -> usize {
+        self.cap - self.buf.len()
+    }
+
+    /// Is output part way through a line?
+    pub fn mid_line(&self) -> bool {
+        !self.line_start
+    }
+
+    /// Feed `bytes` (at most `room()` of them), appending what should be output to `out`: the
+    /// complete lines, and, if the buffer is full, the incomplete line.
+    pub fn feed(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        assert!(bytes.len() <= self.room());
+        self.buf.extend_from_slice(bytes);
+        let end = match self.buf.iter().rposition(|b| *b == b'\n') {
+            _ if self.buf.len() == self.cap => self.buf.len(),
+            Some(i) => i + 1,
+            None => return,
+        };
+        let lines = self.buf.drain(..end).collect::<Vec<_>>();
+        self.emit(&lines, out);
+    }
+
+    /// Append what should be output for the incomplete line (if any) to `out`, once the connection
+    /// has closed.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        let rest = std::mem::take(&mut self.buf);
+        self.emit(&rest, out);
+    }
+
+    fn emit(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        for part in bytes.split_inclusive(|b| *b == b'\n') {
+            if self.tag && self.line_start {
+                out.extend_from_slice(format!("[{}] ", self.id).as_bytes());
+            }
+            out.extend_from_slice(part);
+            self.line_start = part.ends_with(b"\n");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connection;
+
+    #[test]
+    fn test_lines() {
+        let mut c = Connection::new(1, 16, false);
+        let mut out = Vec::new();
+        c.feed(b"ab", &mut out);
+        assert!(out.is_empty());
+        c.feed(b"c\nde\nf", &mut out);
+        assert_eq!(out, b"abc\nde\n");
+        assert_eq!(c.room(), 15);
+        c.finish(&mut out);
+        assert_eq!(out, b"abc\nde\nf");
+        assert!(c.mid_line());
+    }
+
+    #[test]
+    fn test_full() {
+        // A full buffer is output without waiting for the newline.
+        let mut c = Connection::new(7, 4, true);
+        let mut out = Vec::new();
+        c.feed(b"abcd", &mut out);
+        assert_eq!(out, b"[7] abcd");
+        assert_eq!(c.room(), 4);
+        c.feed(b"e\nf\n", &mut out);
+        assert_eq!(out, b"[7] abcde\n[7] f\n");
+        assert!(!c.mid_line());
+        c.finish(&mut out);
+        assert_eq!(out, b"[7] abcde\n[7] f\n");
+    }
+}
@@ -0,0 +1,98 @@
+//! The commands read from the control FIFO (`--control-fifo`). Each command is a line of text: a
+//! command name, then any arguments, separated by whitespace:
+//!
+//!   * `size BYTES`: rotate files at `BYTES` (which may have a `K`, `M`, or `G` suffix, for
+//!     powers of 1024) from now on.
+//!   * `rotate`: rotate the active file now.
+//!   * `stats`: write statistics to stderr.
+
+use crate::size::parse_size;
+
+/// The longest command line: the rest of a longer line is dropped (making it malformed).
+const MAX_LINE: usize = 256;
+
+/// A command read from the control FIFO.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Size(usize),
+    Rotate,
+    Stats,
+}
+
+impl Command {
+    /// Parse a command line (without its newline).
+    pub fn parse(line: &[u8]) -> Result<Self, String> {
+        let line = String::from_utf8_lossy(line);
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["size", s] => match parse_size(s) {
+                Some(n) if n > 0 => Ok(Command::Size(n)),
+                _ => Err(format!("invalid size '{}'", s)),
+            },
+            ["rotate"] => Ok(Command::Rotate),
+            ["stats"] => Ok(Command::Stats),
+            _ => Err(format!("unknown command '{}'", line.trim())),
+        }
+    }
+}
+
+/// Reassembles command lines from what is read from the control FIFO, which may split lines
+/// arbitrarily.
+#[derive(Default)]
+pub struct CommandLines {
+    /// The (first `MAX_LINE` bytes of the) incomplete line read so far.
+    line: Vec<u8>,
+}
+
+impl CommandLines {
+    /// Feed `bytes`, returning the lines (without their newlines) that they complete.
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        while !bytes.is_empty() {
+            let (part, eol) = match bytes.iter().position(|b| *b == b'\n') {
+                Some(i) => (&bytes[..i], true),
+                None => (bytes, false),
+            };
+            bytes = &bytes[(part.len() + usize::from(eol))..];
+            let room = MAX_LINE.saturating_sub(self.line.len());
+            self.line.extend_from_slice(&part[..part.len().min(room)]);
+            if eol {
+                lines.push(std::mem::take(&mut self.line));
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, CommandLines, MAX_LINE};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse(b"size 16M"), Ok(Command::Size(16 << 20)));
+        assert_eq!(Command::parse(b"  size\t100 "), Ok(Command::Size(100)));
+        assert_eq!(Command::parse(b"rotate"), Ok(Command::Rotate));
+        assert_eq!(Command::parse(b"stats\r"), Ok(Command::Stats));
+        assert!(Command::parse(b"size 0").is_err());
+        assert!(Command::parse(b"size").is_err());
+        assert!(Command::parse(b"size 1 2").is_err());
+        assert!(Command::parse(b"rotate now").is_err());
+        assert!(Command::parse(b"").is_err());
+        assert!(Command::parse(b"\xffsize 1").is_err());
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut c = CommandLines::default();
+        assert!(c.feed(b"si").is_empty());
+        assert_eq!(
+            c.feed(b"ze 1\nrotate\nst"),
+            vec![b"size 1".to_vec(), b"rotate".to_vec()]
+        );
+        assert_eq!(c.feed(b"ats\n"), vec![b"stats".to_vec()]);
+        let long = vec![b'x'; MAX_LINE * 2];
+        assert_eq!(c.feed(&long), Vec::<Vec<u8>>::new());
+        assert_eq!(c.feed(b"\n")[0].len(), MAX_LINE);
+    }
+}
@@ -0,0 +1,91 @@
+//! Normalising CRLF line endings to LF (`--crlf lf`).
+
+/// Rewrites `\r\n` to `\n` in a stream, however the stream is split into chunks: a `\r` at the end
+/// of a chunk is held back until the next chunk shows whether it starts with `\n`. Every other
+/// byte, including a `\r` not followed by `\n`, is passed through as is.
+#[derive(Default)]
+pub struct CrlfFilter {
+    /// Is a `\r` being held back?
+    cr: bool,
+}
+
+impl CrlfFilter {
+    /// Feed `bytes`, appending what should be output to `out`.
+    pub fn feed(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        out.reserve(bytes.len());
+        for &b in bytes {
+            if self.cr && b != b'\n' {
+                out.push(b'\r');
+            }
+            self.cr = b == b'\r';
+            if !self.cr {
+                out.push(b);
+            }
+        }
+    }
+
+    /// Append any `\r` held back to `out`, once input has ended.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if std::mem::take(&mut self.cr) {
+            out.push(b'\r');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CrlfFilter;
+    use proptest::prelude::*;
+
+    /// Feed `input` split into chunks of `chunk` bytes.
+    fn filter(input: &[u8], chunk: usize) -> Vec<u8> {
+        let mut f = CrlfFilter::default();
+        let mut out = Vec::new();
+        for c in input.chunks(chunk) {
+            f.feed(c, &mut out);
+        }
+        f.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_filter() {
+        for (input, expected) in [
+            (&b"a\r\nb\r\n"[..], &b"a\nb\n"[..]),
+            (b"a\rb\n", b"a\rb\n"),
+            (b"\r\r\n\n\r", b"\r\n\n\r"),
+            (b"\r", b"\r"),
+            (b"\r\r", b"\r\r"),
+            (b"\xff\r\n\x00", b"\xff\n\x00"),
+            (b"", b""),
+        ] {
+            for chunk in 1..=input.len().max(1) {
+                assert_eq!(filter(input, chunk), expected);
+            }
+        }
+    }
+
+    proptest! {
+        /// However input is split, the output is the input with each `\r\n` replaced by `\n`.
+        #[test]
+        fn prop_filter(
+            input in prop::collection::vec(any::<u8>(), 0..200),
+            chunk in 1usize..20,
+        ) {
+            // Mostly `\r` and `\n`, so that there are plenty of runs of them.
+            let input = input.iter().map(|b| b"\r\nx\xff"[usize::from(b % 4)]).collect::<Vec<_>>();
+            let mut expected = Vec::new();
+            let mut i = 0;
+            while i < input.len() {
+                if input[i..].starts_with(b"\r\n") {
+                    expected.push(b'\n');
+                    i += 2;
+                } else {
+                    expected.push(input[i]);
+                    i += 1;
+                }
+            }
+            prop_assert_eq!(filter(&input, chunk), expected);
+        }
+    }
+}
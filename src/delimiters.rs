@@ -0,0 +1,67 @@
+//! Counting occurrences of a delimiter byte (`--split-every`).
+
+/// Counts the occurrences of a byte in a stream of bytes, which may be split arbitrarily across
+/// calls to `scan()`.
+pub struct DelimiterCounter {
+    byte: u8,
+    /// The number of occurrences needed to reach the threshold.
+    threshold: usize,
+    /// The number of occurrences seen since the count was last reset.
+    count: usize,
+}
+
+impl DelimiterCounter {
+    pub fn new(byte: u8, threshold: usize) -> Self {
+        assert!(threshold > 0);
+        Self {
+            byte,
+            threshold,
+            count: 0,
+        }
+    }
+
+    /// Has the threshold been reached?
+    pub fn reached(&self) -> bool {
+        self.count >= self.threshold
+    }
+
+    /// Reset the count of occurrences.
+    pub fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    /// Scan `bytes`, the next part of the stream, counting occurrences. If an occurrence in
+    /// `bytes` makes the count reach the threshold, scanning stops after it and the number of
+    /// bytes scanned is returned. Otherwise all of `bytes` is scanned and `None` is returned.
+    pub fn scan(&mut self, bytes: &[u8]) -> Option<usize> {
+        let mut idx = 0;
+        while let Some(off) = bytes[idx..].iter().position(|b| *b == self.byte) {
+            idx += off + 1;
+            self.count += 1;
+            if self.reached() {
+                return Some(idx);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DelimiterCounter;
+
+    #[test]
+    fn test_scan() {
+        let mut d = DelimiterCounter::new(0, 3);
+        assert_eq!(d.scan(b"a\0b"), None);
+        assert!(!d.reached());
+        assert_eq!(d.scan(b"\0c\0d\0"), Some(3));
+        assert!(d.reached());
+        d.reset();
+        assert_eq!(d.scan(b"d\0"), None);
+        assert_eq!(d.scan(b""), None);
+        assert_eq!(d.scan(b"\0\0"), Some(2));
+        // Once the threshold is reached, scanning stops at every occurrence.
+        assert_eq!(d.scan(b"e\0f\0"), Some(2));
+    }
+}
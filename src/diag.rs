@@ -0,0 +1,339 @@
+//! rotee's logger: where its own diagnostics (errors, warnings, and notes about its operation,
+//! logged with the `log` crate's macros) go, and which of them are shown. Diagnostics go to stderr
+//! or the file given by `--log-file`.
+//!
+//! Library users get whichever logger they install: only the binary installs this one.
+
+use crate::clock::utc_timestamp;
+use log::{Level, LevelFilter, Metadata, Record};
+use std::{
+    cell::RefCell,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+/// Where diagnostics are written.
+enum Target {
+    Stderr,
+    /// A log file (and its path, so that it can be reopened). Each line is timestamped.
+    File(PathBuf, File),
+}
+
+/// A destination for diagnostics.
+pub struct Log {
+    target: Target,
+}
+
+impl Log {
+    /// Write diagnostics to stderr.
+    pub const fn stderr() -> Self {
+        Self {
+            target: Target::Stderr,
+        }
+    }
+
+    /// Append diagnostics to the file `path`, creating it if need be.
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        Ok(Self {
+            target: Target::File(path.to_owned(), open_log(path)?),
+        })
+    }
+
+    /// Reopen the log file (e.g. because it has been rotated away by another program). If it
+    /// can't be opened, diagnostics go to stderr from now on.
+    pub fn reopen(&mut self) {
+        if let Target::File(path, file) = &mut self.target {
+            match open_log(path) {
+                Ok(f) => *file = f,
+                Err(e) => {
+                    eprintln!(
+                        "warning: can't reopen --log-file '{}': {}: logging to stderr",
+                        path.display(),
+                        e
+                    );
+                    self.target = Target::Stderr;
+                }
+            }
+        }
+    }
+
+    /// Is this log a file, rather than stderr?
+    pub fn is_file(&self) -> bool {
+        matches!(self.target, Target::File(..))
+    }
+
+    /// Write the line `msg`, timestamped with `now` if it goes to a file. If the log file can't be
+    /// written to, `msg` goes to stderr instead.
+    pub fn write(&mut self, now: SystemTime, msg: fmt::Arguments) {
+        if let Target::File(_, f) = &mut self.target {
+            // One `write` per line, so that lines from several writers to one file aren't mixed.
+            let line = format!("{} {}\n", utc_timestamp(now, true), msg);
+            if f.write_all(line.as_bytes()).is_ok() {
+                return;
+            }
+        }
+        eprintln!("{}", msg);
+    }
+}
+
+fn open_log(path: &Path) -> Result<File, io::Error> {
+    // Like all files opened by `std`, this is close-on-exec.
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Which diagnostics are shown: a level for each target (module path) prefix, and a default.
+#[derive(Debug, PartialEq)]
+pub struct Filter {
+    default: LevelFilter,
+    /// `(target prefix, level)` pairs.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// Show diagnostics of `level` and above.
+    pub fn new(level: LevelFilter) -> Self {
+        Self {
+            default: level,
+            directives: Vec::new(),
+        }
+    }
+
+    /// Parse `s` (e.g. `ROTEE_LOG`) in `env_logger` syntax: comma-separated directives, each a
+    /// level (e.g. `debug`), a target (e.g. `rotee::echo_queue`, meaning all levels), or
+    /// `target=level`. Levels not given default to those of `self`.
+    pub fn parse(mut self, s: &str) -> Result<Self, String> {
+        let level = |l: &str| {
+            l.parse::<LevelFilter>()
+                .map_err(|_| format!("invalid level '{}' in ROTEE_LOG", l))
+        };
+        for d in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match d.split_once('=') {
+                Some((t, l)) => self.directives.push((t.to_owned(), level(l)?)),
+                None => match level(d) {
+                    Ok(l) => self.default = l,
+                    Err(_) => self.directives.push((d.to_owned(), LevelFilter::Trace)),
+                },
+            }
+        }
+        Ok(self)
+    }
+
+    /// The most detailed level shown for `target`: that of the longest matching prefix.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(t, _)| {
+                target
+                    .strip_prefix(t.as_str())
+                    .is_some_and(|r| r.is_empty() || r.starts_with("::"))
+            })
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.default, |&(_, l)| l)
+    }
+
+    /// The most detailed level shown for any target.
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|&(_, l)| l)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// What a diagnostic of `level` is introduced with.
+fn prefix(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warning",
+        Level::Info | Level::Debug => "note",
+        Level::Trace => "trace",
+    }
+}
+
+/// Where diagnostics are written.
+static LOG: Mutex<Log> = Mutex::new(Log::stderr());
+
+/// Set (by a signal handler) when the log file should be reopened before the next write.
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// While a `Deferred` exists, the diagnostics of its thread that it holds back. Other threads
+    /// (e.g. the `--write-timeout` watchdog, which may be reporting a stalled rotation) aren't held
+    /// back.
+    static DEFERRED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+struct Logger {
+    filter: Filter,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let (level, args) = (prefix(record.level()), record.args());
+        let deferred = DEFERRED.with_borrow_mut(|d| match d {
+            Some(d) => {
+                d.push(format!("{}: {}", level, args));
+                true
+            }
+            None => false,
+        });
+        if !deferred {
+            emit(format_args!("{}: {}", level, args));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install rotee's logger, showing the diagnostics that `filter` allows.
+pub fn init(filter: Filter) {
+    log::set_max_level(filter.max_level());
+    // If another logger has been installed, it is used instead.
+    let _ = log::set_boxed_logger(Box::new(Logger { filter }));
+}
+
+/// Send diagnostics to `log` from now on.
+pub fn set_log(log: Log) {
+    *LOG.lock().unwrap_or_else(|e| e.into_inner()) = log;
+}
+
+/// Ask for the log file to be reopened before the next diagnostic is written. Async-signal-safe.
+pub fn request_reopen() {
+    REOPEN.store(true, Ordering::SeqCst);
+}
+
+/// Write `msg` to the log whatever the filter: for output that was asked for (e.g. the response
+/// to a control command), and errors that end rotee.
+pub fn emit(msg: fmt::Arguments) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if REOPEN.swap(false, Ordering::SeqCst) {
+        log.reopen();
+    }
+    log.write(SystemTime::now(), msg);
+}
+
+/// Report an error that ends rotee. It is logged, and also written to stderr if the log is a
+/// file, so that it can't go unnoticed.
+pub fn error(e: &dyn fmt::Display) {
+    let log_is_file = LOG.lock().unwrap_or_else(|e| e.into_inner()).is_file();
+    emit(format_args!("error: {}", e));
+    if log_is_file {
+        eprintln!("error: {}", e);
+    }
+}
+
+/// Holds back the diagnostics of the thread that creates it (e.g. while signals are blocked around
+/// a rotation) until it is dropped.
+pub struct Deferred {
+    /// Did this start the deferral (rather than it already being in effect)?
+    outer: bool,
+}
+
+impl Deferred {
+    pub fn new() -> Self {
+        let outer = DEFERRED.with_borrow_mut(|d| {
+            let outer = d.is_none();
+            if outer {
+                *d = Some(Vec::new());
+            }
+            outer
+        });
+        Self { outer }
+    }
+}
+
+impl Default for Deferred {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        if self.outer {
+            let held = DEFERRED.with_borrow_mut(mem::take);
+            for m in held.unwrap_or_default() {
+                emit(format_args!("{}", m));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, Log};
+    use log::LevelFilter;
+    use std::{fs, time::UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_log_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("log");
+        let mut log = Log::open(&path).unwrap();
+        assert!(log.is_file());
+        log.write(UNIX_EPOCH, format_args!("warning: {}", 1));
+        // Reopening a file that has been moved away starts a new one.
+        fs::rename(&path, dir.path().join("log.old")).unwrap();
+        log.reopen();
+        log.write(UNIX_EPOCH, format_args!("warning: 2"));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("log.old")).unwrap(),
+            "1970-01-01T00:00:00.000Z warning: 1\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "1970-01-01T00:00:00.000Z warning: 2\n"
+        );
+        // If the file can't be reopened, the log falls back to stderr.
+        fs::remove_dir_all(dir.path()).unwrap();
+        log.reopen();
+        assert!(!log.is_file());
+    }
+
+    #[test]
+    fn test_filter() {
+        let f = Filter::new(LevelFilter::Info);
+        assert_eq!(f.level("rotee"), LevelFilter::Info);
+        assert_eq!(f.max_level(), LevelFilter::Info);
+
+        let f = Filter::new(LevelFilter::Info)
+            .parse("warn, rotee::echo_queue=trace,rotee::diag")
+            .unwrap();
+        assert_eq!(f.level("rotee"), LevelFilter::Warn);
+        assert_eq!(f.level("rotee::echo_queue"), LevelFilter::Trace);
+        assert_eq!(f.level("rotee::echo_queue::x"), LevelFilter::Trace);
+        assert_eq!(f.level("rotee::echo_queues"), LevelFilter::Warn);
+        assert_eq!(f.level("rotee::diag"), LevelFilter::Trace);
+        assert_eq!(f.max_level(), LevelFilter::Trace);
+
+        // The most specific directive wins.
+        let f = Filter::new(LevelFilter::Info)
+            .parse("rotee=off,rotee::echo_queue=debug")
+            .unwrap();
+        assert_eq!(f.level("rotee"), LevelFilter::Off);
+        assert_eq!(f.level("rotee::echo_queue"), LevelFilter::Debug);
+        assert_eq!(f.level("other"), LevelFilter::Info);
+
+        assert_eq!(
+            Filter::new(LevelFilter::Info).parse(""),
+            Ok(Filter::new(LevelFilter::Info))
+        );
+        assert!(Filter::new(LevelFilter::Info).parse("rotee=loud").is_err());
+    }
+}
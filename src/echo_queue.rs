@@ -0,0 +1,273 @@
+//! Echoing through a bounded queue drained by a thread of its own (`--echo-overflow`), so that a
+//! stalled echo consumer needn't hold up writing the files.
+
+use log::warn;
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// What to do with echoed bytes that don't fit in the queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Wait for the consumer to make room.
+    Block,
+    /// Drop them, counting how many were dropped.
+    Drop,
+}
+
+struct State {
+    buf: VecDeque<u8>,
+    /// Has a flush been asked for since the writer last took from `buf`?
+    flush: bool,
+    /// Is the writer part way through writing (and flushing) what it last took from `buf`?
+    busy: bool,
+    /// Has the queue been closed (so the writer should stop once `buf` is empty)?
+    closed: bool,
+    /// The error that stopped the writer (taken by the next `write()`).
+    error: Option<io::Error>,
+    /// Has the writer stopped because of an error?
+    failed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever `state` changes.
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn wait<'a>(&self, st: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.changed.wait(st).unwrap()
+    }
+}
+
+/// Queues what is written to it, up to `cap` bytes, for a thread to write to the underlying
+/// writer. With `Overflow::Drop`, writing never waits for the consumer: what doesn't fit is
+/// dropped. Dropping the queue waits for everything queued to be written.
+pub struct EchoQueue {
+    shared: Arc<Shared>,
+    cap: usize,
+    overflow: Overflow,
+    /// The number of bytes dropped in total.
+    dropped: Arc<AtomicU64>,
+    /// The number of bytes dropped since the consumer last kept up.
+    dropping: u64,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl EchoQueue {
+    pub fn spawn<W: Write + Send + 'static>(inner: W, cap: usize, overflow: Overflow) -> Self {
+        assert!(cap > 0);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                buf: VecDeque::new(),
+                flush: false,
+                busy: false,
+                closed: false,
+                error: None,
+                failed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let s = Arc::clone(&shared);
+        let writer = thread::spawn(move || drain(&s, inner));
+        Self {
+            shared,
+            cap,
+            overflow,
+            dropped: Arc::new(AtomicU64::new(0)),
+            dropping: 0,
+            writer: Some(writer),
+        }
+    }
+
+    /// The number of bytes dropped so far, which stays readable after the queue has gone.
+    pub fn dropped(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.dropped)
+    }
+}
+
+/// Write what is queued in `shared` to `inner` until the queue is closed and empty.
+fn drain<W: Write>(shared: &Shared, mut inner: W) {
+    let mut st = shared.lock();
+    loop {
+        while st.buf.is_empty() && !st.flush && !st.closed {
+            st = shared.wait(st);
+        }
+        if st.buf.is_empty() && !st.flush {
+            return;
+        }
+        let batch = st.buf.drain(..).collect::<Vec<_>>();
+        let flush = mem::take(&mut st.flush);
+        st.busy = true;
+        drop(st);
+        let res = inner
+            .write_all(&batch)
+            .and_then(|_| if flush { inner.flush() } else { Ok(()) });
+        st = shared.lock();
+        st.busy = false;
+        shared.changed.notify_all();
+        if let Err(e) = res {
+            st.error = Some(e);
+            st.failed = true;
+            st.buf.clear();
+            return;
+        }
+    }
+}
+
+/// The error for writing to a queue whose writer has already failed (and reported why).
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "echo has stopped")
+}
+
+impl Write for EchoQueue {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut st = self.shared.lock();
+        loop {
+            if let Some(e) = st.error.take() {
+                return Err(e);
+            }
+            if st.failed {
+                return Err(stopped());
+            }
+            let room = self.cap - st.buf.len();
+            if room > 0 || self.overflow == Overflow::Drop {
+                break;
+            }
+            st = self.shared.wait(st);
+        }
+        let room = self.cap - st.buf.len();
+        let n = usize::min(room, bytes.len());
+        st.buf.extend(&bytes[..n]);
+        self.shared.changed.notify_all();
+        drop(st);
+        if self.overflow == Overflow::Block {
+            return Ok(n);
+        }
+        if n < bytes.len() {
+            if self.dropping == 0 {
+                warn!("echo isn't keeping up: dropping output");
+            }
+            let d = (bytes.len() - n) as u64;
+            self.dropping += d;
+            self.dropped.fetch_add(d, Ordering::Relaxed);
+        } else if self.dropping > 0 {
+            warn!(
+                "echo has resumed: {} bytes were dropped",
+                mem::take(&mut self.dropping)
+            );
+        }
+        Ok(bytes.len())
+    }
+
+    /// Ask for what is queued to be flushed once it is written. With `Overflow::Block`, wait for
+    /// that to be done.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut st = self.shared.lock();
+        st.flush = true;
+        self.shared.changed.notify_all();
+        if self.overflow == Overflow::Drop {
+            return Ok(());
+        }
+        while (st.flush || st.busy || !st.buf.is_empty()) && !st.failed {
+            st = self.shared.wait(st);
+        }
+        match st.error.take() {
+            Some(e) => Err(e),
+            None if st.failed => Err(stopped()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for EchoQueue {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(w) = self.writer.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EchoQueue, Overflow};
+    use std::{
+        io::{self, Write},
+        sync::{atomic::Ordering, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    /// A writer that takes a while over each write, recording what it was given.
+    struct Slow(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Slow {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(1));
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_block() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut q = EchoQueue::spawn(Slow(Arc::clone(&out)), 10, Overflow::Block);
+        let data = (0..1000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for c in data.chunks(7) {
+            q.write_all(c).unwrap();
+        }
+        q.flush().unwrap();
+        assert_eq!(*out.lock().unwrap(), data);
+        assert_eq!(q.dropped().load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_drop() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut q = EchoQueue::spawn(Slow(Arc::clone(&out)), 10, Overflow::Drop);
+        let dropped = q.dropped();
+        for _ in 0..1000 {
+            q.write_all(b"xxxxxxx").unwrap();
+        }
+        drop(q);
+        let dropped = dropped.load(Ordering::Relaxed);
+        assert!(dropped > 0);
+        assert_eq!(out.lock().unwrap().len() as u64 + dropped, 7000);
+    }
+
+    #[test]
+    fn test_error() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut q = EchoQueue::spawn(Broken, 10, Overflow::Block);
+        q.write_all(b"x").unwrap();
+        assert_eq!(q.flush().unwrap_err().to_string(), "gone");
+        assert!(q.write_all(b"x").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Encrypting sealed output files to age recipients (`--encrypt-recipient`). Encryption needs the
+//! `encrypt` feature: without it, `Recipients::parse()` always fails, so rotee refuses to start
+//! rather than writing plaintext that the user asked to be encrypted.
+
+#[cfg(feature = "encrypt")]
+use crate::temp::TempFile;
+#[cfg(feature = "encrypt")]
+use std::{fs::File, io::BufWriter};
+use std::{io, path::Path};
+
+/// The age (X25519) recipients that sealed files are encrypted to.
+#[derive(Clone)]
+pub struct Recipients {
+    #[cfg(feature = "encrypt")]
+    keys: Vec<age::x25519::Recipient>,
+}
+
+impl Recipients {
+    /// Parse age public keys (`age1...`), of which there must be at least one.
+    #[cfg(feature = "encrypt")]
+    pub fn parse(keys: &[String]) -> Result<Self, String> {
+        assert!(!keys.is_empty());
+        let keys = keys
+            .iter()
+            .map(|k| {
+                k.parse::<age::x25519::Recipient>()
+                    .map_err(|e| format!("invalid age recipient '{}': {}", k, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { keys })
+    }
+
+    #[cfg(not(feature = "encrypt"))]
+    pub fn parse(_keys: &[String]) -> Result<Self, String> {
+        Err("--encrypt-recipient needs rotee to be built with the 'encrypt' feature".to_owned())
+    }
+
+    /// Encrypt the file `from` into the file `to`, which is only created (by renaming a temporary
+    /// file) once the ciphertext has been completely written and synced. `from` is left as is. If
+    /// encryption fails, the temporary file is removed, unless `keep_temps` is true.
+    #[cfg(feature = "encrypt")]
+    pub fn encrypt_file(&self, from: &Path, to: &Path, keep_temps: bool) -> Result<(), io::Error> {
+        let tmp = TempFile::new(to, keep_temps);
+        let recipients = self
+            .keys
+            .iter()
+            .cloned()
+            .map(|r| Box::new(r) as Box<dyn age::Recipient + Send>)
+            .collect::<Vec<_>>();
+        let mut w = age::Encryptor::with_recipients(recipients)
+            .unwrap()
+            .wrap_output(BufWriter::new(File::create(tmp.path())?))?;
+        io::copy(&mut File::open(from)?, &mut w)?;
+        let f = w.finish()?.into_inner().map_err(|e| e.into_error())?;
+        f.sync_all()?;
+        tmp.persist(to)
+    }
+
+    #[cfg(not(feature = "encrypt"))]
+    pub fn encrypt_file(
+        &self,
+        _from: &Path,
+        _to: &Path,
+        _keep_temps: bool,
+    ) -> Result<(), io::Error> {
+        unreachable!()
+    }
+}
+
+#[cfg(all(test, feature = "encrypt"))]
+mod tests {
+    use super::Recipients;
+    use age::{x25519::Identity, Decryptor};
+    use std::{
+        fs::{self, File},
+        io::Read,
+        iter,
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let key = Identity::generate().to_public().to_string();
+        assert!(Recipients::parse(std::slice::from_ref(&key)).is_ok());
+        assert!(Recipients::parse(&[key, "age1nope".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let (from, to) = (dir.path().join("rotee.0"), dir.path().join("rotee.1.age"));
+        let data = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(&from, &data).unwrap();
+        let (a, b) = (Identity::generate(), Identity::generate());
+        let r = Recipients::parse(&[a.to_public().to_string(), b.to_public().to_string()]).unwrap();
+        r.encrypt_file(&from, &to, false).unwrap();
+        assert_eq!(fs::read(&from).unwrap(), data);
+        // No temporary file is left behind.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // Either recipient can decrypt the file.
+        for id in [&a, &b] {
+            let Decryptor::Recipients(d) = Decryptor::new(File::open(&to).unwrap()).unwrap() else {
+                panic!()
+            };
+            let mut plain = Vec::new();
+            d.decrypt(iter::once(id as &dyn age::Identity))
+                .unwrap()
+                .read_to_end(&mut plain)
+                .unwrap();
+            assert_eq!(plain, data);
+        }
+    }
+}
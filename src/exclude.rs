@@ -0,0 +1,124 @@
+//! Dropping input lines that match a regular expression (`--exclude`).
+
+use regex::bytes::Regex;
+
+/// Drops the lines of a stream that match a regular expression, however the stream is split into
+/// chunks: an incomplete line is held back until its newline arrives. Only the first `cap` bytes
+/// of a line are matched against (and held back): once a line reaches that length, it is kept or
+/// dropped on the strength of them, and the rest of it follows suit.
+pub struct Excluder {
+    re: Regex,
+    cap: usize,
+    /// The (first `cap` bytes of the) current, incomplete, line.
+    line: Vec<u8>,
+    /// Once the current line has reached `cap` bytes: is it being kept?
+    keep_rest: Option<bool>,
+}
+
+impl Excluder {
+    pub fn new(re: Regex, cap: usize) -> Self {
+        assert!(cap > 0);
+        Self {
+            re,
+            cap,
+            line: Vec::new(),
+            keep_rest: None,
+        }
+    }
+
+    /// Feed `bytes`, appending the lines (and parts of lines) to keep to `out`.
+    pub fn feed(&mut self, mut bytes: &[u8], out: &mut Vec<u8>) {
+        while !bytes.is_empty() {
+            let (part, eol) = match bytes.iter().position(|b| *b == b'\n') {
+                Some(i) => (&bytes[..=i], true),
+                None => (bytes, false),
+            };
+            bytes = &bytes[part.len()..];
+            let rest = match self.keep_rest {
+                Some(_) => part,
+                None => {
+                    let n = usize::min(part.len(), self.cap - self.line.len());
+                    self.line.extend_from_slice(&part[..n]);
+                    if self.line.len() == self.cap && !(eol && n == part.len()) {
+                        self.keep_rest = Some(self.decide(out));
+                    }
+                    &part[n..]
+                }
+            };
+            if self.keep_rest == Some(true) {
+                out.extend_from_slice(rest);
+            }
+            if eol {
+                if self.keep_rest.is_none() {
+                    self.decide(out);
+                }
+                self.keep_rest = None;
+            }
+        }
+    }
+
+    /// Once input has ended, append the incomplete line held back (if any), unless it matches, to
+    /// `out`.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.keep_rest.is_none() && !self.line.is_empty() {
+            self.decide(out);
+        }
+        self.keep_rest = None;
+    }
+
+    /// Decide whether to keep the line (or the start of a line) held back, appending it to `out`
+    /// if so, and returning whether it was kept.
+    fn decide(&mut self, out: &mut Vec<u8>) -> bool {
+        let line = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+        let keep = !self.re.is_match(line);
+        if keep {
+            out.extend_from_slice(&self.line);
+        }
+        self.line.clear();
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Excluder;
+    use regex::bytes::Regex;
+
+    /// Feed `input` split into chunks of `chunk` bytes.
+    fn exclude(re: &str, cap: usize, input: &[u8], chunk: usize) -> Vec<u8> {
+        let mut e = Excluder::new(Regex::new(re).unwrap(), cap);
+        let mut out = Vec::new();
+        for c in input.chunks(chunk) {
+            e.feed(c, &mut out);
+        }
+        e.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_exclude() {
+        for (re, cap, input, expected) in [
+            ("DEBUG", 100, &b"a\nDEBUG b\nc\n"[..], &b"a\nc\n"[..]),
+            ("^$", 100, b"a\n\n\nb", b"a\nb"),
+            // An incomplete last line is matched too.
+            ("b", 100, b"a\nb", b"a\n"),
+            // Lines longer than the cap go by their first `cap` bytes.
+            ("^x", 3, b"xyzzy\nabcxyz\nxy\n", b"abcxyz\n"),
+            ("z", 3, b"xyzzy\nab\n", b"ab\n"),
+            ("zz", 3, b"xyzzy\nab\n", b"xyzzy\nab\n"),
+            // A line of exactly `cap` bytes is matched whole, newline aside.
+            ("c$", 3, b"abc\nabd\n", b"abd\n"),
+            (".", 3, b"", b""),
+        ] {
+            for chunk in 1..=input.len().max(1) {
+                assert_eq!(
+                    exclude(re, cap, input, chunk),
+                    expected,
+                    "{:?} in chunks of {}",
+                    input,
+                    chunk
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Fault injection for crash-consistency testing.
+//!
+//! If `ROTEE_FAIL_AT` is set to `<point>` or `<point>:<n>`, the process aborts the first (or
+//! `n`th) time that the named fail point is reached (or, for an error point, the operation there
+//! fails). This module only exists when the `fault-injection` feature is enabled: without it,
+//! fail points compile to nothing.
+
+use std::{
+    env, io, process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The number of times the fail point named in `ROTEE_FAIL_AT` has been reached so far.
+static HITS: AtomicUsize = AtomicUsize::new(0);
+
+/// Abort the process if `point` is the fail point named in `ROTEE_FAIL_AT` and it has now been
+/// reached the requested number of times.
+pub fn fail_point(point: &str) {
+    if reached(point) {
+        process::abort();
+    }
+}
+
+/// As `fail_point()`, but return an error, for the caller to treat as the failure of whatever it
+/// was about to do, rather than aborting.
+pub fn fail_point_error(point: &str) -> Result<(), io::Error> {
+    match reached(point) {
+        true => Err(io::Error::other(format!("injected failure at {}", point))),
+        false => Ok(()),
+    }
+}
+
+/// Is `point` the fail point named in `ROTEE_FAIL_AT`, now reached the requested number of times?
+fn reached(point: &str) -> bool {
+    let spec = match env::var("ROTEE_FAIL_AT") {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    let (name, nth) = match spec.split_once(':') {
+        Some((name, n)) => (
+            name,
+            n.parse::<usize>().expect("invalid ROTEE_FAIL_AT count"),
+        ),
+        None => (spec.as_str(), 1),
+    };
+    name == point && HITS.fetch_add(1, Ordering::Relaxed) + 1 == nth
+}
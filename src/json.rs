@@ -0,0 +1,167 @@
+//! JSON lines output (`--format json`): each line of input becomes a JSON object, on a line of its
+//! own, recording when the line was read, which stream it came from, and its contents.
+
+use crate::clock::utc_timestamp;
+use std::time::SystemTime;
+
+/// Reassembles lines from chunks of input and turns each into a JSON record.
+pub struct JsonLines {
+    /// The value of each record's `stream` field.
+    stream: &'static str,
+    /// The most bytes of a line that are kept: the rest are dropped, and the record marked as
+    /// truncated.
+    cap: usize,
+    /// The (first `cap` bytes of the) incomplete line read so far.
+    line: Vec<u8>,
+    /// When the first byte of the incomplete line was read.
+    start: Option<SystemTime>,
+    /// Were bytes of the incomplete line dropped?
+    truncated: bool,
+}
+
+impl JsonLines {
+    pub fn new(stream: &'static str, cap: usize) -> Self {
+        Self {
+            stream,
+            cap,
+            line: Vec::new(),
+            start: None,
+            truncated: false,
+        }
+    }
+
+    /// Feed `bytes`, read at `now`, appending a record to `out` for each line they complete.
+    pub fn feed(&mut self, now: SystemTime, mut bytes: &[u8], out: &mut Vec<u8>) {
+        while !bytes.is_empty() {
+            self.start.get_or_insert(now);
+            let (part, eol) = match bytes.iter().position(|b| *b == b'\n') {
+                Some(i) => (&bytes[..i], true),
+                None => (bytes, false),
+            };
+            let keep = usize::min(part.len(), self.cap - self.line.len());
+            self.line.extend_from_slice(&part[..keep]);
+            self.truncated |= keep < part.len();
+            bytes = &bytes[part.len() + usize::from(eol)..];
+            if eol {
+                self.emit(out);
+            }
+        }
+    }
+
+    /// Append a record for the incomplete line (e.g. at EOF), if there is one, to `out`.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        if self.start.is_some() {
+            self.emit(out);
+        }
+    }
+
+    fn emit(&mut self, out: &mut Vec<u8>) {
+        let start = self.start.take().unwrap();
+        out.extend_from_slice(b"{\"ts\":\"");
+        out.extend_from_slice(utc_timestamp(start, true).as_bytes());
+        out.extend_from_slice(b"\",\"stream\":");
+        escape(self.stream, out);
+        out.extend_from_slice(b",\"line\":");
+        match std::str::from_utf8(&self.line) {
+            Ok(s) => escape(s, out),
+            Err(_) => {
+                // Keep the original bytes, so that nothing is lost.
+                escape(&String::from_utf8_lossy(&self.line), out);
+                out.extend_from_slice(b",\"raw\":\"");
+                out.extend_from_slice(base64(&self.line).as_bytes());
+                out.push(b'"');
+            }
+        }
+        if self.truncated {
+            out.extend_from_slice(b",\"truncated\":true");
+        }
+        out.extend_from_slice(b"}\n");
+        self.line.clear();
+        self.truncated = false;
+    }
+}
+
+/// Append `s` to `out` as a JSON string (with quotes).
+pub fn escape(s: &str, out: &mut Vec<u8>) {
+    out.push(b'"');
+    for c in s.chars() {
+        match c {
+            '"' => out.extend_from_slice(b"\\\""),
+            '\\' => out.extend_from_slice(b"\\\\"),
+            '\n' => out.extend_from_slice(b"\\n"),
+            '\r' => out.extend_from_slice(b"\\r"),
+            '\t' => out.extend_from_slice(b"\\t"),
+            c if c < ' ' => out.extend_from_slice(format!("\\u{:04x}", u32::from(c)).as_bytes()),
+            c => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    out.push(b'"');
+}
+
+/// The standard (padded) base64 encoding of `bytes`.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for c in bytes.chunks(3) {
+        let n = c
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= c.len() {
+                s.push(char::from(ALPHABET[(n >> (18 - 6 * i)) as usize & 63]));
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, escape, JsonLines};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_escape() {
+        let mut out = Vec::new();
+        escape("a\"b\\c\n\u{1}é", &mut out);
+        assert_eq!(out, "\"a\\\"b\\\\c\\n\\u0001é\"".as_bytes());
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(&[0xff, 0xfe]), "//4=");
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let t = UNIX_EPOCH + Duration::from_millis(1500);
+        let mut j = JsonLines::new("stdout", 4);
+        let mut out = Vec::new();
+        // Lines are reassembled across chunks, and timestamped when they start.
+        j.feed(t, b"ab", &mut out);
+        assert!(out.is_empty());
+        j.feed(t + Duration::from_secs(1), b"c\nabcdef\n\xff\n", &mut out);
+        j.feed(t, b"x", &mut out);
+        j.flush(&mut out);
+        j.flush(&mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "{\"ts\":\"1970-01-01T00:00:01.500Z\",\"stream\":\"stdout\",\"line\":\"abc\"}\n",
+                "{\"ts\":\"1970-01-01T00:00:02.500Z\",\"stream\":\"stdout\",\"line\":\"abcd\",",
+                "\"truncated\":true}\n",
+                "{\"ts\":\"1970-01-01T00:00:02.500Z\",\"stream\":\"stdout\",\"line\":\"\u{fffd}\",",
+                "\"raw\":\"/w==\"}\n",
+                "{\"ts\":\"1970-01-01T00:00:01.500Z\",\"stream\":\"stdout\",\"line\":\"x\"}\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! The parts of rotee that are useful to test (and fuzz) in isolation from the binary.
+
+pub mod checksum;
+pub mod chunker;
+pub mod cidr;
+pub mod clock;
+pub mod connection;
+pub mod control;
+pub mod crlf;
+pub mod delimiters;
+pub mod diag;
+pub mod echo_queue;
+pub mod encrypt;
+pub mod exclude;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod json;
+pub mod matches;
+pub mod pipe_copy;
+pub mod pty;
+pub mod read_buf;
+pub mod session;
+pub mod sink;
+pub mod size;
+pub mod squeeze;
+pub mod syslog;
+pub mod temp;
+pub mod thin;
+pub mod timing;
+pub mod utf8;
+pub mod verify;
+pub mod watchdog;
+pub mod xattrs;
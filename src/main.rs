@@ -1,228 +1,8817 @@
+mod subcommands;
+
 use app::{App, Opt};
-use libc::{sigemptyset, sigfillset, sigprocmask, sigset_t, SIG_BLOCK, SIG_SETMASK};
+use libc::{
+    c_char, c_int, fchown, getgrnam_r, getpwnam_r, gid_t, group, nfds_t, passwd, poll, pollfd,
+    sigaction, sigemptyset, sigfillset, sigprocmask, sigset_t, uid_t, utimensat, AT_FDCWD, POLLIN,
+    SIG_BLOCK, SIG_SETMASK,
+};
+use log::{debug, info, warn, LevelFilter};
+use regex::bytes::Regex;
+use rotee::{
+    checksum::Crc32,
+    chunker::Chunker,
+    cidr::Cidr,
+    clock::{utc_timestamp, Clock, DayTracker, Interval, SystemClock},
+    connection::Connection,
+    control::{Command as ControlCommand, CommandLines},
+    crlf::CrlfFilter,
+    delimiters::DelimiterCounter,
+    diag,
+    echo_queue::{EchoQueue, Overflow},
+    encrypt::Recipients,
+    exclude::Excluder,
+    json::JsonLines,
+    matches::MatchCounter,
+    pipe_copy::PipeCopy,
+    pty::{self, PtyMaster},
+    read_buf::ReadBuf,
+    session::{Action, Reason, Records, Session},
+    sink::Sink,
+    size::{parse_size, retain_layout},
+    squeeze::Squeezer,
+    syslog::{self, Syslog},
+    temp::{self, TempFile},
+    thin::Thinning,
+    timing,
+    utf8::{Invalid, Utf8Filter},
+    watchdog::{Stall, Watchdog},
+    xattrs::{self, FileMeta},
+};
 use std::{
+    collections::BTreeSet,
     default::Default,
+    env,
     error::Error,
-    fs::{rename, File},
-    io::{self, Read, Write},
+    ffi::CString,
+    fs::{self, rename, File, OpenOptions, Permissions},
+    io::{self, BufRead, BufReader, Read, Seek, Write},
     mem::MaybeUninit,
-    path::PathBuf,
-    process, ptr,
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        io::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::{UnixListener, UnixStream},
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::{self, Child, Command, ExitStatus, Stdio},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// Mark a point at which a fault can be injected (see the `fault` module). Unless the
+/// `fault-injection` feature is enabled, fail points compile to nothing (their arguments are
+/// type-checked, but never evaluated).
+#[cfg(feature = "fault-injection")]
+macro_rules! fail_point {
+    ($($arg:tt)*) => {
+        rotee::fault::fail_point(&format!($($arg)*))
+    };
+}
+#[cfg(not(feature = "fault-injection"))]
+macro_rules! fail_point {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format!($($arg)*);
+        }
+    };
+}
+#[cfg(feature = "fault-injection")]
+macro_rules! fail_point_error {
+    ($($arg:tt)*) => {
+        rotee::fault::fail_point_error(&format!($($arg)*))
+    };
+}
+#[cfg(not(feature = "fault-injection"))]
+macro_rules! fail_point_error {
+    ($($arg:tt)*) => {
+        Ok::<(), io::Error>(())
+    };
+}
+
+#[derive(Clone)]
 struct Config {
     /// The prefix of output filenames.
     file_prefix: String,
+    /// Put output files in a directory named after the current date, starting a fresh series each
+    /// day.
+    date_dir: bool,
     /// The size (in bytes) at which files will be rotated.
     file_size: usize,
+    /// Compute `file_size` so that the input is split into this many files.
+    split_into: Option<usize>,
+    /// The total size of the input (default: the remaining size of stdin, if it is a file).
+    input_size: Option<u64>,
+    /// Read input from the connections to a Unix domain stream socket bound to this path, rather
+    /// than from stdin.
+    listen_unix: Option<String>,
+    /// The mode (in octal) to give the `listen_unix` socket.
+    listen_mode: Option<String>,
+    /// `listen_mode` parsed.
+    listen_perms: Option<u32>,
+    /// Write a marker line when each connection opens and closes.
+    connection_markers: bool,
+    /// Prefix each line with the id of the connection it came from.
+    connection_tags: bool,
+    /// Read input from the connections to a TCP socket bound to this address (`[ADDR:]PORT`, with
+    /// `ADDR` defaulting to 127.0.0.1), one connection at a time, rather than from stdin.
+    listen_tcp: Option<String>,
+    /// `listen_tcp` parsed.
+    listen_tcp_addr: Option<SocketAddr>,
+    /// Only accept TCP connections from these address ranges (comma separated, e.g.
+    /// `10.0.0.0/8,::1`).
+    listen_allow: Option<String>,
+    /// `listen_allow` parsed (empty if connections are accepted from anywhere).
+    allowed_peers: Vec<Cidr>,
+    /// What to do with TCP connections made while another is active: `queue` or `refuse`.
+    listen_busy: String,
+    /// Is `listen_busy` `refuse`?
+    refuse_busy: bool,
+    /// The minimum time between rotations (e.g. `500ms`, `10s`, `5m`).
+    min_rotate_interval: Option<String>,
+    /// `min_rotate_interval` parsed into a `Duration`.
+    rotate_interval: Option<Duration>,
     /// The maximum number of files to use in rotation.
     num_files: usize,
+    /// `-s`, if given (otherwise, `file_size` is the default or derived from `retain`).
+    file_size_arg: Option<usize>,
+    /// `-n`, if given (otherwise, `num_files` is the default or derived from `retain`).
+    num_files_arg: Option<usize>,
+    /// Keep about this many bytes of output (e.g. `5G`), deriving `-s` and `-n` (or whichever
+    /// isn't given).
+    retain: Option<String>,
+    /// Append rotee's own diagnostics to this file, rather than writing them to stderr.
+    log_file: Option<String>,
+    /// The signal that reopens `log_file` (e.g. after it has been rotated away).
+    log_reopen_signal: Option<String>,
+    /// `log_reopen_signal` as a signal number.
+    log_reopen_signo: Option<c_int>,
+    /// Leave temporary files in place: those of failed atomic replacements, and those that
+    /// earlier runs left behind.
+    keep_temps: bool,
+    /// If the output directory disappears, recreate it (once per file created) rather than fail.
+    recreate_dir: bool,
+    /// Stop (successfully) after running for this long (e.g. `10m`).
+    max_runtime: Option<String>,
+    /// `max_runtime` as a `Duration`.
+    runtime_limit: Option<Duration>,
+    /// At EOF, rotate the active file (unless it's empty), so that all output is in rotated files.
+    rotate_at_eof: bool,
+    /// At startup, rotate a non-empty newest file left by an earlier run, rather than truncating it.
+    rotate_at_start: bool,
+    /// Decide when to rotate by the active file's size on disk, which something else may have
+    /// changed, rather than by what has been written to it.
+    stat_size: bool,
+    /// Start each file with a line giving its number (counting from 1 for each run) and when it
+    /// was started.
+    header_line: bool,
+    /// Hold each file in memory until it is complete, then write it in one go, so that files only
+    /// ever appear on disk whole.
+    buffer_files: bool,
+    /// With `buffer_files`, make each file durable before it appears on disk.
+    buffer_files_sync: bool,
+    /// When rotating, read the file being rotated back from storage, and check that it is what
+    /// was written to it.
+    verify: bool,
+    /// When rotating, stamp the file with extended attributes describing it.
+    xattrs: bool,
+    /// Keep a `{prefix}active` file naming the active file.
+    active_marker: bool,
+    /// Remove rotated files last modified longer ago than this (e.g. `30d`).
+    max_age: Option<String>,
+    /// `max_age` as a `Duration`.
+    max_file_age: Option<Duration>,
+    /// Keep ever sparser samples of older rotated files (e.g. `10:1,100:10,1000:100`).
+    thin: Option<String>,
+    /// `thin` parsed.
+    thinning: Option<Thinning>,
+    /// A shell command to copy everything written to the output files to (e.g. `xz > all.xz`).
+    full_copy_compressed: Option<String>,
+    /// A FIFO to read commands (see `rotee::control`) from, created if need be.
+    control_fifo: Option<String>,
+    /// age public keys to encrypt sealed (i.e. rotated) files to.
+    encrypt_recipient: Vec<String>,
+    /// `encrypt_recipient` parsed, if there are any.
+    recipients: Option<Recipients>,
     /// Do not echo input back to stdout.
     no_echo: bool,
+    /// Do not write any output files: just echo stdin to stdout.
+    no_files: bool,
     /// Buffer size used for reading from stdin.
     buffer_size: usize,
+    /// The most bytes to ask for in any one read from stdin.
+    max_read: Option<usize>,
+    /// When reading a regular file, only treat a zero-length read as EOF if a second read, after
+    /// a short delay, also returns nothing.
+    confirm_eof: bool,
+    /// Only flush the echo to stdout at the end of each line, rather than after every read.
+    echo_flush_lines: bool,
+    /// Echo through a queue of this many bytes, written out by a thread of its own.
+    echo_queue_size: Option<usize>,
+    /// What to do when the echo queue is full: `block` (wait for it to drain) or `drop` (drop
+    /// what doesn't fit).
+    echo_overflow: String,
+    /// The size of the echo queue and what to do when it's full, if there is one.
+    echo_queue: Option<(usize, Overflow)>,
+    /// Read stdin a line at a time rather than in `buffer_size` blocks.
+    line_input: bool,
+    /// The longest line (in bytes) that will be read in one go in line input mode.
+    max_line_bytes: usize,
+    /// In line input mode, written after the first `max_line_bytes` of a longer line.
+    truncation_marker: Option<String>,
+    /// The user name (or uid) to give ownership of output files to.
+    owner: Option<String>,
+    /// The group name (or gid) to give ownership of output files to.
+    group: Option<String>,
+    /// The mode (in octal) to give rotated files, which are no longer written to.
+    finalized_mode: Option<String>,
+    /// `finalized_mode` parsed.
+    finalized_perms: Option<u32>,
+    /// Remove the write permission bits of rotated files.
+    seal_readonly: bool,
+    /// The user name (or uid) to switch to once the output files are open.
+    setuid: Option<String>,
+    /// The group name (or gid) to switch to once the output files are open.
+    setgid: Option<String>,
+    /// Check that every write, and every output file, has exactly the number of bytes intended.
+    strict_writes: bool,
+    /// The number of failed writes to the output files to carry on after, skipping what they
+    /// would have written.
+    max_write_errors: usize,
+    /// Report writes to the output files that take longer than this.
+    write_timeout: Option<String>,
+    /// What to do about a write that takes longer than `write_timeout`: `warn` or `abort`.
+    write_timeout_action: Option<String>,
+    /// `write_timeout` parsed, and what to do when it's exceeded.
+    write_watchdog: Option<(Duration, Stall)>,
+    /// A file to create once all input has been consumed without error.
+    done_file: Option<String>,
+    /// A file to write our PID to while running.
+    pid_file: Option<String>,
+    /// What to do about an existing `pid_file` whose process has gone: `error` or `replace`.
+    stale_pid_file: String,
+    /// Is `stale_pid_file` `replace`?
+    replace_stale_pid: bool,
+    /// A file whose mtime is updated after every rotation.
+    heartbeat_file: Option<String>,
+    /// Also update `heartbeat_file` on reading input, at most this often.
+    heartbeat_interval: Option<String>,
+    /// `heartbeat_interval` parsed into a `Duration`.
+    heartbeat_every: Option<Duration>,
+    /// Rotate after this many lines have matched `match_pattern`.
+    rotate_after_matches: Option<usize>,
+    /// The regular expression that lines are matched against for `rotate_after_matches`.
+    match_pattern: Option<String>,
+    /// `match_pattern` compiled.
+    match_regex: Option<Regex>,
+    /// Rotate after this many occurrences of `split_byte`.
+    split_every: Option<usize>,
+    /// The byte counted for `split_every` (by default a newline).
+    split_byte: Option<String>,
+    /// `split_byte` parsed.
+    split_delimiter: u8,
+    /// Write files under this prefix, only moving them to `file_prefix` when committed.
+    staging_prefix: Option<String>,
+    /// The signal (e.g. `USR1`) that commits the staged files.
+    commit_signal: String,
+    /// Commit even if nothing has been written since the last commit.
+    rotate_empty: bool,
+    /// Defer rotations triggered by `--rotate-after-matches` and `--split-every`, and commits,
+    /// until the active file holds at least this many bytes.
+    min_size: usize,
+    /// `commit_signal` as a signal number.
+    commit_signo: c_int,
+    /// The file size (`-s`) of the alternate profile, which `profile_signal` switches to and from.
+    alt_file_size: Option<usize>,
+    /// The buffer size (`-b`) of the alternate profile.
+    alt_buffer_size: Option<usize>,
+    /// Whether the alternate profile echoes input: `on` or `off`.
+    alt_echo: Option<String>,
+    /// `alt_echo` parsed.
+    alt_echo_on: Option<bool>,
+    /// The signal (default `USR2`) that switches between the normal and alternate profiles, from
+    /// the next file on.
+    profile_signal: Option<String>,
+    /// `profile_signal` as a signal number.
+    profile_signo: c_int,
+    /// Print the signals that are handled, and what each does, and exit.
+    list_signals: bool,
+    /// Print the files that the first few rotations would create, rename, and remove, and exit.
+    explain_naming: bool,
+    /// A command (and its arguments) to run, whose stdout is read instead of stdin.
+    exec: Vec<String>,
+    /// In exec mode, capture the command's stderr into a separate series with this prefix (or, with
+    /// `stderr_fd`, what is read from that fd).
+    stderr_prefix: Option<String>,
+    /// An fd to read alongside stdin, as if it were a command's stderr (`--stderr-fd`).
+    stderr_fd_arg: Option<usize>,
+    /// `stderr_fd_arg` as an fd.
+    stderr_fd: Option<RawFd>,
+    /// Fds that everything written to the files is also copied to (`--copy-fd`).
+    copy_fd: Vec<usize>,
+    /// `copy_fd` as fds.
+    copy_fds: Vec<RawFd>,
+    /// The file size for the stderr series (default: `file_size`).
+    stderr_file_size: Option<usize>,
+    /// The number of files for the stderr series (default: `num_files`).
+    stderr_num_files: Option<usize>,
+    /// In exec mode, the comma separated signals to forward to the command.
+    forward_signals: String,
+    /// `forward_signals` as signal numbers.
+    forward_signos: Vec<c_int>,
+    /// In exec mode, when to restart the command: `no` or `on-failure[:max-restarts]`.
+    restart: String,
+    /// The maximum number of times to restart the command (`usize::MAX` for no limit).
+    max_restarts: usize,
+    /// In exec mode, run the command on a pseudo-terminal rather than a pipe.
+    pty: bool,
+    /// The format of output: `raw` (input as is) or `json` (a JSON record per line).
+    format: String,
+    /// Is `format` `json`?
+    json: bool,
+    /// What to do with invalid UTF-8 in input: `pass` (write it as is), `replace` (with U+FFFD),
+    /// or `reject` (fail).
+    utf8: String,
+    /// `utf8` as what to do, if input is to be validated.
+    utf8_invalid: Option<Invalid>,
+    /// Line endings in output files: `keep` (as is) or `lf` (`\r\n` rewritten to `\n`).
+    crlf: String,
+    /// Is `crlf` `lf`?
+    crlf_lf: bool,
+    /// Rewrite `\r\n` to `\n` in the echo too (rather than echoing input as is).
+    crlf_echo: bool,
+    /// Write each chunk of input read as a frame: its length (4 bytes, big-endian), then its bytes.
+    frame_length: bool,
+    /// Start each line with the time since rotee started (e.g. `+12.345s `).
+    prefix_relative: bool,
+    /// When rotee started, which `prefix_relative` times are relative to.
+    started: SystemTime,
+    /// Also send each line of input to syslog, with this `FACILITY.PRIORITY` (e.g. `user.info`).
+    syslog: Option<String>,
+    /// `syslog` as the `PRI` value of syslog messages.
+    syslog_pri: Option<c_int>,
+    /// Drop lines matching this regular expression from `exclude_target`.
+    exclude: Option<String>,
+    /// `exclude` compiled.
+    exclude_regex: Option<Regex>,
+    /// What `--exclude` drops lines from: `file`, `echo`, or `both`.
+    exclude_target: String,
+    /// Does `exclude_target` include the files?
+    exclude_files: bool,
+    /// Does `exclude_target` include the echo?
+    exclude_echo: bool,
+    /// In line input mode, write a run of identical lines as one line and a summary.
+    squeeze_repeats: bool,
+    /// Squeeze repeats in the echo too (rather than echoing every line).
+    squeeze_echo: bool,
+    /// Summarise a run of repeats that has gone on for this long (e.g. `1m`), even if it hasn't
+    /// ended.
+    squeeze_interval: Option<String>,
+    /// `squeeze_interval` as a `Duration`.
+    squeeze_every: Option<Duration>,
+    /// Write a mark line after each period of this length (e.g. `20m`) in which no input arrived.
+    mark: Option<String>,
+    /// `mark` as a `Duration`.
+    mark_every: Option<Duration>,
+    /// The text of mark lines, in which `{time}` is replaced by the time.
+    mark_text: String,
+    /// Echo mark lines as well as writing them to the output files.
+    mark_echo: bool,
+    /// Record the timing of writes into files named `timing_file<N>`, alongside the output files.
+    timing_file: Option<String>,
+    /// Write everything to this one file, never rotating it.
+    single_file: Option<String>,
+    /// Append to a series that other `--shared` processes append to too, coordinating through the
+    /// lock file `file_prefix` + `lock`.
+    shared: bool,
+    /// Warn (once) when the `single_file` grows beyond this many bytes.
+    warn_size: Option<u64>,
+    /// Periodically make the output durable, and record the number of bytes written in this file.
+    checkpoint_file: Option<String>,
+    /// How often (e.g. `30s`) to write a checkpoint.
+    checkpoint_interval: Option<String>,
+    /// `checkpoint_interval` as a `Duration`.
+    checkpoint_every: Option<Duration>,
+    /// The resolved `(uid, gid)` to `fchown` output files to (-1 means "leave unchanged").
+    file_owner: Option<(uid_t, gid_t)>,
+    /// `setuid` and `setgid` resolved.
+    run_as: Option<(Option<uid_t>, gid_t)>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             file_prefix: String::from("rotee."),
+            date_dir: false,
             file_size: 1024 * 1024 * 8, // 8 MiB
+            split_into: None,
+            input_size: None,
+            listen_unix: None,
+            listen_mode: None,
+            listen_perms: None,
+            listen_tcp: None,
+            listen_tcp_addr: None,
+            listen_allow: None,
+            allowed_peers: Vec::new(),
+            listen_busy: String::from("queue"),
+            refuse_busy: false,
+            connection_markers: false,
+            connection_tags: false,
+            min_rotate_interval: None,
+            rotate_interval: None,
             num_files: 8,
+            file_size_arg: None,
+            num_files_arg: None,
+            retain: None,
+            log_file: None,
+            log_reopen_signal: None,
+            log_reopen_signo: None,
+            keep_temps: false,
+            recreate_dir: false,
+            max_runtime: None,
+            rotate_at_eof: false,
+            rotate_at_start: false,
+            stat_size: false,
+            header_line: false,
+            buffer_files: false,
+            buffer_files_sync: false,
+            verify: false,
+            xattrs: false,
+            active_marker: false,
+            runtime_limit: None,
+            max_age: None,
+            max_file_age: None,
+            thin: None,
+            thinning: None,
+            full_copy_compressed: None,
+            control_fifo: None,
+            encrypt_recipient: Vec::new(),
+            recipients: None,
             no_echo: false,
+            no_files: false,
             buffer_size: 1024 * 1024, // 1 MiB
+            max_read: None,
+            confirm_eof: false,
+            echo_flush_lines: false,
+            echo_queue_size: None,
+            echo_overflow: String::from("block"),
+            echo_queue: None,
+            line_input: false,
+            max_line_bytes: 1024 * 1024, // 1 MiB
+            truncation_marker: None,
+            owner: None,
+            finalized_mode: None,
+            finalized_perms: None,
+            seal_readonly: false,
+            group: None,
+            setuid: None,
+            setgid: None,
+            strict_writes: false,
+            max_write_errors: 0,
+            write_timeout: None,
+            write_timeout_action: None,
+            write_watchdog: None,
+            done_file: None,
+            pid_file: None,
+            stale_pid_file: String::from("replace"),
+            replace_stale_pid: true,
+            heartbeat_file: None,
+            heartbeat_interval: None,
+            heartbeat_every: None,
+            rotate_after_matches: None,
+            split_every: None,
+            split_byte: None,
+            split_delimiter: b'\n',
+            match_pattern: None,
+            match_regex: None,
+            staging_prefix: None,
+            commit_signal: String::from("WINCH"),
+            alt_file_size: None,
+            alt_buffer_size: None,
+            alt_echo: None,
+            alt_echo_on: None,
+            profile_signal: None,
+            list_signals: false,
+            explain_naming: false,
+            profile_signo: libc::SIGUSR2,
+            commit_signo: libc::SIGWINCH,
+            rotate_empty: false,
+            min_size: 0,
+            exec: Vec::new(),
+            stderr_prefix: None,
+            stderr_fd_arg: None,
+            stderr_fd: None,
+            copy_fd: Vec::new(),
+            copy_fds: Vec::new(),
+            stderr_file_size: None,
+            stderr_num_files: None,
+            forward_signals: String::from("TERM,INT,HUP,QUIT"),
+            forward_signos: Vec::new(),
+            restart: String::from("no"),
+            max_restarts: 0,
+            pty: false,
+            exclude: None,
+            exclude_regex: None,
+            exclude_target: String::from("file"),
+            exclude_files: false,
+            exclude_echo: false,
+            squeeze_repeats: false,
+            squeeze_echo: false,
+            squeeze_interval: None,
+            squeeze_every: None,
+            mark: None,
+            mark_every: None,
+            mark_text: String::from("-- MARK {time} --"),
+            mark_echo: false,
+            format: String::from("raw"),
+            json: false,
+            utf8: String::from("pass"),
+            utf8_invalid: None,
+            crlf: String::from("keep"),
+            crlf_lf: false,
+            crlf_echo: false,
+            frame_length: false,
+            prefix_relative: false,
+            started: UNIX_EPOCH,
+            syslog: None,
+            syslog_pri: None,
+            timing_file: None,
+            single_file: None,
+            shared: false,
+            warn_size: None,
+            checkpoint_file: None,
+            checkpoint_interval: None,
+            checkpoint_every: None,
+            file_owner: None,
+            run_as: None,
         }
     }
 }
 
-fn fatal(m: &str) {
-    eprintln!("error: {}", m);
-    process::exit(1);
-}
+/// Options that can't be used together: an option (if given, see `Config::given()`) with any of
+/// those listed after it. Options are named as in the error message saying so.
+const CONFLICTS: &[(&str, &[&str])] = &[
+    (
+        "--retain",
+        &["--no-files (-N)", "--single-file", "--split-into", "--thin"],
+    ),
+    (
+        "--utf8 replace and reject",
+        &["--no-files (-N)", "--format json", "--frame-length"],
+    ),
+    (
+        "--echo-queue-size and --echo-overflow",
+        &["--no-files (-N)"],
+    ),
+    (
+        "--crlf lf",
+        &["--no-files (-N)", "--format json", "--frame-length"],
+    ),
+    (
+        "--frame-length",
+        &["--line-input (-l)", "--format json", "--mark"],
+    ),
+    (
+        "--prefix-relative",
+        &[
+            "--format json",
+            "--frame-length",
+            "--listen-unix",
+            "--listen-tcp",
+            "--exclude",
+            "--squeeze-repeats",
+        ],
+    ),
+    ("--truncation-marker", &["--format json"]),
+    (
+        "--listen-unix",
+        &[
+            "a command (--)",
+            "--line-input (-l)",
+            "--format json",
+            "--frame-length",
+            "--syslog",
+            "--split-into",
+        ],
+    ),
+    (
+        "--listen-tcp",
+        &[
+            "--listen-unix",
+            "a command (--)",
+            "--line-input (-l)",
+            "--format json",
+            "--frame-length",
+            "--syslog",
+            "--split-into",
+        ],
+    ),
+    (
+        "--split-every",
+        &[
+            "--rotate-after-matches",
+            "--format json",
+            "--frame-length",
+            "--squeeze-repeats",
+        ],
+    ),
+    (
+        "--stderr-fd",
+        &["a command (--)", "--listen-unix", "--listen-tcp"],
+    ),
+    ("--copy-fd", &["--no-files (-N)"]),
+    ("--stderr-prefix", &["--no-files (-N)", "--line-input (-l)"]),
+    ("--max-runtime", &["--no-files (-N)"]),
+    (
+        "--rotate-at-eof",
+        &["--no-files (-N)", "--single-file", "--split-into"],
+    ),
+    (
+        "--rotate-at-start",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--shared",
+            "--buffer-files",
+        ],
+    ),
+    (
+        "--stat-size",
+        &["--no-files (-N)", "--single-file", "--shared"],
+    ),
+    (
+        "--buffer-files",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--shared",
+            "--staging-prefix",
+            "--date-dir (-D)",
+            "--timing-file",
+            "--checkpoint-file",
+            "--strict-writes (-S)",
+            "--stat-size",
+            "--rotate-at-eof",
+            "--min-rotate-interval",
+        ],
+    ),
+    (
+        "--verify",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--shared",
+            "--stat-size",
+            "--buffer-files",
+            "--encrypt-recipient",
+        ],
+    ),
+    (
+        "--xattrs",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--shared",
+            "--stat-size",
+            "--buffer-files",
+            "--encrypt-recipient",
+        ],
+    ),
+    ("--explain-naming", &["--no-files (-N)", "--single-file"]),
+    ("--recreate-dir", &["--no-files (-N)", "--single-file"]),
+    (
+        "--active-marker",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--shared",
+            "--buffer-files",
+        ],
+    ),
+    (
+        "--max-write-errors",
+        &["--no-files (-N)", "--strict-writes (-S)", "--xattrs"],
+    ),
+    ("--write-timeout", &["--no-files (-N)", "--buffer-files"]),
+    (
+        "--header-line",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--shared",
+            "--format json",
+            "--frame-length",
+        ],
+    ),
+    (
+        "--shared",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--split-into",
+            "--staging-prefix",
+            "--date-dir (-D)",
+            "--checkpoint-file",
+            "--timing-file",
+            "--control-fifo",
+            "--encrypt-recipient",
+            "--rotate-after-matches",
+            "--split-every",
+            "--min-rotate-interval",
+            "--min-size",
+            "--rotate-at-eof",
+            "--strict-writes (-S)",
+        ],
+    ),
+    ("--max-age", &["--no-files (-N)", "--single-file"]),
+    (
+        "--thin",
+        &["--no-files (-N)", "--single-file", "--split-into"],
+    ),
+    ("--full-copy-compressed", &["--no-files (-N)"]),
+    ("--control-fifo", &["--no-files (-N)", "--single-file"]),
+    (
+        "--encrypt-recipient",
+        &[
+            "--no-files (-N)",
+            "--single-file",
+            "--staging-prefix",
+            "--date-dir (-D)",
+        ],
+    ),
+    (
+        "--single-file",
+        &[
+            "--no-files (-N)",
+            "--date-dir (-D)",
+            "--staging-prefix",
+            "--rotate-after-matches",
+            "--split-every",
+            "--split-into",
+            "--timing-file",
+            "--stderr-prefix",
+        ],
+    ),
+    (
+        "--timing-file",
+        &["--no-files (-N)", "--date-dir (-D)", "--staging-prefix"],
+    ),
+    (
+        "--alt-file-size, --alt-buf-size, and --alt-echo",
+        &["--no-files (-N)", "--single-file", "--shared"],
+    ),
+    ("--checkpoint-file", &["--no-files (-N)"]),
+    (
+        "--exclude",
+        &[
+            "--no-files (-N)",
+            "--format json",
+            "--frame-length",
+            "--squeeze-repeats",
+            "--utf8",
+            "--crlf lf",
+        ],
+    ),
+    ("--squeeze-repeats", &["--no-files (-N)", "--format json"]),
+    ("--mark", &["--no-files (-N)", "--format json"]),
+    ("--finalized-mode", &["--no-files (-N)", "--single-file"]),
+    ("--seal-readonly", &["--no-files (-N)", "--single-file"]),
+    ("--setuid and --setgid", &["--owner (-o)", "--group (-g)"]),
+];
 
-fn outfile_path(prefix: &str, suffix: usize) -> PathBuf {
-    PathBuf::from(format!("{}{}", prefix, suffix))
-}
+impl Config {
+    /// The configuration of the `--stderr-prefix` series. Commits (`--staging-prefix`), timing
+    /// files (`--timing-file`), checkpoints (`--checkpoint-file`), thinning (`--thin`), the
+    /// control FIFO (`--control-fifo`), the full copy (`--full-copy-compressed`), the copies
+    /// (`--copy-fd`), the runtime limit (`--max-runtime`), and the alternate profile
+    /// (`--profile-signal`) only apply to the main series.
+    fn stderr_config(&self) -> Config {
+        Config {
+            file_prefix: self.stderr_prefix.clone().unwrap_or_default(),
+            file_size: self.stderr_file_size.unwrap_or(self.file_size),
+            num_files: self.stderr_num_files.unwrap_or(self.num_files),
+            staging_prefix: None,
+            timing_file: None,
+            checkpoint_file: None,
+            checkpoint_every: None,
+            thinning: None,
+            control_fifo: None,
+            full_copy_compressed: None,
+            copy_fds: Vec::new(),
+            runtime_limit: None,
+            alt_file_size: None,
+            alt_buffer_size: None,
+            alt_echo_on: None,
+            ..self.clone()
+        }
+    }
 
-fn rotate(config: &Config, old_file: File, all_sigs: sigset_t) -> Result<File, Box<dyn Error>> {
-    // `rotate_inner()` must not be interrupted, or output files may go missing. We block signals
-    // that would kill us (the ones we can) until we are done rotating.
-    //
-    // We can use a full signal block set here. `sigprocmask` will ignore the unmaskable ones.
-    let mut old_sigs = MaybeUninit::uninit();
-    if unsafe { sigprocmask(SIG_BLOCK, &all_sigs, old_sigs.as_mut_ptr()) } == -1 {
-        return Err("sigprocmask failed".into());
+    /// Is there an alternate profile for `--profile-signal` to switch to?
+    fn alt_profile(&self) -> bool {
+        self.alt_file_size.is_some() || self.alt_buffer_size.is_some() || self.alt_echo_on.is_some()
     }
-    let old_sigs = unsafe { old_sigs.assume_init() };
 
-    // Signals are now blocked. Do the rotation.
-    let res = rotate_inner(config, old_file);
+    /// Check the options for invalid values and conflicts, and derive the settings that depend on
+    /// them (e.g. the number of files from `--retain` or `--thin`).
+    fn validate(&mut self) -> Result<(), String> {
+        self.check_conflicts()?;
+        self.validate_retention()?;
+        self.validate_input()?;
+        self.validate_rotation()?;
+        self.validate_network()?;
+        self.validate_exec()?;
+        self.validate_files()?;
+        self.validate_encryption()?;
+        self.validate_signals()
+    }
 
-    // Restore the old signal mask.
-    if unsafe { sigprocmask(SIG_SETMASK, &old_sigs, ptr::null_mut()) } == -1 {
-        return Err("sigprocmask failed".into());
+    /// Is the option that `CONFLICTS` calls `name` in effect?
+    fn given(&self, name: &str) -> bool {
+        match name {
+            "a command (--)" => !self.exec.is_empty(),
+            "--active-marker" => self.active_marker,
+            "--alt-file-size, --alt-buf-size, and --alt-echo" => {
+                self.alt_file_size.is_some()
+                    || self.alt_buffer_size.is_some()
+                    || self.alt_echo.is_some()
+            }
+            "--buffer-files" => self.buffer_files,
+            "--checkpoint-file" => self.checkpoint_file.is_some(),
+            "--control-fifo" => self.control_fifo.is_some(),
+            "--copy-fd" => !self.copy_fd.is_empty(),
+            "--crlf lf" => self.crlf == "lf",
+            "--date-dir (-D)" => self.date_dir,
+            "--echo-queue-size and --echo-overflow" => {
+                self.echo_queue_size.is_some() || self.echo_overflow == "drop"
+            }
+            "--encrypt-recipient" => !self.encrypt_recipient.is_empty(),
+            "--exclude" => self.exclude.is_some(),
+            "--explain-naming" => self.explain_naming,
+            "--finalized-mode" => self.finalized_mode.is_some(),
+            "--format json" => self.format == "json",
+            "--frame-length" => self.frame_length,
+            "--full-copy-compressed" => self.full_copy_compressed.is_some(),
+            "--group (-g)" => self.group.is_some(),
+            "--header-line" => self.header_line,
+            "--line-input (-l)" => self.line_input,
+            "--listen-tcp" => self.listen_tcp.is_some(),
+            "--listen-unix" => self.listen_unix.is_some(),
+            "--mark" => self.mark.is_some(),
+            "--max-age" => self.max_age.is_some(),
+            "--max-runtime" => self.max_runtime.is_some(),
+            "--max-write-errors" => self.max_write_errors > 0,
+            "--min-rotate-interval" => self.min_rotate_interval.is_some(),
+            "--min-size" => self.min_size > 0,
+            "--no-files (-N)" => self.no_files,
+            "--owner (-o)" => self.owner.is_some(),
+            "--prefix-relative" => self.prefix_relative,
+            "--recreate-dir" => self.recreate_dir,
+            "--retain" => self.retain.is_some(),
+            "--rotate-after-matches" => self.rotate_after_matches.is_some(),
+            "--rotate-at-eof" => self.rotate_at_eof,
+            "--rotate-at-start" => self.rotate_at_start,
+            "--seal-readonly" => self.seal_readonly,
+            "--setuid and --setgid" => self.setuid.is_some() || self.setgid.is_some(),
+            "--shared" => self.shared,
+            "--single-file" => self.single_file.is_some(),
+            "--split-every" => self.split_every.is_some(),
+            "--split-into" => self.split_into.is_some(),
+            "--squeeze-repeats" => self.squeeze_repeats,
+            "--staging-prefix" => self.staging_prefix.is_some(),
+            "--stat-size" => self.stat_size,
+            "--stderr-fd" => self.stderr_fd_arg.is_some(),
+            "--stderr-prefix" => self.stderr_prefix.is_some(),
+            "--strict-writes (-S)" => self.strict_writes,
+            "--syslog" => self.syslog.is_some(),
+            "--thin" => self.thin.is_some(),
+            "--timing-file" => self.timing_file.is_some(),
+            "--truncation-marker" => self.truncation_marker.is_some(),
+            "--utf8" | "--utf8 replace and reject" => {
+                self.utf8 == "replace" || self.utf8 == "reject"
+            }
+            "--verify" => self.verify,
+            "--write-timeout" => self.write_timeout.is_some(),
+            "--xattrs" => self.xattrs,
+            _ => unreachable!("unknown option {} in CONFLICTS", name),
+        }
     }
 
-    res.map_err(|e| e.into())
-}
+    /// Check that no option in `CONFLICTS` is given with any of those it conflicts with.
+    fn check_conflicts(&self) -> Result<(), String> {
+        for &(opt, others) in CONFLICTS {
+            if self.given(opt) && others.iter().any(|o| self.given(o)) {
+                return Err(format!("{} can't be used with {}", opt, or_list(others)));
+            }
+        }
+        Ok(())
+    }
 
-/// Rotate the output files, returning the freshly created file to use next.
-fn rotate_inner(config: &Config, old_file: File) -> Result<File, io::Error> {
-    drop(old_file);
+    /// Check and derive the options that bound how much is kept: `--retain`, `-n`, `--thin`, and
+    /// `--max-age`.
+    fn validate_retention(&mut self) -> Result<(), String> {
+        match &self.retain {
+            Some(r) => {
+                let Some(budget) = parse_size(r) else {
+                    return Err(format!("invalid --retain size '{}'", r));
+                };
+                let (s, n) = retain_layout(budget, self.file_size_arg, self.num_files_arg)?;
+                self.file_size = s;
+                self.num_files = n;
+                debug!("--retain {}: {} files (-n) of {} bytes (-s)", r, n, s);
+            }
+            None => {
+                self.file_size = self.file_size_arg.unwrap_or(self.file_size);
+                self.num_files = self.num_files_arg.unwrap_or(self.num_files);
+            }
+        }
 
-    for i in (0..(config.num_files - 1)).rev() {
-        let old_path = outfile_path(&config.file_prefix, i);
-        if old_path.exists() {
-            let new_path = outfile_path(&config.file_prefix, i + 1);
-            rename(old_path, new_path)?;
+        if self.num_files == 0 {
+            return Err("number of files (-n) must be non-zero".into());
         }
+
+        if let Some(s) = &self.thin {
+            let t = Thinning::parse(s)?;
+            // The last tier's limit is the highest index kept, so it replaces `-n`.
+            if let Some(n) = self.num_files_arg.filter(|&n| n != t.limit() + 1) {
+                return Err(format!(
+                    "-n {} conflicts with --thin {}, which keeps files up to {} (-n {})",
+                    n,
+                    s,
+                    outfile_path(&self.file_prefix, t.limit()).display(),
+                    t.limit() + 1
+                ));
+            }
+            self.num_files = t.limit() + 1;
+            self.thinning = Some(t);
+        }
+
+        if let Some(s) = &self.max_age {
+            match parse_duration(s)? {
+                d if d.is_zero() => return Err("--max-age must be non-zero".into()),
+                d => self.max_file_age = Some(d),
+            }
+        }
+        Ok(())
     }
-    Ok(File::create(outfile_path(&config.file_prefix, 0))?)
-}
 
-fn main() {
-    let mut config = Config::default();
+    /// Check and derive the options that say how input is read and turned into output: block and
+    /// line sizes, formats, and line filters.
+    fn validate_input(&mut self) -> Result<(), String> {
+        if self.buffer_size == 0 {
+            return Err("buffer size (-b) must be non-zero".into());
+        }
 
-    App::new("rotee")
-        .desc("Split stdin between rotating output files")
-        .opt(
-            Opt::new("buf-size", &mut config.buffer_size)
-                .short('b')
-                .help("size of the buffer used to read from stdin"),
-        )
-        .opt(
-            Opt::new("no-echo", &mut config.no_echo)
-                .short('e')
-                .help("do not re-echo stdout"),
-        )
-        .opt(
-            Opt::new("num-files", &mut config.num_files)
-                .short('n')
-                .help("maximum number of files to use"),
-        )
-        .opt(
-            Opt::new("file-prefix", &mut config.file_prefix)
-                .short('p')
-                .help("output filename prefix"),
-        )
-        .opt(
-            Opt::new("file-size", &mut config.file_size)
-                .short('s')
-                .help("size (in bytes) after which to rotate output files"),
-        )
-        .parse_args();
+        if self.max_read == Some(0) {
+            return Err("--max-read must be non-zero".into());
+        }
 
-    if config.buffer_size == 0 {
-        fatal("buffer size (-b) must be non-zero");
-    }
+        if self.max_line_bytes == 0 {
+            return Err("maximum line size (-m) must be non-zero".into());
+        }
 
-    if config.num_files == 0 {
-        fatal("number of files (-n) must be non-zero");
-    }
+        if self.no_files && self.no_echo {
+            return Err("no-files (-N) and no-echo (-e) are mutually exclusive".into());
+        }
 
-    if config.file_size == 0 {
-        fatal("file size (-s) must be non-zero");
-    }
+        match self.format.as_str() {
+            "raw" => (),
+            "json" => self.json = true,
+            f => return Err(format!("invalid format '{}' (expected raw or json)", f)),
+        }
 
-    if let Err(e) = run(&config) {
-        eprintln!("error: {}", e);
-        process::exit(1);
-    }
-}
+        match self.utf8.as_str() {
+            "pass" => (),
+            "replace" => self.utf8_invalid = Some(Invalid::Replace),
+            "reject" => self.utf8_invalid = Some(Invalid::Reject),
+            u => {
+                return Err(format!(
+                    "invalid --utf8 '{}' (expected pass, replace, or reject)",
+                    u
+                ))
+            }
+        }
 
-fn run(config: &Config) -> Result<(), Box<dyn Error>> {
-    let mut of = File::create(outfile_path(&config.file_prefix, 0))?;
-    let mut cur_size = 0;
-    let mut buf = Vec::with_capacity(config.buffer_size);
-    buf.resize(config.buffer_size, 0);
+        let mut overflow = Overflow::Block;
+        match self.echo_overflow.as_str() {
+            "block" => (),
+            "drop" => overflow = Overflow::Drop,
+            o => {
+                return Err(format!(
+                    "invalid --echo-overflow '{}' (expected block or drop)",
+                    o
+                ))
+            }
+        }
+        if self.echo_queue_size.is_some() || overflow == Overflow::Drop {
+            match self.echo_queue_size.unwrap_or(ECHO_QUEUE_DEFAULT) {
+                0 => return Err("--echo-queue-size must be non-zero".into()),
+                n => self.echo_queue = Some((n, overflow)),
+            }
+        }
 
-    // Compute the full set of signals for when we have to block signals.
-    let mut all_sigs = MaybeUninit::uninit();
-    if unsafe { sigemptyset(all_sigs.as_mut_ptr()) } == -1 {
-        return Err("sigemptyset failed".into());
-    }
-    let mut all_sigs = unsafe { all_sigs.assume_init() };
-    if unsafe { sigfillset(&mut all_sigs as *mut sigset_t) } == -1 {
-        return Err("sigfillset failed".into());
+        match self.crlf.as_str() {
+            "keep" => (),
+            "lf" => self.crlf_lf = true,
+            c => return Err(format!("invalid --crlf '{}' (expected keep or lf)", c)),
+        }
+        if self.crlf_lf {
+            if self.squeeze_echo && !self.crlf_echo {
+                return Err("--squeeze-echo with --crlf lf requires --crlf-echo".into());
+            }
+        } else if self.crlf_echo {
+            return Err("--crlf-echo requires --crlf lf".into());
+        }
+
+        if self.frame_length && u32::try_from(self.buffer_size).is_err() {
+            return Err("--frame-length requires a buffer size (-b) that fits in 4 bytes".into());
+        }
+
+        if self.truncation_marker.is_some() && !self.line_input {
+            return Err("--truncation-marker requires --line-input (-l)".into());
+        }
+
+        if let Some(p) = &self.exclude {
+            match Regex::new(p) {
+                Ok(re) => self.exclude_regex = Some(re),
+                Err(e) => return Err(format!("invalid regular expression '{}': {}", p, e)),
+            }
+            match self.exclude_target.as_str() {
+                "file" => self.exclude_files = true,
+                "echo" => self.exclude_echo = true,
+                "both" => (self.exclude_files, self.exclude_echo) = (true, true),
+                t => {
+                    return Err(format!(
+                        "invalid --exclude-target '{}' (expected file, echo, or both)",
+                        t
+                    ))
+                }
+            }
+        } else if self.exclude_target != "file" {
+            return Err("--exclude-target requires --exclude".into());
+        }
+
+        if self.squeeze_repeats {
+            if !self.line_input {
+                return Err("--squeeze-repeats requires --line-input (-l)".into());
+            }
+        } else if self.squeeze_echo || self.squeeze_interval.is_some() {
+            return Err("--squeeze-echo and --squeeze-interval require --squeeze-repeats".into());
+        }
+
+        if let Some(s) = &self.squeeze_interval {
+            match parse_duration(s)? {
+                d if d.is_zero() => return Err("--squeeze-interval must be non-zero".into()),
+                d => self.squeeze_every = Some(d),
+            }
+        }
+
+        match &self.mark {
+            Some(s) => match parse_duration(s)? {
+                d if d.is_zero() => return Err("--mark must be non-zero".into()),
+                d => self.mark_every = Some(d),
+            },
+            None if self.mark_echo => return Err("--mark-echo requires --mark".into()),
+            None => (),
+        }
+
+        if let Some(s) = &self.syslog {
+            self.syslog_pri = Some(syslog::parse_spec(s)?);
+        }
+        Ok(())
     }
 
-    loop {
-        match io::stdin().read(&mut buf)? {
-            0 => break, // EOF.
-            nbytes => {
-                let mut idx = 0;
-                while idx < nbytes {
-                    let write_size = usize::min(nbytes - idx, config.file_size - cur_size);
-                    let bytes = &buf[idx..(idx + write_size)];
-                    of.write_all(bytes)?;
-                    if !config.no_echo {
-                        io::stdout().write_all(&bytes)?;
+    /// Check and derive the options that say when files are rotated (or committed), and how big
+    /// they get.
+    fn validate_rotation(&mut self) -> Result<(), String> {
+        // A file size of 0 turns off size-based rotation, leaving it to another trigger (and `-n` still
+        // bounds the number of files kept).
+        if self.file_size == 0
+            && self.single_file.is_none()
+            && self.rotate_after_matches.is_none()
+            && self.split_every.is_none()
+            && !self.date_dir
+            && self.staging_prefix.is_none()
+            && self.split_into.is_none()
+        {
+            return Err("file size (-s) can only be 0 (unlimited) with another rotation trigger: --rotate-after-matches, --split-every, --date-dir (-D), or --staging-prefix".into());
+        }
+
+        if let Some(n) = self.split_into {
+            if n == 0 {
+                return Err("--split-into must be non-zero".into());
+            }
+            let total = match self.input_size {
+                Some(s) => Some(s),
+                None if self.exec.is_empty() => stdin_size(),
+                None => None,
+            };
+            match total.map(|t| (t, split_size(t, n))) {
+                Some((_, Some(s))) => self.file_size = s,
+                Some((t, None)) => return Err(format!("can't split {} bytes into {} files", t, n)),
+                None if self.exec.is_empty() => return Err(
+                    "--split-into needs the input's size: use --input-size, or redirect stdin from a file".into(),
+                ),
+                None => return Err("--split-into needs --input-size when running a command".into()),
+            }
+            // Keep every file of the split.
+            self.num_files = self.num_files.max(n);
+        } else if self.input_size.is_some() {
+            return Err("--input-size can only be used with --split-into".into());
+        }
+
+        match (self.rotate_after_matches, &self.match_pattern) {
+            (Some(0), _) => return Err("--rotate-after-matches must be non-zero".into()),
+            (Some(_), Some(p)) => match Regex::new(p) {
+                Ok(re) => self.match_regex = Some(re),
+                Err(e) => return Err(format!("invalid regular expression '{}': {}", p, e)),
+            },
+            (None, None) => (),
+            _ => return Err("--rotate-after-matches and --match must be used together".into()),
+        }
+
+        match self.split_every {
+            Some(0) => return Err("--split-every must be non-zero".into()),
+            Some(_) => {
+                if let Some(b) = &self.split_byte {
+                    match parse_byte(b) {
+                        Some(b) => self.split_delimiter = b,
+                        None => return Err(format!(
+                            "invalid --split-byte '{}' (expected a single byte, \\n, \\t, \\r, \\0, or 0xNN)",
+                            b
+                        )),
                     }
+                }
+            }
+            None if self.split_byte.is_some() => {
+                return Err("--split-byte requires --split-every".into())
+            }
+            None => (),
+        }
+
+        if self.file_size != 0 && self.min_size > self.file_size {
+            return Err("--min-size can't be larger than the file size (-s)".into());
+        }
+
+        if let Some(s) = &self.min_rotate_interval {
+            self.rotate_interval = Some(parse_duration(s)?);
+        }
+
+        if let Some(s) = &self.max_runtime {
+            match parse_duration(s)? {
+                d if d.is_zero() => return Err("--max-runtime must be non-zero".into()),
+                d => self.runtime_limit = Some(d),
+            }
+        }
+
+        if self.staging_prefix.is_some() && self.date_dir {
+            return Err("--staging-prefix and --date-dir (-D) are mutually exclusive".into());
+        }
+
+        if self.single_file.is_none() && self.warn_size.is_some() {
+            return Err("--warn-size requires --single-file".into());
+        }
+
+        match self.alt_echo.as_deref() {
+            None => (),
+            Some("on") => self.alt_echo_on = Some(true),
+            Some("off") => self.alt_echo_on = Some(false),
+            Some(e) => return Err(format!("invalid --alt-echo '{}' (expected on or off)", e)),
+        }
+        if let Some(s) = self.alt_file_size {
+            if s == 0 {
+                return Err("--alt-file-size must be non-zero".into());
+            }
+            if self.file_size == 0 || self.split_into.is_some() {
+                return Err(
+                    "--alt-file-size can't be used with a file size (-s) of 0 or --split-into"
+                        .into(),
+                );
+            }
+        }
+        match self.alt_buffer_size {
+            Some(0) => return Err("--alt-buf-size must be non-zero".into()),
+            Some(b) if self.frame_length && u32::try_from(b).is_err() => {
+                return Err("--frame-length requires an --alt-buf-size that fits in 4 bytes".into())
+            }
+            _ => (),
+        }
+        Ok(())
+    }
 
-                    idx += write_size;
-                    cur_size += write_size;
-                    if cur_size >= config.file_size {
-                        of = rotate(config, of, all_sigs)?;
-                        cur_size = 0;
+    /// Check and derive the options for `--listen-unix` and `--listen-tcp`.
+    fn validate_network(&mut self) -> Result<(), String> {
+        if self.listen_unix.is_some() {
+            if let Some(m) = &self.listen_mode {
+                match u32::from_str_radix(m, 8) {
+                    Ok(mode) if mode <= 0o777 => self.listen_perms = Some(mode),
+                    _ => {
+                        return Err(format!(
+                            "invalid --listen-mode '{}' (expected an octal mode, e.g. 0660)",
+                            m
+                        ))
                     }
                 }
             }
+        } else if self.listen_mode.is_some() || self.connection_markers || self.connection_tags {
+            return Err(
+                "--listen-mode, --connection-markers, and --connection-tags require --listen-unix"
+                    .into(),
+            );
+        }
+
+        if let Some(a) = &self.listen_tcp {
+            // A bare port is only reachable locally.
+            let addr = match a.parse::<u16>() {
+                Ok(port) => Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port))),
+                Err(_) => a.parse::<SocketAddr>(),
+            };
+            match addr {
+                Ok(addr) => self.listen_tcp_addr = Some(addr),
+                Err(_) => {
+                    return Err(format!(
+                        "invalid --listen-tcp '{}' (expected [ADDR:]PORT, e.g. 127.0.0.1:9000)",
+                        a
+                    ))
+                }
+            }
+            if let Some(l) = &self.listen_allow {
+                match l.split(',').map(str::parse).collect() {
+                    Ok(v) => self.allowed_peers = v,
+                    Err(e) => return Err(format!("invalid --listen-allow: {}", e)),
+                }
+            }
+            match self.listen_busy.as_str() {
+                "queue" => (),
+                "refuse" => self.refuse_busy = true,
+                b => {
+                    return Err(format!(
+                        "invalid --listen-busy '{}' (expected queue or refuse)",
+                        b
+                    ))
+                }
+            }
+        } else if self.listen_allow.is_some() || self.listen_busy != "queue" {
+            return Err("--listen-allow and --listen-busy require --listen-tcp".into());
         }
+        Ok(())
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use libc::{kill, SIGTERM};
-    use rand::Rng;
-    use std::{env, fs::File, path::PathBuf, process::Command, thread, time::Duration};
-    use tempfile::TempDir;
+    /// Check and derive the options for running a command (after `--`), its pseudo-terminal, and
+    /// the `--stderr-prefix` series, and for `--setuid` and `--setgid`.
+    fn validate_exec(&mut self) -> Result<(), String> {
+        if let Some(fd) = self.stderr_fd_arg {
+            if self.stderr_prefix.is_none() {
+                return Err("--stderr-fd requires --stderr-prefix".into());
+            }
+            match RawFd::try_from(fd) {
+                Ok(fd) if fd <= 2 => {
+                    return Err("--stderr-fd can't be stdin, stdout, or stderr (0, 1, or 2)".into())
+                }
+                // The fd is ours to read from now: children (e.g. the `--full-copy-compressed` command)
+                // don't inherit it.
+                Ok(fd) if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != -1 => {
+                    self.stderr_fd = Some(fd)
+                }
+                _ => return Err(format!("--stderr-fd {} isn't open", fd)),
+            }
+        }
 
-    #[cfg(cargo_profile = "release")]
-    static CARGO_PROFILE: &str = "release";
-    #[cfg(not(cargo_profile = "release"))]
-    static CARGO_PROFILE: &str = "debug";
+        if self.stderr_prefix.is_some() {
+            if self.exec.is_empty() && self.stderr_fd.is_none() {
+                return Err("--stderr-prefix can only be used when running a command (after '--') or with --stderr-fd".into());
+            }
+            if self.stderr_prefix.as_ref() == Some(&self.file_prefix) && !self.json {
+                return Err(
+                    "--stderr-prefix can only be the same as --file-prefix (-p) with --format json"
+                        .into(),
+                );
+            }
+        }
 
-    /// Check (best we can) that delivering catchable signals cannot interrupt file rotation.
-    /// https://github.com/vext01/rotee/issues/1
-    #[test]
-    fn test_signal() {
-        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
-        let mut rng = rand::thread_rng();
+        if self.pty {
+            if self.exec.is_empty() {
+                return Err("--pty can only be used when running a command (after '--')".into());
+            }
+            if self.stderr_prefix.is_some() {
+                return Err("--pty and --stderr-prefix are mutually exclusive".into());
+            }
+        }
 
-        for _ in 0..50 {
-            let p = [&md, "target", CARGO_PROFILE, "rotee"]
-                .iter()
-                .collect::<PathBuf>();
-            let dir = TempDir::new().unwrap();
-            env::set_current_dir(dir.path()).unwrap();
-            let outfile0 = [dir.path().to_str().unwrap(), "rotee.0"]
-                .iter()
-                .collect::<PathBuf>();
+        if self.stderr_file_size == Some(0) || self.stderr_num_files == Some(0) {
+            return Err("--stderr-file-size and --stderr-num-files must be non-zero".into());
+        }
 
-            // Pipe /dev/zero into a rotee with a very small output file size, so that rotation
-            // happens very frequently.
-            let zero = File::open("/dev/zero").unwrap();
-            let mut child = Command::new(p)
-                .stdin(zero)
-                .args(&["-s", "1", "-e"])
-                .spawn()
-                .unwrap();
+        // Commits only apply to the main series, so they can't stand in for size-based rotation in the
+        // stderr series.
+        if self.stderr_prefix.is_some()
+            && self.file_size == 0
+            && self.stderr_file_size.is_none()
+            && self.rotate_after_matches.is_none()
+            && self.split_every.is_none()
+            && !self.date_dir
+        {
+            return Err("--stderr-prefix with an unlimited file size (-s 0) needs --stderr-file-size, --rotate-after-matches, --split-every, or --date-dir (-D)".into());
+        }
 
-            // Wait for `rotee.0` to appear for the first time.
-            while !outfile0.exists() {
-                thread::sleep(Duration::from_nanos(10));
+        self.forward_signos = self
+            .forward_signals
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(parse_signal)
+            .collect::<Result<_, _>>()?;
+
+        self.max_restarts = parse_restart(&self.restart)?;
+
+        if self.setuid.is_some() || self.setgid.is_some() {
+            // Switching user while keeping root's group would leave privileges behind.
+            let Some(gid) = &self.setgid else {
+                return Err("--setuid requires --setgid".into());
+            };
+            let gid = resolve_gid(gid)?;
+            self.run_as = Some((self.setuid.as_deref().map(resolve_uid).transpose()?, gid));
+        }
+        Ok(())
+    }
+
+    /// Check and derive the options that say how output files are written, finished, and watched
+    /// (other than when they're rotated, and how many are kept).
+    fn validate_files(&mut self) -> Result<(), String> {
+        for &fd in &self.copy_fd {
+            // Unlike `--stderr-fd`, the fd isn't made close-on-exec: it is left as it was passed.
+            match RawFd::try_from(fd) {
+                Ok(fd) if fd <= 2 => {
+                    return Err("--copy-fd can't be stdin, stdout, or stderr (0, 1, or 2)".into())
+                }
+                Ok(fd) if self.stderr_fd == Some(fd) || self.copy_fds.contains(&fd) => {
+                    return Err(format!("--copy-fd {} is already in use", fd))
+                }
+                Ok(fd) if is_writable_fd(fd) => self.copy_fds.push(fd),
+                _ => return Err(format!("--copy-fd {} isn't open for writing", fd)),
+            }
+        }
+
+        if self.buffer_files {
+            // Each file is held in memory until it is complete, so its size bounds the memory used.
+            if [
+                Some(self.file_size),
+                self.stderr_file_size,
+                self.alt_file_size,
+            ]
+            .iter()
+            .flatten()
+            .any(|s| *s == 0 || *s > BUFFER_FILES_MAX)
+            {
+                return Err(format!(
+                    "--buffer-files needs a non-zero file size (-s) of at most {} bytes",
+                    BUFFER_FILES_MAX
+                ));
             }
+        } else if self.buffer_files_sync {
+            return Err("--buffer-files-sync needs --buffer-files".into());
+        }
 
-            // After a random amount of time, send rotee a catchable signal.
-            thread::sleep(Duration::from_millis(rng.gen_range(0..101)));
-            unsafe { kill(i32::try_from(child.id()).unwrap(), SIGTERM) };
+        let mut stall = Stall::Warn;
+        match self.write_timeout_action.as_deref() {
+            None | Some("warn") => (),
+            Some("abort") => stall = Stall::Abort,
+            Some(a) => {
+                return Err(format!(
+                    "invalid --write-timeout-action '{}' (expected warn or abort)",
+                    a
+                ))
+            }
+        }
+        match &self.write_timeout {
+            Some(s) => match parse_duration(s)? {
+                d if d.is_zero() => return Err("--write-timeout must be non-zero".into()),
+                d => self.write_watchdog = Some((d, stall)),
+            },
+            None if self.write_timeout_action.is_some() => {
+                return Err("--write-timeout-action requires --write-timeout".into())
+            }
+            None => (),
+        }
 
-            // rotee should exit with failure.
-            assert!(!child.wait().unwrap().success());
-            // and `rotee.0` should always exist.
-            assert!(outfile0.exists());
+        // Otherwise a file could be full before anything but its header is written to it.
+        if self.header_line
+            && [
+                Some(self.file_size),
+                self.stderr_file_size,
+                self.alt_file_size,
+            ]
+            .iter()
+            .flatten()
+            .any(|s| *s != 0 && *s <= HEADER_LINE_MAX)
+        {
+            return Err(format!(
+                "--header-line needs a file size (-s) of 0 or more than {} bytes",
+                HEADER_LINE_MAX
+            ));
+        }
+
+        if let Some(s) = &self.heartbeat_interval {
+            if self.heartbeat_file.is_none() {
+                return Err("--heartbeat-interval requires --heartbeat-file".into());
+            }
+            self.heartbeat_every = Some(parse_duration(s)?);
+        }
+
+        match (&self.checkpoint_file, &self.checkpoint_interval) {
+            (Some(_), Some(s)) => match parse_duration(s)? {
+                d if d.is_zero() => return Err("--checkpoint-interval must be non-zero".into()),
+                d => self.checkpoint_every = Some(d),
+            },
+            (None, None) => (),
+            _ => {
+                return Err(
+                    "--checkpoint-file and --checkpoint-interval must be used together".into(),
+                )
+            }
+        }
+
+        if self.owner.is_some() || self.group.is_some() {
+            let uid = self
+                .owner
+                .as_deref()
+                .map(resolve_uid)
+                .transpose()?
+                .unwrap_or(uid_t::MAX);
+            let gid = self
+                .group
+                .as_deref()
+                .map(resolve_gid)
+                .transpose()?
+                .unwrap_or(gid_t::MAX);
+            self.file_owner = Some((uid, gid));
+        }
+
+        if let Some(m) = &self.finalized_mode {
+            match u32::from_str_radix(m, 8) {
+                Ok(mode) if mode <= 0o7777 => self.finalized_perms = Some(mode),
+                _ => {
+                    return Err(format!(
+                        "invalid --finalized-mode '{}' (expected an octal mode, e.g. 0644)",
+                        m
+                    ))
+                }
+            }
+        }
+
+        match self.stale_pid_file.as_str() {
+            "error" => self.replace_stale_pid = false,
+            "replace" => self.replace_stale_pid = true,
+            s => {
+                return Err(format!(
+                    "invalid --stale-pid-file '{}' (expected error or replace)",
+                    s
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Check and derive `--encrypt-recipient`.
+    fn validate_encryption(&mut self) -> Result<(), String> {
+        if !self.encrypt_recipient.is_empty() {
+            self.recipients = Some(Recipients::parse(&self.encrypt_recipient)?);
+        }
+        Ok(())
+    }
+
+    /// Check and derive the signals rotee handles (see `handled_signals()`), which must all differ.
+    fn validate_signals(&mut self) -> Result<(), String> {
+        self.commit_signo = parse_signal(&self.commit_signal)?;
+
+        if self.pty && self.staging_prefix.is_some() && self.commit_signo == libc::SIGWINCH {
+            return Err("--pty uses SIGWINCH: choose another --commit-signal".into());
+        }
+
+        if self.alt_profile() {
+            if let Some(s) = &self.profile_signal {
+                self.profile_signo = parse_signal(s)?;
+            }
+            if self.staging_prefix.is_some() && self.profile_signo == self.commit_signo {
+                return Err("--profile-signal and --commit-signal must differ".into());
+            }
+            if self.pty && self.profile_signo == libc::SIGWINCH {
+                return Err("--pty uses SIGWINCH: choose another --profile-signal".into());
+            }
+        } else if self.profile_signal.is_some() {
+            return Err(
+                "--profile-signal requires --alt-file-size, --alt-buf-size, or --alt-echo".into(),
+            );
+        }
+
+        if let Some(s) = &self.log_reopen_signal {
+            if self.log_file.is_none() {
+                return Err("--log-reopen-signal requires --log-file".into());
+            }
+            let signo = parse_signal(s)?;
+            if handled_signals(self).iter().any(|&(s, _)| s == signo) {
+                return Err("--log-reopen-signal must differ from the other signals rotee handles (see --list-signals)".into());
+            }
+            self.log_reopen_signo = Some(signo);
+        }
+        Ok(())
+    }
+}
+
+/// `items` as an English list: `a`, `a or b`, or `a, b, or c`.
+fn or_list(items: &[&str]) -> String {
+    match items {
+        [] => String::new(),
+        [a] => a.to_string(),
+        [a, b] => format!("{} or {}", a, b),
+        [init @ .., last] => format!("{}, or {}", init.join(", "), last),
+    }
+}
+
+fn fatal(m: &str) {
+    diag::error(&m);
+    process::exit(1);
+}
+
+/// Install rotee's logger, showing diagnostics of `level` and above, unless `ROTEE_LOG` says
+/// otherwise.
+fn init_log(level: LevelFilter) {
+    match env::var("ROTEE_LOG") {
+        Ok(s) => match diag::Filter::new(level).parse(&s) {
+            Ok(f) => diag::init(f),
+            Err(e) => fatal(&e),
+        },
+        Err(_) => diag::init(diag::Filter::new(level)),
+    }
+}
+
+/// Parse a byte given as itself (e.g. `;`), as one of the escapes `\n`, `\t`, `\r`, and `\0`, or
+/// in hex (e.g. `0x1e`).
+fn parse_byte(s: &str) -> Option<u8> {
+    match s {
+        "\\n" => Some(b'\n'),
+        "\\t" => Some(b'\t'),
+        "\\r" => Some(b'\r'),
+        "\\0" => Some(0),
+        _ => match s.strip_prefix("0x") {
+            Some(h) if h.len() == 2 => u8::from_str_radix(h, 16).ok(),
+            Some(_) => None,
+            None => (s.len() == 1).then(|| s.as_bytes()[0]),
+        },
+    }
+}
+
+/// Parse a duration such as `250ms`, `10s`, `5m`, `2h` or `1d`. A bare number is taken to be
+/// seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num = num
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    let mult = match unit {
+        "ms" => return Ok(Duration::from_millis(num)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("invalid duration '{}'", s)),
+    };
+    num.checked_mul(mult)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", s))
+}
+
+/// Parse a signal name, with or without a `SIG` prefix (e.g. `USR1` or `SIGUSR1`), or number.
+fn parse_signal(s: &str) -> Result<c_int, String> {
+    if let Ok(n) = s.parse::<c_int>() {
+        if n > 0 && n < libc::SIGRTMAX() {
+            return Ok(n);
+        }
+    }
+    let signo = match s.strip_prefix("SIG").unwrap_or(s) {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        "URG" => libc::SIGURG,
+        "WINCH" => libc::SIGWINCH,
+        _ => return Err(format!("invalid signal '{}'", s)),
+    };
+    Ok(signo)
+}
+
+/// The name of signal `signo` (e.g. `SIGUSR1`), or its number if it isn't one that
+/// `parse_signal()` knows by name.
+fn signal_name(signo: c_int) -> String {
+    let name = match signo {
+        libc::SIGHUP => "HUP",
+        libc::SIGINT => "INT",
+        libc::SIGQUIT => "QUIT",
+        libc::SIGUSR1 => "USR1",
+        libc::SIGUSR2 => "USR2",
+        libc::SIGALRM => "ALRM",
+        libc::SIGTERM => "TERM",
+        libc::SIGURG => "URG",
+        libc::SIGWINCH => "WINCH",
+        _ => return signo.to_string(),
+    };
+    format!("SIG{}", name)
+}
+
+/// Parse a `--restart` policy into the maximum number of restarts.
+fn parse_restart(s: &str) -> Result<usize, String> {
+    match s.split_once(':') {
+        _ if s == "no" => Ok(0),
+        _ if s == "on-failure" => Ok(usize::MAX),
+        Some(("on-failure", n)) => n
+            .parse()
+            .map_err(|_| format!("invalid maximum number of restarts '{}'", n)),
+        _ => Err(format!("invalid restart policy '{}'", s)),
+    }
+}
+
+/// The system-wide configuration file.
+static SYSTEM_CONF: &str = "/etc/rotee.conf";
+/// The per-user configuration file, relative to `$HOME`.
+static USER_CONF: &str = ".config/rotee.conf";
+
+/// Convert the contents of a configuration file into command-line arguments. Each non-blank line
+/// that doesn't start with `#` names a long option, optionally followed by `=` and a value (which
+/// extends to the end of the line), e.g. `file-size = 1048576` or `no-echo`.
+fn conf_args(conf: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    for line in conf.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((k, v)) => {
+                args.push(format!("--{}", k.trim()));
+                args.push(v.trim().to_owned());
+            }
+            None => args.push(format!("--{}", line)),
+        }
+    }
+    args
+}
+
+/// Read the configuration file at `path` (if it exists) and convert it into arguments.
+fn read_conf(path: &Path) -> Result<Vec<String>, String> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(conf_args(&s)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("can't read {}: {}", path.display(), e)),
+    }
+}
+
+/// Layer default arguments underneath the command-line arguments `cli` (which includes `argv[0]`).
+/// Later arguments override earlier ones so the precedence, lowest first, is: the system
+/// configuration file, the user configuration file, `ROTEE_DEFAULT_ARGS`, then the command line.
+///
+/// A flag can't be given a value, so one set by the defaults can't be overridden in the usual way.
+/// Instead, `--no-FLAG` on the command line removes any `--FLAG` from the defaults (and `--FLAG`
+/// removes `--no-FLAG`, so `--echo` undoes a default `--no-echo`).
+fn layer_args(
+    system: Vec<String>,
+    user: Vec<String>,
+    env: Option<&str>,
+    cli: &[String],
+) -> Vec<String> {
+    let mut defaults = system;
+    defaults.extend(user);
+    if let Some(e) = env {
+        defaults.extend(e.split_whitespace().map(String::from));
+    }
+    let mut args = cli[..1].to_vec();
+    let mut cli = cli[1..].to_vec();
+    // Everything after `--` is a command to run.
+    let mut end = cli.iter().position(|a| a == "--").unwrap_or(cli.len());
+    let mut i = 0;
+    while i < end {
+        let negated = cli[i]
+            .strip_prefix("--")
+            .map(|f| match f.strip_prefix("no-") {
+                Some(g) => format!("--{}", g),
+                None => format!("--no-{}", f),
+            });
+        if negated.is_some_and(|n| remove_flag(&mut defaults, &n)) {
+            cli.remove(i);
+            end -= 1;
+        } else {
+            i += 1;
+        }
+    }
+    args.extend(defaults);
+    args.extend(cli);
+    args
+}
+
+/// Remove every occurrence of the flag `flag` (e.g. `--verify`) from `args`, returning whether
+/// there were any. An occurrence followed by something other than another option is taken to be
+/// an option with a value, and left alone.
+fn remove_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let mut removed = false;
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == flag && args.get(i + 1).is_none_or(|a| a.starts_with('-')) {
+            args.remove(i);
+            removed = true;
+        } else {
+            i += 1;
+        }
+    }
+    removed
+}
+
+/// Remove the `-v`s (which can be repeated, e.g. `-vv`) and `--verbose`s from `args`, returning how
+/// many there were: `app` can't count repeated flags.
+fn take_verbosity(args: &mut Vec<String>) -> usize {
+    let mut n = 0;
+    args.retain(|a| {
+        let v = match a.strip_prefix('-') {
+            Some("-verbose") => 1,
+            Some(f) if !f.is_empty() && f.bytes().all(|b| b == b'v') => f.len(),
+            _ => return true,
+        };
+        n += v;
+        false
+    });
+    n
+}
+
+/// Insert a directory called `date` into `prefix` just before its filename component, e.g.
+/// `logs/rotee.` becomes `logs/2024-06-01/rotee.`.
+fn date_prefix(prefix: &str, date: &str) -> String {
+    match prefix.rsplit_once('/') {
+        Some((dir, file)) => format!("{}/{}/{}", dir, date, file),
+        None => format!("{}/{}", date, prefix),
+    }
+}
+
+/// The prefix of the output files in the series currently being written. `date` is the date of
+/// the series when `--date-dir` is in use.
+fn series_prefix(config: &Config, date: Option<&str>) -> String {
+    match date {
+        Some(d) => date_prefix(&config.file_prefix, d),
+        None => config.file_prefix.clone(),
+    }
+}
+
+fn outfile_path(prefix: &str, suffix: usize) -> PathBuf {
+    PathBuf::from(format!("{}{}", prefix, suffix))
+}
+
+/// The path of output file `suffix`: as `outfile_path()`, except that, with
+/// `--encrypt-recipient`, sealed files (all but the newest) have a `.age` suffix.
+fn sealed_path(config: &Config, prefix: &str, suffix: usize) -> PathBuf {
+    match config.recipients {
+        Some(_) if suffix > 0 => PathBuf::from(format!("{}{}.age", prefix, suffix)),
+        _ => outfile_path(prefix, suffix),
+    }
+}
+
+/// Resolve a user name (or numeric uid) to a uid.
+fn resolve_uid(name: &str) -> Result<uid_t, String> {
+    if let Ok(uid) = name.parse::<uid_t>() {
+        return Ok(uid);
+    }
+    let cname = CString::new(name).map_err(|_| format!("invalid user name '{}'", name))?;
+    let mut pwd = MaybeUninit::<passwd>::uninit();
+    let found = lookup_entry(|buf, res: &mut *mut passwd| unsafe {
+        getpwnam_r(
+            cname.as_ptr(),
+            pwd.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            res,
+        )
+    })
+    .map_err(|e| format!("can't look up user '{}': {}", name, e))?;
+    if !found {
+        return Err(format!("unknown user '{}'", name));
+    }
+    Ok(unsafe { pwd.assume_init() }.pw_uid)
+}
+
+/// Resolve a group name (or numeric gid) to a gid.
+fn resolve_gid(name: &str) -> Result<gid_t, String> {
+    if let Ok(gid) = name.parse::<gid_t>() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name).map_err(|_| format!("invalid group name '{}'", name))?;
+    let mut grp = MaybeUninit::<group>::uninit();
+    let found = lookup_entry(|buf, res: &mut *mut group| unsafe {
+        getgrnam_r(
+            cname.as_ptr(),
+            grp.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            res,
+        )
+    })
+    .map_err(|e| format!("can't look up group '{}': {}", name, e))?;
+    if !found {
+        return Err(format!("unknown group '{}'", name));
+    }
+    Ok(unsafe { grp.assume_init() }.gr_gid)
+}
+
+/// The most buffer space that `lookup_entry()` offers for an entry's strings.
+const MAX_ENTRY_BUF: usize = 1 << 20;
+
+/// Call `lookup`, a wrapper around one of the `get*nam_r()` functions, with a buffer for the
+/// entry's strings, growing the buffer for as long as the entry doesn't fit (`ERANGE`). Returns
+/// whether an entry was found, or the error that the lookup failed with.
+fn lookup_entry<T>(
+    mut lookup: impl FnMut(&mut [c_char], &mut *mut T) -> c_int,
+) -> Result<bool, io::Error> {
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let mut res = ptr::null_mut();
+        match lookup(&mut buf, &mut res) {
+            0 => return Ok(!res.is_null()),
+            libc::ERANGE if buf.len() < MAX_ENTRY_BUF => buf.resize(buf.len() * 2, 0),
+            rc => return Err(io::Error::from_raw_os_error(rc)),
+        }
+    }
+}
+
+/// Create (truncating) an output file, giving it the configured ownership.
+fn create_outfile(config: &Config, path: &Path) -> Result<File, io::Error> {
+    let f = File::create(path)?;
+    // With `--stat-size`, write at the end of the file, wherever something else has left it.
+    if config.stat_size {
+        let fd = f.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_APPEND) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    chown_outfile(config, &f, path)?;
+    Ok(f)
+}
+
+/// Create, or rename a file to, `path` with `f`. With `--recreate-dir`, if that fails because
+/// `path`'s directory has gone (e.g. it was removed by a clean up of `/tmp`), recreate the
+/// directory and try once more.
+fn recreating_dir<T>(
+    config: &Config,
+    path: &Path,
+    mut f: impl FnMut() -> Result<T, io::Error>,
+) -> Result<T, io::Error> {
+    match f() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound && config.recreate_dir => {
+            let dir = path
+                .parent()
+                .filter(|d| !d.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            if dir.exists() {
+                return Err(e);
+            }
+            warn!("{} has gone: recreating it", dir.display());
+            fs::create_dir_all(dir)?;
+            f()
+        }
+        r => r,
+    }
+}
+
+/// Open the `--single-file` (or, with `--shared`, active file) at `path` for appending, creating it
+/// if need be, and giving it the configured ownership.
+fn open_single(config: &Config, path: &Path) -> Result<File, io::Error> {
+    let f = OpenOptions::new().create(true).append(true).open(path)?;
+    chown_outfile(config, &f, path)?;
+    Ok(f)
+}
+
+/// Give the output file `f` (whose path is `path`) the configured ownership.
+fn chown_outfile(config: &Config, f: &File, path: &Path) -> Result<(), io::Error> {
+    if let Some((uid, gid)) = config.file_owner {
+        if unsafe { fchown(f.as_raw_fd(), uid, gid) } == -1 {
+            let e = io::Error::last_os_error();
+            return Err(io::Error::new(
+                e.kind(),
+                format!("can't change ownership of {}: {}", path.display(), e),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// With `--finalized-mode`, give the output file at `path`, which will not be written to again,
+/// the finalized mode, and with `--seal-readonly`, remove its write permission bits.
+fn finalize_outfile(config: &Config, path: &Path) -> Result<(), io::Error> {
+    let mut mode = config.finalized_perms;
+    if config.seal_readonly {
+        let m = match mode {
+            Some(m) => m,
+            None => fs::metadata(path)?.permissions().mode() & 0o7777,
+        };
+        mode = Some(m & !0o222);
+    }
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("can't change the mode of {}: {}", path.display(), e),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Write all of `bytes` to `w`. With `--strict-writes`, the number of bytes each `write()`
+/// reports is checked, and any discrepancy is an error.
+fn write_bytes<W: Write>(config: &Config, w: &mut W, bytes: &[u8]) -> Result<(), io::Error> {
+    write_counted(config, w, bytes, &mut 0)
+}
+
+/// As `write_bytes()`, but counting the bytes written in `done`, which, if writing fails part way
+/// through, says how many of `bytes` were written before it did.
+fn write_counted<W: Write>(
+    config: &Config,
+    w: &mut W,
+    bytes: &[u8],
+    done: &mut usize,
+) -> Result<(), io::Error> {
+    while *done < bytes.len() {
+        match w.write(&bytes[*done..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!("short write: wrote {} of {} bytes", done, bytes.len()),
+                ))
+            }
+            Ok(n) if config.strict_writes && n > bytes.len() - *done => {
+                return Err(io::Error::other(format!(
+                    "write reported {} bytes written, but only {} were given",
+                    n,
+                    bytes.len() - *done
+                )))
+            }
+            Ok(n) => *done += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Echo `bytes` to `w`. Stdout is line buffered, so complete lines are written at once; unless
+/// `--echo-flush-lines` is given, any partial line at the end of `bytes` is flushed too, so that
+/// the echo keeps up with every read (e.g. showing a prompt that isn't followed by a newline).
+fn write_echo<W: Write>(config: &Config, w: &mut W, bytes: &[u8]) -> Result<(), io::Error> {
+    write_bytes(config, w, bytes)?;
+    if !config.echo_flush_lines {
+        w.flush()?;
+    }
+    Ok(())
+}
+
+/// With `--strict-writes`, check that the file called `name` (which is `len` bytes long) contains
+/// exactly `expected` bytes.
+fn check_size(config: &Config, name: &str, len: u64, expected: usize) -> Result<(), io::Error> {
+    if config.strict_writes && u64::try_from(expected).ok() != Some(len) {
+        return Err(io::Error::other(format!(
+            "{} contains {} bytes, but {} bytes were written to it",
+            name, len, expected
+        )));
+    }
+    Ok(())
+}
+
+/// With `--strict-writes`, check that `f` (whose path is `path`) contains exactly `expected`
+/// bytes.
+fn check_file_size(
+    config: &Config,
+    f: &File,
+    path: &Path,
+    expected: usize,
+) -> Result<(), io::Error> {
+    if config.strict_writes {
+        check_size(
+            config,
+            &path.display().to_string(),
+            f.metadata()?.len(),
+            expected,
+        )?;
+    }
+    Ok(())
+}
+
+/// The output files named `prefix<N>` on disk.
+struct FileSink<'a> {
+    config: &'a Config,
+    prefix: &'a str,
+}
+
+impl Sink for FileSink<'_> {
+    type File = File;
+
+    fn exists(&self, idx: usize) -> bool {
+        sealed_path(self.config, self.prefix, idx).exists()
+    }
+
+    fn rename(&mut self, from: usize, to: usize) -> Result<(), io::Error> {
+        // With `--encrypt-recipient`, the newest file is only renamed here: `encrypt_sealed()`
+        // encrypts it once signals are unblocked.
+        let to_path = match from {
+            0 => outfile_path(self.prefix, to),
+            _ => sealed_path(self.config, self.prefix, to),
+        };
+        rename(sealed_path(self.config, self.prefix, from), to_path)
+    }
+
+    fn finalize(&mut self, idx: usize) -> Result<(), io::Error> {
+        match self.config.recipients {
+            // `encrypt_sealed()` finalizes the ciphertext.
+            Some(_) => Ok(()),
+            None => finalize_outfile(self.config, &sealed_path(self.config, self.prefix, idx)),
+        }
+    }
+
+    fn create(&mut self, idx: usize) -> Result<File, io::Error> {
+        let path = outfile_path(self.prefix, idx);
+        recreating_dir(self.config, &path, || match self.config.shared {
+            // Never truncate what another writer may already have appended.
+            true => open_single(self.config, &path),
+            false => create_outfile(self.config, &path),
+        })
+    }
+
+    fn size(&self, f: &File) -> Result<u64, io::Error> {
+        Ok(f.metadata()?.len())
+    }
+
+    fn name(&self, idx: usize) -> String {
+        sealed_path(self.config, self.prefix, idx)
+            .display()
+            .to_string()
+    }
+}
+
+/// Run `f` with all signals that would kill us (the ones we can) blocked, so that it can't be
+/// interrupted part way through.
+fn signals_blocked<T>(
+    all_sigs: sigset_t,
+    f: impl FnOnce() -> Result<T, io::Error>,
+) -> Result<T, Box<dyn Error>> {
+    // We can use a full signal block set here. `sigprocmask` will ignore the unmaskable ones.
+    let mut old_sigs = MaybeUninit::uninit();
+    if unsafe { sigprocmask(SIG_BLOCK, &all_sigs, old_sigs.as_mut_ptr()) } == -1 {
+        return Err("sigprocmask failed".into());
+    }
+    let old_sigs = unsafe { old_sigs.assume_init() };
+
+    // Signals are now blocked. Diagnostics are held back until they are unblocked, so as not to
+    // prolong the blocked section.
+    let deferred = diag::Deferred::new();
+    let res = f();
+
+    // Restore the old signal mask.
+    if unsafe { sigprocmask(SIG_SETMASK, &old_sigs, ptr::null_mut()) } == -1 {
+        return Err("sigprocmask failed".into());
+    }
+    drop(deferred);
+
+    res.map_err(|e| e.into())
+}
+
+/// Rotate the files under `prefix`, returning the freshly created file to use next. `written` is
+/// the number of bytes written to `old_file` and, with `--verify`, `checksum` is their CRC-32 (if
+/// known). With `--active-marker`, `sequence` is the number of the file to use next.
+fn rotate(
+    config: &Config,
+    prefix: &str,
+    old_file: File,
+    written: usize,
+    checksum: Option<u32>,
+    sequence: Option<u64>,
+    all_sigs: sigset_t,
+) -> Result<File, Box<dyn Error>> {
+    // Reading the file back can take a while, so is done before signals are blocked: nothing has
+    // been renamed yet, so if rotee is killed meanwhile, the next run finds the file as it was.
+    if let Some(c) = checksum {
+        verify_file(&old_file, &outfile_path(prefix, 0), c)?;
+    }
+    // `rotate_inner()` must not be interrupted, or output files may go missing.
+    let f = signals_blocked(all_sigs, || {
+        sync_closing(config, &old_file)?;
+        remove_oldest(config, prefix)?;
+        let f = rotate_inner(config, &mut FileSink { config, prefix }, old_file, written)?;
+        if let Some(n) = sequence {
+            write_active_marker(config, prefix, Some(n))?;
+        }
+        remove_old(config, prefix)?;
+        thin(config, prefix)?;
+        Ok(f)
+    })?;
+    encrypt_sealed(config, prefix)?;
+    Ok(f)
+}
+
+/// With `--encrypt-recipient`, encrypt the plaintext file 1 under `prefix` (the file that the last
+/// rotation sealed), if there is one, and then remove it. Encrypting can take a while, so this runs
+/// with signals unblocked: if rotee is killed part way through, the plaintext is left behind, and
+/// the next run encrypts it when it starts.
+fn encrypt_sealed(config: &Config, prefix: &str) -> Result<(), io::Error> {
+    let Some(r) = &config.recipients else {
+        return Ok(());
+    };
+    let (from, to) = (outfile_path(prefix, 1), sealed_path(config, prefix, 1));
+    if !from.exists() {
+        return Ok(());
+    }
+    // The plaintext is only removed once the ciphertext is durable. If a previous run was killed
+    // after that, but before removing the plaintext, the ciphertext is simply written again.
+    r.encrypt_file(&from, &to, config.keep_temps)?;
+    finalize_outfile(config, &to)?;
+    fs::remove_file(from)
+}
+
+/// The path of rotated file `idx` under `prefix` as it is on disk: as `sealed_path()`, except that,
+/// with `--encrypt-recipient`, file 1 is plaintext until `encrypt_sealed()` has encrypted it.
+fn rotated_path(config: &Config, prefix: &str, idx: usize) -> PathBuf {
+    let plain = outfile_path(prefix, idx);
+    match config.recipients {
+        Some(_) if idx == 1 && plain.exists() => plain,
+        _ => sealed_path(config, prefix, idx),
+    }
+}
+
+/// With `--verify`, check that the file at `path` (which `f` is open on) reads back as what was
+/// written to it, whose CRC-32 is `expected`. The file is synced, and its pages dropped from the
+/// page cache, first, so that what is read back comes from storage.
+fn verify_file(f: &File, path: &Path, expected: u32) -> Result<(), io::Error> {
+    f.sync_data()?;
+    // This is only advice: if the kernel doesn't take it, the file is read from the cache.
+    unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    let mut crc = Crc32::default();
+    let mut r = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => crc.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    if crc.digest() != expected {
+        return Err(io::Error::other(format!(
+            "{} failed verification: what was written has CRC-32 {:08x}, but what was read back \
+             has {:08x}",
+            path.display(),
+            expected,
+            crc.digest()
+        )));
+    }
+    Ok(())
+}
+
+/// With `--buffer-files`, write `buf`, a complete file, as the newest file under `prefix`, first
+/// moving the others up one place.
+fn rotate_buffered(
+    config: &Config,
+    prefix: &str,
+    buf: &[u8],
+    all_sigs: sigset_t,
+) -> Result<(), Box<dyn Error>> {
+    signals_blocked(all_sigs, || {
+        remove_oldest(config, prefix)?;
+        shift_files(config, &mut FileSink { config, prefix })?;
+        write_whole(config, &outfile_path(prefix, 0), buf)?;
+        remove_old(config, prefix)?;
+        thin(config, prefix)
+    })?;
+    Ok(encrypt_sealed(config, prefix)?)
+}
+
+/// Remove the oldest file under `prefix` before a rotation. Rotating only replaces the oldest file
+/// by renaming the one before it, which `--max-age` or `--thin` may have removed.
+fn remove_oldest(config: &Config, prefix: &str) -> Result<(), io::Error> {
+    if let Some(idx) = oldest_index(config) {
+        match fs::remove_file(sealed_path(config, prefix, idx)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// The number of the oldest file that `remove_oldest()` removes before a rotation, if any.
+fn oldest_index(config: &Config) -> Option<usize> {
+    (config.num_files > 1).then(|| config.num_files - 1)
+}
+
+/// The output files of `--explain-naming`: which exist is tracked, and what would be done to
+/// them recorded, but nothing is done on disk.
+struct PlanSink<'a> {
+    config: &'a Config,
+    prefix: &'a str,
+    files: BTreeSet<usize>,
+    ops: Vec<String>,
+}
+
+impl Sink for PlanSink<'_> {
+    type File = io::Sink;
+
+    fn exists(&self, idx: usize) -> bool {
+        self.files.contains(&idx)
+    }
+
+    fn rename(&mut self, from: usize, to: usize) -> Result<(), io::Error> {
+        let (from_path, to_path) = (
+            sealed_path(self.config, self.prefix, from),
+            sealed_path(self.config, self.prefix, to),
+        );
+        match &self.config.recipients {
+            Some(_) if from == 0 => {
+                let plain = outfile_path(self.prefix, to);
+                self.ops.push(format!(
+                    "rename {} {}",
+                    from_path.display(),
+                    plain.display()
+                ));
+                self.ops
+                    .push(format!("encrypt {} {}", plain.display(), to_path.display()));
+                self.ops.push(format!("remove {}", plain.display()));
+            }
+            _ => self.ops.push(format!(
+                "rename {} {}",
+                from_path.display(),
+                to_path.display()
+            )),
+        }
+        self.files.remove(&from);
+        self.files.insert(to);
+        Ok(())
+    }
+
+    fn finalize(&mut self, idx: usize) -> Result<(), io::Error> {
+        if self.config.finalized_perms.is_some() || self.config.seal_readonly {
+            self.ops.push(format!("chmod {}", self.name(idx)));
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, idx: usize) -> Result<io::Sink, io::Error> {
+        let verb = match self.config.buffer_files {
+            true => "write",
+            false => "create",
+        };
+        self.ops.push(format!("{} {}", verb, self.name(idx)));
+        self.files.insert(idx);
+        Ok(io::sink())
+    }
+
+    fn size(&self, _: &io::Sink) -> Result<u64, io::Error> {
+        Ok(0)
+    }
+
+    fn name(&self, idx: usize) -> String {
+        sealed_path(self.config, self.prefix, idx)
+            .display()
+            .to_string()
+    }
+}
+
+/// The number of rotations that `--explain-naming` shows.
+const EXPLAIN_ROTATIONS: usize = 5;
+
+/// With `--explain-naming`, print the files that would be created, renamed, and removed, starting
+/// from an empty directory, by the first few rotations: enough to fill the series, and to drop
+/// its oldest file, unless that takes more than `EXPLAIN_ROTATIONS`.
+fn explain_naming(config: &Config, clock: &dyn Clock) {
+    let day = config.date_dir.then(|| DayTracker::new(clock.now()));
+    let live = series_prefix(config, day.as_ref().map(|d| d.date()));
+    let prefix = config
+        .staging_prefix
+        .clone()
+        .unwrap_or_else(|| live.clone());
+    let mut sink = PlanSink {
+        config,
+        prefix: &prefix,
+        files: BTreeSet::new(),
+        ops: Vec::new(),
+    };
+    // With `--buffer-files`, the first file is only written when it is complete.
+    if !config.buffer_files {
+        sink.create(0).unwrap();
+    }
+    println!("start:");
+    for op in sink.ops.drain(..) {
+        println!("  {}", op);
+    }
+    for i in 1..=config.num_files.min(EXPLAIN_ROTATIONS) {
+        if let Some(idx) = oldest_index(config).filter(|&idx| sink.exists(idx)) {
+            sink.ops.push(format!("remove {}", sink.name(idx)));
+            sink.files.remove(&idx);
+        }
+        if config.buffer_files {
+            shift_files(config, &mut sink).unwrap();
+            sink.create(0).unwrap();
+        } else {
+            rotate_inner(config, &mut sink, io::sink(), 0).unwrap();
+        }
+        println!("rotation {}:", i);
+        for op in sink.ops.drain(..) {
+            println!("  {}", op);
+        }
+    }
+    if config.staging_prefix.is_some() {
+        println!(
+            "commit: each {}N is renamed to {}N, and each other {}N removed",
+            prefix, live, live
+        );
+    }
+}
+
+/// Write `buf` as the file at `path` in one go: it is written to a temporary file, (with
+/// `--buffer-files-sync`) made durable, and only then renamed into place, so that the file at
+/// `path` is never seen part written. With `--buffer-files-sync`, the rename is made durable too.
+fn write_whole(config: &Config, path: &Path, buf: &[u8]) -> Result<(), io::Error> {
+    let tmp = TempFile::new(path, config.keep_temps);
+    let mut f = create_outfile(config, tmp.path())?;
+    f.write_all(buf)?;
+    if !config.buffer_files_sync {
+        return tmp.persist(path);
+    }
+    f.sync_data()?;
+    tmp.persist(path)?;
+    let dir = path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
+/// With `--checkpoint-file`, make what was written to `f`, which is being closed, durable: the
+/// next checkpoint's offset covers it, but only the active file is synced then.
+fn sync_closing(config: &Config, f: &File) -> Result<(), io::Error> {
+    match config.checkpoint_file {
+        Some(_) => f.sync_data(),
+        None => Ok(()),
+    }
+}
+
+/// Make everything written to `of` durable, then atomically replace the checkpoint file at
+/// `path` with one recording `offset`, the number of bytes written to the series in total. A
+/// reader of `path` thus never sees an offset beyond what would survive a crash.
+fn write_checkpoint(config: &Config, path: &Path, of: &File, offset: u64) -> Result<(), io::Error> {
+    of.sync_data()?;
+    let tmp = TempFile::new(path, config.keep_temps);
+    let mut f = File::create(tmp.path())?;
+    writeln!(f, "{}", offset)?;
+    f.sync_all()?;
+    tmp.persist(path)?;
+    // Make the rename itself durable.
+    let dir = path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
+/// Set by the `--commit-signal` handler.
+static COMMIT: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_commit_signal(_: c_int) {
+    COMMIT.store(true, Ordering::SeqCst);
+    wake();
+}
+
+/// Toggled by the `--profile-signal` handler: should the alternate profile be in effect?
+static ALT_PROFILE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_profile_signal(_: c_int) {
+    ALT_PROFILE.fetch_xor(true, Ordering::SeqCst);
+}
+
+/// The `--log-reopen-signal` handler. The log file is reopened before the next diagnostic is
+/// written to it.
+extern "C" fn on_reopen_log_signal(_: c_int) {
+    diag::request_reopen();
+}
+
+/// Set (to the signal number) when, in exec mode or with `--listen-unix` or `--listen-tcp`, we
+/// are asked to terminate.
+static TERMINATE: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_terminate_signal(signo: c_int) {
+    TERMINATE.store(signo, Ordering::SeqCst);
+    wake();
+}
+
+/// The (read, write) ends of a pipe that signal handlers write to, so that waiting for input (see
+/// `poll_inputs()`) can't miss a signal that arrives just before the wait starts. -1 until a
+/// handler is installed.
+static SIGNAL_PIPE: [AtomicI32; 2] = [AtomicI32::new(-1), AtomicI32::new(-1)];
+
+/// Wake up anything waiting in `poll_inputs()`. Async-signal-safe.
+fn wake() {
+    let fd = SIGNAL_PIPE[1].load(Ordering::SeqCst);
+    if fd != -1 {
+        // If the pipe is full, a wake up is already pending.
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// Wait until at least one of `fds` is readable (or at EOF), a signal handler has run, or
+/// `timeout` (if given) has passed. Returns which of `fds` are ready (none, if woken by a signal or
+/// the timeout).
+fn poll_inputs(fds: &[RawFd], timeout: Option<Duration>) -> Result<Vec<bool>, io::Error> {
+    let sig_fd = SIGNAL_PIPE[0].load(Ordering::SeqCst);
+    // Negative fds are ignored by `poll`.
+    let mut pfds = fds
+        .iter()
+        .chain([&sig_fd])
+        .map(|&fd| pollfd {
+            fd,
+            events: POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    let nfds = nfds_t::try_from(pfds.len()).unwrap();
+    // Round up, so that we don't wake just before the timeout and have to wait again.
+    let ms = timeout.map_or(-1, |t| {
+        c_int::try_from(t.as_micros().div_ceil(1000)).unwrap_or(c_int::MAX)
+    });
+    if unsafe { poll(pfds.as_mut_ptr(), nfds, ms) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Ok(vec![false; fds.len()]);
+        }
+        return Err(e);
+    }
+    if pfds.last().unwrap().revents != 0 {
+        // Drain the pipe: the caller will check what the handlers did.
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(sig_fd, buf.as_mut_ptr().cast(), buf.len()) } > 0 {}
+    }
+    Ok(pfds[..fds.len()].iter().map(|p| p.revents != 0).collect())
+}
+
+/// How to wait for `clock` to reach `deadline` (if there is one) alongside input: a file
+/// descriptor to add to `poll_inputs()` (-1 for none), and a timeout.
+fn deadline_wait(clock: &dyn Clock, deadline: Option<SystemTime>) -> (RawFd, Option<Duration>) {
+    match (deadline, clock.fd()) {
+        (None, _) => (-1, None),
+        (Some(_), Some(fd)) => (fd, None),
+        (Some(d), None) => (-1, Some(d.duration_since(clock.now()).unwrap_or_default())),
+    }
+}
+
+/// Install `handler` for `signo`. The handler doesn't restart system calls, and wakes up
+/// `poll_inputs()`, so the signal is dealt with without waiting for more input.
+fn install_handler(signo: c_int, handler: extern "C" fn(c_int)) -> Result<(), io::Error> {
+    if SIGNAL_PIPE[0].load(Ordering::SeqCst) == -1 {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        SIGNAL_PIPE[0].store(fds[0], Ordering::SeqCst);
+        SIGNAL_PIPE[1].store(fds[1], Ordering::SeqCst);
+    }
+    let mut sa = unsafe { MaybeUninit::<sigaction>::zeroed().assume_init() };
+    sa.sa_sigaction = handler as usize;
+    if unsafe { sigemptyset(&mut sa.sa_mask) } == -1
+        || unsafe { libc::sigaction(signo, &sa, ptr::null_mut()) } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// What a signal that we handle does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SignalAction {
+    /// Commit the staged files (`--commit-signal`).
+    Commit,
+    /// Switch to the other profile (`--profile-signal`).
+    Profile,
+    /// Stop reading, and exit (in exec mode, or with `--listen-unix` or `--listen-tcp`).
+    Terminate,
+    /// Forward the signal to the `--` command (`--forward-signals`).
+    Forward,
+    /// Copy our terminal's window size to the `--pty` terminal.
+    Resize,
+    /// Reopen the `--log-file`.
+    ReopenLog,
+}
+
+impl SignalAction {
+    fn handler(self) -> extern "C" fn(c_int) {
+        match self {
+            SignalAction::Commit => on_commit_signal,
+            SignalAction::Profile => on_profile_signal,
+            SignalAction::Terminate => on_terminate_signal,
+            SignalAction::Forward => on_forward_signal,
+            SignalAction::Resize => on_winch_signal,
+            SignalAction::ReopenLog => on_reopen_log_signal,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            SignalAction::Commit => "commit the staged files (--commit-signal)",
+            SignalAction::Profile => "switch to the other profile (--profile-signal)",
+            SignalAction::Terminate => "stop reading and exit",
+            SignalAction::Forward => "forward to the command (--forward-signals)",
+            SignalAction::Resize => "copy the terminal's window size to the command's (--pty)",
+            SignalAction::ReopenLog => "reopen the log file (--log-reopen-signal)",
+        }
+    }
+}
+
+/// The signals that we handle with `config`, and what each does. Signals not listed have their
+/// default effect.
+fn handled_signals(config: &Config) -> Vec<(c_int, SignalAction)> {
+    let mut sigs = Vec::new();
+    if config.staging_prefix.is_some() {
+        sigs.push((config.commit_signo, SignalAction::Commit));
+    }
+    if config.alt_profile() {
+        sigs.push((config.profile_signo, SignalAction::Profile));
+    }
+    if !config.exec.is_empty() {
+        for &s in &config.forward_signos {
+            sigs.push((s, SignalAction::Forward));
+        }
+        for s in [libc::SIGINT, libc::SIGTERM] {
+            if !config.forward_signos.contains(&s) {
+                sigs.push((s, SignalAction::Terminate));
+            }
+        }
+        if config.pty {
+            sigs.push((libc::SIGWINCH, SignalAction::Resize));
+        }
+    } else if config.listen_unix.is_some() || config.listen_tcp_addr.is_some() {
+        for s in [libc::SIGINT, libc::SIGTERM] {
+            sigs.push((s, SignalAction::Terminate));
+        }
+    }
+    if let Some(s) = config.log_reopen_signo {
+        sigs.push((s, SignalAction::ReopenLog));
+    }
+    sigs
+}
+
+/// Install the handlers of those of `handled_signals(config)` whose action is one of `actions`.
+fn install_handlers(config: &Config, actions: &[SignalAction]) -> Result<(), io::Error> {
+    for (signo, action) in handled_signals(config) {
+        if actions.contains(&action) {
+            install_handler(signo, action.handler())?;
+        }
+    }
+    Ok(())
+}
+
+/// With `--list-signals`, print the signals that we handle with `config`, and what each does.
+fn list_signals(config: &Config) {
+    let sigs = handled_signals(config);
+    if sigs.is_empty() {
+        println!("no signals are handled: all have their default effect");
+    }
+    for (signo, action) in sigs {
+        println!("{:<9} {}", signal_name(signo), action.describe());
+    }
+}
+
+/// Commit the staged files: rename each `staging<N>` over `live<N>` and remove any `live<N>`
+/// without a staged counterpart, so that the live set becomes exactly the staged set, and files
+/// committed earlier are gone. The steps are first recorded in a journal (see
+/// `commit_journal_path()`), which is only removed once they are all done, so that a commit
+/// interrupted by a crash is completed by `finish_commit()` when rotee next starts.
+/// Returns a fresh active staging file. `written` is the number of bytes written to `old_file`.
+/// With `--active-marker`, `sequence` is the number of the fresh file.
+fn commit(
+    config: &Config,
+    staging: &str,
+    live: &str,
+    old_file: File,
+    written: usize,
+    sequence: Option<u64>,
+    all_sigs: sigset_t,
+) -> Result<File, Box<dyn Error>> {
+    check_file_size(config, &old_file, &outfile_path(staging, 0), written)?;
+    sync_closing(config, &old_file)?;
+    drop(old_file);
+    signals_blocked(all_sigs, || {
+        let steps = (0..config.num_files)
+            .map(|idx| match outfile_path(staging, idx).exists() {
+                true => format!("rename {}\n", idx),
+                false => format!("remove {}\n", idx),
+            })
+            .collect::<String>();
+        let journal = commit_journal_path(live);
+        let tmp = TempFile::new(&journal, config.keep_temps);
+        recreating_dir(config, &journal, || fs::write(tmp.path(), &steps))?;
+        tmp.persist(&journal)?;
+        fail_point!("commit-journaled");
+        finish_commit(config, staging, live)?;
+        let path = outfile_path(staging, 0);
+        let f = recreating_dir(config, &path, || create_outfile(config, &path))?;
+        if let Some(n) = sequence {
+            write_active_marker(config, staging, Some(n))?;
+        }
+        Ok(f)
+    })
+}
+
+/// With `--staging-prefix`, the path of the journal of a commit to the live series at `prefix`,
+/// which exists only while the commit is in progress.
+fn commit_journal_path(prefix: &str) -> PathBuf {
+    PathBuf::from(format!("{}commit", prefix))
+}
+
+/// If there is a commit journal for the live series at `live`, carry out its steps, then remove
+/// it. Each step can be carried out again without harm (a file already renamed, or already
+/// removed, is skipped), so this completes a commit that was interrupted part way through.
+fn finish_commit(config: &Config, staging: &str, live: &str) -> Result<(), io::Error> {
+    let journal = commit_journal_path(live);
+    let steps = match fs::read_to_string(&journal) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for (i, line) in steps.lines().enumerate() {
+        let malformed = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: line {} is malformed", journal.display(), i + 1),
+            )
+        };
+        let (step, idx) = line.split_once(' ').ok_or_else(malformed)?;
+        let idx = idx.parse::<usize>().map_err(|_| malformed())?;
+        let to = outfile_path(live, idx);
+        match step {
+            "rename" => {
+                let from = outfile_path(staging, idx);
+                if from.exists() {
+                    recreating_dir(config, &to, || rename(&from, &to))?;
+                }
+                // The active file is finished with once it's committed.
+                if idx == 0 && to.exists() {
+                    finalize_outfile(config, &to)?;
+                }
+                fail_point!("commit-renamed-{}", idx);
+            }
+            "remove" => match fs::remove_file(&to) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            },
+            _ => return Err(malformed()),
+        }
+    }
+    remove_old(config, live)?;
+    thin(config, live)?;
+    fs::remove_file(&journal)
+}
+
+/// With `--active-marker`, the path of the marker of the series at `prefix`.
+fn active_marker_path(prefix: &str) -> PathBuf {
+    PathBuf::from(format!("{}active", prefix))
+}
+
+/// Atomically replace the marker of the series at `prefix`, so that it names the active file,
+/// which is file number `sequence` (see `--header-line`) of this run, or, if `sequence` is `None`,
+/// says that no file is active.
+fn write_active_marker(
+    config: &Config,
+    prefix: &str,
+    sequence: Option<u64>,
+) -> Result<(), io::Error> {
+    let path = active_marker_path(prefix);
+    let contents = match sequence {
+        Some(n) => {
+            let active = outfile_path(prefix, 0);
+            format!("{} {}\n", active.file_name().unwrap().to_string_lossy(), n)
+        }
+        None => "none\n".to_owned(),
+    };
+    let tmp = TempFile::new(&path, config.keep_temps);
+    fs::write(tmp.path(), contents)?;
+    tmp.persist(&path)
+}
+
+/// With `--max-age`, remove the rotated files under `prefix` (i.e. all but the newest) that were
+/// last modified longer ago than the limit. Modification times are real times, so the age is
+/// measured against the system clock, not `Clock`.
+fn remove_old(config: &Config, prefix: &str) -> Result<(), io::Error> {
+    let Some(max_age) = config.max_file_age else {
+        return Ok(());
+    };
+    let now = SystemTime::now();
+    for idx in 1..config.num_files {
+        let path = rotated_path(config, prefix, idx);
+        let modified = match path.metadata().and_then(|m| m.modified()) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if now.duration_since(modified).is_ok_and(|age| age > max_age) {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// With `--thin`, remove the rotated files under `prefix` that the thinning specification doesn't
+/// keep. This runs after `-n` and `--max-age` have removed files, and judges what they left.
+fn thin(config: &Config, prefix: &str) -> Result<(), io::Error> {
+    let Some(thinning) = &config.thinning else {
+        return Ok(());
+    };
+    let present = (1..config.num_files)
+        .filter(|&idx| rotated_path(config, prefix, idx).exists())
+        .collect::<Vec<_>>();
+    for idx in thinning.to_remove(&present) {
+        match fs::remove_file(rotated_path(config, prefix, idx)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Rotate the files in `sink`, returning the freshly created file to use next. `written` is the
+/// number of bytes written to `old_file`.
+fn rotate_inner<S: Sink>(
+    config: &Config,
+    sink: &mut S,
+    old_file: S::File,
+    written: usize,
+) -> Result<S::File, io::Error> {
+    if config.strict_writes {
+        check_size(config, &sink.name(0), sink.size(&old_file)?, written)?;
+    }
+    drop(old_file);
+    fail_point!("rotate-dropped-old");
+    shift_files(config, sink)?;
+    fail_point!("rotate-before-create");
+    let f = sink.create(0)?;
+    fail_point!("rotate-after-create");
+    Ok(f)
+}
+
+/// Move each of the files in `sink` up one place, the oldest dropping off the end, leaving no
+/// file 0.
+fn shift_files<S: Sink>(config: &Config, sink: &mut S) -> Result<(), io::Error> {
+    // The number of renames performed so far (only used by fail points).
+    let mut renames = 0;
+    for i in (0..(config.num_files - 1)).rev() {
+        if sink.exists(i) {
+            // Another process may change the directory between our check and the rename. A file
+            // that it created at `i + 1` is replaced (`rename` overwrites atomically), just as if
+            // it had been there all along; if it removed file `i`, there's nothing to move.
+            match sink.rename(i, i + 1) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                r => r?,
+            }
+            if i == 0 {
+                sink.finalize(1)?;
+            }
+            renames += 1;
+            fail_point!("rotate-renamed-{}", renames);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let subcommand: Option<fn(&[String]) -> i32> = match env::args().nth(1).as_deref() {
+        Some("replay-timing") => Some(subcommands::replay_timing::replay_timing),
+        Some("decrypt") => Some(subcommands::decrypt::decrypt),
+        Some("cat") => Some(subcommands::cat::cat),
+        Some("tail") => Some(subcommands::tail::tail),
+        Some("prune") => Some(subcommands::prune::prune),
+        Some("ls") => Some(subcommands::ls::ls),
+        Some("locate") => Some(subcommands::locate::locate),
+        Some("pack") => Some(subcommands::pack::pack),
+        Some("selftest" | "--selftest") => Some(subcommands::selftest::selftest),
+        _ => None,
+    };
+    if let Some(f) = subcommand {
+        init_log(LevelFilter::Info);
+        process::exit(f(&env::args().skip(2).collect::<Vec<_>>()));
+    }
+
+    let mut config = Config::default();
+
+    // With `ROTEE_NO_CONFIG` (e.g. for `rotee selftest`'s captures), neither configuration file is
+    // read.
+    let no_config = env::var_os("ROTEE_NO_CONFIG").is_some_and(|v| !v.is_empty());
+    let system = match no_config {
+        true => Ok(Vec::new()),
+        false => read_conf(Path::new(SYSTEM_CONF)),
+    };
+    let user = match env::var_os("HOME") {
+        Some(h) if !no_config => read_conf(&Path::new(&h).join(USER_CONF)),
+        _ => Ok(Vec::new()),
+    };
+    let (system, user) = match (system, user) {
+        (Ok(s), Ok(u)) => (s, u),
+        (Err(e), _) | (_, Err(e)) => return fatal(&e),
+    };
+    let mut args = layer_args(
+        system,
+        user,
+        env::var("ROTEE_DEFAULT_ARGS").ok().as_deref(),
+        &env::args().collect::<Vec<_>>(),
+    );
+    // Everything after `--` is a command to run.
+    if let Some(i) = args.iter().position(|a| a == "--") {
+        config.exec = args.split_off(i + 1);
+        args.pop();
+        if config.exec.is_empty() {
+            fatal("no command given after '--'");
+        }
+    }
+
+    let level = match take_verbosity(&mut args) {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    init_log(level);
+
+    App::new("rotee")
+        .desc("Split stdin between rotating output files")
+        .opt(
+            Opt::new("buf-size", &mut config.buffer_size)
+                .short('b')
+                .help("size of the buffer used to read from stdin"),
+        )
+        .opt(
+            Opt::new("group", &mut config.group)
+                .short('g')
+                .help("group (name or gid) to give ownership of output files to"),
+        )
+        .opt(
+            Opt::new("date-dir", &mut config.date_dir)
+                .short('D')
+                .help("write each day's files into a directory named after the date"),
+        )
+        .opt(
+            Opt::new("done-file", &mut config.done_file)
+                .short('d')
+                .help("file to create when input is exhausted without error"),
+        )
+        .opt(
+            Opt::new("pid-file", &mut config.pid_file)
+                .help("file to write rotee's PID to while it runs"),
+        )
+        .opt(
+            Opt::new("stale-pid-file", &mut config.stale_pid_file)
+                .help("if --pid-file names a process that has gone: error or replace (default)"),
+        )
+        .opt(
+            Opt::new("heartbeat-file", &mut config.heartbeat_file)
+                .help("file whose modification time is updated after every rotation"),
+        )
+        .opt(
+            Opt::new("heartbeat-interval", &mut config.heartbeat_interval)
+                .help("also update the heartbeat file on reading input, at most this often"),
+        )
+        .opt(
+            Opt::new("checkpoint-file", &mut config.checkpoint_file)
+                .help("file in which to record the number of bytes durably written"),
+        )
+        .opt(
+            Opt::new("checkpoint-interval", &mut config.checkpoint_interval)
+                .help("how often (e.g. 30s) to make output durable and update the checkpoint file"),
+        )
+        .opt(
+            Opt::new("no-echo", &mut config.no_echo)
+                .short('e')
+                .help("do not re-echo stdout"),
+        )
+        .opt(
+            Opt::new("line-input", &mut config.line_input)
+                .short('l')
+                .help("read stdin a line at a time"),
+        )
+        .opt(
+            Opt::new("mark", &mut config.mark)
+                .help("write a mark line after each idle period of this length (e.g. 20m)"),
+        )
+        .opt(
+            Opt::new("mark-echo", &mut config.mark_echo)
+                .help("echo mark lines as well as writing them to the output files"),
+        )
+        .opt(
+            Opt::new("mark-text", &mut config.mark_text)
+                .help("text of mark lines, where {time} is the time (default: -- MARK {time} --)"),
+        )
+        .opt(
+            Opt::new("match", &mut config.match_pattern).help(
+                "regular expression that lines are matched against (see --rotate-after-matches)",
+            ),
+        )
+        .opt(
+            Opt::new("max-line-bytes", &mut config.max_line_bytes)
+                .short('m')
+                .help("maximum number of bytes to read at once in line input mode"),
+        )
+        .opt(
+            Opt::new("max-read", &mut config.max_read)
+                .help("maximum number of bytes to read from stdin at once (default: -b)"),
+        )
+        .opt(
+            Opt::new("echo-flush-lines", &mut config.echo_flush_lines).help(
+                "only flush the echo to stdout at the end of each line, not after every read",
+            ),
+        )
+        .opt(
+            Opt::new("echo-queue-size", &mut config.echo_queue_size).help(
+                "echo through a queue of this many bytes, so file writes needn't wait for it",
+            ),
+        )
+        .opt(
+            Opt::new("echo-overflow", &mut config.echo_overflow).help(
+                "when the echo queue is full, block (default) until it drains or drop the echo",
+            ),
+        )
+        .opt(
+            Opt::new("confirm-eof", &mut config.confirm_eof).help(
+                "when reading a regular file, read again before treating a 0-byte read as EOF",
+            ),
+        )
+        .opt(
+            Opt::new("min-rotate-interval", &mut config.min_rotate_interval)
+                .short('i')
+                .help("minimum time between rotations (e.g. 500ms, 10s, 5m)"),
+        )
+        .opt(
+            Opt::new("no-files", &mut config.no_files)
+                .short('N')
+                .help("don't write output files, only echo stdin to stdout"),
+        )
+        .opt(
+            Opt::new("num-files", &mut config.num_files_arg)
+                .short('n')
+                .help("maximum number of files to use"),
+        )
+        .opt(Opt::new("retain", &mut config.retain).help(
+            "keep about this much output, choosing -s and -n, or whichever isn't given (e.g. \
+                 --retain 5G, or --retain 5G -s 64M)",
+        ))
+        .opt(
+            Opt::new("log-file", &mut config.log_file)
+                .help("append rotee's own warnings and notes to this file, not stderr"),
+        )
+        .opt(
+            Opt::new("log-reopen-signal", &mut config.log_reopen_signal)
+                .help("signal that reopens the --log-file (e.g. HUP)"),
+        )
+        .opt(
+            Opt::new("keep-temps", &mut config.keep_temps)
+                .help("don't remove temporary files, even those left by failures (for debugging)"),
+        )
+        .opt(
+            Opt::new("recreate-dir", &mut config.recreate_dir)
+                .help("if the output directory disappears, recreate it rather than fail"),
+        )
+        .opt(
+            Opt::new("max-runtime", &mut config.max_runtime)
+                .help("stop successfully after running for this long (e.g. 10m)"),
+        )
+        .opt(
+            Opt::new("rotate-at-eof", &mut config.rotate_at_eof)
+                .help("at EOF, rotate the active file (unless it's empty), leaving it empty"),
+        )
+        .opt(
+            Opt::new("rotate-at-start", &mut config.rotate_at_start)
+                .help("at startup, rotate a non-empty newest file rather than truncating it"),
+        )
+        .opt(
+            Opt::new("stat-size", &mut config.stat_size).help(
+                "rotate by the active file's size on disk, in case something else changes it",
+            ),
+        )
+        .opt(
+            Opt::new("header-line", &mut config.header_line)
+                .help("start each file with a line like '# rotee file 1 started <time>'"),
+        )
+        .opt(
+            Opt::new("buffer-files", &mut config.buffer_files)
+                .help("hold each file in memory, writing it only once it's complete"),
+        )
+        .opt(
+            Opt::new("buffer-files-sync", &mut config.buffer_files_sync)
+                .help("with --buffer-files, fsync each file before it appears"),
+        )
+        .opt(
+            Opt::new("verify", &mut config.verify).help(
+                "when rotating, read the file back from storage and check it's what was written",
+            ),
+        )
+        .opt(
+            Opt::new("active-marker", &mut config.active_marker)
+                .help("keep a PREFIXactive file naming the active file, or 'none' on exit"),
+        )
+        .opt(
+            Opt::new("xattrs", &mut config.xattrs)
+                .help("when rotating, record the file's sequence, offset, start and CRC-32 in user.rotee.* extended attributes"),
+        )
+        .opt(
+            Opt::new("max-age", &mut config.max_age)
+                .help("remove rotated files last modified longer ago than this (e.g. 30d)"),
+        )
+        .opt(
+            Opt::new("thin", &mut config.thin).help(
+                "keep ever sparser samples of older rotated files (e.g. 10:1,100:10,1000:100)",
+            ),
+        )
+        .opt(
+            Opt::new("full-copy-compressed", &mut config.full_copy_compressed).help(
+                "also pipe everything written to the output files into this shell command \
+                 (e.g. 'xz > all.xz')",
+            ),
+        )
+        .opt(
+            Opt::new("control-fifo", &mut config.control_fifo)
+                .help("read commands (size, rotate, stats) from this named pipe"),
+        )
+        .opt(
+            Opt::new("encrypt-recipient", &mut config.encrypt_recipient)
+                .help("encrypt rotated files to this age public key (can be given more than once)"),
+        )
+        .opt(
+            Opt::new("setuid", &mut config.setuid)
+                .help("user (name or uid) to switch to once the output files are open"),
+        )
+        .opt(
+            Opt::new("setgid", &mut config.setgid)
+                .help("group (name or gid) to switch to once the output files are open"),
+        )
+        .opt(
+            Opt::new("owner", &mut config.owner)
+                .short('o')
+                .help("user (name or uid) to give ownership of output files to"),
+        )
+        .opt(
+            Opt::new("finalized-mode", &mut config.finalized_mode)
+                .help("octal mode (e.g. 0644) to give files once they are rotated"),
+        )
+        .opt(
+            Opt::new("seal-readonly", &mut config.seal_readonly)
+                .help("make files read-only once they are rotated"),
+        )
+        .opt(
+            Opt::new("file-prefix", &mut config.file_prefix)
+                .short('p')
+                .help("output filename prefix"),
+        )
+        .opt(
+            Opt::new("rotate-after-matches", &mut config.rotate_after_matches)
+                .help("rotate after this many lines have matched --match"),
+        )
+        .opt(
+            Opt::new("split-every", &mut config.split_every)
+                .help("rotate after this many occurrences of --split-byte"),
+        )
+        .opt(Opt::new("split-byte", &mut config.split_byte).help(
+            "the byte counted by --split-every: itself, \\n, \\t, \\r, \\0, or 0xNN (default: \\n)",
+        ))
+        .opt(
+            Opt::new("staging-prefix", &mut config.staging_prefix)
+                .help("write files under this prefix until committed (see --commit-signal)"),
+        )
+        .opt(
+            Opt::new("commit-signal", &mut config.commit_signal)
+                .help("signal that moves staged files to the output prefix (default WINCH)"),
+        )
+        .opt(
+            Opt::new("alt-file-size", &mut config.alt_file_size)
+                .help("file size of the alternate profile (see --profile-signal)"),
+        )
+        .opt(
+            Opt::new("alt-buf-size", &mut config.alt_buffer_size)
+                .help("buffer size of the alternate profile (see --profile-signal)"),
+        )
+        .opt(
+            Opt::new("alt-echo", &mut config.alt_echo)
+                .help("whether the alternate profile echoes input: on or off"),
+        )
+        .opt(Opt::new("profile-signal", &mut config.profile_signal).help(
+            "signal that switches to and from the alternate profile, from the next file on \
+                 (default USR2)",
+        ))
+        .opt(
+            Opt::new("explain-naming", &mut config.explain_naming)
+                .help("print the renames and removals the first few rotations would make, then exit"),
+        )
+        .opt(
+            Opt::new("list-signals", &mut config.list_signals)
+                .help("print which signals are handled, and what each does, then exit"),
+        )
+        .opt(
+            Opt::new("min-size", &mut config.min_size).help(
+                "defer match- and record-triggered rotations, and commits, until the file has this many bytes",
+            ),
+        )
+        .opt(
+            Opt::new("rotate-empty", &mut config.rotate_empty)
+                .help("commit even if nothing has been staged since the last commit"),
+        )
+        .opt(
+            Opt::new("exclude", &mut config.exclude)
+                .help("drop lines matching this regular expression (see --exclude-target)"),
+        )
+        .opt(
+            Opt::new("exclude-target", &mut config.exclude_target)
+                .help("what --exclude drops lines from: file (default), echo, or both"),
+        )
+        .opt(
+            Opt::new("squeeze-repeats", &mut config.squeeze_repeats)
+                .help("in line input mode, write runs of identical lines once, with a count"),
+        )
+        .opt(
+            Opt::new("squeeze-echo", &mut config.squeeze_echo)
+                .help("squeeze repeated lines in the echo too"),
+        )
+        .opt(
+            Opt::new("squeeze-interval", &mut config.squeeze_interval)
+                .help("summarise a run of repeated lines at least this often (e.g. 1m)"),
+        )
+        .opt(
+            Opt::new("stderr-prefix", &mut config.stderr_prefix).help(
+                "capture the command's stderr (or the --stderr-fd) into files with this prefix",
+            ),
+        )
+        .opt(
+            Opt::new("stderr-fd", &mut config.stderr_fd_arg)
+                .help("also read this fd, as if it were a command's stderr (with --stderr-prefix)"),
+        )
+        .opt(
+            Opt::new("copy-fd", &mut config.copy_fd)
+                .help("also copy everything written to the files to this fd (can be repeated)"),
+        )
+        .opt(
+            Opt::new("stderr-file-size", &mut config.stderr_file_size)
+                .help("size (in bytes) after which to rotate stderr files (default: -s)"),
+        )
+        .opt(
+            Opt::new("stderr-num-files", &mut config.stderr_num_files)
+                .help("maximum number of stderr files to use (default: -n)"),
+        )
+        .opt(
+            Opt::new("frame-length", &mut config.frame_length)
+                .help("write each chunk read prefixed by its length (4 bytes, big-endian)"),
+        )
+        .opt(
+            Opt::new("prefix-relative", &mut config.prefix_relative)
+                .help("start each line with the time since rotee started (e.g. '+12.345s ')"),
+        )
+        .opt(
+            Opt::new("format", &mut config.format)
+                .help("output format: raw (default), or json for a JSON record per line"),
+        )
+        .opt(Opt::new("utf8", &mut config.utf8).help(
+            "invalid UTF-8 in input: pass (default) to write it as is, replace to write U+FFFD \
+             instead, or reject to fail",
+        ))
+        .opt(Opt::new("crlf", &mut config.crlf).help(
+            "line endings in output files: keep (default), or lf to rewrite \\r\\n to \\n \
+             (which alters binary input!)",
+        ))
+        .opt(
+            Opt::new("crlf-echo", &mut config.crlf_echo)
+                .help("with --crlf lf, rewrite \\r\\n to \\n in the echo too"),
+        )
+        .opt(
+            Opt::new("syslog", &mut config.syslog)
+                .help("also send each line to syslog as FACILITY.PRIORITY (e.g. user.info)"),
+        )
+        .opt(
+            Opt::new("forward-signals", &mut config.forward_signals).help(
+                "in exec mode, signals to forward to the command (default TERM,INT,HUP,QUIT)",
+            ),
+        )
+        .opt(
+            Opt::new("restart", &mut config.restart).help(
+                "in exec mode, restart the command: no (default) or on-failure[:max-restarts]",
+            ),
+        )
+        .opt(
+            Opt::new("pty", &mut config.pty)
+                .help("in exec mode, run the command on a pseudo-terminal"),
+        )
+        .opt(
+            Opt::new("single-file", &mut config.single_file)
+                .help("append everything to this one file, never rotating it"),
+        )
+        .opt(
+            Opt::new("shared", &mut config.shared)
+                .help("append to files that other rotee --shared processes append to too"),
+        )
+        .opt(
+            Opt::new("warn-size", &mut config.warn_size)
+                .help("with --single-file, warn once when the file grows beyond this many bytes"),
+        )
+        .opt(
+            Opt::new("timing-file", &mut config.timing_file).help(
+                "record the timing of output into files with this prefix (see replay-timing)",
+            ),
+        )
+        .opt(
+            Opt::new("truncation-marker", &mut config.truncation_marker)
+                .help("in line input mode, mark where a line longer than -m is cut"),
+        )
+        .opt(
+            Opt::new("split-into", &mut config.split_into).help(
+                "compute the file size (-s) to split input of known size into this many files",
+            ),
+        )
+        .opt(
+            Opt::new("input-size", &mut config.input_size)
+                .help("total size of the input for --split-into (default: stdin's file size)"),
+        )
+        .opt(
+            Opt::new("listen-unix", &mut config.listen_unix)
+                .help("read the connections to a Unix socket bound to this path, not stdin"),
+        )
+        .opt(
+            Opt::new("listen-mode", &mut config.listen_mode)
+                .help("octal mode (e.g. 0660) to give the --listen-unix socket"),
+        )
+        .opt(
+            Opt::new("connection-markers", &mut config.connection_markers)
+                .help("with --listen-unix, write a line when each connection opens and closes"),
+        )
+        .opt(
+            Opt::new("connection-tags", &mut config.connection_tags)
+                .help("with --listen-unix, prefix each line with its connection's id"),
+        )
+        .opt(Opt::new("listen-tcp", &mut config.listen_tcp).help(
+            "read the connections to a TCP socket bound to [ADDR:]PORT (default ADDR: \
+                 127.0.0.1), one at a time, not stdin",
+        ))
+        .opt(
+            Opt::new("listen-allow", &mut config.listen_allow)
+                .help("with --listen-tcp, only accept connections from these CIDR ranges"),
+        )
+        .opt(Opt::new("listen-busy", &mut config.listen_busy).help(
+            "with --listen-tcp, queue (default) or refuse connections made while one is active",
+        ))
+        .opt(
+            Opt::new("strict-writes", &mut config.strict_writes)
+                .short('S')
+                .help("check that exactly the intended number of bytes is written"),
+        )
+        .opt(
+            Opt::new("max-write-errors", &mut config.max_write_errors)
+                .help("carry on after this many failed writes to the output files (default: 0)"),
+        )
+        .opt(
+            Opt::new("write-timeout", &mut config.write_timeout)
+                .help("report writes to the output files that take longer than this (e.g. 30s)"),
+        )
+        .opt(
+            Opt::new("write-timeout-action", &mut config.write_timeout_action)
+                .help("with --write-timeout, warn (default) or abort when a write takes too long"),
+        )
+        .opt(
+            Opt::new("file-size", &mut config.file_size_arg)
+                .short('s')
+                .help("size (in bytes) after which to rotate output files"),
+        )
+        .parse_strings(args);
+
+    if let Err(e) = config.validate() {
+        return fatal(&e);
+    }
+
+    // In debug builds, tests can control time by writing to a file descriptor (see
+    // `rotee::clock::FdClock`).
+    #[cfg(debug_assertions)]
+    let clock: Box<dyn Clock> = match env::var("ROTEE_FAKE_TIME_FD") {
+        Ok(fd) => match fd.parse().map(rotee::clock::FdClock::new) {
+            Ok(Ok(c)) => Box::new(c),
+            _ => return fatal("invalid ROTEE_FAKE_TIME_FD"),
+        },
+        Err(_) => Box::new(SystemClock),
+    };
+    #[cfg(not(debug_assertions))]
+    let clock: Box<dyn Clock> = Box::new(SystemClock);
+    config.started = clock.now();
+
+    if config.list_signals {
+        list_signals(&config);
+        process::exit(0);
+    }
+    if config.explain_naming {
+        explain_naming(&config, &*clock);
+        process::exit(0);
+    }
+    // A `--done-file` left by an earlier run mustn't claim that this one finished.
+    if let Some(p) = &config.done_file {
+        match fs::remove_file(p) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return fatal(&format!("can't remove --done-file '{}': {}", p, e));
+            }
+            _ => (),
+        }
+    }
+    if let Some(p) = &config.log_file {
+        match diag::Log::open(Path::new(p)) {
+            Ok(l) => diag::set_log(l),
+            Err(e) => warn!("can't open --log-file '{}': {}: logging to stderr", p, e),
+        }
+        if let Err(e) = install_handlers(&config, &[SignalAction::ReopenLog]) {
+            return fatal(&format!(
+                "can't install the --log-reopen-signal handler: {}",
+                e
+            ));
+        }
+    }
+    // The pid file is created once nothing else can fail before rotee runs, so that its removal
+    // below is never skipped.
+    if let Some(p) = &config.pid_file {
+        if let Err(e) = create_pid_file(Path::new(p), config.replace_stale_pid) {
+            return fatal(&e);
+        }
+    }
+    let code = if config.exec.is_empty() {
+        match run(&config, &*clock) {
+            Ok(()) => 0,
+            Err(e) => {
+                diag::error(&e);
+                1
+            }
+        }
+    } else {
+        exec(&config, &*clock)
+    };
+    if let Some(p) = &config.pid_file {
+        let _ = fs::remove_file(p);
+    }
+    process::exit(code);
+}
+
+/// The directory that the files of `prefix` are in.
+fn prefix_dir(prefix: &str) -> &Path {
+    match Path::new(prefix).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    }
+}
+
+/// With `--setuid` and `--setgid`, switch to the given user and group now that `series` are open,
+/// then check that the directories that files will be created in later (by rotation, commits,
+/// and so on) can still be written to.
+fn drop_privileges(config: &Config, series: &[Series]) -> Result<(), Box<dyn Error>> {
+    let Some((uid, gid)) = config.run_as else {
+        return Ok(());
+    };
+    // The supplementary groups (e.g. root's) and the group must be changed while we can still do
+    // so, i.e. before the user.
+    if unsafe { libc::setgroups(1, &gid) } == -1 || unsafe { libc::setgid(gid) } == -1 {
+        return Err(format!(
+            "can't switch to group {}: {}",
+            gid,
+            io::Error::last_os_error()
+        )
+        .into());
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } == -1 {
+            return Err(format!(
+                "can't switch to user {}: {}",
+                uid,
+                io::Error::last_os_error()
+            )
+            .into());
+        }
+    }
+
+    let mut dirs = Vec::new();
+    if config.single_file.is_none() {
+        for s in series {
+            dirs.push(prefix_dir(&s.prefix));
+            dirs.extend(s.live.as_deref().map(prefix_dir));
+            // New days' directories are created alongside the current one.
+            if config.date_dir {
+                dirs.push(prefix_dir(&s.config.file_prefix));
+            }
+        }
+    }
+    dirs.extend(config.timing_file.as_deref().map(prefix_dir));
+    dirs.extend(config.checkpoint_file.as_deref().map(prefix_dir));
+    for d in dirs {
+        let c_dir = CString::new(d.as_os_str().as_bytes())?;
+        if unsafe { libc::access(c_dir.as_ptr(), libc::W_OK | libc::X_OK) } == -1 {
+            return Err(format!(
+                "after --setuid/--setgid, can't create files in {}: {}",
+                d.display(),
+                io::Error::last_os_error()
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Create the `--pid-file` at `path`, containing our PID. The file is created exclusively, so
+/// that two instances can't both think they own it. An existing file naming a process that has
+/// gone is replaced if `replace_stale` is true; otherwise, any existing file is an error.
+fn create_pid_file(path: &Path, replace_stale: bool) -> Result<(), String> {
+    let create = || {
+        let mut f = OpenOptions::new().write(true).create_new(true).open(path)?;
+        writeln!(f, "{}", process::id())
+    };
+    let e = match create() {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => e,
+        Err(e) => return Err(format!("can't create pid file {}: {}", path.display(), e)),
+    };
+    let pid = fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse::<libc::pid_t>().ok())
+        .filter(|&pid| pid > 0);
+    let Some(pid) = pid else {
+        return Err(format!("pid file {} exists: {}", path.display(), e));
+    };
+    // A process that exists, but that we can't signal, is still running.
+    let running = unsafe { libc::kill(pid, 0) } == 0
+        || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+    if running {
+        return Err(format!(
+            "pid file {} exists, and process {} is still running",
+            path.display(),
+            pid
+        ));
+    }
+    if !replace_stale {
+        return Err(format!(
+            "pid file {} exists, but process {} has gone (see --stale-pid-file)",
+            path.display(),
+            pid
+        ));
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(format!(
+                "can't remove stale pid file {}: {}",
+                path.display(),
+                e
+            ))
+        }
+        _ => (),
+    }
+    // If another instance replaced the stale file first, this fails: it owns the file now.
+    create().map_err(|e| format!("can't create pid file {}: {}", path.display(), e))
+}
+
+/// Open the newest file of the series named `prefix<N>` for writing, truncating it. With
+/// `--rotate-at-start`, if a previous run (perhaps one that crashed) left data in the newest file,
+/// it is rotated into the series instead.
+fn open_newest(config: &Config, prefix: &str, all_sigs: sigset_t) -> Result<File, Box<dyn Error>> {
+    let newest = outfile_path(prefix, 0);
+    if let Some(dir) = newest.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    // Finish encrypting a file that a previous run sealed, before rotating anything onto it.
+    encrypt_sealed(config, prefix)?;
+    match newest.metadata() {
+        Ok(md) if config.rotate_at_start && md.len() > 0 => {
+            let len = usize::try_from(md.len())?;
+            rotate(
+                config,
+                prefix,
+                File::open(&newest)?,
+                len,
+                None,
+                None,
+                all_sigs,
+            )
+        }
+        _ => {
+            let f = create_outfile(config, &newest)?;
+            signals_blocked(all_sigs, || {
+                remove_old(config, prefix)?;
+                thin(config, prefix)
+            })?;
+            Ok(f)
+        }
+    }
+}
+
+/// With `--buffer-files`, prepare the directory of the files under `prefix`. A newest file left by
+/// an earlier run was written whole, so is left as it is (to be moved up by the first rotation),
+/// unless it's empty.
+fn open_buffered(config: &Config, prefix: &str, all_sigs: sigset_t) -> Result<(), Box<dyn Error>> {
+    let newest = outfile_path(prefix, 0);
+    if let Some(dir) = newest.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    if newest.metadata().is_ok_and(|md| md.len() == 0) {
+        fs::remove_file(&newest)?;
+    }
+    encrypt_sealed(config, prefix)?;
+    signals_blocked(all_sigs, || {
+        remove_old(config, prefix)?;
+        thin(config, prefix)
+    })
+}
+
+/// Buffer `input` for reading. We use our own buffering (rather than, for stdin, std's) so that
+/// `pump()` can tell when the buffer is empty and, with `--max-read`, so that no single `read()`
+/// asks for more than `max_read` bytes.
+fn input_reader<R: Read>(config: &Config, input: R) -> BufReader<R> {
+    BufReader::with_capacity(config.max_read.unwrap_or(8 * 1024), input)
+}
+
+/// The number of bytes remaining in stdin, if it is a regular file.
+fn stdin_size() -> Option<u64> {
+    let mut stdin = File::from(io::stdin().as_fd().try_clone_to_owned().ok()?);
+    let meta = stdin.metadata().ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    Some(meta.len().saturating_sub(stdin.stream_position().ok()?))
+}
+
+/// The smallest file size that splits `total` bytes into at most `n` files (the last of which may
+/// be smaller than the others), or `None` if `total` is too small to fill `n` files.
+fn split_size(total: u64, n: usize) -> Option<usize> {
+    let n = u64::try_from(n).ok()?;
+    if total < n {
+        return None;
+    }
+    usize::try_from(total.div_ceil(n)).ok()
+}
+
+/// Open (a duplicate of) stdin for reading.
+fn stdin_reader(config: &Config) -> Result<BufReader<File>, io::Error> {
+    let stdin = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+    Ok(input_reader(config, stdin))
+}
+
+/// The size of the echo queue if `--echo-overflow drop` is given without `--echo-queue-size`.
+const ECHO_QUEUE_DEFAULT: usize = 1024 * 1024;
+
+/// How much of what is written, but not yet read by the `--full-copy-compressed` command, is
+/// buffered before the copy starts dropping input.
+const FULL_COPY_BUFFER: usize = 16 * 1024 * 1024;
+
+/// With `--confirm-eof`, how long to wait before reading a regular file again after a read
+/// returned nothing.
+const EOF_RECHECK_DELAY: Duration = Duration::from_millis(10);
+
+/// The most bytes a `--header-line` can take: `# rotee file N started YYYY-MM-DDTHH:MM:SSZ\n`,
+/// with `N` as long as a `u64` can be.
+const HEADER_LINE_MAX: usize = 63;
+
+/// The largest file size (`-s`) that `--buffer-files` will hold in memory.
+const BUFFER_FILES_MAX: usize = 64 * 1024 * 1024;
+
+/// Is `fd` a regular file?
+fn is_regular_file(fd: RawFd) -> bool {
+    let mut st = MaybeUninit::<libc::stat>::uninit();
+    unsafe {
+        libc::fstat(fd, st.as_mut_ptr()) == 0
+            && st.assume_init().st_mode & libc::S_IFMT == libc::S_IFREG
+    }
+}
+
+/// Read the next piece of input into `buf`, returning the number of bytes read (0 at EOF).
+fn read_input<R: BufRead>(
+    config: &Config,
+    input: &mut R,
+    buf: &mut ReadBuf,
+) -> Result<usize, io::Error> {
+    if config.line_input {
+        // Yield (at most `max_line_bytes` of) a line at a time. If a signal interrupts the read, a
+        // partial line is yielded early so that the signal is dealt with promptly.
+        buf.clear();
+        let mut eol = false;
+        while !eol && buf.len() < config.max_line_bytes {
+            let avail = match input.fill_buf() {
+                Ok(b) => b,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted && !buf.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            if avail.is_empty() {
+                break; // EOF.
+            }
+            let avail = &avail[..usize::min(avail.len(), config.max_line_bytes - buf.len())];
+            let n = match avail.iter().position(|b| *b == b'\n') {
+                Some(i) => {
+                    eol = true;
+                    i + 1
+                }
+                None => avail.len(),
+            };
+            buf.extend_from_slice(&avail[..n]);
+            input.consume(n);
+        }
+        if let Some(m) = &config.truncation_marker {
+            // A line that is exactly `max_line_bytes` long (without its newline) isn't cut.
+            if !eol && buf.len() == config.max_line_bytes {
+                let cut = match input.fill_buf() {
+                    Ok(b) => b.first().is_some_and(|&b| b != b'\n'),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => true,
+                    Err(e) => return Err(e),
+                };
+                if cut {
+                    buf.extend_from_slice(m.as_bytes());
+                }
+            }
+        }
+        Ok(buf.len())
+    } else {
+        let len = config
+            .max_read
+            .map_or(buf.len(), |m| usize::min(m, buf.len()));
+        input.read(&mut buf[..len])
+    }
+}
+
+/// Update the modification time of `path` to now, creating it (empty) if it doesn't exist.
+fn touch(path: &Path) -> Result<(), io::Error> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { utimensat(AT_FDCWD, cpath.as_ptr(), ptr::null(), 0) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        e if e.kind() == io::ErrorKind::NotFound => {
+            OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(())
+        }
+        e => Err(e),
+    }
+}
+
+/// Touch the `--heartbeat-file` (if requested). Failure isn't fatal (the capture itself is fine),
+/// but is reported the first time it happens.
+fn heartbeat(config: &Config) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if let Some(p) = &config.heartbeat_file {
+        if let Err(e) = touch(Path::new(p)) {
+            if !WARNED.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "can't update heartbeat file '{}': {} (further failures won't be \
+                     reported)",
+                    p, e
+                );
+            }
+        }
+    }
+}
+
+/// Create the `--done-file` (if requested), signalling that all input was consumed.
+fn mark_done(config: &Config) -> Result<(), io::Error> {
+    if let Some(p) = &config.done_file {
+        File::create(p)?;
+    }
+    Ok(())
+}
+
+/// Open the `--control-fifo` at `path`, creating it if it doesn't exist. It is opened for writing
+/// too, so that reads never see EOF when no writer has it open, and in non-blocking mode, so that
+/// it can be read until it's empty.
+fn open_control_fifo(path: &Path) -> Result<File, io::Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    if !f.metadata()?.file_type().is_fifo() {
+        return Err(io::Error::other(format!(
+            "{} exists and isn't a FIFO",
+            path.display()
+        )));
+    }
+    Ok(f)
+}
+
+/// Is `fd` open for writing?
+fn is_writable_fd(fd: RawFd) -> bool {
+    match unsafe { libc::fcntl(fd, libc::F_GETFL) } {
+        -1 => false,
+        flags => flags & libc::O_ACCMODE != libc::O_RDONLY,
+    }
+}
+
+/// With `--shared`, open (creating if need be) the lock file of the series under `prefix`.
+fn open_lock(prefix: &str) -> Result<File, io::Error> {
+    let path = PathBuf::from(format!("{}lock", prefix));
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't open {}: {}", path.display(), e)))
+}
+
+/// An exclusive `flock()` on a file, released when dropped.
+struct Flock(RawFd);
+
+impl Flock {
+    /// Wait for, and take, an exclusive lock on `f`.
+    fn exclusive(f: &File) -> Result<Self, io::Error> {
+        let fd = f.as_raw_fd();
+        while unsafe { libc::flock(fd, libc::LOCK_EX) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl Drop for Flock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.0, libc::LOCK_UN) };
+    }
+}
+
+/// The full set of signals, for when we have to block signals.
+fn all_signals() -> Result<sigset_t, Box<dyn Error>> {
+    let mut all_sigs = MaybeUninit::uninit();
+    if unsafe { sigemptyset(all_sigs.as_mut_ptr()) } == -1 {
+        return Err("sigemptyset failed".into());
+    }
+    let mut all_sigs = unsafe { all_sigs.assume_init() };
+    if unsafe { sigfillset(&mut all_sigs as *mut sigset_t) } == -1 {
+        return Err("sigfillset failed".into());
+    }
+    Ok(all_sigs)
+}
+
+/// A series of rotating output files, and the state needed to decide when to rotate them.
+struct Series<'a> {
+    config: &'a Config,
+    /// Decides how what is written is split between files.
+    session: Session,
+    /// Limits how often reading input touches the heartbeat file.
+    heartbeat_interval: Option<Interval>,
+    days: Option<DayTracker>,
+    /// The prefix that files are written under.
+    prefix: String,
+    /// With `--staging-prefix`, the prefix that files are committed to.
+    live: Option<String>,
+    /// Has anything been staged (by this run, or left by an earlier one) since the last commit?
+    staged: bool,
+    /// The active (newest) file. This is only `None` while the series is being rotated, and with
+    /// `--buffer-files`, which has no active file on disk.
+    of: Option<File>,
+    /// With `--buffer-files`, what has been written to the active file so far.
+    buffer: Option<Vec<u8>>,
+    /// Where to echo what is written (if anywhere).
+    echo: Option<Box<dyn Write>>,
+    /// With an echo queue (`--echo-queue-size` or `--echo-overflow drop`), the number of bytes it
+    /// has dropped.
+    echo_dropped: Option<Arc<AtomicU64>>,
+    /// With `--alt-echo`, where the profile not in effect echoes to, if it echoes and the one in
+    /// effect doesn't: switching profiles swaps this with `echo`.
+    parked_echo: Option<Box<dyn Write>>,
+    /// Is the alternate profile (see `--profile-signal`) in effect?
+    alt: bool,
+    /// The buffer size (`-b`) of the profile in effect.
+    buffer_size: usize,
+    timing: Option<Timing>,
+    /// With `--mark`, restarted whenever input arrives.
+    mark_interval: Option<Interval>,
+    /// Was the last byte written a newline (or has nothing been written yet)? Marks are only
+    /// written at the start of a line.
+    at_line_start: bool,
+    utf8: Option<Utf8Filter>,
+    crlf: Option<CrlfFilter>,
+    exclude: Option<Excluder>,
+    squeeze: Option<Squeezer>,
+    /// With `--squeeze-interval`, started when a run of repeats starts (or is summarised).
+    squeeze_interval: Option<Interval>,
+    /// With `--checkpoint-interval`, restarted at each checkpoint.
+    checkpoint_interval: Option<Interval>,
+    /// The number of bytes written to the series' files in total.
+    total_written: u64,
+    /// The number of writes to the output files that have failed (see `--max-write-errors`).
+    write_errors: usize,
+    /// With `--write-timeout`, watches for writes to the active file that take too long.
+    watchdog: Option<Watchdog>,
+    /// With `--warn-size`, the value of `total_written` beyond which the single file is too big
+    /// (`None` once the warning has been given).
+    warn_at: Option<u64>,
+    /// With `--control-fifo`, the FIFO and the commands being read from it.
+    control: Option<(File, CommandLines)>,
+    /// With `--full-copy-compressed`, the command that everything written is copied to.
+    full_copy: Option<PipeCopy>,
+    /// With `--copy-fd`, the fds that everything written is copied to.
+    copies: Vec<PipeCopy>,
+    /// The number of rotations (and commits) performed by this run.
+    rotations: u64,
+    /// With `--max-runtime`, started when the series is opened.
+    runtime: Option<Interval>,
+    /// With `--shared`, the lock file that writers hold while they append or rotate.
+    shared_lock: Option<File>,
+    /// The number of files started by this run (so the active file's number in `--header-line`).
+    files_started: u64,
+    /// The size of the active file's `--header-line` (0 if there isn't one).
+    header_size: usize,
+    /// With `--verify` or `--xattrs`, the CRC-32 of what has been written to the active file.
+    checksum: Option<Crc32>,
+    /// The offset (in `total_written`) of the active file's first byte, and when it was started.
+    file_start: (u64, SystemTime),
+    /// With `--xattrs`, whether rotated files are still stamped (they aren't once the file system
+    /// has turned out not to support extended attributes).
+    xattrs: bool,
+    all_sigs: sigset_t,
+}
+
+impl<'a> Series<'a> {
+    /// Open the series, echoing what is written to `echo` unless `--no-echo` (`-e`) is given.
+    fn open(
+        config: &'a Config,
+        clock: &dyn Clock,
+        echo: Box<dyn Write + Send>,
+        all_sigs: sigset_t,
+    ) -> Result<Self, Box<dyn Error>> {
+        let days = config.date_dir.then(|| DayTracker::new(clock.now()));
+        let mut prefix = series_prefix(config, days.as_ref().map(|d| d.date()));
+        // With `--staging-prefix`, files are written there and `prefix` is only used on commit.
+        let live = match &config.staging_prefix {
+            Some(s) => Some(std::mem::replace(&mut prefix, s.clone())),
+            None => None,
+        };
+        install_handlers(config, &[SignalAction::Commit, SignalAction::Profile])?;
+        // A commit that an earlier run was killed part way through is completed before the staged
+        // files are touched.
+        if let Some(l) = &live {
+            finish_commit(config, &prefix, l)?;
+        }
+        // Earlier runs that were killed part way through replacing a file leave its temporary
+        // file behind.
+        if !config.keep_temps {
+            let mut dirs = Vec::new();
+            if config.single_file.is_none() {
+                dirs.push(prefix_dir(&prefix));
+                dirs.extend(live.as_deref().map(prefix_dir));
+            }
+            dirs.extend(config.checkpoint_file.as_deref().map(prefix_dir));
+            for d in dirs {
+                temp::remove_stale(d)?;
+            }
+        }
+        let (echo, echo_dropped): (Box<dyn Write>, _) = match config.echo_queue {
+            Some((cap, overflow)) => {
+                let q = EchoQueue::spawn(echo, cap, overflow);
+                let dropped = q.dropped();
+                (Box::new(q), Some(dropped))
+            }
+            None => (echo, None),
+        };
+        let (echo, parked_echo) = match config.no_echo {
+            false => (Some(echo), None),
+            true => (None, Some(echo)),
+        };
+        let mut chunker = match config.file_size {
+            0 => Chunker::unlimited(),
+            s => Chunker::new(s),
+        };
+        let mut warn_at = None;
+        let mut shared_lock = None;
+        let of = match &config.single_file {
+            // The single file is never rotated, so its size is only tracked (starting with what
+            // is already in it) for `--strict-writes` and `--warn-size`.
+            Some(p) => {
+                let f = open_single(config, Path::new(p))?;
+                let len = f.metadata()?.len();
+                chunker = Chunker::unlimited();
+                chunker.advance(usize::try_from(len)?);
+                warn_at = config.warn_size.map(|w| w.saturating_sub(len));
+                Some(f)
+            }
+            None if config.shared => {
+                let lock = open_lock(&prefix)?;
+                let _held = Flock::exclusive(&lock)?;
+                shared_lock = Some(lock);
+                Some(open_single(config, &outfile_path(&prefix, 0))?)
+            }
+            None if config.buffer_files => {
+                open_buffered(config, &prefix, all_sigs)?;
+                None
+            }
+            None => Some(open_newest(config, &prefix, all_sigs)?),
+        };
+        // With `--rotate-at-start`, a non-empty newest file left by an earlier run has just been
+        // rotated.
+        let staged = config.rotate_at_start && live.is_some() && outfile_path(&prefix, 1).exists();
+        let timing = match &config.timing_file {
+            Some(p) => Some(Timing::open(config, p, clock, all_sigs)?),
+            None => None,
+        };
+        let full_copy =
+            match &config.full_copy_compressed {
+                Some(c) => Some(PipeCopy::spawn(c, FULL_COPY_BUFFER).map_err(|e| {
+                    format!("can't run --full-copy-compressed command '{}': {}", c, e)
+                })?),
+                None => None,
+            };
+        let copies = config
+            .copy_fds
+            .iter()
+            .map(|&fd| {
+                let f = unsafe { File::from_raw_fd(fd) };
+                PipeCopy::new(f, &format!("--copy-fd {}", fd), FULL_COPY_BUFFER)
+                    .map_err(|e| format!("can't use --copy-fd {}: {}", fd, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let control = match &config.control_fifo {
+            Some(p) => Some((open_control_fifo(Path::new(p))?, CommandLines::default())),
+            None => None,
+        };
+        // JSON records, and the lines of `--squeeze-repeats`, are never split between files, and
+        // nor are frames (each of which is written in one go).
+        let records = match () {
+            _ if config.frame_length => Records::Whole,
+            _ if config.json || config.squeeze_repeats => Records::Lines,
+            _ => Records::Bytes,
+        };
+        let session = Session::new(
+            chunker,
+            // Lines longer than `max_line_bytes` are only matched against their first
+            // `max_line_bytes`.
+            config
+                .match_regex
+                .clone()
+                .zip(config.rotate_after_matches)
+                .map(|(re, n)| MatchCounter::new(re, n, config.max_line_bytes)),
+            config.split_every.map(|n| {
+                (
+                    DelimiterCounter::new(config.split_delimiter, n),
+                    config.split_delimiter,
+                )
+            }),
+            config.min_size,
+            records,
+            config
+                .rotate_interval
+                .map(|i| Interval::new(i, clock.now())),
+            !config.echo_flush_lines,
+        );
+        let mut series = Self {
+            config,
+            session,
+            heartbeat_interval: config
+                .heartbeat_every
+                .map(|i| Interval::new(i, clock.now())),
+            days,
+            prefix,
+            live,
+            staged,
+            of,
+            buffer: config.buffer_files.then(Vec::new),
+            echo,
+            echo_dropped,
+            parked_echo,
+            alt: false,
+            buffer_size: config.buffer_size,
+            timing,
+            mark_interval: config.mark_every.map(|i| Interval::new(i, clock.now())),
+            at_line_start: true,
+            utf8: config.utf8_invalid.map(Utf8Filter::new),
+            crlf: config.crlf_lf.then(CrlfFilter::default),
+            exclude: config
+                .exclude_regex
+                .clone()
+                .map(|re| Excluder::new(re, config.max_line_bytes)),
+            squeeze: config
+                .squeeze_repeats
+                .then(|| Squeezer::new(config.max_line_bytes)),
+            squeeze_interval: config.squeeze_every.map(|i| Interval::new(i, clock.now())),
+            checkpoint_interval: config
+                .checkpoint_every
+                .map(|i| Interval::new(i, clock.now())),
+            total_written: 0,
+            write_errors: 0,
+            watchdog: config
+                .write_watchdog
+                .map(|(timeout, stall)| Watchdog::spawn(timeout, stall)),
+            warn_at,
+            control,
+            full_copy,
+            copies,
+            rotations: 0,
+            runtime: config.runtime_limit.map(|d| Interval::new(d, clock.now())),
+            shared_lock,
+            files_started: 0,
+            header_size: 0,
+            checksum: (config.verify || config.xattrs).then(Crc32::default),
+            file_start: (0, clock.now()),
+            xattrs: config.xattrs,
+            all_sigs,
+        };
+        series.start_file(clock)?;
+        if config.active_marker {
+            write_active_marker(config, &series.prefix, Some(series.files_started))?;
+        }
+        Ok(series)
+    }
+
+    /// Start a new (empty) active file: with `--header-line`, write its header. The header counts
+    /// towards the file's size, but isn't echoed, and doesn't make the file count as staged.
+    fn start_file(&mut self, clock: &dyn Clock) -> Result<(), io::Error> {
+        self.files_started += 1;
+        if let Some(c) = &mut self.checksum {
+            *c = Crc32::default();
+        }
+        self.file_start = (self.total_written, clock.now());
+        self.switch_profile_if_requested();
+        if !self.config.header_line {
+            return Ok(());
+        }
+        let header = format!(
+            "# rotee file {} started {}\n",
+            self.files_started,
+            utc_timestamp(clock.now(), false)
+        );
+        let staged = self.staged;
+        let written = self.put(clock, header.as_bytes(), false)?;
+        self.staged = staged;
+        self.session.chunker.advance(written);
+        self.header_size = written;
+        Ok(())
+    }
+
+    /// If `--profile-signal` has asked for the other profile since the last file was started,
+    /// switch to it.
+    fn switch_profile_if_requested(&mut self) {
+        let config = self.config;
+        let alt = ALT_PROFILE.load(Ordering::SeqCst);
+        if !config.alt_profile() || alt == self.alt {
+            return;
+        }
+        self.alt = alt;
+        let (file_size, buffer_size) = match alt {
+            true => (
+                config.alt_file_size.unwrap_or(config.file_size),
+                config.alt_buffer_size.unwrap_or(config.buffer_size),
+            ),
+            false => (config.file_size, config.buffer_size),
+        };
+        if config.alt_file_size.is_some() {
+            self.session.chunker.set_file_size(file_size);
+        }
+        self.buffer_size = buffer_size;
+        if config.alt_echo_on == Some(config.no_echo) {
+            std::mem::swap(&mut self.echo, &mut self.parked_echo);
+        }
+    }
+
+    /// The most bytes to read from input in one go: the buffer size of the profile in effect,
+    /// capped by `--max-read`.
+    fn read_size(&self) -> usize {
+        self.config
+            .max_read
+            .map_or(self.buffer_size, |m| usize::min(m, self.buffer_size))
+    }
+
+    /// Has nothing but its header (if any) been written to the active file?
+    fn active_empty(&self) -> bool {
+        self.session.chunker.cur_size() <= self.header_size
+    }
+
+    /// The path of the active file.
+    fn active_path(&self) -> PathBuf {
+        match &self.config.single_file {
+            Some(p) => PathBuf::from(p),
+            None => outfile_path(&self.prefix, 0),
+        }
+    }
+
+    /// If a commit has been requested (see `--commit-signal`), commit the staged files.
+    fn commit_if_requested(&mut self, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+        // With `--min-size`, a requested commit stays pending until the active file is big enough.
+        if self.live.is_some()
+            && self.session.chunker.cur_size() >= self.config.min_size
+            && COMMIT.swap(false, Ordering::SeqCst)
+        {
+            // Committing an empty staged set would only push the live files out of existence.
+            if !self.staged && !self.config.rotate_empty {
+                return Ok(());
+            }
+            self.staged = false;
+            self.seal_run(clock)?;
+            if self.live.is_some() {
+                self.stamp_xattrs()?;
+            }
+            if let Some(live) = &self.live {
+                let of = self.of.take().unwrap();
+                self.of = Some(commit(
+                    self.config,
+                    &self.prefix,
+                    live,
+                    of,
+                    self.session.chunker.cur_size(),
+                    self.config.active_marker.then_some(self.files_started + 1),
+                    self.all_sigs,
+                )?);
+                self.session.committed();
+                self.rotations += 1;
+                self.start_file(clock)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Has the `--max-runtime` passed? Once it has, input should no longer be read.
+    fn expired(&mut self, clock: &dyn Clock) -> bool {
+        self.runtime
+            .as_mut()
+            .is_some_and(|i| i.elapsed(clock.now()))
+    }
+
+    /// The file descriptor of the `--control-fifo` (-1 if there isn't one), to wait on alongside
+    /// input.
+    fn control_fd(&self) -> RawFd {
+        self.control.as_ref().map_or(-1, |(f, _)| f.as_raw_fd())
+    }
+
+    /// Run any commands waiting in the `--control-fifo`. Malformed commands are ignored, with a
+    /// warning.
+    fn run_control(&mut self, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+        let Some((f, lines)) = &mut self.control else {
+            return Ok(());
+        };
+        let mut cmds = Vec::new();
+        let mut buf = [0; 512];
+        loop {
+            match f.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => cmds.extend(lines.feed(&buf[..n])),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for cmd in cmds {
+            match ControlCommand::parse(&cmd) {
+                Ok(ControlCommand::Size(n)) => self.session.chunker.set_file_size(n),
+                // Rotating an empty file would only push the oldest file out of existence.
+                Ok(ControlCommand::Rotate) => {
+                    if !self.active_empty() || self.config.rotate_empty {
+                        self.rotate(clock)?;
+                    }
+                }
+                // Asked for, so shown whatever the log level.
+                Ok(ControlCommand::Stats) => diag::emit(format_args!(
+                    "stats: {} bytes written, {} rotations, {} has {} bytes{}{}{}",
+                    self.total_written,
+                    self.rotations,
+                    self.active_path().display(),
+                    self.session.chunker.cur_size(),
+                    match &self.echo_dropped {
+                        Some(d) => format!(", {} echo bytes dropped", d.load(Ordering::Relaxed)),
+                        None => String::new(),
+                    },
+                    match self.config.max_write_errors {
+                        0 => String::new(),
+                        _ => format!(", {} write errors", self.write_errors),
+                    },
+                    self.copies
+                        .iter()
+                        .map(|c| format!(", {} bytes dropped from {}", c.dropped(), c.name()))
+                        .collect::<String>()
+                )),
+                Err(e) => warn!("ignoring control command: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write input `data` to the series, rotating as needed.
+    fn write(&mut self, clock: &dyn Clock, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        if let Some(i) = &mut self.mark_interval {
+            i.reset(clock.now());
+        }
+        if let Some(ex) = &mut self.exclude {
+            let mut kept = Vec::new();
+            ex.feed(data, &mut kept);
+            return self.write_excluded(clock, data, &kept);
+        }
+        let mut valid = Vec::new();
+        let res = match &mut self.utf8 {
+            Some(u) => u.feed(data, &mut valid),
+            None => Ok(()),
+        };
+        let text = if self.utf8.is_some() { &valid } else { data };
+        let mut lf = Vec::new();
+        let text = match &mut self.crlf {
+            Some(c) => {
+                c.feed(text, &mut lf);
+                &lf
+            }
+            None => text,
+        };
+        self.write_filtered(clock, data, text)?;
+        res.map_err(|o| invalid_utf8(o).into())
+    }
+
+    /// Write `data`, which is `input` after any `--utf8` validation and `--crlf` rewriting, to the
+    /// series, rotating as needed. Unless `--crlf-echo` is given, `input` is what is echoed.
+    fn write_filtered(
+        &mut self,
+        clock: &dyn Clock,
+        input: &[u8],
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let echo = if self.config.crlf_echo { data } else { input };
+        let Some(sq) = &mut self.squeeze else {
+            if (self.utf8.is_none() && self.crlf.is_none()) || self.config.crlf_echo {
+                return self.write_out(clock, data, true);
+            }
+            if let Some(e) = &mut self.echo {
+                write_bytes(self.config, e, echo)?;
+            }
+            return self.write_out(clock, data, false);
+        };
+        let repeats = sq.repeats();
+        let mut out = Vec::new();
+        sq.feed(data, &mut out);
+        if repeats == 0 && sq.repeats() > 0 {
+            if let Some(i) = &mut self.squeeze_interval {
+                i.reset(clock.now());
+            }
+        }
+        if !self.config.squeeze_echo {
+            if let Some(e) = &mut self.echo {
+                write_bytes(self.config, e, echo)?;
+            }
+        }
+        self.write_out(clock, &out, self.config.squeeze_echo)
+    }
+
+    /// With `--exclude`, write `input` to the series, where `kept` is `input` less the lines to
+    /// exclude, which are only dropped from the `--exclude-target`.
+    fn write_excluded(
+        &mut self,
+        clock: &dyn Clock,
+        input: &[u8],
+        kept: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let config = self.config;
+        if config.exclude_files && config.exclude_echo {
+            return self.write_out(clock, kept, true);
+        }
+        let (echo, data) = match config.exclude_files {
+            true => (input, kept),
+            false => (kept, input),
+        };
+        if let Some(e) = &mut self.echo {
+            write_bytes(config, e, echo)?;
+        }
+        self.write_out(clock, data, false)
+    }
+
+    /// When `tick()` next has something to do (a mark, or a summary of repeats), if ever.
+    fn next_tick(&self) -> Option<SystemTime> {
+        let squeeze = self
+            .squeeze_interval
+            .as_ref()
+            .filter(|_| self.squeeze.as_ref().is_some_and(|sq| sq.repeats() > 0));
+        [
+            self.mark_interval.as_ref(),
+            squeeze,
+            self.checkpoint_interval.as_ref(),
+            self.runtime.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(Interval::due)
+        .min()
+    }
+
+    /// Do anything that is due with the passing of time: summarise a long run of repeats (see
+    /// `--squeeze-interval`); if a `--mark` period has passed without input, write a mark line;
+    /// and write any due checkpoint (see `--checkpoint-interval`).
+    fn tick(&mut self, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+        let now = clock.now();
+        if let (Some(sq), Some(i)) = (&mut self.squeeze, &mut self.squeeze_interval) {
+            if sq.repeats() > 0 && i.elapsed(now) {
+                i.reset(now);
+                let mut summary = Vec::new();
+                sq.summary(&mut summary);
+                self.write_out(clock, &summary, self.config.squeeze_echo)?;
+            }
+        }
+
+        if let Some(i) = &mut self.mark_interval {
+            if i.elapsed(now) {
+                i.reset(now);
+                // Never split a line of input.
+                if self.at_line_start {
+                    // The mark ends any run of repeats.
+                    self.seal_run(clock)?;
+                    let time = utc_timestamp(now, false);
+                    let mark = format!("{}\n", self.config.mark_text.replace("{time}", &time));
+                    self.write_out(clock, mark.as_bytes(), self.config.mark_echo)?;
+                }
+            }
+        }
+
+        if let Some(i) = &mut self.checkpoint_interval {
+            if i.elapsed(now) {
+                i.reset(now);
+                self.checkpoint()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// With `--checkpoint-file`, make everything written so far durable and record how much that
+    /// is. This is done with signals blocked, so that a checkpoint is never half written.
+    fn checkpoint(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(p) = &self.config.checkpoint_file {
+            let of = self.of.as_ref().unwrap();
+            signals_blocked(self.all_sigs, || {
+                write_checkpoint(self.config, Path::new(p), of, self.total_written)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// With `--squeeze-repeats`, end any run of repeats in the active file, so that each file is
+    /// self-contained: a summary of repeats is written to the file with the line repeated, and
+    /// the next line is written in full even if it is the same.
+    fn seal_run(&mut self, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+        let mut summary = Vec::new();
+        if let Some(sq) = &mut self.squeeze {
+            sq.forget(&mut summary);
+        }
+        if !summary.is_empty() {
+            let written = self.put(clock, &summary, self.config.squeeze_echo)?;
+            self.session.chunker.advance(written);
+        }
+        Ok(())
+    }
+
+    /// Write `bytes` to the active file (and, if `echo` is true, echo them), without rotating,
+    /// returning the number of bytes written. That is less than `bytes.len()` only if writing
+    /// failed and `--max-write-errors` let rotee carry on: then only the bytes that were written
+    /// are checksummed, counted, copied, and echoed.
+    fn put(&mut self, clock: &dyn Clock, bytes: &[u8], echo: bool) -> Result<usize, io::Error> {
+        let written = self.put_file(clock, bytes)?;
+        if let Some(e) = self.echo.as_mut().filter(|_| echo) {
+            write_echo(self.config, e, &bytes[..written])?;
+        }
+        Ok(written)
+    }
+
+    /// As `put()`, but without echoing.
+    fn put_file(&mut self, clock: &dyn Clock, bytes: &[u8]) -> Result<usize, io::Error> {
+        let config = self.config;
+        let mut bytes = bytes;
+        match &mut self.buffer {
+            Some(b) => b.extend_from_slice(bytes),
+            None => {
+                let mut done = 0;
+                let res = {
+                    let _watch = self.watchdog.as_ref().map(|w| w.watch(&self.active_path()));
+                    fail_point_error!("write-active").and_then(|_| {
+                        write_counted(config, self.of.as_mut().unwrap(), bytes, &mut done)
+                    })
+                };
+                if let Err(e) = res {
+                    self.write_failed(e, bytes.len())?;
+                    bytes = &bytes[..done];
+                }
+            }
+        }
+        if let Some(c) = &mut self.checksum {
+            c.update(bytes);
+        }
+        self.total_written += bytes.len() as u64;
+        if let Some(c) = &mut self.full_copy {
+            c.feed(bytes);
+        }
+        for c in &mut self.copies {
+            c.feed(bytes);
+        }
+        if self.warn_at.is_some_and(|w| self.total_written > w) {
+            self.warn_at = None;
+            warn!(
+                "{} has grown beyond --warn-size ({} bytes)",
+                self.active_path().display(),
+                config.warn_size.unwrap()
+            );
+        }
+        self.staged |= !bytes.is_empty();
+        if let Some(t) = &mut self.timing {
+            t.record(config, clock.now(), bytes.len())?;
+        }
+        if let Some(&b) = bytes.last() {
+            self.at_line_start = b == b'\n';
+        }
+        Ok(bytes.len())
+    }
+
+    /// Count a failed write of `len` bytes to the active file, carrying on without them unless more
+    /// than `--max-write-errors` writes have now failed.
+    fn write_failed(&mut self, e: io::Error, len: usize) -> Result<(), io::Error> {
+        let max = self.config.max_write_errors;
+        self.write_errors += 1;
+        if self.write_errors > max {
+            if max == 0 {
+                return Err(e);
+            }
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "{} (more than --max-write-errors {} writes have failed)",
+                    e, max
+                ),
+            ));
+        }
+        warn!(
+            "writing {} bytes to {} failed: {} (write error {} of at most {})",
+            len,
+            self.active_path().display(),
+            e,
+            self.write_errors,
+            max
+        );
+        Ok(())
+    }
+
+    /// With `--shared`, append `data` to the active file (and, if `echo` is true, echo it) in one
+    /// go, first rotating if `data` would take the file beyond the file size. The size comes from
+    /// the file itself, since other writers append to it too, and the lock is held throughout, so
+    /// that only one writer rotates, and `data` is never interleaved with others' writes.
+    fn write_shared(
+        &mut self,
+        clock: &dyn Clock,
+        data: &[u8],
+        echo: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let _held = Flock::exclusive(self.shared_lock.as_ref().unwrap())?;
+        // Another writer may have rotated the active file since we last wrote.
+        let path = outfile_path(&self.prefix, 0);
+        let md = self.of.as_ref().unwrap().metadata()?;
+        match fs::metadata(&path) {
+            Ok(p) if (p.dev(), p.ino()) == (md.dev(), md.ino()) => (),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => self.of = Some(open_single(self.config, &path)?),
+        }
+        let size = self.of.as_ref().unwrap().metadata()?.len();
+        let limit = u64::try_from(self.config.file_size)?;
+        if limit > 0 && size > 0 && size + data.len() as u64 > limit {
+            self.rotate(clock)?;
+        }
+        self.put(clock, data, echo)?;
+        Ok(())
+    }
+
+    /// Write `data` to the series (and, if `echo` is true, echo it), rotating as needed.
+    fn write_out(
+        &mut self,
+        clock: &dyn Clock,
+        data: &[u8],
+        echo: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let config = self.config;
+        if let Some(i) = &mut self.heartbeat_interval {
+            if i.elapsed(clock.now()) {
+                heartbeat(config);
+                i.reset(clock.now());
+            }
+        }
+        if config.shared {
+            return self.write_shared(clock, data, echo);
+        }
+        if self.days.as_mut().is_some_and(|d| d.new_day(clock.now())) {
+            self.seal_run(clock)?;
+            if let Some(d) = &self.days {
+                // Start a fresh series in the new day's directory.
+                check_file_size(
+                    config,
+                    self.of.as_ref().unwrap(),
+                    &outfile_path(&self.prefix, 0),
+                    self.session.chunker.cur_size(),
+                )?;
+                sync_closing(config, self.of.as_ref().unwrap())?;
+                if config.active_marker {
+                    write_active_marker(config, &self.prefix, None)?;
+                }
+                self.prefix = series_prefix(config, Some(d.date()));
+                self.of = Some(open_newest(config, &self.prefix, self.all_sigs)?);
+                self.session.chunker.rotated();
+                self.session.restart_interval(clock.now());
+                self.start_file(clock)?;
+                if config.active_marker {
+                    write_active_marker(config, &self.prefix, Some(self.files_started))?;
+                }
+            }
+        }
+
+        // `session` decides what to do; this only does it.
+        let mut idx = 0;
+        while idx < data.len() {
+            // With `--stat-size`, the active file's size on disk decides how much more it takes.
+            if config.stat_size && self.sync_size()? && self.session.rotation_allowed(clock.now()) {
+                self.rotate(clock)?;
+            }
+            // A rotation may have switched profiles (see `--alt-echo`), so whether there is an
+            // echo can change from step to step.
+            let echo = echo && self.echo.is_some();
+            let step = &data[idx..];
+            let (mut len, mut written, mut size_due) = (0, 0, true);
+            for action in self.session.next(step, echo, clock.now()) {
+                match action {
+                    Action::Write(r) => {
+                        len = r.end;
+                        written = self.put_file(clock, &step[r.clone()])?;
+                        if written < r.len() {
+                            // The bytes that were dropped don't take up room in the active file.
+                            let cur_size = self.session.chunker.cur_size() - (r.len() - written);
+                            size_due = self.session.chunker.set_cur_size(cur_size);
+                        }
+                    }
+                    Action::Echo(r) => {
+                        let e = self.echo.as_mut().unwrap();
+                        write_bytes(config, e, &step[r.start..r.start + written])?;
+                    }
+                    Action::FlushEcho => self.echo.as_mut().unwrap().flush()?,
+                    Action::Rotate(Reason::Size) if !size_due => (),
+                    Action::Rotate(_) => self.rotate(clock)?,
+                }
+            }
+            idx += len;
+        }
+        Ok(())
+    }
+
+    /// With `--stat-size`, take the active file's size to be its size on disk, warning if that
+    /// isn't what was written to it. Returns `true` if something else changed the file's size
+    /// such that it is now due to be rotated.
+    fn sync_size(&mut self) -> Result<bool, Box<dyn Error>> {
+        let len = usize::try_from(self.of.as_ref().unwrap().metadata()?.len())?;
+        let expected = self.session.chunker.cur_size();
+        if len == expected {
+            return Ok(false);
+        }
+        warn!(
+            "{} has {} bytes, not the {} written to it: something else has changed it",
+            self.active_path().display(),
+            len,
+            expected
+        );
+        Ok(self.session.chunker.set_cur_size(len))
+    }
+
+    /// With `--xattrs`, stamp the active file, which is about to be rotated, with extended
+    /// attributes describing it. If the file system doesn't support them, warn (once).
+    fn stamp_xattrs(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.xattrs {
+            return Ok(());
+        }
+        let meta = FileMeta {
+            sequence: self.files_started,
+            first_offset: self.file_start.0,
+            start: self.file_start.1,
+            checksum: self.checksum.as_ref().unwrap().digest(),
+        };
+        let path = self.active_path();
+        let supported = xattrs::stamp(self.of.as_ref().unwrap(), &meta).map_err(|e| {
+            format!(
+                "can't set the extended attributes of {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        if !supported {
+            warn!(
+                "the file system of {} doesn't support extended attributes: --xattrs has \
+                 no effect",
+                path.display()
+            );
+            self.xattrs = false;
+        }
+        Ok(())
+    }
+
+    /// Rotate the series' files now.
+    fn rotate(&mut self, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+        let config = self.config;
+        debug!(
+            "rotating {} ({} bytes): rotation {}",
+            self.active_path().display(),
+            self.session.chunker.cur_size(),
+            self.rotations + 1
+        );
+        self.seal_run(clock)?;
+        match &mut self.buffer {
+            Some(b) => {
+                rotate_buffered(config, &self.prefix, b, self.all_sigs)?;
+                b.clear();
+            }
+            None => {
+                self.stamp_xattrs()?;
+                let of = self.of.take().unwrap();
+                self.of = Some(rotate(
+                    config,
+                    &self.prefix,
+                    of,
+                    self.session.chunker.cur_size(),
+                    self.checksum.filter(|_| config.verify).map(|c| c.digest()),
+                    config.active_marker.then_some(self.files_started + 1),
+                    self.all_sigs,
+                )?);
+            }
+        }
+        self.rotations += 1;
+        if let Some(t) = &mut self.timing {
+            t.rotate(config, self.all_sigs)?;
+        }
+        heartbeat(config);
+        self.session.rotated(clock.now());
+        self.start_file(clock)?;
+        Ok(())
+    }
+
+    /// Write anything held back (an incomplete line of `--exclude`, an incomplete sequence of
+    /// `--utf8`, a `\r` of `--crlf lf`, and the incomplete line and repeats of
+    /// `--squeeze-repeats`), rotate if `--rotate-at-eof` is given, and flush everything written to
+    /// the series.
+    fn finish(mut self, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+        if let Some(ex) = &mut self.exclude {
+            // What was held back has already been written to whichever of the files and the echo
+            // it isn't excluded from.
+            let mut tail = Vec::new();
+            ex.finish(&mut tail);
+            self.write_excluded(clock, &[], &tail)?;
+        }
+        if self.utf8.is_some() || self.crlf.is_some() {
+            // Unless `--crlf-echo` is given, what was held back has already been echoed.
+            let mut tail = Vec::new();
+            let res = self.utf8.as_mut().map_or(Ok(()), |u| u.finish(&mut tail));
+            if let Some(c) = &mut self.crlf {
+                let mut lf = Vec::new();
+                c.feed(&tail, &mut lf);
+                c.finish(&mut lf);
+                tail = lf;
+            }
+            self.write_filtered(clock, &[], &tail)?;
+            res.map_err(invalid_utf8)?;
+        }
+        let mut rest = Vec::new();
+        if let Some(sq) = &mut self.squeeze {
+            sq.finish(&mut rest);
+        }
+        self.write_out(clock, &rest, self.config.squeeze_echo)?;
+        // As with a rotation requested through the `--control-fifo`, rotating an empty file would
+        // only push the oldest file out of existence.
+        if self.config.rotate_at_eof && (!self.active_empty() || self.config.rotate_empty) {
+            self.rotate(clock)?;
+        }
+        self.checkpoint()?;
+        // With `--buffer-files`, the active file is written as the newest file, as if by a
+        // rotation, unless it's empty (or has nothing but a header).
+        if self.buffer.is_some() && !self.active_empty() {
+            let b = self.buffer.take().unwrap();
+            rotate_buffered(self.config, &self.prefix, &b, self.all_sigs)?;
+        }
+        if let Some(mut of) = self.of.take() {
+            of.flush()?;
+            check_file_size(
+                self.config,
+                &of,
+                &self.active_path(),
+                self.session.chunker.cur_size(),
+            )?;
+        }
+        if self.config.active_marker {
+            write_active_marker(self.config, &self.prefix, None)?;
+        }
+        if let Some(t) = self.timing.take() {
+            t.finish(self.config)?;
+        }
+        if let Some(c) = self.full_copy.take() {
+            let (status, dropped) = c.finish()?;
+            if dropped > 0 {
+                warn!(
+                    "{} bytes were dropped from the --full-copy-compressed copy",
+                    dropped
+                );
+            }
+            if let Some(s) = status.filter(|s| !s.success()) {
+                return Err(format!(
+                    "--full-copy-compressed command exited with status {}",
+                    exit_code(s)
+                )
+                .into());
+            }
+        }
+        for c in self.copies.drain(..) {
+            let name = c.name().to_owned();
+            let (_, dropped) = c.finish()?;
+            if dropped > 0 {
+                warn!("{} bytes were dropped from the {} copy", dropped, name);
+            }
+        }
+        if let Some(e) = &mut self.echo {
+            e.flush()?;
+        }
+        // Wait for an echo queue to drain.
+        drop(self.echo.take());
+        drop(self.parked_echo.take());
+        if let Some(d) = self.echo_dropped.map(|d| d.load(Ordering::Relaxed)) {
+            if d > 0 {
+                warn!("{} bytes were dropped from the echo", d);
+            }
+        }
+        if self.write_errors > 0 {
+            warn!(
+                "{} writes to the output files failed, so the output is incomplete",
+                self.write_errors
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The timing files (`--timing-file`) of a series: one per output file, with the same suffix,
+/// rotated in lockstep with the output files.
+struct Timing {
+    prefix: String,
+    /// The active (newest) timing file. This is only `None` while the files are being rotated.
+    of: Option<File>,
+    /// The number of bytes written to `of`.
+    written: usize,
+    /// When the previous chunk was written.
+    last: SystemTime,
+}
+
+impl Timing {
+    fn open(
+        config: &Config,
+        prefix: &str,
+        clock: &dyn Clock,
+        all_sigs: sigset_t,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            prefix: prefix.to_owned(),
+            of: Some(open_newest(config, prefix, all_sigs)?),
+            written: 0,
+            last: clock.now(),
+        })
+    }
+
+    /// Record that a chunk of `len` bytes was written at `now`.
+    fn record(&mut self, config: &Config, now: SystemTime, len: usize) -> Result<(), io::Error> {
+        // If the clock went backwards, the chunk was written without delay.
+        let delay = now.duration_since(self.last).unwrap_or_default();
+        self.last = now;
+        let e = timing::entry(delay, len);
+        write_bytes(config, self.of.as_mut().unwrap(), e.as_bytes())?;
+        self.written += e.len();
+        Ok(())
+    }
+
+    /// Rotate the timing files (after their output files have been rotated).
+    fn rotate(&mut self, config: &Config, all_sigs: sigset_t) -> Result<(), Box<dyn Error>> {
+        let of = self.of.take().unwrap();
+        self.of = Some(rotate(
+            config,
+            &self.prefix,
+            of,
+            self.written,
+            None,
+            None,
+            all_sigs,
+        )?);
+        self.written = 0;
+        Ok(())
+    }
+
+    fn finish(mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        let of = self.of.take().unwrap();
+        check_file_size(config, &of, &outfile_path(&self.prefix, 0), self.written)?;
+        Ok(())
+    }
+}
+
+/// The error for invalid UTF-8 at `offset` in input, with `--utf8 reject`.
+fn invalid_utf8(offset: u64) -> String {
+    format!("invalid UTF-8 in input at byte offset {}", offset)
+}
+
+/// Formats input for output: with `--format json`, as JSON records (which are only output once
+/// each line is complete); with `--frame-length`, as length-prefixed frames; otherwise as is.
+struct Formatter {
+    json: Option<JsonLines>,
+    frame: bool,
+    /// With `--prefix-relative`, when rotee started, and whether the next byte starts a line.
+    relative: Option<(SystemTime, bool)>,
+    records: Vec<u8>,
+}
+
+impl Formatter {
+    /// A formatter for the input stream named `stream` (in JSON records).
+    fn new(config: &Config, stream: &'static str) -> Self {
+        Self {
+            json: config
+                .json
+                .then(|| JsonLines::new(stream, config.max_line_bytes)),
+            frame: config.frame_length,
+            relative: config.prefix_relative.then_some((config.started, true)),
+            records: Vec::new(),
+        }
+    }
+
+    /// The output for `bytes`, read at `now`.
+    fn format<'b>(&'b mut self, now: SystemTime, bytes: &'b [u8]) -> &'b [u8] {
+        match &mut self.json {
+            Some(j) => {
+                self.records.clear();
+                j.feed(now, bytes, &mut self.records);
+                &self.records
+            }
+            None if self.frame => {
+                self.records.clear();
+                // `main()` checks that the buffer size, and thus the length, fits in 4 bytes.
+                let len = u32::try_from(bytes.len()).unwrap();
+                self.records.extend_from_slice(&len.to_be_bytes());
+                self.records.extend_from_slice(bytes);
+                &self.records
+            }
+            None => match &mut self.relative {
+                // A line that spans reads is given the time its first byte was read.
+                Some((start, at_line_start)) => {
+                    self.records.clear();
+                    let t = now.duration_since(*start).unwrap_or_default();
+                    let prefix = format!("+{}.{:03}s ", t.as_secs(), t.subsec_millis());
+                    for line in bytes.split_inclusive(|&b| b == b'\n') {
+                        if *at_line_start {
+                            self.records.extend_from_slice(prefix.as_bytes());
+                        }
+                        self.records.extend_from_slice(line);
+                        *at_line_start = line.ends_with(b"\n");
+                    }
+                    &self.records
+                }
+                None => bytes,
+            },
+        }
+    }
+
+    /// The output for any incomplete line, once input has ended.
+    fn finish(&mut self) -> &[u8] {
+        self.records.clear();
+        if let Some(j) = &mut self.json {
+            j.flush(&mut self.records);
+        }
+        &self.records
+    }
+}
+
+/// With `--syslog`, a sender of the lines of one input stream to syslog.
+fn syslog_sender(config: &Config) -> Option<Syslog> {
+    // In debug builds, tests can send lines to a socket of their own (or to one that doesn't
+    // exist).
+    #[cfg(debug_assertions)]
+    let path = env::var_os("ROTEE_SYSLOG_SOCKET")
+        .map_or_else(|| PathBuf::from(syslog::DEV_LOG), PathBuf::from);
+    #[cfg(not(debug_assertions))]
+    let path = PathBuf::from(syslog::DEV_LOG);
+    config
+        .syslog_pri
+        .map(|pri| Syslog::new(&path, pri, "rotee", config.max_line_bytes))
+}
+
+/// Write `data` to `series` or, if there's no series (`--no-files`), only echo it to stdout.
+fn output(
+    config: &Config,
+    clock: &dyn Clock,
+    series: Option<&mut Series>,
+    data: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    match series {
+        Some(s) => s.write(clock, data),
+        None => Ok(write_echo(config, &mut io::stdout(), data)?),
+    }
+}
+
+/// Read `input` (named `stream` in JSON records) until EOF (or until we are asked to terminate),
+/// writing it to `series` or, if there's no series (`--no-files`), only echoing it to stdout.
+fn pump<R: Read + AsRawFd>(
+    config: &Config,
+    clock: &dyn Clock,
+    mut series: Option<&mut Series>,
+    input: &mut BufReader<R>,
+    stream: &'static str,
+) -> Result<(), Box<dyn Error>> {
+    let mut buf = ReadBuf::new(config.buffer_size);
+    let mut fmt = Formatter::new(config, stream);
+    let mut syslog = syslog_sender(config);
+    // Some filesystems and drivers can transiently return nothing from a read of a regular file
+    // that hasn't ended. Pipes and the like are unaffected: for them, 0 is always EOF.
+    let confirm_eof = config.confirm_eof && is_regular_file(input.get_ref().as_raw_fd());
+    let mut zero_read = false;
+    while TERMINATE.load(Ordering::SeqCst) == 0 {
+        if let Some(s) = &mut series {
+            if s.expired(clock) {
+                break;
+            }
+            s.commit_if_requested(clock)?;
+            // In line input mode, `buf` holds a line (however long), not a block.
+            if !config.line_input {
+                buf.resize(s.read_size());
+            }
+            s.run_control(clock)?;
+            s.tick(clock)?;
+        }
+
+        // Rather than blocking in `read()`, where we could miss a signal that arrived just before
+        // the read started, wait for either input, a signal, the next mark, or a control command.
+        if input.buffer().is_empty() {
+            let (clock_fd, timeout) =
+                deadline_wait(clock, series.as_ref().and_then(|s| s.next_tick()));
+            let control_fd = series.as_ref().map_or(-1, |s| s.control_fd());
+            if !poll_inputs(
+                &[input.get_ref().as_raw_fd(), clock_fd, control_fd],
+                timeout,
+            )?[0]
+            {
+                continue;
+            }
+        }
+
+        match read_input(config, input, &mut buf) {
+            // A signal (e.g. a commit) interrupted the read.
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+            Ok(0) if confirm_eof && !zero_read => {
+                zero_read = true;
+                clock.sleep_until(clock.now() + EOF_RECHECK_DELAY);
+            }
+            Ok(0) => break, // EOF.
+            Ok(nbytes) => {
+                zero_read = false;
+                if let Some(s) = &mut syslog {
+                    s.feed(&buf[..nbytes]);
+                }
+                let data = fmt.format(clock.now(), &buf[..nbytes]);
+                output(config, clock, series.as_deref_mut(), data)?;
+            }
+        }
+    }
+    if let Some(s) = &mut syslog {
+        s.finish();
+    }
+    output(config, clock, series, fmt.finish())
+}
+
+/// Bind a Unix domain stream socket at `path` for `--listen-unix`, giving it the `--listen-mode`
+/// (if any) before anything can connect. A socket left at `path` by an earlier run, which nothing
+/// is listening on, is replaced.
+fn listen_unix(config: &Config, path: &Path) -> Result<UnixListener, Box<dyn Error>> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+        && UnixStream::connect(path).is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+    {
+        fs::remove_file(path)?;
+    }
+    // Until the socket has the right mode, only we can connect to it.
+    let old_umask = config.listen_perms.map(|_| unsafe { libc::umask(0o077) });
+    let res = UnixListener::bind(path);
+    if let Some(m) = old_umask {
+        unsafe { libc::umask(m) };
+    }
+    let listener = res.map_err(|e| format!("can't listen on {}: {}", path.display(), e))?;
+    if let Some(mode) = config.listen_perms {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    listener.set_nonblocking(true)?;
+    install_handlers(config, &[SignalAction::Terminate])?;
+    Ok(listener)
+}
+
+/// Bind a TCP socket at `addr` for `--listen-tcp`.
+fn listen_tcp(config: &Config, addr: SocketAddr) -> Result<TcpListener, Box<dyn Error>> {
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("can't listen on {}: {}", addr, e))?;
+    listener.set_nonblocking(true)?;
+    install_handlers(config, &[SignalAction::Terminate])?;
+    Ok(listener)
+}
+
+/// Accept connections on `listener` and read them, one at a time, until we are asked to terminate,
+/// writing what each sends to `series` (or, with `--no-files`, only echoing it to stdout) as if it
+/// had been read from stdin. While a connection is active, others wait in the listen queue or,
+/// with `--listen-busy refuse`, are closed at once, as are connections from outside
+/// `--listen-allow`. When we are asked to terminate, what the active connection has already sent
+/// is still written.
+fn pump_tcp(
+    config: &Config,
+    clock: &dyn Clock,
+    mut series: Option<&mut Series>,
+    listener: &TcpListener,
+) -> Result<(), Box<dyn Error>> {
+    let len = config
+        .max_read
+        .map_or(config.buffer_size, |m| usize::min(m, config.buffer_size));
+    let mut buf = ReadBuf::new(len);
+    let mut active: Option<TcpStream> = None;
+    while TERMINATE.load(Ordering::SeqCst) == 0 {
+        if let Some(s) = &mut series {
+            if s.expired(clock) {
+                break;
+            }
+            s.commit_if_requested(clock)?;
+            buf.resize(s.read_size());
+            s.run_control(clock)?;
+            s.tick(clock)?;
+        }
+
+        let (clock_fd, timeout) = deadline_wait(clock, series.as_ref().and_then(|s| s.next_tick()));
+        let control_fd = series.as_ref().map_or(-1, |s| s.control_fd());
+        let accepting = active.is_none() || config.refuse_busy;
+        let ready = poll_inputs(
+            &[
+                if accepting { listener.as_raw_fd() } else { -1 },
+                active.as_ref().map_or(-1, |s| s.as_raw_fd()),
+                clock_fd,
+                control_fd,
+            ],
+            timeout,
+        )?;
+
+        // The active connection is read first, so that if it has closed, a waiting connection can
+        // take its place at once.
+        if ready[1] {
+            match active.as_mut().unwrap().read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Ok(0) => active = None,
+                // E.g. the connection was reset: either way, its input has ended.
+                Err(e) => {
+                    warn!("connection failed: {}", e);
+                    active = None;
+                }
+                Ok(n) => output(config, clock, series.as_deref_mut(), &buf[..n])?,
+            }
+        }
+
+        if ready[0] {
+            while active.is_none() || config.refuse_busy {
+                match listener.accept() {
+                    Ok((_, peer))
+                        if !config.allowed_peers.is_empty()
+                            && !config.allowed_peers.iter().any(|c| c.contains(peer.ip())) =>
+                    {
+                        warn!("refusing connection from {} (not in --listen-allow)", peer);
+                    }
+                    Ok((_, peer)) if active.is_some() => warn!(
+                        "refusing connection from {} (another connection is active)",
+                        peer
+                    ),
+                    Ok((s, _)) => active = Some(s),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    // E.g. the client gave up before we accepted it.
+                    Err(e) => {
+                        warn!("can't accept connection: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    // Only what has already arrived is read: a sender that never stops mustn't hold us up.
+    let Some(s) = active else {
+        return Ok(());
+    };
+    let mut pending: c_int = 0;
+    if unsafe { libc::ioctl(s.as_raw_fd(), libc::FIONREAD, &mut pending) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut rest = Vec::new();
+    if let Err(e) = (&s).take(u64::try_from(pending)?).read_to_end(&mut rest) {
+        warn!("connection failed: {}", e);
+    }
+    output(config, clock, series, &rest)
+}
+
+/// Accept connections on `listener` and read them until we are asked to terminate, writing their
+/// lines (see `Connection`) to `series` or, if there's no series (`--no-files`), only echoing them
+/// to stdout. A connection is only read from when what it has sent can be written, so a slow
+/// series holds senders up rather than input being buffered without limit.
+fn pump_listen(
+    config: &Config,
+    clock: &dyn Clock,
+    mut series: Option<&mut Series>,
+    listener: &UnixListener,
+) -> Result<(), Box<dyn Error>> {
+    let mut conns = Vec::<(UnixStream, Connection)>::new();
+    let mut next_id = 1;
+    let mut buf = ReadBuf::new(config.buffer_size);
+    let mut out = Vec::new();
+    let marker = |c: &Connection, what: &str, out: &mut Vec<u8>| {
+        if config.connection_markers {
+            if c.mid_line() {
+                out.push(b'\n');
+            }
+            out.extend_from_slice(format!("-- connection {} {} --\n", c.id(), what).as_bytes());
+        }
+    };
+    while TERMINATE.load(Ordering::SeqCst) == 0 {
+        if let Some(s) = &mut series {
+            if s.expired(clock) {
+                break;
+            }
+            s.commit_if_requested(clock)?;
+            buf.resize(s.read_size());
+            s.run_control(clock)?;
+            s.tick(clock)?;
+        }
+
+        let (clock_fd, timeout) = deadline_wait(clock, series.as_ref().and_then(|s| s.next_tick()));
+        let control_fd = series.as_ref().map_or(-1, |s| s.control_fd());
+        let ready = poll_inputs(
+            &[listener.as_raw_fd(), clock_fd, control_fd]
+                .into_iter()
+                .chain(conns.iter().map(|(s, _)| s.as_raw_fd()))
+                .collect::<Vec<_>>(),
+            timeout,
+        )?;
+
+        out.clear();
+        if ready[0] {
+            loop {
+                match listener.accept() {
+                    Ok((s, _)) => {
+                        let c =
+                            Connection::new(next_id, config.max_line_bytes, config.connection_tags);
+                        next_id += 1;
+                        marker(&c, "opened", &mut out);
+                        conns.push((s, c));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    // E.g. the client gave up before we accepted it.
+                    Err(e) => {
+                        warn!("can't accept connection: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Read (at most) once from each ready connection, so that none can starve the others.
+        let mut i = 0;
+        for ready in ready.into_iter().skip(3) {
+            let (s, c) = &mut conns[i];
+            if ready {
+                let room = usize::min(c.room(), buf.len());
+                match s.read(&mut buf[..room]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Ok(0) | Err(_) => {
+                        c.finish(&mut out);
+                        marker(c, "closed", &mut out);
+                        conns.remove(i);
+                        continue;
+                    }
+                    Ok(n) => c.feed(&buf[..n], &mut out),
+                }
+            }
+            i += 1;
+        }
+        output(config, clock, series.as_deref_mut(), &out)?;
+    }
+    out.clear();
+    for (_, c) in &mut conns {
+        c.finish(&mut out);
+        marker(c, "closed", &mut out);
+    }
+    output(config, clock, series, &out)
+}
+
+/// Read `out` (a command's stdout, or stdin with `--stderr-fd`, as named by `out_name`) and `err`
+/// until both are at EOF (or until we are asked to terminate), writing each to its own series in
+/// `series` or, if there's only one series (see `open_series()`), both to it.
+fn pump_split(
+    config: &Config,
+    clock: &dyn Clock,
+    series: &mut [Series],
+    out: File,
+    out_name: &'static str,
+    err: File,
+) -> Result<(), Box<dyn Error>> {
+    let mut inputs = [Some(out), Some(err)];
+    let len = config
+        .max_read
+        .map_or(config.buffer_size, |m| usize::min(m, config.buffer_size));
+    let mut buf = ReadBuf::new(len);
+    let mut fmts = [
+        Formatter::new(config, out_name),
+        Formatter::new(config, "stderr"),
+    ];
+    let mut syslogs = [syslog_sender(config), syslog_sender(config)];
+    let last = series.len() - 1;
+
+    while TERMINATE.load(Ordering::SeqCst) == 0 && inputs.iter().any(Option::is_some) {
+        if series[0].expired(clock) {
+            break;
+        }
+        series[0].commit_if_requested(clock)?;
+        buf.resize(series[0].read_size());
+        series[0].run_control(clock)?;
+        for s in series.iter_mut() {
+            s.tick(clock)?;
+        }
+
+        // Closed inputs are given as -1, which `poll` ignores.
+        let (clock_fd, timeout) =
+            deadline_wait(clock, series.iter().filter_map(Series::next_tick).min());
+        let ready = poll_inputs(
+            &inputs
+                .iter()
+                .map(|i| i.as_ref().map_or(-1, |f| f.as_raw_fd()))
+                .chain([clock_fd, series[0].control_fd()])
+                .collect::<Vec<_>>(),
+            timeout,
+        )?;
+
+        // Read (at most) once from each ready input, so that neither can starve the other.
+        for (i, (ready, input)) in ready.into_iter().zip(&mut inputs).enumerate() {
+            if !ready {
+                continue;
+            }
+            match input.as_mut().unwrap().read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+                Ok(0) => *input = None, // EOF.
+                Ok(nbytes) => {
+                    if let Some(s) = &mut syslogs[i] {
+                        s.feed(&buf[..nbytes]);
+                    }
+                    let data = fmts[i].format(clock.now(), &buf[..nbytes]);
+                    series[i.min(last)].write(clock, data)?;
+                }
+            }
+        }
+    }
+    for s in syslogs.iter_mut().flatten() {
+        s.finish();
+    }
+    for (i, fmt) in fmts.iter_mut().enumerate() {
+        series[i.min(last)].write(clock, fmt.finish())?;
+    }
+    Ok(())
+}
+
+/// Open the series that input is written to: none with `--no-files`; one for stdin (or the `--`
+/// command's stdout); and, with `--stderr-prefix`, one for the command's stderr (or the
+/// `--stderr-fd`), unless
+/// `--stderr-prefix` is the same as `--file-prefix`, in which case both streams' JSON records are
+/// written to the one series.
+fn open_series<'a>(
+    config: &'a Config,
+    err_config: &'a Config,
+    clock: &dyn Clock,
+) -> Result<Vec<Series<'a>>, Box<dyn Error>> {
+    let mut series = Vec::new();
+    if !config.no_files {
+        let all_sigs = all_signals()?;
+        series.push(Series::open(
+            config,
+            clock,
+            Box::new(io::stdout()),
+            all_sigs,
+        )?);
+        if config.stderr_prefix.is_some() && err_config.file_prefix != config.file_prefix {
+            series.push(Series::open(
+                err_config,
+                clock,
+                Box::new(io::stderr()),
+                all_sigs,
+            )?);
+        }
+    }
+    Ok(series)
+}
+
+/// Flush everything written. Once this succeeds, it is safe to signal completion: consumers never
+/// see the marker for a partial capture.
+fn finish(series: Vec<Series>, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+    for s in series {
+        s.finish(clock)?;
+    }
+    io::stdout().flush()?;
+    Ok(())
+}
+
+/// Read stdin until EOF (or, with `--listen-unix` or `--listen-tcp`, the socket's connections until
+/// we are asked to terminate), writing it to the output files. With `--stderr-fd`, that fd is read
+/// too, into the `--stderr-prefix` series.
+fn run(config: &Config, clock: &dyn Clock) -> Result<(), Box<dyn Error>> {
+    let err_config = config.stderr_config();
+    let mut series = open_series(config, &err_config, clock)?;
+    let listener = match &config.listen_unix {
+        Some(p) => Some(listen_unix(config, Path::new(p))?),
+        None => None,
+    };
+    let tcp_listener = match config.listen_tcp_addr {
+        Some(a) => Some(listen_tcp(config, a)?),
+        None => None,
+    };
+    drop_privileges(config, &series)?;
+    match (listener, tcp_listener) {
+        (Some(l), _) => {
+            let res = pump_listen(config, clock, series.first_mut(), &l);
+            // Nothing can connect once we stop listening, so don't leave the socket behind.
+            let _ = fs::remove_file(config.listen_unix.as_ref().unwrap());
+            res?;
+        }
+        (None, Some(l)) => pump_tcp(config, clock, series.first_mut(), &l)?,
+        (None, None) if config.stderr_fd.is_some() => {
+            let stdin = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+            // `main()` checked that the fd is open, and nothing else uses it.
+            let err = unsafe { File::from_raw_fd(config.stderr_fd.unwrap()) };
+            pump_split(config, clock, &mut series, stdin, "stdin", err)?
+        }
+        (None, None) => pump(
+            config,
+            clock,
+            series.first_mut(),
+            &mut stdin_reader(config)?,
+            "stdin",
+        )?,
+    }
+    let expired = series.first_mut().is_some_and(|s| s.expired(clock));
+    finish(series, clock)?;
+    // Stopping at the `--max-runtime` leaves input unread.
+    if !expired {
+        mark_done(config)?;
+    }
+    Ok(())
+}
+
+/// The process group of the `--` command, to which signals are forwarded (0 if there is none).
+static CHILD_PGID: AtomicI32 = AtomicI32::new(0);
+
+/// Set when a forwarded signal asks the `--` command to stop.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_forward_signal(signo: c_int) {
+    if signo != libc::SIGHUP {
+        STOPPING.store(true, Ordering::SeqCst);
+    }
+    let pgid = CHILD_PGID.load(Ordering::SeqCst);
+    if pgid > 0 {
+        unsafe { libc::kill(-pgid, signo) };
+    }
+}
+
+/// Install the exec mode signal handlers: signals in `--forward-signals` are forwarded to the
+/// command; otherwise `SIGINT` and `SIGTERM` make us stop reading and terminate the command.
+fn install_exec_handlers(config: &Config) -> Result<(), io::Error> {
+    install_handlers(config, &[SignalAction::Forward, SignalAction::Terminate])?;
+    if config.pty {
+        if let Some(fd) = [1, 2, 0]
+            .into_iter()
+            .find(|&fd| pty::window_size(fd).is_some())
+        {
+            TTY_FD.store(fd, Ordering::SeqCst);
+            install_handlers(config, &[SignalAction::Resize])?;
+        }
+    }
+    Ok(())
+}
+
+/// Our own terminal (the first of stdout, stderr, and stdin that is one), whose window size is
+/// copied to the `--pty` terminal, or -1 if there isn't one.
+static TTY_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// The master side of the `--pty` terminal (-1 if there is none).
+static PTY_MASTER_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Copy our terminal's window size to the `--pty` terminal. Only makes system calls, so it is
+/// async-signal-safe.
+extern "C" fn on_winch_signal(_: c_int) {
+    let (tty, master) = (
+        TTY_FD.load(Ordering::SeqCst),
+        PTY_MASTER_FD.load(Ordering::SeqCst),
+    );
+    if tty != -1 && master != -1 {
+        if let Some(ws) = pty::window_size(tty) {
+            let _ = pty::set_window_size(master, &ws);
+        }
+    }
+}
+
+/// Start the `--` command in its own process group. With `--pty`, the command is given a new
+/// pseudo-terminal (as its stdout, stderr, and controlling terminal, in a new session), whose
+/// master side is returned; otherwise its stdout (and, with `--stderr-prefix`, stderr) is a pipe.
+fn spawn(config: &Config) -> Result<(Child, Option<PtyMaster>), String> {
+    let can_t_run = |e: io::Error| format!("can't run '{}': {}", config.exec[0], e);
+    let mut cmd = Command::new(&config.exec[0]);
+    cmd.args(&config.exec[1..]);
+    let mut master = None;
+    if config.pty {
+        let tty = TTY_FD.load(Ordering::SeqCst);
+        let (m, slave) = pty::open((tty != -1).then(|| pty::window_size(tty)).flatten())
+            .map_err(|e| format!("can't open a pseudo-terminal: {}", e))?;
+        cmd.stdout(slave.try_clone().map_err(can_t_run)?)
+            .stderr(slave);
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() == -1 || libc::ioctl(1, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        PTY_MASTER_FD.store(m.as_raw_fd(), Ordering::SeqCst);
+        master = Some(m);
+    } else {
+        let stderr = match config.stderr_prefix {
+            Some(_) => Stdio::piped(),
+            None => Stdio::inherit(),
+        };
+        cmd.stdout(Stdio::piped()).stderr(stderr).process_group(0);
+    }
+    // The command is started before the output files are opened, and so before we switch user, but
+    // must only ever run as the `--setuid` user. (Setting the uid drops supplementary groups too.)
+    if let Some((uid, gid)) = config.run_as {
+        cmd.gid(gid);
+        if let Some(uid) = uid {
+            cmd.uid(uid);
+        }
+    }
+    // Spawning drops our copies of the slave, so that reading the master sees the command exit.
+    let child = cmd.spawn().map_err(can_t_run)?;
+    CHILD_PGID.store(i32::try_from(child.id()).unwrap(), Ordering::SeqCst);
+    Ok((child, master))
+}
+
+/// Capture the output of `child` (from `master` if it runs on a `--pty` terminal) into `series`.
+fn pump_child(
+    config: &Config,
+    clock: &dyn Clock,
+    series: &mut [Series],
+    child: &mut Child,
+    master: Option<PtyMaster>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(m) = master {
+        let res = pump(
+            config,
+            clock,
+            series.first_mut(),
+            &mut input_reader(config, m),
+            "stdout",
+        );
+        PTY_MASTER_FD.store(-1, Ordering::SeqCst);
+        return res;
+    }
+    let stdout = child.stdout.take().unwrap();
+    match child.stderr.take() {
+        Some(stderr) => pump_split(
+            config,
+            clock,
+            series,
+            File::from(OwnedFd::from(stdout)),
+            "stdout",
+            File::from(OwnedFd::from(stderr)),
+        ),
+        None => {
+            let mut input = input_reader(config, stdout);
+            pump(config, clock, series.first_mut(), &mut input, "stdout")
+        }
+    }
+}
+
+/// The delay before the `n`th (from 1) restart of the `--` command: 100ms, doubling with each
+/// restart, up to a maximum of 10s.
+fn restart_backoff(n: usize) -> Duration {
+    let ms = 100u64.saturating_mul(1 << u32::try_from(n - 1).unwrap_or(u32::MAX).min(7));
+    Duration::from_millis(ms.min(10_000))
+}
+
+/// Run the `--` command, capturing its output, and return the status that rotee should exit with:
+/// the command's exit status, or 128 plus the number of the signal that killed it. Signals in
+/// `--forward-signals` are forwarded to the command and capture continues until it closes its
+/// output. With `--restart`, the command is restarted when it fails, writing to the same series.
+///
+/// If rotee is asked to terminate by a signal that isn't forwarded (`SIGINT` or `SIGTERM`), it
+/// stops reading, sends the command `SIGTERM`, and exits with 128 plus the number of the signal
+/// it received.
+fn exec(config: &Config, clock: &dyn Clock) -> i32 {
+    match exec_inner(config, clock) {
+        Ok(code) => code,
+        Err(e) => {
+            diag::error(&e);
+            1
+        }
+    }
+}
+
+fn exec_inner(config: &Config, clock: &dyn Clock) -> Result<i32, Box<dyn Error>> {
+    install_exec_handlers(config)?;
+    let (mut child, mut master) = spawn(config)?;
+    let err_config = config.stderr_config();
+    let mut series = open_series(config, &err_config, clock)?;
+    drop_privileges(config, &series)?;
+    let mut restarts = 0;
+    loop {
+        let res = pump_child(config, clock, &mut series, &mut child, master.take());
+
+        // The command's output is now closed. Unless we read it to the end, make sure that the
+        // command doesn't outlive us.
+        let terminated = TERMINATE.load(Ordering::SeqCst);
+        let expired = series.first_mut().is_some_and(|s| s.expired(clock));
+        if res.is_err() || terminated != 0 || expired {
+            terminate_child(&child);
+        }
+        let status = wait_child(&mut child);
+        CHILD_PGID.store(0, Ordering::SeqCst);
+        res?;
+        if terminated != 0 {
+            finish(series, clock)?;
+            return Ok(128 + terminated);
+        }
+        if expired {
+            finish(series, clock)?;
+            return Ok(0);
+        }
+        let status = status?;
+
+        if !status.success() && restarts < config.max_restarts {
+            restarts += 1;
+            let delay = restart_backoff(restarts);
+            let max = match config.max_restarts {
+                usize::MAX => String::new(),
+                m => format!(" of {}", m),
+            };
+            info!(
+                "'{}' exited with status {}; restarting in {}ms (restart {}{})",
+                config.exec[0],
+                exit_code(status),
+                delay.as_millis(),
+                restarts,
+                max
+            );
+            clock.sleep_until(clock.now() + delay);
+            if !STOPPING.load(Ordering::SeqCst)
+                && TERMINATE.load(Ordering::SeqCst) == 0
+                && !series.first_mut().is_some_and(|s| s.expired(clock))
+            {
+                (child, master) = spawn(config)?;
+                continue;
+            }
+        }
+
+        finish(series, clock)?;
+        // A command stopped by a forwarded signal didn't complete.
+        if !STOPPING.load(Ordering::SeqCst) && TERMINATE.load(Ordering::SeqCst) == 0 {
+            mark_done(config)?;
+        }
+        return Ok(exit_code(status));
+    }
+}
+
+/// Ask `child` to terminate (it may already have exited).
+fn terminate_child(child: &Child) {
+    if let Ok(pid) = i32::try_from(child.id()) {
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+    }
+}
+
+/// Wait for `child` to exit, even if we are interrupted by signals.
+fn wait_child(child: &mut Child) -> Result<ExitStatus, io::Error> {
+    loop {
+        match child.wait() {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            r => return r,
+        }
+    }
+}
+
+/// The shell-style exit code for `status`.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(c), _) => c,
+        (None, Some(s)) => 128 + s,
+        (None, None) => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        conf_args, create_pid_file, date_prefix, is_regular_file, layer_args, lookup_entry,
+        or_list, parse_byte, parse_duration, parse_restart, parse_signal, read_input,
+        restart_backoff, rotate_inner, signal_name, split_size, take_verbosity, touch, Config,
+        CONFLICTS, MAX_ENTRY_BUF,
+    };
+    use libc::{c_int, kill, SIGKILL, SIGTERM, SIGUSR1, SIGUSR2};
+    use proptest::prelude::*;
+    use rand::Rng;
+    use rotee::{
+        checksum::Crc32,
+        chunker::Chunker,
+        clock::{local_date, parse_timestamp},
+        json::base64,
+        read_buf::ReadBuf,
+        sink::{MemSink, Sink},
+        verify::check_series,
+    };
+    use std::{
+        env,
+        ffi::CString,
+        fs::{self, File, OpenOptions},
+        io::{self, BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        ops::Range,
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+            io::{AsRawFd, FromRawFd},
+            net::UnixStream,
+            process::CommandExt,
+        },
+        path::PathBuf,
+        process::{Command, Stdio},
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+    use tempfile::TempDir;
+
+    #[cfg(cargo_profile = "release")]
+    pub(crate) static CARGO_PROFILE: &str = "release";
+    #[cfg(not(cargo_profile = "release"))]
+    pub(crate) static CARGO_PROFILE: &str = "debug";
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+
+    #[test]
+    fn test_parse_byte() {
+        assert_eq!(parse_byte(";"), Some(b';'));
+        assert_eq!(parse_byte("\\n"), Some(b'\n'));
+        assert_eq!(parse_byte("\\0"), Some(0));
+        assert_eq!(parse_byte("0x1e"), Some(0x1e));
+        assert_eq!(parse_byte("0"), Some(b'0'));
+        for s in ["", "ab", "0x1", "0x1g", "0x100", "é"] {
+            assert_eq!(parse_byte(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("USR1"), Ok(SIGUSR1));
+        assert_eq!(parse_signal("SIGUSR1"), Ok(SIGUSR1));
+        assert_eq!(parse_signal(&SIGUSR1.to_string()), Ok(SIGUSR1));
+        assert!(parse_signal("").is_err());
+        assert!(parse_signal("0").is_err());
+        assert!(parse_signal("SIGFOO").is_err());
+        // Names round trip, and signals without one are numbered.
+        assert_eq!(signal_name(SIGUSR1), "SIGUSR1");
+        assert_eq!(parse_signal(&signal_name(SIGTERM)), Ok(SIGTERM));
+        assert_eq!(signal_name(libc::SIGRTMIN()), libc::SIGRTMIN().to_string());
+    }
+
+    /// Options can be checked without running anything, and the settings derived from them are
+    /// filled in.
+    #[test]
+    fn test_validate() {
+        let mut config = Config {
+            retain: Some(String::from("5G")),
+            ..Config::default()
+        };
+        config.validate().unwrap();
+        assert_eq!((config.num_files, config.file_size), (20, 256 << 20));
+
+        let mut config = Config {
+            retain: Some(String::from("5G")),
+            no_files: true,
+            ..Config::default()
+        };
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .starts_with("--retain can't be used"));
+        let mut config = Config {
+            buffer_size: 0,
+            ..Config::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(String::from("buffer size (-b) must be non-zero"))
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        // Every option in the table is one that `given()` knows, and none is given by default.
+        let config = Config::default();
+        for (opt, others) in CONFLICTS {
+            for o in [opt].into_iter().chain(*others) {
+                assert!(!config.given(o), "{}", o);
+            }
+        }
+
+        let config = Config {
+            mark: Some(String::from("1s")),
+            format: String::from("json"),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.check_conflicts(),
+            Err(String::from(
+                "--mark can't be used with --no-files (-N) or --format json"
+            ))
+        );
+        assert_eq!(or_list(&["a"]), "a");
+        assert_eq!(or_list(&["a", "b", "c"]), "a, b, or c");
+    }
+
+    #[test]
+    fn test_max_read() {
+        let config = Config {
+            max_read: Some(3),
+            ..Config::default()
+        };
+        let mut input = &b"abcdefg"[..];
+        let mut buf = ReadBuf::new(100);
+        assert_eq!(read_input(&config, &mut input, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+    }
+
+    #[test]
+    fn test_truncation_marker() {
+        let config = Config {
+            line_input: true,
+            max_line_bytes: 4,
+            truncation_marker: Some(String::from("[cut]\n")),
+            ..Config::default()
+        };
+        let mut input = &b"abcdefgh\nabcd\nabc"[..];
+        let mut buf = ReadBuf::new(0);
+        let mut lines = Vec::new();
+        while read_input(&config, &mut input, &mut buf).unwrap() > 0 {
+            lines.push(String::from_utf8(buf.to_vec()).unwrap());
+        }
+        assert_eq!(lines, ["abcd[cut]\n", "efgh", "\n", "abcd", "\n", "abc"]);
+    }
+
+    #[test]
+    fn test_split_size() {
+        assert_eq!(split_size(1 << 30, 10), Some(107374183));
+        assert_eq!(split_size(12, 3), Some(4));
+        assert_eq!(split_size(10, 3), Some(4));
+        assert_eq!(split_size(3, 3), Some(1));
+        assert_eq!(split_size(2, 3), None);
+        assert_eq!(split_size(0, 1), None);
+    }
+
+    #[test]
+    fn test_touch() {
+        let dir = TempDir::new().unwrap();
+        let p = dir.path().join("hb");
+        touch(&p).unwrap();
+        assert_eq!(fs::read(&p).unwrap(), b"");
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&p)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        touch(&p).unwrap();
+        assert!(fs::metadata(&p).unwrap().modified().unwrap() > old);
+        assert!(touch(&dir.path().join("no/such/dir")).is_err());
+    }
+
+    #[test]
+    fn test_is_regular_file() {
+        let dir = TempDir::new().unwrap();
+        let f = File::create(dir.path().join("f")).unwrap();
+        assert!(is_regular_file(f.as_raw_fd()));
+        assert!(!is_regular_file(
+            File::open(dir.path()).unwrap().as_raw_fd()
+        ));
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (r, _w) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        assert!(!is_regular_file(r.as_raw_fd()));
+        assert!(!is_regular_file(-1));
+    }
+
+    /// With `--confirm-eof`, the end of a regular file is still the end of input.
+    #[test]
+    fn test_confirm_eof() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("input");
+        fs::write(&input, b"abc\ndef").unwrap();
+        for args in [&["-e", "--confirm-eof"][..], &["-e", "-l", "--confirm-eof"]] {
+            let status = Command::new(&p)
+                .current_dir(dir.path())
+                .args(args)
+                .stdin(File::open(&input).unwrap())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert_eq!(fs::read(dir.path().join("rotee.0")).unwrap(), b"abc\ndef");
+        }
+    }
+
+    #[test]
+    fn test_create_pid_file() {
+        let dir = TempDir::new().unwrap();
+        let p = dir.path().join("pid");
+        create_pid_file(&p, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&p).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        // We're still running, so the file is never replaced.
+        assert!(create_pid_file(&p, true).is_err());
+
+        // A process that has exited.
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        fs::write(&p, format!("{}\n", pid)).unwrap();
+        assert!(create_pid_file(&p, false).is_err());
+        create_pid_file(&p, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(&p).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        fs::write(&p, "junk").unwrap();
+        assert!(create_pid_file(&p, true).is_err());
+    }
+
+    /// The `--pid-file` holds rotee's PID while it runs, and is removed when it exits.
+    #[test]
+    fn test_pid_file() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let pid_file = dir.path().join("rotee.pid");
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "--pid-file", "rotee.pid"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let expect = format!("{}\n", child.id());
+        while fs::read_to_string(&pid_file).ok().as_ref() != Some(&expect) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // A second instance can't take over the file.
+        let out = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-p", "other.", "--pid-file", "rotee.pid"])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        assert_eq!(fs::read_to_string(&pid_file).unwrap(), expect);
+        drop(child.stdin.take());
+        assert!(child.wait().unwrap().success());
+        assert!(!pid_file.exists());
+
+        // Nor is the file left behind when rotee fails to start.
+        fs::create_dir(dir.path().join("done")).unwrap();
+        fs::write(dir.path().join("file"), "").unwrap();
+        for args in [&["--done-file", "done"][..], &["-p", "file/rotee."]] {
+            let out = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["-e", "--pid-file", "rotee.pid"])
+                .args(args)
+                .stdin(Stdio::null())
+                .output()
+                .unwrap();
+            assert!(!out.status.success());
+            assert!(!pid_file.exists());
+        }
+    }
+
+    /// By default, the echo of each read is flushed at once, even if it ends mid-line; with
+    /// `--echo-flush-lines`, a partial line is only echoed once its newline arrives.
+    #[test]
+    fn test_echo_flush() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        for flush_lines in [false, true] {
+            let dir = TempDir::new().unwrap();
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .args(flush_lines.then_some("--echo-flush-lines"))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut stdout = child.stdout.take().unwrap();
+            let (tx, rx) = mpsc::channel();
+            let reader = thread::spawn(move || {
+                let mut buf = [0; 64];
+                while let Ok(n @ 1..) = stdout.read(&mut buf) {
+                    tx.send(buf[..n].to_vec()).unwrap();
+                }
+            });
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b"a\nprompt: ").unwrap();
+            let mut seen = Vec::new();
+            while seen != b"a\nprompt: " {
+                match rx.recv_timeout(Duration::from_millis(200)) {
+                    Ok(b) => seen.extend(b),
+                    Err(_) => break,
+                }
+            }
+            if flush_lines {
+                assert_eq!(seen, b"a\n");
+                stdin.write_all(b"yes\n").unwrap();
+                while seen != b"a\nprompt: yes\n" {
+                    seen.extend(rx.recv().unwrap());
+                }
+            } else {
+                assert_eq!(seen, b"a\nprompt: ");
+            }
+            drop(stdin);
+            assert!(child.wait().unwrap().success());
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_conf_args() {
+        let conf = "# A comment\n\nfile-size = 1024\n  no-echo  \nfile-prefix= /a b/c.\n";
+        assert_eq!(
+            conf_args(conf),
+            vec![
+                "--file-size",
+                "1024",
+                "--no-echo",
+                "--file-prefix",
+                "/a b/c."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rotate_cascade() {
+        let config = Config {
+            num_files: 3,
+            strict_writes: true,
+            ..Config::default()
+        };
+        let mut sink = MemSink::new();
+        let mut f = sink.create(0).unwrap();
+        for (i, data) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+            f.write_all(*data).unwrap();
+            f = rotate_inner(&config, &mut sink, f, 1).unwrap();
+            assert_eq!(sink.indices().len(), usize::min(i + 2, 3));
+        }
+        assert_eq!(sink.contents(0).unwrap(), b"");
+        assert_eq!(sink.contents(1).unwrap(), b"d");
+        assert_eq!(sink.contents(2).unwrap(), b"c");
+
+        // `--strict-writes` notices a file that doesn't contain what was written.
+        f.write_all(b"xy").unwrap();
+        assert!(rotate_inner(&config, &mut sink, f, 1).is_err());
+    }
+
+    /// A sink whose files are changed by "another process" between `exists()` and `rename()`.
+    struct InterferingSink {
+        inner: MemSink,
+        /// Create file `n + 1` after `exists(n)`.
+        create: Option<usize>,
+        /// Remove file `n` after `exists(n)`.
+        remove: Option<usize>,
+    }
+
+    impl Sink for InterferingSink {
+        type File = <MemSink as Sink>::File;
+
+        fn exists(&self, idx: usize) -> bool {
+            self.inner.exists(idx)
+        }
+
+        fn rename(&mut self, from: usize, to: usize) -> Result<(), io::Error> {
+            if self.create == Some(from) {
+                self.inner.create(to)?.write_all(b"intruder")?;
+            }
+            if self.remove == Some(from) {
+                // Moving the file out of the way is as good as removing it.
+                self.inner.rename(from, 100)?;
+            }
+            self.inner.rename(from, to)
+        }
+
+        fn create(&mut self, idx: usize) -> Result<Self::File, io::Error> {
+            self.inner.create(idx)
+        }
+
+        fn size(&self, f: &Self::File) -> Result<u64, io::Error> {
+            self.inner.size(f)
+        }
+
+        fn name(&self, idx: usize) -> String {
+            self.inner.name(idx)
+        }
+    }
+
+    #[test]
+    fn test_rotate_interference() {
+        let config = Config {
+            num_files: 4,
+            ..Config::default()
+        };
+        let mut sink = InterferingSink {
+            inner: MemSink::new(),
+            create: None,
+            remove: None,
+        };
+        let mut f = sink.create(0).unwrap();
+        for data in [b"a", b"b", b"c"] {
+            f.write_all(data).unwrap();
+            f = rotate_inner(&config, &mut sink, f, 1).unwrap();
+        }
+        // Files 1..=3 are "c", "b", "a". File 2 disappears just before it would be moved (so
+        // file 3 is left alone), and a file then created at 2 is replaced by file 1.
+        sink.create = Some(1);
+        sink.remove = Some(2);
+        f.write_all(b"d").unwrap();
+        rotate_inner(&config, &mut sink, f, 1).unwrap();
+        assert_eq!(sink.inner.indices(), vec![0, 1, 2, 3, 100]);
+        assert_eq!(sink.inner.contents(3).unwrap(), b"a");
+        assert_eq!(sink.inner.contents(2).unwrap(), b"c");
+        assert_eq!(sink.inner.contents(1).unwrap(), b"d");
+        assert_eq!(sink.inner.contents(0).unwrap(), b"");
+    }
+
+    /// Split input between files in memory, as `run()` would, and check the result.
+    #[test]
+    fn test_split_in_memory() {
+        let config = Config {
+            num_files: 3,
+            file_size: 4,
+            ..Config::default()
+        };
+        let mut sink = MemSink::new();
+        let mut chunker = Chunker::new(config.file_size);
+        let mut f = sink.create(0).unwrap();
+        let input = b"0123456789abcdefg";
+        for chunk in input.chunks(3) {
+            for (range, rotate) in chunker.split(chunk.len()) {
+                f.write_all(&chunk[range]).unwrap();
+                if rotate {
+                    f = rotate_inner(&config, &mut sink, f, config.file_size).unwrap();
+                }
+            }
+        }
+        assert_eq!(sink.contents(2).unwrap(), b"89ab");
+        assert_eq!(sink.contents(1).unwrap(), b"cdef");
+        assert_eq!(sink.contents(0).unwrap(), b"g");
+    }
+
+    #[test]
+    fn test_date_prefix() {
+        assert_eq!(date_prefix("rotee.", "2024-06-01"), "2024-06-01/rotee.");
+        assert_eq!(
+            date_prefix("/var/log/rotee.", "2024-06-01"),
+            "/var/log/2024-06-01/rotee."
+        );
+        assert_eq!(date_prefix("logs/", "2024-06-01"), "logs/2024-06-01/");
+    }
+
+    #[test]
+    fn test_take_verbosity() {
+        let s = |v: &[&str]| v.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let mut args = s(&["rotee", "-v", "-e", "-vv", "--verbose", "-", "-s", "1"]);
+        assert_eq!(take_verbosity(&mut args), 4);
+        assert_eq!(args, s(&["rotee", "-e", "-", "-s", "1"]));
+        let mut args = s(&["rotee", "-e"]);
+        assert_eq!(take_verbosity(&mut args), 0);
+        assert_eq!(args, s(&["rotee", "-e"]));
+    }
+
+    /// Later arguments take precedence, so the layering must be system < user < env < CLI.
+    #[test]
+    fn test_layer_args() {
+        let s = |v: &[&str]| v.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            layer_args(
+                s(&["--num-files", "1"]),
+                s(&["--num-files", "2"]),
+                Some(" -n 3  -e "),
+                &s(&["rotee", "-n", "4"])
+            ),
+            s(&[
+                "rotee",
+                "--num-files",
+                "1",
+                "--num-files",
+                "2",
+                "-n",
+                "3",
+                "-e",
+                "-n",
+                "4"
+            ])
+        );
+        assert_eq!(
+            layer_args(vec![], vec![], None, &s(&["rotee"])),
+            s(&["rotee"])
+        );
+        // `--no-FLAG` and `--FLAG` undo each other's defaults, but leave options with values, and
+        // the command after `--`, alone.
+        assert_eq!(
+            layer_args(
+                s(&["--verify", "--no-echo", "--mark", "20m"]),
+                s(&["--verify"]),
+                Some("--xattrs -s 4"),
+                &s(&[
+                    "rotee",
+                    "--no-verify",
+                    "--echo",
+                    "--no-mark",
+                    "--",
+                    "--no-xattrs"
+                ])
+            ),
+            s(&[
+                "rotee",
+                "--mark",
+                "20m",
+                "--xattrs",
+                "-s",
+                "4",
+                "--no-mark",
+                "--",
+                "--no-xattrs"
+            ])
+        );
+    }
+
+    /// An entry that doesn't fit the buffer is looked up again with a bigger one, and errors are
+    /// told apart from there being no such entry.
+    #[test]
+    fn test_lookup_entry() {
+        let mut entry = 0u8;
+        let mut sizes = Vec::new();
+        let found = lookup_entry(|buf, res: &mut *mut u8| {
+            sizes.push(buf.len());
+            if buf.len() < 5000 {
+                return libc::ERANGE;
+            }
+            *res = &mut entry;
+            0
+        });
+        assert!(found.unwrap());
+        assert_eq!(sizes, [1024, 2048, 4096, 8192]);
+
+        assert!(!lookup_entry(|_, _: &mut *mut u8| 0).unwrap());
+        let e = lookup_entry(|_, _: &mut *mut u8| libc::EIO).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EIO));
+        // The buffer doesn't grow without limit.
+        let e = lookup_entry(|buf, _: &mut *mut u8| {
+            assert!(buf.len() <= MAX_ENTRY_BUF);
+            libc::ERANGE
+        })
+        .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ERANGE));
+    }
+
+    /// Repeatedly run a rapidly rotating rotee, and after a random delay from `delays`
+    /// (milliseconds), send it `sig`, then `check` the directory it ran in.
+    fn signal_stress(sig: c_int, delays: Range<u64>, iters: usize, check: impl Fn(&TempDir)) {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..iters {
+            let dir = TempDir::new().unwrap();
+            let outfile0 = dir.path().join("rotee.0");
+
+            // Pipe /dev/zero into a rotee with a very small output file size, so that rotation
+            // happens very frequently.
+            let zero = File::open("/dev/zero").unwrap();
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .stdin(zero)
+                .args(["-s", "1", "-e"])
+                .spawn()
+                .unwrap();
+
+            // Wait for `rotee.0` to appear for the first time.
+            while !outfile0.exists() {
+                thread::sleep(Duration::from_nanos(10));
+            }
+
+            // After a random amount of time, send rotee the signal.
+            thread::sleep(Duration::from_millis(rng.gen_range(delays.clone())));
+            unsafe { kill(i32::try_from(child.id()).unwrap(), sig) };
+
+            // rotee should exit with failure.
+            assert!(!child.wait().unwrap().success());
+            check(&dir);
+        }
+    }
+
+    /// Check (best we can) that delivering catchable signals cannot interrupt file rotation:
+    /// `rotee.0` should always exist. https://github.com/vext01/rotee/issues/1
+    fn check_catchable(dir: &TempDir) {
+        assert!(dir.path().join("rotee.0").exists());
+    }
+
+    // The range of delays is split across several tests so that they can run in parallel.
+    #[test]
+    fn test_signal_early() {
+        signal_stress(SIGTERM, 0..34, 17, check_catchable);
+    }
+
+    #[test]
+    fn test_signal_mid() {
+        signal_stress(SIGTERM, 34..68, 17, check_catchable);
+    }
+
+    #[test]
+    fn test_signal_late() {
+        signal_stress(SIGTERM, 68..101, 16, check_catchable);
+    }
+
+    /// SIGKILL can't be blocked, so it can interrupt a rotation part way through (possibly
+    /// leaving no `rotee.0`). Check that a subsequent run always recovers.
+    #[test]
+    fn test_signal_kill() {
+        signal_stress(SIGKILL, 0..101, 16, |dir| {
+            let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+            let p = [&md, "target", CARGO_PROFILE, "rotee"]
+                .iter()
+                .collect::<PathBuf>();
+            let status = Command::new(p)
+                .current_dir(dir.path())
+                .stdin(Stdio::null())
+                .args(["-s", "1", "-e"])
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert!(dir.path().join("rotee.0").exists());
+        });
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        /// Run random data through the real binary, with random buffer and file sizes, and check
+        /// that the output files, concatenated in rotation order, reproduce the input exactly and
+        /// that every file but the newest is exactly `file_size` bytes. The in-process equivalent
+        /// in the `chunker` module covers many more cases; this covers the process-level plumbing.
+        #[test]
+        fn test_no_bytes_lost(
+            data in prop::collection::vec(any::<u8>(), 0..8192),
+            buf_size in 1usize..1024,
+            file_size in 1usize..1024,
+        ) {
+            let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+            let p = [&md, "target", CARGO_PROFILE, "rotee"]
+                .iter()
+                .collect::<PathBuf>();
+            let dir = TempDir::new().unwrap();
+            // Make sure no file is ever rotated out of existence.
+            let num_files = data.len() / file_size + 2;
+            // The checks assume rotee's defaults, so no configuration may apply.
+            let mut child = Command::new(p)
+                .current_dir(dir.path())
+                .env_remove("HOME")
+                .env_remove("ROTEE_DEFAULT_ARGS")
+                .env("ROTEE_NO_CONFIG", "1")
+                .stdin(Stdio::piped())
+                .args([
+                    "-e",
+                    "-S",
+                    "-b",
+                    &buf_size.to_string(),
+                    "-s",
+                    &file_size.to_string(),
+                    "-n",
+                    &num_files.to_string(),
+                ])
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(&data).unwrap();
+            prop_assert!(child.wait().unwrap().success());
+
+            let files = (0..num_files)
+                .map(|i| fs::read(dir.path().join(format!("rotee.{}", i))).ok())
+                .collect::<Vec<_>>();
+            prop_assert_eq!(check_series("rotee.", &files, file_size, num_files, &data), Ok(()));
+        }
+    }
+
+    /// By default, rotee neither adds nor removes a trailing newline (or anything else): whether
+    /// or not input ends with one, the files, concatenated in rotation order, and the echo are
+    /// exactly the input, however the input is split between files.
+    #[test]
+    fn test_newline_transparent() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        for input in [
+            &b"a\nb\n"[..],
+            b"a\nb",
+            b"",
+            b"\n",
+            b"\n\n",
+            b"a",
+            b"a\r\n",
+            b"a\r",
+            b"\xff\x00",
+        ] {
+            for (buf_size, file_size) in [(4096, 1), (4096, 2), (1, 3), (2, 64)] {
+                let dir = TempDir::new().unwrap();
+                let num_files = input.len() / file_size + 2;
+                let mut child = Command::new(&p)
+                    .current_dir(dir.path())
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .args(["-b", &buf_size.to_string(), "-s", &file_size.to_string()])
+                    .args(["-n", &num_files.to_string()])
+                    .spawn()
+                    .unwrap();
+                child.stdin.take().unwrap().write_all(input).unwrap();
+                let out = child.wait_with_output().unwrap();
+                assert!(out.status.success());
+                assert_eq!(out.stdout, input);
+                let files = (0..num_files)
+                    .rev()
+                    .map(|i| dir.path().join(format!("rotee.{}", i)))
+                    .filter(|p| p.exists())
+                    .map(|p| fs::read(p).unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(files.concat(), input);
+            }
+        }
+    }
+
+    /// Step rotee's clock (via `ROTEE_FAKE_TIME_FD`) over a day boundary, and backwards over it
+    /// again, checking that `--date-dir` starts exactly one new series.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_fake_time_date_dir() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut times = unsafe { File::from_raw_fd(fds[1]) };
+
+        // Pick times either side of a local midnight.
+        let day1 = UNIX_EPOCH + Duration::from_secs(1_717_243_200); // 2024-06-01T12:00:00Z.
+        let mut day2 = day1;
+        while local_date(day2) == local_date(day1) {
+            day2 += Duration::from_secs(60 * 60);
+        }
+        let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        writeln!(times, "{}", secs(day1)).unwrap();
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", fds[0].to_string())
+            .stdin(Stdio::piped())
+            .args(["-e", "-D"])
+            .spawn()
+            .unwrap();
+        unsafe { libc::close(fds[0]) };
+        let mut stdin = child.stdin.take().unwrap();
+        // Wait for each write to land in a file before changing the time.
+        let wait_for = |path: PathBuf, len: u64| {
+            while fs::metadata(&path).map(|m| m.len()).unwrap_or(0) < len {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let path1 = dir.path().join(local_date(day1)).join("rotee.0");
+        let path2 = dir.path().join(local_date(day2)).join("rotee.0");
+        stdin.write_all(b"a\n").unwrap();
+        wait_for(path1.clone(), 2);
+        writeln!(times, "{}", secs(day2)).unwrap();
+        stdin.write_all(b"b\n").unwrap();
+        wait_for(path2.clone(), 2);
+        // Going backwards over midnight, and forwards again, must not start new series.
+        writeln!(times, "{}", secs(day1)).unwrap();
+        stdin.write_all(b"c\n").unwrap();
+        wait_for(path2.clone(), 4);
+        writeln!(times, "{}", secs(day2)).unwrap();
+        stdin.write_all(b"d\n").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(fs::read(path1).unwrap(), b"a\n");
+        assert_eq!(fs::read(path2).unwrap(), b"b\nc\nd\n");
+    }
+
+    /// With an unlimited file size (`-s 0`), `--date-dir` is the only trigger: each day's file
+    /// holds that day's input, however much (or little) there is.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_fake_time_unlimited_size() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut times = unsafe { File::from_raw_fd(fds[1]) };
+
+        let day1 = UNIX_EPOCH + Duration::from_secs(1_717_243_200); // 2024-06-01T12:00:00Z.
+        let mut day2 = day1;
+        while local_date(day2) == local_date(day1) {
+            day2 += Duration::from_secs(60 * 60);
+        }
+        let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        writeln!(times, "{}", secs(day1)).unwrap();
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", fds[0].to_string())
+            .stdin(Stdio::piped())
+            .args(["-e", "-D", "-s", "0", "-b", "4096"])
+            .spawn()
+            .unwrap();
+        unsafe { libc::close(fds[0]) };
+        let mut stdin = child.stdin.take().unwrap();
+        let path1 = dir.path().join(local_date(day1)).join("rotee.0");
+        let path2 = dir.path().join(local_date(day2)).join("rotee.0");
+        stdin.write_all(b"a\n").unwrap();
+        while fs::metadata(&path1).map(|m| m.len()).unwrap_or(0) < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        writeln!(times, "{}", secs(day2)).unwrap();
+        let big = (0..1_000_000u32)
+            .map(|i| b'a' + (i % 26) as u8)
+            .collect::<Vec<_>>();
+        stdin.write_all(&big).unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+
+        assert_eq!(fs::read(path1).unwrap(), b"a\n");
+        assert_eq!(fs::read(path2).unwrap(), big);
+    }
+
+    /// With `--prefix-relative`, each line starts with the time since rotee started, as of when
+    /// its first byte was read.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_fake_time_prefix_relative() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut times = unsafe { File::from_raw_fd(fds[1]) };
+        writeln!(times, "1717243200").unwrap(); // 2024-06-01T12:00:00Z.
+
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", fds[0].to_string())
+            .stdin(Stdio::piped())
+            .args(["-e", "--prefix-relative"])
+            .spawn()
+            .unwrap();
+        unsafe { libc::close(fds[0]) };
+        let mut stdin = child.stdin.take().unwrap();
+        let path = dir.path().join("rotee.0");
+        let wait_for = |len| {
+            while fs::metadata(&path).map(|m| m.len()).unwrap_or(0) < len {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        stdin.write_all(b"a\n").unwrap();
+        wait_for(10);
+        writeln!(times, "1717243212").unwrap();
+        stdin.write_all(b"b\nc").unwrap();
+        wait_for(31);
+        writeln!(times, "1717243275").unwrap();
+        stdin.write_all(b"d\n").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "+0.000s a\n+12.000s b\n+12.000s cd\n"
+        );
+    }
+
+    /// With `--header-line`, each file starts with a numbered header, which counts towards its
+    /// size.
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_fake_time_header_line() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut times = unsafe { File::from_raw_fd(fds[1]) };
+        writeln!(times, "1717243200").unwrap(); // 2024-06-01T12:00:00Z.
+        drop(times);
+
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", fds[0].to_string())
+            .stdin(Stdio::piped())
+            .args(["-e", "--header-line", "-s", "80", "-n", "3"])
+            .spawn()
+            .unwrap();
+        unsafe { libc::close(fds[0]) };
+        let line = [&[b'a'; 29][..], b"\n"].concat();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(&line.repeat(3))
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let header = |n| format!("# rotee file {} started 2024-06-01T12:00:00Z\n", n);
+        let input = line.repeat(3);
+        for (i, n, data) in [
+            (2, 1, &input[..36]),
+            (1, 2, &input[36..72]),
+            (0, 3, &input[72..]),
+        ] {
+            let f = fs::read(dir.path().join(format!("rotee.{}", i))).unwrap();
+            assert_eq!(f, [header(n).as_bytes(), data].concat());
+        }
+    }
+
+    /// A checkpoint is written each `--checkpoint-interval`, and when input ends, recording the
+    /// number of bytes written so far.
+    #[test]
+    fn test_fake_time_checkpoint() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut times = unsafe { File::from_raw_fd(fds[1]) };
+        let t0 = 1_717_243_200; // 2024-06-01T12:00:00Z.
+
+        writeln!(times, "{}", t0).unwrap();
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", fds[0].to_string())
+            .stdin(Stdio::piped())
+            .args(["-e", "-s", "3", "--checkpoint-file", "cp"])
+            .args(["--checkpoint-interval", "10s"])
+            .spawn()
+            .unwrap();
+        unsafe { libc::close(fds[0]) };
+        let mut stdin = child.stdin.take().unwrap();
+        let cp = dir.path().join("cp");
+        let wait_for = |s: &str| {
+            while fs::read_to_string(&cp).ok().as_deref() != Some(s) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        stdin.write_all(b"abcd\n").unwrap();
+        while fs::metadata(dir.path().join("rotee.0")).map_or(0, |m| m.len()) < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(!cp.exists());
+        writeln!(times, "{}", t0 + 10).unwrap();
+        wait_for("5\n");
+        stdin.write_all(b"ef").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+        wait_for("7\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+
+    /// Marks are written exactly when a `--mark` period passes without input, and never in the
+    /// middle of a line.
+    #[test]
+    fn test_fake_time_mark() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut times = unsafe { File::from_raw_fd(fds[1]) };
+        let t0 = 1_717_243_200; // 2024-06-01T12:00:00Z.
+
+        writeln!(times, "{}", t0).unwrap();
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", fds[0].to_string())
+            .stdin(Stdio::piped())
+            .args(["-e", "--mark", "10s"])
+            .spawn()
+            .unwrap();
+        unsafe { libc::close(fds[0]) };
+        let mut stdin = child.stdin.take().unwrap();
+        let path = dir.path().join("rotee.0");
+        let size = || fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let wait_for = |len: u64| {
+            while size() < len {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let mark_len = "-- MARK 2024-06-01T12:00:10Z --\n".len() as u64;
+
+        stdin.write_all(b"a\n").unwrap();
+        wait_for(2);
+        for (secs, len) in [
+            (5, 2),
+            (10, 2 + mark_len),
+            (15, 2 + mark_len),
+            (20, 2 + 2 * mark_len),
+        ] {
+            writeln!(times, "{}", t0 + secs).unwrap();
+            // Give rotee the chance to write a mark that isn't due.
+            thread::sleep(Duration::from_millis(50));
+            wait_for(len);
+            assert_eq!(size(), len);
+        }
+        stdin.write_all(b"b").unwrap();
+        wait_for(3 + 2 * mark_len);
+        writeln!(times, "{}", t0 + 35).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stdin.write_all(b"\n").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            concat!(
+                "a\n",
+                "-- MARK 2024-06-01T12:00:10Z --\n",
+                "-- MARK 2024-06-01T12:00:20Z --\n",
+                "b\n"
+            )
+        );
+    }
+
+    /// A `--done-file` left by an earlier run is removed before any input is read, so that it
+    /// doesn't survive a run that is killed.
+    #[test]
+    fn test_stale_done_file() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let done = dir.path().join("done");
+        fs::write(&done, "").unwrap();
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "--done-file", "done"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"a\n").unwrap();
+        let path = dir.path().join("rotee.0");
+        while fs::metadata(&path).map(|m| m.len()).unwrap_or(0) < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!done.exists());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!done.exists());
+        drop(stdin);
+    }
+
+    /// `--max-runtime` stops rotee (successfully, but without a `--done-file`) even while no input
+    /// arrives.
+    #[test]
+    fn test_fake_time_max_runtime() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut times = unsafe { File::from_raw_fd(fds[1]) };
+        let t0 = 1_717_243_200;
+
+        writeln!(times, "{}", t0).unwrap();
+        let mut child = Command::new(p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", fds[0].to_string())
+            .stdin(Stdio::piped())
+            .args(["-e", "--max-runtime", "10s", "--done-file", "done"])
+            .spawn()
+            .unwrap();
+        unsafe { libc::close(fds[0]) };
+        let mut stdin = child.stdin.take().unwrap();
+        let path = dir.path().join("rotee.0");
+        stdin.write_all(b"a\n").unwrap();
+        while fs::metadata(&path).map(|m| m.len()).unwrap_or(0) < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        writeln!(times, "{}", t0 + 9).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(child.try_wait().unwrap().is_none());
+        writeln!(times, "{}", t0 + 10).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(fs::read_to_string(path).unwrap(), "a\n");
+        assert!(!dir.path().join("done").exists());
+        drop(stdin);
+    }
+
+    /// Expanding the summaries of `--squeeze-repeats` recovers the input exactly, and no file
+    /// starts with a summary (which would refer to a line in another file).
+    #[test]
+    fn test_squeeze_repeats() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut input = Vec::new();
+        for _ in 0..200 {
+            let line = format!("line {}\n", rng.gen_range(0..3));
+            for _ in 0..rng.gen_range(1..20) {
+                input.extend(line.as_bytes());
+            }
+        }
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "-l", "--squeeze-repeats", "-s", "100", "-n", "1000"])
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&input).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let mut got = String::new();
+        for i in (0..1000).rev() {
+            let Ok(d) = fs::read_to_string(dir.path().join(format!("rotee.{}", i))) else {
+                continue;
+            };
+            let mut prev: Option<&str> = None;
+            for l in d.split_inclusive('\n') {
+                match l.strip_prefix("-- previous line repeated ") {
+                    Some(rest) => {
+                        let n = rest.split(' ').next().unwrap().parse().unwrap();
+                        got.push_str(&prev.unwrap().repeat(n));
+                    }
+                    None => {
+                        got.push_str(l);
+                        prev = Some(l);
+                    }
+                }
+            }
+        }
+        assert_eq!(got.as_bytes(), input);
+    }
+
+    /// Re-running rotee over an existing set truncates the newest file, unless
+    /// `--rotate-at-start` is given, when it is rotated instead.
+    #[test]
+    fn test_restart() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        for (args, rotated) in [(&[][..], None), (&["--rotate-at-start"], Some(&b"12"[..]))] {
+            let dir = TempDir::new().unwrap();
+            for input in [&b"12"[..], b"34"] {
+                let mut child = Command::new(&p)
+                    .current_dir(dir.path())
+                    .stdin(Stdio::piped())
+                    .args(["-e", "-s", "4"])
+                    .args(args)
+                    .spawn()
+                    .unwrap();
+                child.stdin.take().unwrap().write_all(input).unwrap();
+                assert!(child.wait().unwrap().success());
+            }
+            assert_eq!(
+                fs::read(dir.path().join("rotee.1")).ok().as_deref(),
+                rotated
+            );
+            assert_eq!(fs::read(dir.path().join("rotee.0")).unwrap(), b"34");
+        }
+    }
+
+    /// `ROTEE_NO_CONFIG` keeps the user's configuration file from being read.
+    #[test]
+    fn test_no_config() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        for (no_config, rotated) in [("", true), ("1", false)] {
+            let dir = TempDir::new().unwrap();
+            fs::create_dir(dir.path().join(".config")).unwrap();
+            fs::write(dir.path().join(".config/rotee.conf"), "file-size = 2\n").unwrap();
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .env("HOME", dir.path())
+                .env("ROTEE_NO_CONFIG", no_config)
+                .env_remove("ROTEE_DEFAULT_ARGS")
+                .stdin(Stdio::piped())
+                .arg("-e")
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(b"abcd").unwrap();
+            assert!(child.wait().unwrap().success());
+            assert_eq!(dir.path().join("rotee.1").exists(), rotated);
+        }
+    }
+
+    /// A flag set in a configuration file can be turned off on the command line.
+    #[test]
+    fn test_conf_negation() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        for (args, echoed) in [(&[][..], &b""[..]), (&["--echo"][..], &b"abcd"[..])] {
+            let dir = TempDir::new().unwrap();
+            fs::create_dir(dir.path().join(".config")).unwrap();
+            fs::write(dir.path().join(".config/rotee.conf"), "no-echo\n").unwrap();
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .env("HOME", dir.path())
+                .env_remove("ROTEE_NO_CONFIG")
+                .env_remove("ROTEE_DEFAULT_ARGS")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .args(args)
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(b"abcd").unwrap();
+            let out = child.wait_with_output().unwrap();
+            assert!(out.status.success());
+            assert_eq!(out.stdout, echoed);
+            assert_eq!(fs::read(dir.path().join("rotee.0")).unwrap(), b"abcd");
+        }
+    }
+
+    /// Staged files only appear under the live prefix, all together, when committed.
+    #[test]
+    fn test_commit() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read(dir.path().join(name)).ok();
+        // Wait for `name` to contain `expect`.
+        let wait = |name: &str, expect: &[u8]| {
+            while read(name).as_deref() != Some(expect) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "-s", "2", "--staging-prefix", "staging."])
+            .args(["--commit-signal", "USR1"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        // An old live file that won't be part of the committed set.
+        fs::write(dir.path().join("rotee.5"), b"old").unwrap();
+
+        stdin.write_all(b"abcde").unwrap();
+        wait("staging.0", b"e");
+        assert_eq!(read("rotee.0"), None);
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR1) };
+        // A new staging file is only created once the commit is complete.
+        wait("staging.0", b"");
+        assert_eq!(read("rotee.0").unwrap(), b"e");
+        assert_eq!(read("rotee.1").unwrap(), b"cd");
+        assert_eq!(read("rotee.2").unwrap(), b"ab");
+        assert_eq!(read("rotee.5"), None);
+        assert_eq!(read("staging.1"), None);
+
+        assert_eq!(read("rotee.commit"), None);
+
+        // Nothing has been staged since the last commit, so there's nothing to commit.
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR1) };
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(read("rotee.1").unwrap(), b"cd");
+
+        // A smaller staged set still replaces the whole of the earlier one.
+        stdin.write_all(b"f").unwrap();
+        wait("staging.0", b"f");
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR1) };
+        wait("staging.0", b"");
+        assert_eq!(read("rotee.0").unwrap(), b"f");
+        assert_eq!(read("rotee.1"), None);
+        assert_eq!(read("rotee.2"), None);
+
+        // Uncommitted data stays staged.
+        stdin.write_all(b"g").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read("staging.0").unwrap(), b"g");
+        assert_eq!(read("rotee.0").unwrap(), b"f");
+    }
+
+    /// `--profile-signal` switches the file size and echo to the alternate profile's, and back,
+    /// each time from the next file on.
+    #[test]
+    fn test_profile_signal() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read(dir.path().join(name)).ok();
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .args([
+                "-s",
+                "10",
+                "-n",
+                "10",
+                "--alt-file-size",
+                "4",
+                "--alt-echo",
+                "off",
+            ])
+            .spawn()
+            .unwrap();
+        // rotee's state (e.g. `S` when sleeping), failing if it has exited, rather than waiting
+        // for it forever.
+        let stat = format!("/proc/{}/stat", child.id());
+        let state = || {
+            let s = fs::read_to_string(&stat).unwrap();
+            let state = s
+                .rsplit(')')
+                .next()
+                .unwrap()
+                .split(' ')
+                .nth(1)
+                .unwrap()
+                .to_owned();
+            assert_ne!(state, "Z", "rotee exited early");
+            state
+        };
+        // Wait for `name` to contain `expect`.
+        let wait = |name: &str, expect: &[u8]| {
+            while read(name).as_deref() != Some(expect) {
+                state();
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        // Wait for rotee to block reading its input again. A switch only takes effect when a
+        // file is started, which happens after the rotation that is waited for is visible.
+        let idle = || {
+            while state() != "S" {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"aaaaaaaaaa").unwrap();
+        wait("rotee.1", b"aaaaaaaaaa");
+        idle();
+        // The active file was started before the switch, so it keeps the normal profile.
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR2) };
+        stdin.write_all(b"bbbbbbbbbbcccccccc").unwrap();
+        wait("rotee.4", b"aaaaaaaaaa");
+        idle();
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR2) };
+        stdin.write_all(b"ddddeeeeee").unwrap();
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+
+        assert_eq!(out.stdout, b"aaaaaaaaaabbbbbbbbbbeeeeee");
+        for (name, expect) in [
+            ("rotee.5", &b"aaaaaaaaaa"[..]),
+            ("rotee.4", b"bbbbbbbbbb"),
+            ("rotee.3", b"cccc"),
+            ("rotee.2", b"cccc"),
+            ("rotee.1", b"dddd"),
+            ("rotee.0", b"eeeeee"),
+        ] {
+            assert_eq!(read(name).unwrap(), expect, "{}", name);
+        }
+    }
+
+    /// With `--stat-size`, rotation goes by the active file's size on disk, even after something
+    /// else truncates or appends to it.
+    #[test]
+    fn test_stat_size() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read(dir.path().join(name)).ok();
+        // Wait for `name` to contain `expect`.
+        let wait = |name: &str, expect: &[u8]| {
+            while read(name).as_deref() != Some(expect) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let active = || {
+            OpenOptions::new()
+                .append(true)
+                .open(dir.path().join("rotee.0"))
+                .unwrap()
+        };
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .args(["-e", "-s", "10", "--stat-size"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"aaaaaa").unwrap();
+        wait("rotee.0", b"aaaaaa");
+        active().set_len(0).unwrap();
+        stdin.write_all(b"bbbbbbbb").unwrap();
+        wait("rotee.0", b"bbbbbbbb");
+        stdin.write_all(b"cccc").unwrap();
+        wait("rotee.0", b"cc");
+        active().write_all(b"XXXXXXXXXX").unwrap();
+        stdin.write_all(b"dd").unwrap();
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+
+        assert_eq!(read("rotee.2").unwrap(), b"bbbbbbbbcc");
+        assert_eq!(read("rotee.1").unwrap(), b"ccXXXXXXXXXX");
+        assert_eq!(read("rotee.0").unwrap(), b"dd");
+        assert_eq!(read("rotee.3"), None);
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("rotee.0 has 0 bytes, not the 6 written to it"));
+        assert!(stderr.contains("rotee.0 has 12 bytes, not the 2 written to it"));
+    }
+
+    /// With `--buffer-files`, files only ever appear on disk whole.
+    #[test]
+    fn test_buffer_files() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read(dir.path().join(name)).ok();
+        // Check that every file on disk is full, returning how many there are.
+        let check = || {
+            let mut n = 0;
+            for e in fs::read_dir(dir.path()).unwrap() {
+                let e = e.unwrap();
+                if !e.file_name().to_str().unwrap().starts_with('.') {
+                    assert_eq!(e.metadata().unwrap().len(), 10);
+                    n += 1;
+                }
+            }
+            n
+        };
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "-s", "10", "-n", "20", "--buffer-files"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let input = (0..95).map(|i| b'a' + i % 26).collect::<Vec<_>>();
+        for (i, c) in input.chunks(3).enumerate() {
+            stdin.write_all(c).unwrap();
+            stdin.flush().unwrap();
+            // Everything up to the last full file is written by the time it appears.
+            let full = (3 * i + c.len()) / 10;
+            while check() < full {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(check(), full);
+        }
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+
+        let mut files = Vec::new();
+        for i in (0..10).rev() {
+            files.extend(read(&format!("rotee.{}", i)).unwrap());
+        }
+        assert_eq!(files, input);
+        assert_eq!(read("rotee.0").unwrap().len(), 5);
+        assert_eq!(read("rotee.10"), None);
+    }
+
+    /// With `--max-write-errors`, failed writes are warned about and skipped, up to the limit.
+    #[test]
+    fn test_max_write_errors() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let run = |args: &[&str]| {
+            let dir = TempDir::new().unwrap();
+            let mut cmd = Command::new(&p);
+            cmd.current_dir(dir.path())
+                .args(["-e", "-s", "1500", "-n", "10", "-b", "256"])
+                .args(args)
+                .stdin(Stdio::piped())
+                .stderr(Stdio::piped());
+            // Writes that would take a file beyond 1024 bytes fail (with `EFBIG`).
+            unsafe {
+                cmd.pre_exec(|| {
+                    let lim = libc::rlimit {
+                        rlim_cur: 1024,
+                        rlim_max: 1024,
+                    };
+                    if libc::signal(libc::SIGXFSZ, libc::SIG_IGN) == libc::SIG_ERR
+                        || libc::setrlimit(libc::RLIMIT_FSIZE, &lim) == -1
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            let mut child = cmd.spawn().unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(&[b'x'; 3000])
+                .unwrap();
+            let out = child.wait_with_output().unwrap();
+            let sizes = (0..3)
+                .map(|i| fs::metadata(dir.path().join(format!("rotee.{}", i))).map(|m| m.len()))
+                .collect::<Vec<_>>();
+            (out, sizes)
+        };
+
+        let (out, _) = run(&[]);
+        assert!(!out.status.success());
+        let (out, _) = run(&["--max-write-errors", "1"]);
+        assert!(!out.status.success());
+        assert!(String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("(more than --max-write-errors 1 writes have failed)"));
+
+        let (out, sizes) = run(&["--max-write-errors", "100"]);
+        assert!(out.status.success());
+        // Skipped bytes don't count towards the file size, so the active file never fills up.
+        assert_eq!(sizes[0].as_ref().unwrap(), &1024);
+        assert!(sizes[1].is_err());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let errors = stderr
+            .lines()
+            .filter(|l| l.contains("File too large"))
+            .count();
+        assert!(errors >= 2);
+        assert!(stderr.ends_with(&format!(
+            "warning: {} writes to the output files failed, so the output is incomplete\n",
+            errors
+        )));
+    }
+
+    /// `--split-every` with NUL-delimited records.
+    #[test]
+    fn test_split_every_nul() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "0", "--split-every", "2", "--split-byte", "\\0"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"a\0b\nb\0c\0\0d")
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let read = |i| fs::read(dir.path().join(format!("rotee.{}", i))).unwrap();
+        assert_eq!(read(2), b"a\0b\nb\0");
+        assert_eq!(read(1), b"c\0\0");
+        assert_eq!(read(0), b"d");
+    }
+
+    #[test]
+    fn test_seal_readonly() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let run = |args: &[&str], input: &[u8]| {
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            assert!(child.wait().unwrap().success());
+        };
+        let writable = |i: usize| {
+            fs::metadata(dir.path().join(format!("rotee.{}", i)))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o222
+                != 0
+        };
+
+        run(&["-s", "4", "-n", "4", "--seal-readonly"], b"0123456789");
+        assert!(writable(0) && !writable(1) && !writable(2));
+        // A second run rotates the leftover active file, and renames (and removes) sealed files.
+        run(
+            &["-s", "4", "-n", "4", "--seal-readonly", "--rotate-at-start"],
+            b"abcdef",
+        );
+        let contents = (0..4)
+            .map(|i| fs::read_to_string(dir.path().join(format!("rotee.{}", i))).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["ef", "abcd", "89", "4567"]);
+        assert!(writable(0) && (1..4).all(|i| !writable(i)));
+        assert!(!dir.path().join("rotee.4").exists());
+        // Sealed files can be pruned.
+        run(&["prune", "-n", "2"], b"");
+        assert!(dir.path().join("rotee.1").exists() && !dir.path().join("rotee.2").exists());
+    }
+
+    /// With `--xattrs`, rotated files carry extended attributes describing them.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_xattrs() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let getxattr = |i: usize, name: &str| {
+            let path = CString::new(
+                dir.path()
+                    .join(format!("rotee.{}", i))
+                    .as_os_str()
+                    .as_bytes(),
+            )
+            .unwrap();
+            let name = CString::new(name).unwrap();
+            let mut buf = [0u8; 64];
+            let n = unsafe {
+                libc::getxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                )
+            };
+            (n >= 0).then(|| String::from_utf8(buf[..n as usize].to_vec()).unwrap())
+        };
+        // Not every file system that a temporary directory might be on supports user extended
+        // attributes.
+        let probe = dir.path().join("probe");
+        fs::write(&probe, "").unwrap();
+        let (pc, name) = (
+            CString::new(probe.as_os_str().as_bytes()).unwrap(),
+            CString::new("user.probe").unwrap(),
+        );
+        if unsafe { libc::setxattr(pc.as_ptr(), name.as_ptr(), b"1".as_ptr().cast(), 1, 0) } != 0 {
+            return;
+        }
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-s", "4", "--xattrs", "--seal-readonly"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"0123456789")
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let before = SystemTime::now();
+        for (i, sequence, first_offset, contents) in [(2, "1", "0", "0123"), (1, "2", "4", "4567")]
+        {
+            assert_eq!(getxattr(i, "user.rotee.sequence").unwrap(), sequence);
+            assert_eq!(
+                getxattr(i, "user.rotee.first_offset").unwrap(),
+                first_offset
+            );
+            let mut crc = Crc32::default();
+            crc.update(contents.as_bytes());
+            assert_eq!(
+                getxattr(i, "user.rotee.checksum").unwrap(),
+                format!("{:08x}", crc.digest())
+            );
+            let ts = getxattr(i, "user.rotee.start_ts").unwrap();
+            assert!(parse_timestamp(&ts).is_some_and(|t| t <= before));
+        }
+        // The active file hasn't been rotated, so isn't stamped.
+        assert_eq!(getxattr(0, "user.rotee.sequence"), None);
+    }
+
+    /// With `--recreate-dir`, an output directory that disappears is recreated at the next
+    /// rotation; without it, rotee fails.
+    #[test]
+    fn test_recreate_dir() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let run = |args: &[&str]| {
+            fs::create_dir(&out).unwrap();
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["-e", "-s", "4", "-p", "out/rotee."])
+                .args(args)
+                .stdin(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b"01").unwrap();
+            while fs::metadata(out.join("rotee.0")).map_or(true, |m| m.len() < 2) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            fs::remove_dir_all(&out).unwrap();
+            // What was written to the file that has gone is lost, but rotating starts afresh.
+            let _ = stdin.write_all(b"2345");
+            drop(stdin);
+            child.wait_with_output().unwrap()
+        };
+
+        let o = run(&["--recreate-dir"]);
+        assert!(o.status.success());
+        assert!(String::from_utf8(o.stderr)
+            .unwrap()
+            .contains("warning: out has gone: recreating it"));
+        assert_eq!(fs::read(out.join("rotee.0")).unwrap(), b"45");
+        assert!(!out.join("rotee.1").exists());
+
+        fs::remove_dir_all(&out).unwrap();
+        let o = run(&[]);
+        assert!(!o.status.success());
+        assert!(!out.exists());
+    }
+
+    /// With `--active-marker`, the marker names the active file, by number, from one rotation to
+    /// the next, and says that none is active once rotee exits.
+    #[test]
+    fn test_active_marker() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).ok();
+        // Wait for `name` to contain `expect`.
+        let wait = |name: &str, expect: &str| {
+            while read(name).as_deref() != Some(expect) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "-s", "4", "--active-marker"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        wait("rotee.active", "rotee.0 1\n");
+        stdin.write_all(b"01234").unwrap();
+        wait("rotee.0", "4");
+        assert_eq!(read("rotee.active").unwrap(), "rotee.0 2\n");
+        stdin.write_all(b"56789").unwrap();
+        wait("rotee.0", "89");
+        assert_eq!(read("rotee.active").unwrap(), "rotee.0 3\n");
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read("rotee.active").unwrap(), "none\n");
+        // The marker isn't part of the series.
+        let out = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["ls"])
+            .output()
+            .unwrap();
+        assert!(!String::from_utf8(out.stdout)
+            .unwrap()
+            .contains("rotee.active"));
+    }
+
+    #[test]
+    fn test_write_timeout() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        // The active file is a FIFO, so writes to it stall until the test reads from it.
+        let run = |action: &str, drain: bool| {
+            let dir = TempDir::new().unwrap();
+            let fifo = CString::new(dir.path().join("rotee.0").as_os_str().as_bytes()).unwrap();
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+            let mut reader = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(dir.path().join("rotee.0"))
+                .unwrap();
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["-e", "--write-timeout", "100ms"])
+                .args(["--write-timeout-action", action])
+                .stdin(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut stdin = child.stdin.take().unwrap();
+            let writer = thread::spawn(move || {
+                let _ = stdin.write_all(&[b'x'; 100000]);
+            });
+            thread::sleep(Duration::from_millis(500));
+            let mut n = 0;
+            while drain && n < 100000 {
+                match reader.read(&mut [0; 65536]) {
+                    Ok(r) => n += r,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            writer.join().unwrap();
+            let out = child.wait_with_output().unwrap();
+            (out.status.success(), String::from_utf8(out.stderr).unwrap())
+        };
+
+        let (ok, stderr) = run("warn", true);
+        assert!(ok, "{}", stderr);
+        assert!(stderr.contains("warning: writing to rotee.0 has taken more than 100ms\n"));
+        assert!(stderr.contains("warning: writing to rotee.0 completed after "));
+        let (ok, stderr) = run("abort", false);
+        assert!(!ok);
+        assert_eq!(
+            stderr,
+            "error: writing to rotee.0 has taken more than 100ms: giving up\n"
+        );
+    }
+
+    /// With `--verify`, a file that doesn't read back as what was written to it fails rotation.
+    #[test]
+    fn test_verify() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read(dir.path().join(name)).ok();
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .args(["-e", "-s", "10", "--verify"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"0123456789abcd").unwrap();
+        while read("rotee.0").as_deref() != Some(b"abcd") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(read("rotee.1").unwrap(), b"0123456789");
+        // Corrupt the active file without changing its size.
+        let f = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("rotee.0"))
+            .unwrap();
+        f.write_all_at(b"X", 1).unwrap();
+        stdin.write_all(b"efghij").unwrap();
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        assert!(!out.status.success());
+
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains(
+            "rotee.0 failed verification: what was written has CRC-32 3981703a, but what was \
+             read back has"
+        ));
+        // The corrupt file isn't rotated.
+        assert_eq!(read("rotee.0").unwrap(), b"aXcdefghij");
+        assert_eq!(read("rotee.1").unwrap(), b"0123456789");
+    }
+
+    /// Temporary files left by earlier runs that have gone are removed at startup, unless
+    /// `--keep-temps` is given.
+    #[test]
+    fn test_stale_temps() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        // No process has a pid this large.
+        let stale = dir.path().join(".rotee.0.tmp.4194305.7");
+        let live = dir
+            .path()
+            .join(format!(".rotee.0.tmp.{}.0", std::process::id()));
+        let run = |args: &[&str]| {
+            fs::write(&stale, "x").unwrap();
+            fs::write(&live, "x").unwrap();
+            let st = Command::new(&p)
+                .current_dir(dir.path())
+                .stdin(Stdio::null())
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(st.success());
+        };
+        run(&["-e", "--keep-temps"]);
+        assert!(stale.exists() && live.exists());
+        run(&["-e"]);
+        assert!(!stale.exists() && live.exists());
+    }
+
+    /// With `--echo-overflow drop`, a stalled echo consumer doesn't hold up the files: the echo
+    /// drops what doesn't fit in its queue, and says so when the consumer catches up.
+    #[test]
+    fn test_echo_overflow_drop() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        // A file that is renamed while this looks at it is missed, and counted next time.
+        let files_len = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter_map(|e| e.unwrap().metadata().ok())
+                .map(|m| m.len())
+                .sum::<u64>()
+        };
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .args(["-s", "10000", "-n", "100"])
+            .args(["--echo-queue-size", "4096", "--echo-overflow", "drop"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        // Nothing reads the echo until the files have everything.
+        let input = (0..500_000)
+            .map(|i| b'a' + (i % 26) as u8)
+            .collect::<Vec<_>>();
+        stdin.write_all(&input).unwrap();
+        while files_len() < 500_000 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match stdout.read(&mut buf).unwrap() {
+                    0 => return,
+                    n => tx.send(buf[..n].to_vec()).unwrap(),
+                }
+            }
+        });
+        // Once the queue has drained, the echo takes everything again.
+        let mut echoed = Vec::new();
+        let mut extra = 0;
+        while !echoed.ends_with(b"\n") {
+            stdin.write_all(b"\n").unwrap();
+            extra += 1;
+            while let Ok(b) = rx.recv_timeout(Duration::from_millis(10)) {
+                echoed.extend(b);
+            }
+        }
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        reader.join().unwrap();
+        echoed.extend(rx.try_iter().flatten());
+        assert!(out.status.success());
+
+        assert_eq!(files_len(), 500_000 + extra);
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("echo isn't keeping up: dropping output"));
+        assert!(stderr.contains("echo has resumed"));
+        let dropped = stderr
+            .lines()
+            .find_map(|l| {
+                l.strip_prefix("warning: ")?
+                    .strip_suffix(" bytes were dropped from the echo")
+            })
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert!(dropped > 0);
+        assert_eq!(echoed.len() as u64 + dropped, 500_000 + extra);
+    }
+
+    /// With `--min-size`, a requested commit waits until the active file is big enough.
+    #[test]
+    fn test_commit_min_size() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read(dir.path().join(name)).ok();
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "--staging-prefix", "staging.", "--min-size", "4"])
+            .args(["--commit-signal", "USR1"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"ab").unwrap();
+        while read("staging.0").as_deref() != Some(b"ab") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR1) };
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(read("rotee.0"), None);
+        stdin.write_all(b"cd").unwrap();
+        while read("rotee.0").as_deref() != Some(b"abcd") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        stdin.write_all(b"e").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+        assert_eq!(read("staging.0").unwrap(), b"e");
+    }
+
+    /// With `--rotate-empty`, a commit with nothing staged leaves an empty live set.
+    #[test]
+    fn test_commit_empty() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let read = |name: &str| fs::read(dir.path().join(name)).ok();
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "--staging-prefix", "staging.", "--rotate-empty"])
+            .args(["--commit-signal", "USR1"])
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        fs::write(dir.path().join("rotee.0"), b"old").unwrap();
+        while read("staging.0").is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR1) };
+        while read("rotee.0").as_deref() != Some(b"") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+    }
+
+    /// `--max-age` removes exactly the rotated files that are too old, whatever their position in
+    /// the series, and never the active file.
+    #[test]
+    fn test_max_age() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let old = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        let recent = SystemTime::now() - Duration::from_secs(60 * 60);
+        for (i, (data, mtime)) in [("a", old), ("b", recent), ("c", old), ("d", recent)]
+            .into_iter()
+            .enumerate()
+        {
+            let f = File::create(dir.path().join(format!("rotee.{}", i))).unwrap();
+            (&f).write_all(data.as_bytes()).unwrap();
+            f.set_modified(mtime).unwrap();
+        }
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "--max-age", "1d", "--rotate-at-start"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"x").unwrap();
+        assert!(child.wait().unwrap().success());
+
+        // The old newest file ("a") was rotated, and so is subject to `--max-age` too.
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["rotee.0", "rotee.2", "rotee.4"]);
+        assert_eq!(fs::read(dir.path().join("rotee.0")).unwrap(), b"x");
+        assert_eq!(fs::read(dir.path().join("rotee.2")).unwrap(), b"b");
+        assert_eq!(fs::read(dir.path().join("rotee.4")).unwrap(), b"d");
+    }
+
+    /// Without `--thin`, the oldest file still leaves the series on the rotation that takes the
+    /// series beyond `-n` files, even when `--max-age` has left a gap in front of it.
+    #[test]
+    fn test_max_age_gap() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let old = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        let recent = SystemTime::now() - Duration::from_secs(60 * 60);
+        for (i, data, mtime) in [(1, "b", recent), (2, "c", old), (3, "d", recent)] {
+            let f = File::create(dir.path().join(format!("rotee.{}", i))).unwrap();
+            (&f).write_all(data.as_bytes()).unwrap();
+            f.set_modified(mtime).unwrap();
+        }
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "2", "-n", "4", "--max-age", "1d"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"xxy").unwrap();
+        assert!(child.wait().unwrap().success());
+
+        // "c" was too old, and "d" dropped off the end rather than staying behind the gap.
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["rotee.0", "rotee.1", "rotee.2"]);
+        assert_eq!(fs::read(dir.path().join("rotee.0")).unwrap(), b"y");
+        assert_eq!(fs::read(dir.path().join("rotee.1")).unwrap(), b"xx");
+        assert_eq!(fs::read(dir.path().join("rotee.2")).unwrap(), b"b");
+    }
+
+    /// With `--encrypt-recipient`, rotated files are only kept encrypted (and decrypt to what was
+    /// written), while the active file is plaintext.
+    #[cfg(feature = "encrypt")]
+    #[test]
+    fn test_encrypt() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let id = age::x25519::Identity::generate();
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "4", "-n", "3"])
+            .args(["--encrypt-recipient", &id.to_public().to_string()])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"abcdefghij")
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["rotee.0", "rotee.1.age", "rotee.2.age"]);
+        assert_eq!(fs::read(dir.path().join("rotee.0")).unwrap(), b"ij");
+        for (i, expected) in [(1, b"efgh"), (2, b"abcd")] {
+            let path = dir.path().join(format!("rotee.{}.age", i));
+            assert_eq!(decrypt_file(&id, &path), expected);
+        }
+    }
+
+    /// With `--encrypt-recipient`, a plaintext file 1 left by a run that was killed while
+    /// encrypting it is encrypted at startup, before anything is rotated onto it.
+    #[cfg(feature = "encrypt")]
+    #[test]
+    fn test_encrypt_interrupted() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("rotee.1"), "old").unwrap();
+        let id = age::x25519::Identity::generate();
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "4", "-n", "3"])
+            .args(["--encrypt-recipient", &id.to_public().to_string()])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"abcdef").unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["rotee.0", "rotee.1.age", "rotee.2.age"]);
+        assert_eq!(fs::read(dir.path().join("rotee.0")).unwrap(), b"ef");
+        assert_eq!(decrypt_file(&id, &dir.path().join("rotee.1.age")), b"abcd");
+        assert_eq!(decrypt_file(&id, &dir.path().join("rotee.2.age")), b"old");
+    }
+
+    /// Decrypt the age-encrypted file at `path` with `id`.
+    #[cfg(feature = "encrypt")]
+    fn decrypt_file(id: &age::x25519::Identity, path: &std::path::Path) -> Vec<u8> {
+        let f = File::open(path).unwrap();
+        let age::Decryptor::Recipients(d) = age::Decryptor::new(f).unwrap() else {
+            panic!()
+        };
+        let mut plain = Vec::new();
+        d.decrypt(std::iter::once(id as &dyn age::Identity))
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        plain
+    }
+
+    /// With `--setuid` and `--setgid`, rotee refuses to start if the new user can't create files
+    /// in the output directory, and otherwise creates later files as that user. This can only be
+    /// tested when running as root.
+    #[test]
+    fn test_setuid() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let run = || {
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["-e", "-s", "2", "--setuid", "65534", "--setgid", "65534"])
+                .stdin(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            // rotee may already have refused to start, and closed stdin.
+            let _ = child.stdin.take().unwrap().write_all(b"abc");
+            child.wait_with_output().unwrap()
+        };
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        let out = run();
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .starts_with("error: after --setuid/--setgid, can't create files in ."));
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(run().status.success());
+        // The first file was created as root, but the file created by the rotation wasn't.
+        let owner = |i| {
+            fs::metadata(dir.path().join(format!("rotee.{}", i)))
+                .unwrap()
+                .uid()
+        };
+        assert_eq!(owner(1), 0);
+        assert_eq!(owner(0), 65534);
+    }
+
+    /// `--single-file` appends to what is already in the file, which counts towards `--warn-size`.
+    #[test]
+    fn test_single_file_append() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("out.log");
+        fs::write(&path, b"old\n").unwrap();
+        for (input, warn) in [("a\n", false), ("b\n", true)] {
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["-e", "--strict-writes", "--single-file", "out.log"])
+                .args(["--warn-size", "6"])
+                .stdin(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            let out = child.wait_with_output().unwrap();
+            assert!(out.status.success());
+            assert_eq!(!out.stderr.is_empty(), warn);
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"old\na\nb\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    /// With `--log-file`, warnings are appended to the log file, timestamped, rather than written
+    /// to stderr. If the log file can't be opened, they go to stderr.
+    #[test]
+    fn test_log_file() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let run = |log| {
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["--single-file", "out.log", "--warn-size", "1"])
+                .args(["--log-file", log])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(b"ab").unwrap();
+            let out = child.wait_with_output().unwrap();
+            assert!(out.status.success());
+            String::from_utf8(out.stderr).unwrap()
+        };
+        let warning = "warning: out.log has grown beyond --warn-size (1 bytes)";
+
+        assert_eq!(run("rotee.log"), "");
+        assert_eq!(run("rotee.log"), "");
+        let log = fs::read_to_string(dir.path().join("rotee.log")).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        for l in lines {
+            let (time, msg) = l.split_once(' ').unwrap();
+            assert!(parse_timestamp(time).is_some());
+            assert_eq!(msg, warning);
+        }
+
+        let stderr = run("missing/rotee.log");
+        assert!(stderr.starts_with("warning: can't open --log-file 'missing/rotee.log'"));
+        assert!(stderr.ends_with(&format!("{}\n", warning)));
+    }
+
+    /// In exec mode, terminating rotee with a signal that isn't forwarded stops the capture,
+    /// terminates the command, and exits with the status of the signal received, without marking
+    /// the capture done.
+    #[test]
+    fn test_exec_terminate() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let outfile0 = dir.path().join("rotee.0");
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args([
+                "-e",
+                "-d",
+                "done",
+                "--forward-signals",
+                "INT",
+                "--",
+                "sh",
+                "-c",
+                "echo a; exec sleep 100",
+            ])
+            .spawn()
+            .unwrap();
+        while fs::read(&outfile0).ok().as_deref() != Some(b"a\n") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGTERM) };
+        assert_eq!(child.wait().unwrap().code(), Some(128 + SIGTERM));
+        assert!(!dir.path().join("done").exists());
+    }
+
+    /// In exec mode, `SIGTERM` is forwarded to the command by default, and capture continues
+    /// until the command has shut down, promptly, after which rotee exits with its status.
+    #[test]
+    fn test_exec_forward() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let outfile0 = dir.path().join("rotee.0");
+        let script = "trap 'echo got TERM; exit 0' TERM; echo ready; while :; do sleep 0.01; done";
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-d", "done", "--", "sh", "-c", script])
+            .spawn()
+            .unwrap();
+        while fs::read(&outfile0).ok().as_deref() != Some(b"ready\n") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let before = Instant::now();
+        unsafe { kill(i32::try_from(child.id()).unwrap(), SIGTERM) };
+        assert_eq!(child.wait().unwrap().code(), Some(0));
+        assert!(before.elapsed() < Duration::from_secs(5));
+        assert_eq!(fs::read(&outfile0).unwrap(), b"ready\ngot TERM\n");
+        assert!(!dir.path().join("done").exists());
+    }
+
+    #[test]
+    fn test_parse_restart() {
+        assert_eq!(parse_restart("no"), Ok(0));
+        assert_eq!(parse_restart("on-failure"), Ok(usize::MAX));
+        assert_eq!(parse_restart("on-failure:3"), Ok(3));
+        assert!(parse_restart("on-failure:").is_err());
+        assert!(parse_restart("always").is_err());
+        assert_eq!(restart_backoff(1), Duration::from_millis(100));
+        assert_eq!(restart_backoff(2), Duration::from_millis(200));
+        assert_eq!(restart_backoff(100), Duration::from_secs(10));
+    }
+
+    /// With `--stderr-prefix`, a command writing heavily to both stdout and stderr has all of each
+    /// stream captured, in order, in its own series.
+    #[test]
+    fn test_exec_stderr() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let script = "i=0; while [ $i -lt 2000 ]; do echo o$i; echo e$i >&2; i=$((i+1)); done";
+        let status = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "1000", "-n", "100", "--stderr-prefix", "err."])
+            .args(["--", "sh", "-c", script])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        for (prefix, c) in [("rotee.", 'o'), ("err.", 'e')] {
+            let mut got = Vec::new();
+            for i in (0..100).rev() {
+                if let Ok(d) = fs::read(dir.path().join(format!("{}{}", prefix, i))) {
+                    got.extend(d);
+                }
+            }
+            let expect = (0..2000)
+                .map(|i| format!("{}{}\n", c, i))
+                .collect::<String>();
+            assert_eq!(String::from_utf8(got).unwrap(), expect);
+        }
+    }
+
+    /// With `--stderr-fd`, what is read from stdin and from the fd is captured, in order, each into
+    /// its own series.
+    #[test]
+    fn test_stderr_fd() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        // Close-on-exec, so that no other test's child keeps the write end open.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let (r, mut w) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let mut cmd = Command::new(&p);
+        cmd.current_dir(dir.path())
+            .args(["-e", "-s", "1000", "-n", "100", "--stderr-prefix", "err."])
+            .args(["--stderr-fd", "3"])
+            .stdin(Stdio::piped());
+        unsafe {
+            cmd.pre_exec(move || {
+                // `dup2` leaves close-on-exec set if the pipe is already fd 3.
+                if libc::dup2(r.as_raw_fd(), 3) == -1 || libc::fcntl(3, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn().unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        for i in 0..2000 {
+            writeln!(stdin, "o{}", i).unwrap();
+            writeln!(w, "e{}", i).unwrap();
+        }
+        drop(stdin);
+        drop(w);
+        assert!(child.wait().unwrap().success());
+        for (prefix, c) in [("rotee.", 'o'), ("err.", 'e')] {
+            let mut got = Vec::new();
+            for i in (0..100).rev() {
+                if let Ok(d) = fs::read(dir.path().join(format!("{}{}", prefix, i))) {
+                    got.extend(d);
+                }
+            }
+            let expect = (0..2000)
+                .map(|i| format!("{}{}\n", c, i))
+                .collect::<String>();
+            assert_eq!(String::from_utf8(got).unwrap(), expect);
+        }
+    }
+
+    /// With `--copy-fd`, everything written to the files is also written to each fd, and one that
+    /// can't be written to is given up on without ending capture.
+    #[test]
+    fn test_copy_fd() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        // `(read end, write end)`, close-on-exec so that no other test's child keeps a write end
+        // open. The last pipe's read end is closed before rotee starts.
+        let pipes = (0..3)
+            .map(|_| {
+                let mut fds = [0; 2];
+                assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+                unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+            })
+            .collect::<Vec<_>>();
+        let (readers, writers): (Vec<_>, Vec<_>) = pipes.into_iter().unzip();
+        let mut cmd = Command::new(&p);
+        cmd.current_dir(dir.path())
+            .args(["-e", "-s", "1000", "-n", "100"])
+            .args(["--copy-fd", "100", "--copy-fd", "101", "--copy-fd", "102"])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped());
+        unsafe {
+            cmd.pre_exec(move || {
+                for (w, fd) in writers.iter().zip(100..) {
+                    if libc::dup2(w.as_raw_fd(), fd) == -1
+                        || libc::fcntl(fd, libc::F_SETFD, 0) == -1
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn().unwrap();
+        // Only the child now has the write ends.
+        drop(cmd);
+        let mut readers = readers.into_iter();
+        let copies = readers
+            .by_ref()
+            .take(2)
+            .map(|mut r| {
+                thread::spawn(move || {
+                    let mut got = Vec::new();
+                    r.read_to_end(&mut got).unwrap();
+                    got
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(readers);
+        let mut stdin = child.stdin.take().unwrap();
+        let mut expect = Vec::new();
+        for i in 0..2000 {
+            writeln!(expect, "{}", i).unwrap();
+        }
+        stdin.write_all(&expect).unwrap();
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        for c in copies {
+            assert_eq!(c.join().unwrap(), expect);
+        }
+        let err = String::from_utf8(out.stderr).unwrap();
+        assert!(err.contains("can't write to --copy-fd 102"));
+        assert!(err.contains("bytes were dropped from the --copy-fd 102 copy"));
+        assert!(!err.contains("--copy-fd 100"));
+    }
+
+    /// The command (`--`) and the `--full-copy-compressed` command inherit none of our descriptors
+    /// other than their stdio: neither output files, the control FIFO, the checkpoint file, the
+    /// log file, nor the fds given to us by `--stderr-fd` and `ROTEE_FAKE_TIME_FD`.
+    #[cfg(all(debug_assertions, target_os = "linux"))]
+    #[test]
+    fn test_no_inherited_fds() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let real_dir = fs::canonicalize(dir.path()).unwrap();
+        // What `ls -l /proc/$$/fd` lists for a descriptor of pipe `f`.
+        let pipe_name = |f: &File| format!("pipe:[{}]", f.metadata().unwrap().ino());
+        // Does a listing of descriptors show any that the child shouldn't have?
+        let leaked = |listing: &str, pipes: &[String]| {
+            listing.lines().any(|l| {
+                l.contains(real_dir.to_str().unwrap()) || pipes.iter().any(|p| l.contains(p))
+            })
+        };
+
+        let out = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "--control-fifo", "ctl", "--timing-file", "timing."])
+            .args(["--checkpoint-file", "cp", "--checkpoint-interval", "1h"])
+            .args(["--log-file", "log"])
+            .args(["--", "sh", "-c", "ls -l /proc/$$/fd"])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let listing = fs::read_to_string(dir.path().join("rotee.0")).unwrap();
+        assert!(listing.contains(" 0 -> ") && !leaked(&listing, &[]));
+
+        // Close-on-exec, so that no other test's child keeps the write ends open.
+        let (mut err_fds, mut time_fds) = ([0; 2], [0; 2]);
+        assert_eq!(
+            unsafe { libc::pipe2(err_fds.as_mut_ptr(), libc::O_CLOEXEC) },
+            0
+        );
+        assert_eq!(
+            unsafe { libc::pipe2(time_fds.as_mut_ptr(), libc::O_CLOEXEC) },
+            0
+        );
+        let (err_r, err_w) =
+            unsafe { (File::from_raw_fd(err_fds[0]), File::from_raw_fd(err_fds[1])) };
+        let (time_r, mut time_w) = unsafe {
+            (
+                File::from_raw_fd(time_fds[0]),
+                File::from_raw_fd(time_fds[1]),
+            )
+        };
+        let pipes = [pipe_name(&err_r), pipe_name(&time_r)];
+        writeln!(time_w, "1717243200").unwrap();
+        let mut cmd = Command::new(&p);
+        cmd.current_dir(dir.path())
+            .env("ROTEE_FAKE_TIME_FD", "4")
+            .args(["-e", "--stderr-prefix", "err.", "--stderr-fd", "3"])
+            .args([
+                "--full-copy-compressed",
+                "ls -l /proc/$$/fd >&2; cat >/dev/null",
+            ])
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped());
+        unsafe {
+            cmd.pre_exec(move || {
+                // `dup2` leaves close-on-exec set if a pipe is already at its target fd.
+                for (fd, to) in [(err_r.as_raw_fd(), 3), (time_r.as_raw_fd(), 4)] {
+                    if libc::dup2(fd, to) == -1 || libc::fcntl(to, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let child = cmd.spawn().unwrap();
+        drop(err_w);
+        drop(time_w);
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        let listing = String::from_utf8(out.stderr).unwrap();
+        assert!(listing.contains(" 0 -> ") && !leaked(&listing, &pipes));
+    }
+
+    /// Commands from the `--control-fifo` are run in order, between reads of input, and malformed
+    /// commands are only warned about.
+    #[test]
+    fn test_control_fifo() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let fifo = dir.path().join("ctl");
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "100", "--control-fifo", "ctl"])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+        let wait_for = |path: PathBuf, len: u64| {
+            while fs::metadata(&path).map(|m| m.len()).unwrap_or(0) < len {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        stdin.write_all(b"abc").unwrap();
+        // The FIFO is created before anything is written.
+        wait_for(dir.path().join("rotee.0"), 3);
+        let mut ctl = OpenOptions::new().write(true).open(&fifo).unwrap();
+        ctl.write_all(b"rotate\nsize 2\nsize two\nst").unwrap();
+        ctl.write_all(b"ats\n").unwrap();
+        assert_eq!(
+            stderr.next().unwrap().unwrap(),
+            "warning: ignoring control command: invalid size 'two'"
+        );
+        // Once the stats are out, the new size is in effect.
+        assert_eq!(
+            stderr.next().unwrap().unwrap(),
+            "stats: 3 bytes written, 1 rotations, rotee.0 has 0 bytes"
+        );
+        stdin.write_all(b"defg").unwrap();
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+        for (i, expected) in ["", "fg", "de", "abc"].into_iter().enumerate() {
+            let got = fs::read_to_string(dir.path().join(format!("rotee.{}", i))).unwrap();
+            assert_eq!(got, expected);
+        }
+    }
+
+    /// Concurrent `--shared` writers lose nothing, never interleave their lines, and never take a
+    /// file beyond the file size (as no line is bigger than it).
+    #[test]
+    fn test_shared() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        const LINES: usize = 300;
+        let writers = (b'a'..=b'd')
+            .map(|c| {
+                let mut child = Command::new(&p)
+                    .current_dir(dir.path())
+                    .args(["-e", "-l", "-s", "1000", "-n", "200", "--shared"])
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                let mut stdin = child.stdin.take().unwrap();
+                let feeder = thread::spawn(move || {
+                    let line = [vec![c; 39], vec![b'\n']].concat();
+                    for _ in 0..LINES {
+                        stdin.write_all(&line).unwrap();
+                    }
+                });
+                (child, feeder)
+            })
+            .collect::<Vec<_>>();
+        for (mut child, feeder) in writers {
+            feeder.join().unwrap();
+            assert!(child.wait().unwrap().success());
+        }
+
+        let mut counts = [0; 4];
+        for ent in fs::read_dir(dir.path()).unwrap() {
+            let path = ent.unwrap().path();
+            if path.file_name().unwrap() == "rotee.lock" {
+                continue;
+            }
+            let data = fs::read(&path).unwrap();
+            assert!(data.len() <= 1000);
+            for line in data.split_inclusive(|b| *b == b'\n') {
+                assert_eq!(line.len(), 40);
+                assert!(line[..39].iter().all(|b| *b == line[0]));
+                counts[usize::from(line[0] - b'a')] += 1;
+            }
+        }
+        assert_eq!(counts, [LINES; 4]);
+    }
+
+    /// The lines of concurrent `--listen-unix` clients all arrive, tagged with their connection,
+    /// and each connection's lines are between its markers.
+    #[test]
+    fn test_listen_unix() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let sock = dir.path().join("sock");
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "--listen-unix", "sock", "--listen-mode", "0600"])
+            .args(["--connection-markers", "--connection-tags"])
+            .spawn()
+            .unwrap();
+        // The socket is created with mode 0700 (as only we can connect to it then), and then given
+        // the mode asked for.
+        while fs::metadata(&sock).map_or(true, |m| m.mode() & 0o777 == 0o700) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(fs::metadata(&sock).unwrap().mode() & 0o777, 0o600);
+
+        const LINES: usize = 500;
+        let clients = ["a", "b"]
+            .map(|name| {
+                let mut s = UnixStream::connect(&sock).unwrap();
+                thread::spawn(move || {
+                    for i in 0..LINES {
+                        // Split lines between writes, so that they arrive in pieces.
+                        let line = format!("{}{}\n", name, i);
+                        let (x, y) = line.split_at(line.len() / 2);
+                        s.write_all(x.as_bytes()).unwrap();
+                        s.write_all(y.as_bytes()).unwrap();
+                    }
+                })
+            })
+            .map(|t| t.join());
+        for c in clients {
+            c.unwrap();
+        }
+        // Once both connections are closed, all they sent has been written.
+        let out = dir.path().join("rotee.0");
+        while fs::read_to_string(&out).unwrap().matches("closed").count() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        unsafe { kill(child.id() as c_int, SIGTERM) };
+        assert!(child.wait().unwrap().success());
+        assert!(!sock.exists());
+
+        // Each connection's lines are in order, between its markers.
+        let mut seen = [None, None];
+        for line in fs::read_to_string(&out).unwrap().lines() {
+            if let Some(m) = line.strip_prefix("-- connection ") {
+                let (id, what) = m.strip_suffix(" --").unwrap().split_once(' ').unwrap();
+                let id = id.parse::<usize>().unwrap() - 1;
+                match what {
+                    "opened" => seen[id] = Some(Vec::new()),
+                    "closed" => assert_eq!(seen[id].as_ref().unwrap().len(), LINES),
+                    _ => panic!(),
+                }
+                continue;
+            }
+            let (tag, rest) = line.split_once(' ').unwrap();
+            let id = tag[1..tag.len() - 1].parse::<usize>().unwrap() - 1;
+            seen[id].as_mut().unwrap().push(rest.to_owned());
+        }
+        let mut got = seen.map(Option::unwrap);
+        got.sort();
+        for (name, lines) in ["a", "b"].iter().zip(got) {
+            let expected = (0..LINES).map(|i| format!("{}{}", name, i));
+            assert!(lines.into_iter().eq(expected));
+        }
+    }
+
+    /// With `--listen-tcp`, the series carries on across connections, a connection made while
+    /// another is active is refused, and what the active connection has sent is still written when
+    /// rotee is asked to terminate.
+    #[test]
+    fn test_listen_tcp() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        // Find a free port.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "10", "-n", "5", "--listen-busy", "refuse"])
+            .args(["--listen-tcp", &addr.port().to_string()])
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        // Connect, retrying until rotee is listening and doesn't refuse the connection (by closing
+        // it).
+        let connect = || loop {
+            if let Ok(s) = TcpStream::connect(addr) {
+                s.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+                match (&s).read(&mut [0]) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return s,
+                    Ok(0) | Err(_) => (),
+                    Ok(_) => panic!(),
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        let wait_for = |len: u64| {
+            let written = || {
+                (0..3)
+                    .filter_map(|i| fs::metadata(dir.path().join(format!("rotee.{}", i))).ok())
+                    .map(|m| m.len())
+                    .sum::<u64>()
+            };
+            while written() < len {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        let mut first = connect();
+        first.write_all(b"0123456789abcde").unwrap();
+        let busy = TcpStream::connect(addr).unwrap();
+        assert_eq!((&busy).read(&mut [0]).unwrap(), 0);
+        wait_for(15);
+        drop(first);
+        let mut second = connect();
+        second.write_all(b"fghij").unwrap();
+        wait_for(20);
+        // What is sent just before rotee is asked to terminate still arrives.
+        second.write_all(b"klmno").unwrap();
+        unsafe { kill(child.id() as c_int, SIGTERM) };
+        assert!(child.wait().unwrap().success());
+
+        for (i, expected) in [&b"0123456789"[..], b"abcdefghij", b"klmno"]
+            .iter()
+            .rev()
+            .enumerate()
+        {
+            let f = fs::read(dir.path().join(format!("rotee.{}", i))).unwrap();
+            assert_eq!(&f, expected);
+        }
+        assert!(!dir.path().join("rotee.3").exists());
+    }
+
+    /// Every line of JSON output parses, no record is split between files, and the records
+    /// recover the input's lines, even when the lines arrive in small pieces.
+    #[test]
+    fn test_json_format() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let mut lines: Vec<Vec<u8>> = vec![
+            b"plain".to_vec(),
+            b"".to_vec(),
+            b"quote \" backslash \\ tab \t cr \r nul \0 esc \x1b".to_vec(),
+            "unicode \u{e9}\u{1f600}".as_bytes().to_vec(),
+            b"invalid \xff\xfe utf-8".to_vec(),
+            vec![b'x'; 100],
+        ];
+        lines.extend((0..50).map(|i| format!("line {}", i).into_bytes()));
+        let mut input = lines.join(&b'\n');
+        input.extend(b"\nno newline");
+        lines.push(b"no newline".to_vec());
+
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "--format", "json", "--max-read", "3"])
+            .args(["-s", "200", "-n", "100", "-m", "60"])
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&input).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let mut got = Vec::new();
+        for i in (0..100).rev() {
+            let Ok(d) = fs::read_to_string(dir.path().join(format!("rotee.{}", i))) else {
+                continue;
+            };
+            assert!(d.is_empty() || d.ends_with('\n'));
+            for l in d.lines() {
+                let v = serde_json::from_str::<serde_json::Value>(l).unwrap();
+                assert_eq!(v["stream"].as_str(), Some("stdin"));
+                assert!(v["ts"].as_str().unwrap().ends_with('Z'));
+                got.push(v);
+            }
+        }
+        assert_eq!(got.len(), lines.len());
+        for (v, l) in got.iter().zip(&lines) {
+            let kept = &l[..l.len().min(60)];
+            assert_eq!(
+                v["truncated"].as_bool(),
+                (kept.len() < l.len()).then_some(true)
+            );
+            match std::str::from_utf8(kept) {
+                Ok(s) => assert_eq!(v["line"].as_str(), Some(s)),
+                Err(_) => assert_eq!(v["raw"].as_str(), Some(base64(kept).as_str())),
+            }
+        }
+    }
+
+    /// With `--max-write-errors`, a write that fails is left out of what `--verify` checks, and of
+    /// the file size.
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_injection_dropped_write() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        // Input from a file is read in one go, so it's written in 4 byte pieces, of which the
+        // second is dropped.
+        let input = dir.path().join("input");
+        fs::write(&input, "abcdefghijklmn").unwrap();
+        let out = Command::new(&p)
+            .current_dir(dir.path())
+            .env("ROTEE_FAIL_AT", "write-active:2")
+            .stdin(File::open(&input).unwrap())
+            .args(["-e", "-s", "4", "--verify", "--max-write-errors", "1"])
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(out.status.success(), "{}", stderr);
+        assert!(stderr.contains("injected failure at write-active"));
+        for (i, expected) in [(0, "mn"), (1, "ijkl"), (2, "abcd")] {
+            let path = dir.path().join(format!("rotee.{}", i));
+            assert_eq!(fs::read_to_string(path).unwrap(), expected);
+        }
+        assert!(!dir.path().join("rotee.3").exists());
+    }
+
+    /// Crash rotee part way through a commit, and check that the next run completes it, so that
+    /// the live set becomes the staged set.
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_injection_commit() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let args = ["-e", "-s", "2", "--staging-prefix", "staging."];
+        for point in ["commit-journaled", "commit-renamed-0", "commit-renamed-1"] {
+            let dir = TempDir::new().unwrap();
+            let read = |name: &str| fs::read(dir.path().join(name)).ok();
+            // An earlier commit.
+            for (i, d) in ["x", "y", "z", "w"].iter().enumerate() {
+                fs::write(dir.path().join(format!("rotee.{}", i)), d).unwrap();
+            }
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .env("ROTEE_FAIL_AT", point)
+                .stdin(Stdio::piped())
+                .args(args)
+                .args(["--commit-signal", "USR1"])
+                .spawn()
+                .unwrap();
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(b"abcde").unwrap();
+            while read("staging.0").as_deref() != Some(b"e") {
+                thread::sleep(Duration::from_millis(1));
+            }
+            unsafe { kill(i32::try_from(child.id()).unwrap(), SIGUSR1) };
+            assert!(!child.wait().unwrap().success(), "fail point {}", point);
+            assert!(read("rotee.commit").is_some(), "fail point {}", point);
+
+            // Recover.
+            let status = Command::new(&p)
+                .current_dir(dir.path())
+                .stdin(Stdio::null())
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert_eq!(read("rotee.commit"), None);
+            for (name, expected) in [("rotee.0", "e"), ("rotee.1", "cd"), ("rotee.2", "ab")] {
+                assert_eq!(
+                    read(name).as_deref(),
+                    Some(expected.as_bytes()),
+                    "fail point {}",
+                    point
+                );
+            }
+            assert_eq!(read("rotee.3"), None, "fail point {}", point);
+            assert_eq!(read("staging.1"), None, "fail point {}", point);
+        }
+    }
+
+    /// For every fail point, crash rotee while it rotates, then re-run it with `--rotate-at-start`
+    /// (which recovers the newest file) and check that the newest file exists and that all the
+    /// data written before the crash is present exactly once.
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_injection() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let input = b"0123456789abcdefghij";
+        let file_size = 4;
+
+        let mut points = vec![
+            "rotate-dropped-old".to_owned(),
+            "rotate-before-create".to_owned(),
+            "rotate-after-create".to_owned(),
+        ];
+        points.extend((1..=3).map(|k| format!("rotate-renamed-{}", k)));
+        for point in points {
+            // Rotation `r` renames `r` files, so `rotate-renamed-k` is first reached in rotation
+            // `k`, and every other fail point in the first.
+            let first = point
+                .strip_prefix("rotate-renamed-")
+                .map_or(1, |k| k.parse::<usize>().unwrap());
+            // Crash the `nth` time the fail point is reached.
+            for nth in 1..=3 {
+                let dir = TempDir::new().unwrap();
+                let mut child = Command::new(&p)
+                    .current_dir(dir.path())
+                    .env("ROTEE_FAIL_AT", format!("{}:{}", point, nth))
+                    .stdin(Stdio::piped())
+                    .args(["-e", "-s", &file_size.to_string()])
+                    .spawn()
+                    .unwrap();
+                // rotee may die before reading everything.
+                let _ = child.stdin.take().unwrap().write_all(input);
+                let status = child.wait().unwrap();
+
+                // Every byte before the rotation we crashed in had been written out.
+                let acked = if status.success() {
+                    // This fail point wasn't reached `nth` times.
+                    &input[..]
+                } else {
+                    &input[..(first + nth - 1) * file_size]
+                };
+
+                // Recover.
+                let status = Command::new(&p)
+                    .current_dir(dir.path())
+                    .stdin(Stdio::null())
+                    .args(["-e", "-s", &file_size.to_string(), "--rotate-at-start"])
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+
+                // The series has rotee's default `-n 8`. A crash part-way through shifting the
+                // files up leaves a gap in their numbering (which later rotations keep), so only
+                // the files left are checked.
+                let files = (0..8)
+                    .filter_map(|i| fs::read(dir.path().join(format!("rotee.{}", i))).ok())
+                    .map(Some)
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    check_series("rotee.", &files, file_size, 8, acked),
+                    Ok(()),
+                    "fail point {}:{}",
+                    point,
+                    nth
+                );
+            }
         }
     }
 }
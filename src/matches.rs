@@ -0,0 +1,103 @@
+//! Counting input lines that match a regular expression.
+
+use regex::bytes::Regex;
+
+/// Counts the lines in a stream of bytes that match a regular expression. Lines may be split
+/// arbitrarily across calls to `scan()`. Only the first `cap` bytes of each line are matched
+/// against, so that an enormous line can't use unbounded memory.
+pub struct MatchCounter {
+    re: Regex,
+    /// The number of matching lines needed to reach the threshold.
+    threshold: usize,
+    /// The number of matching lines seen since the count was last reset.
+    count: usize,
+    /// The (first `cap` bytes of the) current, incomplete, line.
+    line: Vec<u8>,
+    cap: usize,
+}
+
+impl MatchCounter {
+    pub fn new(re: Regex, threshold: usize, cap: usize) -> Self {
+        assert!(threshold > 0);
+        Self {
+            re,
+            threshold,
+            count: 0,
+            line: Vec::new(),
+            cap,
+        }
+    }
+
+    /// Has the threshold been reached?
+    pub fn reached(&self) -> bool {
+        self.count >= self.threshold
+    }
+
+    /// Is the stream in the middle of a line (i.e. has part of a line been scanned)?
+    pub fn mid_line(&self) -> bool {
+        !self.line.is_empty()
+    }
+
+    /// Reset the count of matching lines.
+    pub fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    /// Scan `bytes`, the next part of the stream, counting matching lines. If a line completed in
+    /// `bytes` makes the count reach the threshold, scanning stops after that line's newline and
+    /// the number of bytes scanned is returned. Otherwise all of `bytes` is scanned and `None` is
+    /// returned.
+    pub fn scan(&mut self, bytes: &[u8]) -> Option<usize> {
+        let mut idx = 0;
+        while let Some(off) = bytes[idx..].iter().position(|b| *b == b'\n') {
+            self.push(&bytes[idx..idx + off]);
+            idx += off + 1;
+            if self.re.is_match(&self.line) {
+                self.count += 1;
+            }
+            self.line.clear();
+            if self.reached() {
+                return Some(idx);
+            }
+        }
+        self.push(&bytes[idx..]);
+        None
+    }
+
+    /// Append `bytes` to the current line, up to the cap.
+    fn push(&mut self, bytes: &[u8]) {
+        let n = usize::min(bytes.len(), self.cap.saturating_sub(self.line.len()));
+        self.line.extend_from_slice(&bytes[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MatchCounter;
+    use regex::bytes::Regex;
+
+    #[test]
+    fn test_scan() {
+        let mut m = MatchCounter::new(Regex::new("ERR").unwrap(), 2, 1024);
+        assert_eq!(m.scan(b"ERR a\nok\nE"), None);
+        assert!(!m.reached());
+        assert!(m.mid_line());
+        // The line split across calls is reassembled.
+        assert_eq!(m.scan(b"RR b\nERR c\n"), Some(5));
+        assert!(!m.mid_line());
+        assert!(m.reached());
+        m.reset();
+        assert_eq!(m.scan(b"ERR c\n"), None);
+        assert_eq!(m.scan(b"ERR"), None);
+        assert_eq!(m.scan(b"\n"), Some(1));
+    }
+
+    #[test]
+    fn test_cap() {
+        let mut m = MatchCounter::new(Regex::new("^a*$").unwrap(), 1, 3);
+        // Only the first 3 bytes of the line are considered.
+        assert_eq!(m.scan(b"aaab\n"), Some(5));
+        m.reset();
+        assert_eq!(m.scan(b"aab\n"), None);
+    }
+}
@@ -0,0 +1,230 @@
+//! Copying everything written to a command's stdin (`--full-copy-compressed`), e.g. to keep a
+//! complete compressed archive alongside the rotating files, or to a descriptor (`--copy-fd`).
+
+use log::warn;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Write},
+    os::unix::io::{AsRawFd, OwnedFd},
+    process::{Child, Command, ExitStatus, Stdio},
+};
+
+/// Feeds a command's stdin, or a descriptor, without ever blocking: what hasn't yet been read is
+/// buffered, up to a limit, beyond which further bytes are dropped (and counted), so that a slow
+/// reader can't stall capture.
+pub struct PipeCopy {
+    /// What is copied to, for messages (e.g. `--copy-fd 4`).
+    name: String,
+    /// The command, when copying to one.
+    child: Option<Child>,
+    /// `None` once the reader has stopped reading.
+    out: Option<File>,
+    buf: VecDeque<u8>,
+    cap: usize,
+    /// The number of bytes dropped because the buffer was full, or the reader had stopped reading.
+    dropped: u64,
+}
+
+impl PipeCopy {
+    /// Run the shell command `cmd`, buffering up to `cap` bytes that it hasn't yet read.
+    pub fn spawn(cmd: &str, cap: usize) -> Result<Self, io::Error> {
+        let mut child = Command::new("sh")
+            .args(["-c", cmd])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = OwnedFd::from(child.stdin.take().unwrap());
+        let mut c = Self::new(File::from(stdin), "--full-copy-compressed command", cap)?;
+        c.child = Some(child);
+        Ok(c)
+    }
+
+    /// Copy to `out` (named `name` in messages), buffering up to `cap` bytes that haven't yet been
+    /// read. `out`'s file status flags are left alone: they are shared with whoever else has the
+    /// descriptor (e.g. the process that passed it to rotee), so making it non-blocking would
+    /// change how their writes behave too.
+    pub fn new(out: File, name: &str, cap: usize) -> Result<Self, io::Error> {
+        Ok(Self {
+            name: name.to_owned(),
+            child: None,
+            out: Some(out),
+            buf: VecDeque::new(),
+            cap,
+            dropped: 0,
+        })
+    }
+
+    /// What is copied to (e.g. `--copy-fd 4`).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of bytes dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Copy `bytes`, or buffer them if the reader isn't ready for them.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.out.is_none() {
+            self.dropped += bytes.len() as u64;
+            return;
+        }
+        let room = self.cap.saturating_sub(self.buf.len());
+        if room < bytes.len() {
+            if self.dropped == 0 {
+                warn!("{} isn't keeping up: dropping input", self.name);
+            }
+            self.dropped += (bytes.len() - room) as u64;
+        }
+        self.buf.extend(&bytes[..bytes.len().min(room)]);
+        self.drain(false);
+    }
+
+    /// Write as much of the buffer as the reader will take (without blocking, unless `block` is
+    /// true). Without blocking, a write is only made once `poll()` says that `out` is writable,
+    /// and is of at most `PIPE_BUF` bytes, which a writable pipe takes without blocking.
+    fn drain(&mut self, block: bool) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+        while !self.buf.is_empty() {
+            if !block && !poll_out(out, 0) {
+                return;
+            }
+            let (head, _) = self.buf.as_slices();
+            let len = match block {
+                true => head.len(),
+                false => head.len().min(libc::PIPE_BUF),
+            };
+            match out.write(&head[..len]) {
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                // Whoever passed us the descriptor may have made it non-blocking themselves.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => match block {
+                    true => {
+                        poll_out(out, -1);
+                    }
+                    false => return,
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    warn!("can't write to {}: {}", self.name, e);
+                    self.out = None;
+                    self.dropped += self.buf.len() as u64;
+                    self.buf.clear();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Write everything buffered, close the copy and, if copying to a command, wait for it to
+    /// exit. Returns the command's exit status (if there is one) and the number of bytes that were
+    /// dropped.
+    pub fn finish(mut self) -> Result<(Option<ExitStatus>, u64), io::Error> {
+        self.drain(true);
+        drop(self.out.take());
+        let status = self.child.as_mut().map(Child::wait).transpose()?;
+        Ok((status, self.dropped))
+    }
+}
+
+/// Wait up to `timeout` milliseconds (or, if it's -1, for as long as it takes) for `f` to be
+/// writable, returning `true` if it is (or if writing to it would fail at once, e.g. because its
+/// reader has gone). If `poll()` fails (e.g. it's interrupted), returns `false`, to be tried again
+/// later.
+fn poll_out(f: &impl AsRawFd, timeout: libc::c_int) -> bool {
+    let mut pfd = libc::pollfd {
+        fd: f.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, timeout) == 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PipeCopy;
+    use std::{
+        fs::{self, File},
+        io::Read,
+        os::unix::io::{AsRawFd, FromRawFd},
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy() {
+        let dir = TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut c = PipeCopy::spawn(&format!("cat > '{}'", out.display()), 1 << 20).unwrap();
+        let data = (0..500_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for chunk in data.chunks(1000) {
+            c.feed(chunk);
+        }
+        let (status, dropped) = c.finish().unwrap();
+        assert!(status.unwrap().success());
+        assert_eq!(dropped, 0);
+        assert_eq!(fs::read(out).unwrap(), data);
+    }
+
+    #[test]
+    fn test_drop() {
+        // The command doesn't read until after everything has been fed, so what doesn't fit in the
+        // pipe or the buffer is dropped.
+        let dir = TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let mut c =
+            PipeCopy::spawn(&format!("sleep 0.2; cat > '{}'", out.display()), 1000).unwrap();
+        for _ in 0..1000 {
+            c.feed(&[b'x'; 1000]);
+        }
+        let (status, dropped) = c.finish().unwrap();
+        assert!(status.unwrap().success());
+        assert!(dropped > 0);
+        assert_eq!(fs::read(out).unwrap().len() as u64 + dropped, 1_000_000);
+    }
+
+    #[test]
+    fn test_reader_gone() {
+        // Once the reader has gone, what is fed is dropped (and counted), rather than failing.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        drop(unsafe { File::from_raw_fd(fds[0]) });
+        let w = unsafe { File::from_raw_fd(fds[1]) };
+        let mut c = PipeCopy::new(w, "--copy-fd 4", 1000).unwrap();
+        assert_eq!(c.name(), "--copy-fd 4");
+        c.feed(b"abc");
+        c.feed(b"de");
+        assert_eq!(c.dropped(), 5);
+        assert_eq!(c.finish().unwrap(), (None, 5));
+    }
+
+    #[test]
+    fn test_flags_untouched() {
+        // The descriptor's file status flags are shared with other holders of it (here, `w2`), so
+        // they must not be changed, even while the reader lags behind.
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let mut r = unsafe { File::from_raw_fd(fds[0]) };
+        let w = unsafe { File::from_raw_fd(fds[1]) };
+        let w2 = w.try_clone().unwrap();
+        let mut c = PipeCopy::new(w, "--copy-fd 4", 1 << 20).unwrap();
+        // More than the pipe holds, so that some is left buffered.
+        let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        c.feed(&data);
+        assert_eq!(
+            unsafe { libc::fcntl(w2.as_raw_fd(), libc::F_GETFL) } & libc::O_NONBLOCK,
+            0
+        );
+        drop(w2);
+        let reader = std::thread::spawn(move || {
+            let mut got = Vec::new();
+            r.read_to_end(&mut got).unwrap();
+            got
+        });
+        assert_eq!(c.finish().unwrap(), (None, 0));
+        assert_eq!(reader.join().unwrap(), data);
+    }
+}
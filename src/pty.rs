@@ -0,0 +1,109 @@
+//! Pseudo-terminals, for running commands whose output differs when it goes to a pipe.
+
+use libc::{ioctl, openpty, winsize, TIOCGWINSZ, TIOCSWINSZ};
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+/// The master side of a pseudo-terminal. Once every copy of the slave side has been closed,
+/// reading the master fails with `EIO` (rather than returning EOF, as a pipe would): this is
+/// reported as EOF.
+pub struct PtyMaster(File);
+
+impl Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            r => r,
+        }
+    }
+}
+
+impl AsRawFd for PtyMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Open a pseudo-terminal, returning its master and slave sides. The terminal is given the window
+/// size `size` if specified. Neither side is inherited by child processes (other than as their
+/// stdio).
+pub fn open(size: Option<winsize>) -> io::Result<(PtyMaster, OwnedFd)> {
+    let (mut master, mut slave) = (0, 0);
+    let size = size.as_ref().map_or(ptr::null(), |s| s as *const winsize);
+    if unsafe { openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), size) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((PtyMaster(master), slave))
+}
+
+/// The window size of the terminal `fd`, or `None` if `fd` isn't a terminal.
+pub fn window_size(fd: RawFd) -> Option<winsize> {
+    let mut ws = winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    (unsafe { ioctl(fd, TIOCGWINSZ, &mut ws) } != -1).then_some(ws)
+}
+
+/// Set the window size of the terminal `fd` (which notifies its foreground process group with
+/// `SIGWINCH`).
+pub fn set_window_size(fd: RawFd, ws: &winsize) -> io::Result<()> {
+    if unsafe { ioctl(fd, TIOCSWINSZ, ws) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, set_window_size, window_size};
+    use libc::winsize;
+    use std::{fs::File, io::Read, io::Write, os::unix::io::AsRawFd};
+
+    #[test]
+    fn test_pty() {
+        let size = winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let (mut master, slave) = open(Some(size)).unwrap();
+        assert_eq!(window_size(slave.as_raw_fd()).unwrap().ws_col, 80);
+        set_window_size(
+            master.as_raw_fd(),
+            &winsize {
+                ws_col: 100,
+                ..size
+            },
+        )
+        .unwrap();
+        assert_eq!(window_size(slave.as_raw_fd()).unwrap().ws_col, 100);
+
+        let mut slave = File::from(slave);
+        slave.write_all(b"hi\n").unwrap();
+        drop(slave);
+        let mut got = Vec::new();
+        master.read_to_end(&mut got).unwrap();
+        // The terminal translates newlines.
+        assert_eq!(got, b"hi\r\n");
+    }
+
+    #[test]
+    fn test_window_size_not_tty() {
+        let f = File::open("/dev/null").unwrap();
+        assert!(window_size(f.as_raw_fd()).is_none());
+    }
+}
@@ -0,0 +1,101 @@
+//! The buffer that input is read into.
+
+use std::ops::{Deref, DerefMut};
+
+/// A byte buffer whose length can be changed (e.g. when `--alt-buf-size` is toggled) without
+/// re-zeroing bytes that it has held before.
+///
+/// The backing `Vec` is allocated with `vec![0; len]`, which asks the allocator for zeroed memory
+/// rather than zeroing it by hand: for large buffers, that memory comes fresh from the kernel,
+/// already zeroed, so a large `-b` doesn't stall startup. Shrinking the buffer only shortens the
+/// window onto the `Vec`, and growing it back within the `Vec` costs nothing. Only growing beyond
+/// anything held before zeroes new bytes.
+pub struct ReadBuf {
+    buf: Vec<u8>,
+    /// The number of bytes of `buf` that are in use.
+    len: usize,
+}
+
+impl ReadBuf {
+    pub fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0; len],
+            len,
+        }
+    }
+
+    /// Make the buffer `len` bytes long. Bytes beyond the old length have unspecified values.
+    pub fn resize(&mut self, len: usize) {
+        if len > self.buf.len() {
+            self.buf.resize(len, 0);
+        }
+        self.len = len;
+    }
+
+    /// Make the buffer empty.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Append `data` to the buffer.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            self.buf.truncate(self.len);
+            self.buf.extend_from_slice(data);
+        } else {
+            self.buf[self.len..end].copy_from_slice(data);
+        }
+        self.len = end;
+    }
+}
+
+impl Deref for ReadBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for ReadBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadBuf;
+
+    #[test]
+    fn test_resize() {
+        let mut b = ReadBuf::new(4);
+        assert_eq!(&*b, &[0; 4]);
+        b.copy_from_slice(b"abcd");
+        b.resize(2);
+        assert_eq!(&*b, b"ab");
+        // Growing within what was held before keeps the old bytes, rather than zeroing them.
+        b.resize(4);
+        assert_eq!(&*b, b"abcd");
+        b.resize(6);
+        assert_eq!(&*b, b"abcd\0\0");
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut b = ReadBuf::new(4);
+        b.clear();
+        assert!(b.is_empty());
+        b.extend_from_slice(b"ab");
+        b.extend_from_slice(b"cd");
+        assert_eq!(&*b, b"abcd");
+        b.extend_from_slice(b"ef");
+        assert_eq!(&*b, b"abcdef");
+        b.clear();
+        b.extend_from_slice(b"x");
+        assert_eq!(&*b, b"x");
+        b.resize(3);
+        assert_eq!(&*b, b"xbc");
+    }
+}
@@ -0,0 +1,374 @@
+//! The policy that decides how what is written to a series is split between its files, when it
+//! is echoed and the echo flushed, and when the active file is rotated. It does no I/O: it is fed
+//! the data being written and the time, and says what to do, so that the interaction of rotation
+//! triggers can be tested without files.
+
+use crate::{
+    chunker::Chunker, clock::Interval, delimiters::DelimiterCounter, matches::MatchCounter,
+};
+use std::{ops::Range, time::SystemTime};
+
+/// Where a write may be split between files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Records {
+    /// Anywhere.
+    Bytes,
+    /// Only after a newline (JSON records, and the lines of `--squeeze-repeats`).
+    Lines,
+    /// Nowhere: each write is one record (e.g. a frame of `--frame-length`).
+    Whole,
+}
+
+/// Why the active file is to be rotated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    /// It has reached the file size (`-s`).
+    Size,
+    /// Enough lines have matched `--match` (`--rotate-after-matches`).
+    Matches,
+    /// Enough `--split-byte`s have been written (`--split-every`).
+    Delimiters,
+}
+
+/// What to do next, as decided by `Session::next()` and `Session::plan()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Write this range of the data to the active file.
+    Write(Range<usize>),
+    /// Echo this range of the data (or as much of it as the preceding `Write` wrote: bytes that
+    /// didn't reach the active file aren't echoed either).
+    Echo(Range<usize>),
+    /// Flush the echo.
+    FlushEcho,
+    /// Rotate the active file.
+    Rotate(Reason),
+}
+
+impl Action {
+    /// This action, with its range (if any) moved `by` bytes later in the data.
+    fn offset(self, by: usize) -> Self {
+        match self {
+            Action::Write(r) => Action::Write(r.start + by..r.end + by),
+            Action::Echo(r) => Action::Echo(r.start + by..r.end + by),
+            a => a,
+        }
+    }
+}
+
+/// The state of a series that decides how it is split into files: how full the active file is,
+/// the counts of the rotation triggers, and when the last rotation was.
+pub struct Session {
+    pub chunker: Chunker,
+    matches: Option<MatchCounter>,
+    delimiters: Option<DelimiterCounter>,
+    /// The byte that `delimiters` counts.
+    delimiter: u8,
+    /// Rotations due to matches and delimiters are deferred until the active file holds at least
+    /// this many bytes (`--min-size`).
+    min_size: usize,
+    records: Records,
+    /// With `--min-rotate-interval`, the interval that due rotations are held back until.
+    rotate_interval: Option<Interval>,
+    /// Is the echo flushed after every write? If not (`--echo-flush-lines`), it's left to flush
+    /// at line ends.
+    flush_echo: bool,
+}
+
+impl Session {
+    pub fn new(
+        chunker: Chunker,
+        matches: Option<MatchCounter>,
+        delimiters: Option<(DelimiterCounter, u8)>,
+        min_size: usize,
+        records: Records,
+        rotate_interval: Option<Interval>,
+        flush_echo: bool,
+    ) -> Self {
+        let (delimiters, delimiter) = match delimiters {
+            Some((d, b)) => (Some(d), b),
+            None => (None, b'\n'),
+        };
+        Self {
+            chunker,
+            matches,
+            delimiters,
+            delimiter,
+            min_size,
+            records,
+            rotate_interval,
+            flush_echo,
+        }
+    }
+
+    /// Decide the next step of writing `data`, the (non-empty) rest of a write to the series,
+    /// which is to be echoed if `echo` is true: the actions to perform, in order, with ranges
+    /// relative to `data`. The step always starts with a `Write`, which is followed by everything
+    /// it writes being echoed, and then maybe a rotation. The caller must perform the actions,
+    /// calling `rotated()` if it rotates, and then carry on with the rest of `data`.
+    pub fn next(&mut self, data: &[u8], echo: bool, now: SystemTime) -> Vec<Action> {
+        let (len, reason) = self.split(data, now);
+        let mut actions = vec![Action::Write(0..len)];
+        if echo {
+            actions.push(Action::Echo(0..len));
+            if self.flush_echo {
+                actions.push(Action::FlushEcho);
+            }
+        }
+        actions.extend(reason.map(Action::Rotate));
+        actions
+    }
+
+    /// How many of the bytes of `data` the next step writes to the active file, and whether (and
+    /// why) the file is to be rotated after they are written.
+    fn split(&mut self, data: &[u8], now: SystemTime) -> (usize, Option<Reason>) {
+        let mut len = self.chunker.write_size(data.len());
+        // Stop this write at the end of a line that brings the match count to the threshold, so
+        // that the line is the last in the closing file.
+        if let Some(m) = &mut self.matches {
+            if let Some(n) = m.scan(&data[..len]) {
+                len = n;
+            }
+        }
+        // Likewise, stop at the occurrence of the `--split-byte` that reaches `--split-every`.
+        if let Some(d) = &mut self.delimiters {
+            if let Some(n) = d.scan(&data[..len]) {
+                len = n;
+            }
+        }
+        match self.records {
+            Records::Bytes => (),
+            Records::Lines => {
+                if let Some(i) = data[(len - 1)..].iter().position(|b| *b == b'\n') {
+                    len += i;
+                }
+            }
+            Records::Whole => len = data.len(),
+        }
+        // If a rotation is held back by `--min-rotate-interval`, the active file is allowed to
+        // exceed the file size.
+        let size_due = self.chunker.advance(len);
+        // With `--min-size`, a rotation due to matches is deferred until the active file is big
+        // enough. As `scan()` stops at every line end once the count is reached, the rotation
+        // then happens at the end of the line that makes the file big enough.
+        let big_enough = self.chunker.cur_size() >= self.min_size;
+        let matches_due = self
+            .matches
+            .as_ref()
+            .is_some_and(|m| m.reached() && !m.mid_line())
+            && big_enough;
+        let delimiters_due = self
+            .delimiters
+            .as_ref()
+            .is_some_and(|d| d.reached() && data[len - 1] == self.delimiter)
+            && big_enough;
+        let reason = match () {
+            _ if size_due => Some(Reason::Size),
+            _ if matches_due => Some(Reason::Matches),
+            _ if delimiters_due => Some(Reason::Delimiters),
+            _ => None,
+        };
+        (len, reason.filter(|_| self.rotation_allowed(now)))
+    }
+
+    /// Plan the whole of a write of `data` (echoing it if `echo` is true), assuming that
+    /// everything is written and that each rotation is performed as soon as it is asked for.
+    pub fn plan(&mut self, data: &[u8], echo: bool, now: SystemTime) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let mut idx = 0;
+        while idx < data.len() {
+            let mut len = 0;
+            for a in self.next(&data[idx..], echo, now) {
+                match &a {
+                    Action::Write(r) => len = r.end,
+                    Action::Rotate(_) => self.rotated(now),
+                    _ => (),
+                }
+                actions.push(a.offset(idx));
+            }
+            idx += len;
+        }
+        actions
+    }
+
+    /// Can a due rotation be performed now, rather than being held back by
+    /// `--min-rotate-interval`?
+    pub fn rotation_allowed(&mut self, now: SystemTime) -> bool {
+        self.rotate_interval.as_mut().is_none_or(|i| i.elapsed(now))
+    }
+
+    /// Record that the active file was rotated at `now`.
+    pub fn rotated(&mut self, now: SystemTime) {
+        self.committed();
+        self.restart_interval(now);
+    }
+
+    /// Record that the active file was committed (see `--staging-prefix`), which, unlike a
+    /// rotation, doesn't restart the `--min-rotate-interval`.
+    pub fn committed(&mut self) {
+        self.chunker.rotated();
+        if let Some(m) = &mut self.matches {
+            m.reset();
+        }
+        if let Some(d) = &mut self.delimiters {
+            d.reset();
+        }
+    }
+
+    /// Restart the `--min-rotate-interval` at `now` (e.g. because a new day's series has been
+    /// started).
+    pub fn restart_interval(&mut self, now: SystemTime) {
+        if let Some(i) = &mut self.rotate_interval {
+            i.reset(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Records, Session};
+    use crate::{
+        chunker::Chunker, clock::Interval, delimiters::DelimiterCounter, matches::MatchCounter,
+    };
+    use regex::bytes::Regex;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn describe(actions: &[Action]) -> Vec<String> {
+        actions
+            .iter()
+            .map(|a| match a {
+                Action::Write(r) => format!("{}..{}", r.start, r.end),
+                Action::Echo(r) => format!("echo {}..{}", r.start, r.end),
+                Action::FlushEcho => "flush".to_owned(),
+                Action::Rotate(r) => format!("{:?}", r),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_size() {
+        let mut s = Session::new(Chunker::new(4), None, None, 0, Records::Bytes, None, true);
+        assert_eq!(
+            describe(&s.plan(b"0123456789", false, UNIX_EPOCH)),
+            ["0..4", "Size", "4..8", "Size", "8..10"]
+        );
+        // The active file's size carries over between writes.
+        assert_eq!(
+            describe(&s.plan(b"ab", false, UNIX_EPOCH)),
+            ["0..2", "Size"]
+        );
+        assert_eq!(s.chunker.cur_size(), 0);
+    }
+
+    #[test]
+    fn test_echo() {
+        // Each write is echoed, and the echo flushed, before the file is rotated.
+        let mut s = Session::new(Chunker::new(4), None, None, 0, Records::Bytes, None, true);
+        assert_eq!(
+            describe(&s.plan(b"012345", true, UNIX_EPOCH)),
+            [
+                "0..4",
+                "echo 0..4",
+                "flush",
+                "Size",
+                "4..6",
+                "echo 4..6",
+                "flush"
+            ]
+        );
+        // With `--echo-flush-lines`, the echo is left to flush itself.
+        let mut s = Session::new(Chunker::new(4), None, None, 0, Records::Bytes, None, false);
+        assert_eq!(
+            describe(&s.plan(b"012345", true, UNIX_EPOCH)),
+            ["0..4", "echo 0..4", "Size", "4..6", "echo 4..6"]
+        );
+    }
+
+    #[test]
+    fn test_lines() {
+        // Lines aren't split, so files may exceed the file size.
+        let mut s = Session::new(Chunker::new(4), None, None, 0, Records::Lines, None, true);
+        assert_eq!(
+            describe(&s.plan(b"abcdef\ngh\nij", false, UNIX_EPOCH)),
+            ["0..7", "Size", "7..11", "Size", "11..12"]
+        );
+        let mut s = Session::new(Chunker::new(4), None, None, 0, Records::Whole, None, true);
+        assert_eq!(
+            describe(&s.plan(b"abcdef", false, UNIX_EPOCH)),
+            ["0..6", "Size"]
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let m = MatchCounter::new(Regex::new("ERR").unwrap(), 2, 1024);
+        let mut s = Session::new(
+            Chunker::unlimited(),
+            Some(m),
+            None,
+            0,
+            Records::Bytes,
+            None,
+            true,
+        );
+        assert_eq!(
+            describe(&s.plan(b"ERR\nok\nERR\nERR\n", false, UNIX_EPOCH)),
+            ["0..11", "Matches", "11..15"]
+        );
+        // With `--min-size`, the rotation waits for a line end in a big enough file.
+        let m = MatchCounter::new(Regex::new("ERR").unwrap(), 1, 1024);
+        let mut s = Session::new(
+            Chunker::unlimited(),
+            Some(m),
+            None,
+            6,
+            Records::Bytes,
+            None,
+            true,
+        );
+        assert_eq!(
+            describe(&s.plan(b"ERR\nok\nok\n", false, UNIX_EPOCH)),
+            ["0..4", "4..7", "Matches", "7..10"]
+        );
+    }
+
+    #[test]
+    fn test_delimiters() {
+        let d = DelimiterCounter::new(0, 2);
+        let mut s = Session::new(
+            Chunker::new(5),
+            None,
+            Some((d, 0)),
+            0,
+            Records::Bytes,
+            None,
+            true,
+        );
+        // Whichever trigger comes first rotates, and a rotation resets both.
+        assert_eq!(
+            describe(&s.plan(b"a\0b\0cdefgh\0", false, UNIX_EPOCH)),
+            ["0..4", "Delimiters", "4..9", "Size", "9..11"]
+        );
+    }
+
+    #[test]
+    fn test_min_rotate_interval() {
+        let t0 = UNIX_EPOCH + Duration::from_secs(100);
+        let i = Interval::new(Duration::from_secs(10), t0);
+        let mut s = Session::new(
+            Chunker::new(2),
+            None,
+            None,
+            0,
+            Records::Bytes,
+            Some(i),
+            true,
+        );
+        // A due rotation is held back, and the file grows beyond the file size meanwhile.
+        assert_eq!(describe(&s.plan(b"abc", false, t0)), ["0..2", "2..3"]);
+        assert_eq!(s.chunker.cur_size(), 3);
+        let t1 = t0 + Duration::from_secs(10);
+        assert_eq!(describe(&s.plan(b"d", false, t1)), ["0..1", "Size"]);
+        // The interval restarts with the rotation.
+        assert_eq!(describe(&s.plan(b"efg", false, t1)), ["0..2", "2..3"]);
+    }
+}
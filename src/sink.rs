@@ -0,0 +1,129 @@
+//! Storage for a series of rotated output files.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io::{self, Write},
+    rc::Rc,
+};
+
+/// The storage for a series of output files, each identified by its index in the series (0 being
+/// the newest).
+pub trait Sink {
+    /// An open, writeable, file.
+    type File: Write;
+
+    /// Does file `idx` exist?
+    fn exists(&self, idx: usize) -> bool;
+
+    /// Rename file `from` to `to`, replacing file `to` if it exists.
+    fn rename(&mut self, from: usize, to: usize) -> Result<(), io::Error>;
+
+    /// Finish off file `idx`, which has just been rotated out of being the newest file and will
+    /// not be written to again (e.g. by giving it different permissions).
+    fn finalize(&mut self, _idx: usize) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Create file `idx` (truncating it if it already exists) and open it for writing.
+    fn create(&mut self, idx: usize) -> Result<Self::File, io::Error>;
+
+    /// The current size (in bytes) of an open file.
+    fn size(&self, f: &Self::File) -> Result<u64, io::Error>;
+
+    /// A human-readable name for file `idx`, for use in messages.
+    fn name(&self, idx: usize) -> String;
+}
+
+/// A series of files held in memory, for testing.
+#[derive(Default)]
+pub struct MemSink {
+    files: BTreeMap<usize, MemFile>,
+}
+
+impl MemSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The contents of file `idx`, if it exists.
+    pub fn contents(&self, idx: usize) -> Option<Vec<u8>> {
+        self.files.get(&idx).map(|f| f.0.borrow().clone())
+    }
+
+    /// The indices of the files that exist, in ascending order.
+    pub fn indices(&self) -> Vec<usize> {
+        self.files.keys().copied().collect()
+    }
+}
+
+impl Sink for MemSink {
+    type File = MemFile;
+
+    fn exists(&self, idx: usize) -> bool {
+        self.files.contains_key(&idx)
+    }
+
+    fn rename(&mut self, from: usize, to: usize) -> Result<(), io::Error> {
+        let f = self
+            .files
+            .remove(&from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.name(from)))?;
+        self.files.insert(to, f);
+        Ok(())
+    }
+
+    fn create(&mut self, idx: usize) -> Result<MemFile, io::Error> {
+        let f = MemFile::default();
+        self.files.insert(idx, f.clone());
+        Ok(f)
+    }
+
+    fn size(&self, f: &MemFile) -> Result<u64, io::Error> {
+        Ok(u64::try_from(f.0.borrow().len()).unwrap())
+    }
+
+    fn name(&self, idx: usize) -> String {
+        format!("<memory>.{}", idx)
+    }
+}
+
+/// An open file in a `MemSink`. Writes are visible through the sink even after the file has been
+/// renamed (as with a real file descriptor).
+#[derive(Clone, Default)]
+pub struct MemFile(Rc<RefCell<Vec<u8>>>);
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemSink, Sink};
+    use std::io::Write;
+
+    #[test]
+    fn test_mem_sink() {
+        let mut s = MemSink::new();
+        let mut f = s.create(0).unwrap();
+        f.write_all(b"abc").unwrap();
+        assert_eq!(s.size(&f).unwrap(), 3);
+        s.rename(0, 1).unwrap();
+        assert!(!s.exists(0));
+        // Writes through an open file follow it across renames.
+        f.write_all(b"d").unwrap();
+        assert_eq!(s.contents(1).unwrap(), b"abcd");
+        s.create(0).unwrap();
+        s.rename(0, 1).unwrap();
+        assert_eq!(s.contents(1).unwrap(), b"");
+        assert_eq!(s.indices(), vec![1]);
+        assert!(s.rename(0, 1).is_err());
+    }
+}
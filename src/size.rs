@@ -0,0 +1,148 @@
+//! Sizes in bytes: parsing them, and deriving the file size (`-s`) and number of files (`-n`) from
+//! a retention budget (`--retain`).
+
+/// The smallest file size that `retain_layout()` chooses.
+pub const MIN_RETAIN_FILE_SIZE: usize = 4096;
+
+/// The fewest files that `retain_layout()` aims for when it chooses both the file size and the
+/// number of files. It aims for at most twice as many.
+const RETAIN_FILES: usize = 16;
+
+/// Parse a number of bytes with an optional `K`, `M`, or `G` suffix, for powers of 1024 (e.g.
+/// `16M`).
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (n, mul) = match s.char_indices().last()? {
+        (i, 'K' | 'k') => (&s[..i], 1 << 10),
+        (i, 'M' | 'm') => (&s[..i], 1 << 20),
+        (i, 'G' | 'g') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    n.parse::<usize>().ok()?.checked_mul(mul)
+}
+
+/// Choose a `(file size, number of files)` pair that keeps at most `budget` bytes of output
+/// (counting the active file), pinning whichever of `file_size` and `num_files` is given:
+///
+///   * given neither, the file size is the largest power of 2 that gives at least
+///     `RETAIN_FILES` files (i.e. `RETAIN_FILES` to `2 * RETAIN_FILES - 1` files), but at least
+///     `MIN_RETAIN_FILE_SIZE`;
+///   * given the file size, as many files as fit in the budget;
+///   * given the number of files, the budget divided between them, rounded down to a multiple of
+///     `MIN_RETAIN_FILE_SIZE`.
+///
+/// Combinations that can't be met (e.g. a budget too small for even one file, or one that would
+/// make files smaller than `MIN_RETAIN_FILE_SIZE`) are errors.
+pub fn retain_layout(
+    budget: usize,
+    file_size: Option<usize>,
+    num_files: Option<usize>,
+) -> Result<(usize, usize), String> {
+    match (file_size, num_files) {
+        (Some(_), Some(_)) => Err("--retain can't be used with both -s and -n".to_owned()),
+        (Some(0), _) => Err("--retain can't be used with an unlimited file size (-s 0)".to_owned()),
+        (_, Some(0)) => Err("number of files (-n) must be non-zero".to_owned()),
+        (None, None) => {
+            if budget < MIN_RETAIN_FILE_SIZE {
+                return Err(format!(
+                    "--retain must be at least {} bytes",
+                    MIN_RETAIN_FILE_SIZE
+                ));
+            }
+            let target = budget / RETAIN_FILES;
+            let size = match target.checked_ilog2() {
+                Some(l) => usize::max(1 << l, MIN_RETAIN_FILE_SIZE),
+                None => MIN_RETAIN_FILE_SIZE,
+            };
+            Ok((size, budget / size))
+        }
+        (Some(s), None) => match budget / s {
+            0 => Err(format!(
+                "--retain ({} bytes) is smaller than the file size (-s {})",
+                budget, s
+            )),
+            n => Ok((s, n)),
+        },
+        (None, Some(n)) => {
+            let size = budget / n / MIN_RETAIN_FILE_SIZE * MIN_RETAIN_FILE_SIZE;
+            if size == 0 {
+                return Err(format!(
+                    "--retain ({} bytes) split between {} files (-n) makes files smaller than {} bytes",
+                    budget, n, MIN_RETAIN_FILE_SIZE
+                ));
+            }
+            Ok((size, n))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, retain_layout, MIN_RETAIN_FILE_SIZE, RETAIN_FILES};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("3k"), Some(3072));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("1T"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size(&format!("{}G", usize::MAX)), None);
+    }
+
+    #[test]
+    fn test_retain_layout() {
+        assert_eq!(retain_layout(5 << 30, None, None), Ok((256 << 20, 20)));
+        assert_eq!(retain_layout(1 << 30, None, None), Ok((64 << 20, 16)));
+        assert_eq!(retain_layout(10_000, None, None), Ok((4096, 2)));
+        assert_eq!(retain_layout(4096, None, None), Ok((4096, 1)));
+        assert!(retain_layout(4095, None, None).is_err());
+        assert_eq!(
+            retain_layout(5 << 30, Some(1 << 30), None),
+            Ok((1 << 30, 5))
+        );
+        assert!(retain_layout(1 << 20, Some(2 << 20), None).is_err());
+        assert_eq!(
+            retain_layout(5 << 30, None, Some(10)),
+            Ok((536_870_912, 10))
+        );
+        assert_eq!(retain_layout(1_000_000, None, Some(3)), Ok((331_776, 3)));
+        assert!(retain_layout(1 << 20, None, Some(1000)).is_err());
+        assert!(retain_layout(1 << 30, Some(1 << 20), Some(8)).is_err());
+        assert!(retain_layout(1 << 30, Some(0), None).is_err());
+        assert!(retain_layout(1 << 30, None, Some(0)).is_err());
+    }
+
+    proptest! {
+        /// Whatever is derived fits in the budget, without wasting more than a file's worth of it
+        /// (or, when only the number of files is pinned, a file's rounding each).
+        #[test]
+        fn prop_retain_layout(
+            budget in 0usize..(1 << 40),
+            size in 0usize..(1 << 32),
+            num in 0usize..10_000,
+        ) {
+            // 0 stands for not given.
+            let (size, num) = ((size > 0).then_some(size), (num > 0).then_some(num));
+            if size.is_some() && num.is_some() {
+                return Ok(());
+            }
+            if let Ok((s, n)) = retain_layout(budget, size, num) {
+                prop_assert!(s > 0 && n > 0);
+                prop_assert!(s * n <= budget);
+                prop_assert!(size.is_none_or(|x| x == s));
+                prop_assert!(num.is_none_or(|x| x == n));
+                match (size, num) {
+                    (None, None) => {
+                        prop_assert!(s >= MIN_RETAIN_FILE_SIZE && s.is_power_of_two());
+                        prop_assert!(s == MIN_RETAIN_FILE_SIZE || (RETAIN_FILES..2 * RETAIN_FILES).contains(&n));
+                        prop_assert!(budget - s * n < s);
+                    }
+                    (Some(_), None) => prop_assert!(budget - s * n < s),
+                    _ => prop_assert!(budget - s * n < n * MIN_RETAIN_FILE_SIZE),
+                }
+            }
+        }
+    }
+}
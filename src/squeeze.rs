@@ -0,0 +1,150 @@
+//! Squeezing runs of identical lines (`--squeeze-repeats`) into a single line and a summary.
+
+/// Reassembles lines from chunks of input, passing each line through unless it is the same as the
+/// previous one, in which case it is counted. When a run of repeats ends, a summary line giving the
+/// count is output.
+pub struct Squeezer {
+    /// The longest line (including its newline) that is remembered: longer lines are passed
+    /// through as they arrive, and never count as repeats.
+    cap: usize,
+    /// The previous complete line, if it can be compared against.
+    last: Option<Vec<u8>>,
+    /// The (first `cap` bytes of the) incomplete line read so far.
+    line: Vec<u8>,
+    /// Is the incomplete line longer than `cap`, and so being passed through?
+    overflow: bool,
+    /// The number of times `last` has been repeated since it (or the last summary) was output.
+    repeats: usize,
+}
+
+impl Squeezer {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            cap,
+            last: None,
+            line: Vec::new(),
+            overflow: false,
+            repeats: 0,
+        }
+    }
+
+    /// The number of repeats not yet summarised.
+    pub fn repeats(&self) -> usize {
+        self.repeats
+    }
+
+    /// Feed `bytes`, appending what should be output to `out`.
+    pub fn feed(&mut self, mut bytes: &[u8], out: &mut Vec<u8>) {
+        while !bytes.is_empty() {
+            let (part, eol) = match bytes.iter().position(|b| *b == b'\n') {
+                Some(i) => (&bytes[..=i], true),
+                None => (bytes, false),
+            };
+            bytes = &bytes[part.len()..];
+            if self.overflow {
+                out.extend_from_slice(part);
+                self.overflow = !eol;
+                continue;
+            }
+            self.line.extend_from_slice(part);
+            if eol {
+                if self.last.as_ref() == Some(&self.line) {
+                    self.repeats += 1;
+                    self.line.clear();
+                } else {
+                    self.summary(out);
+                    out.extend_from_slice(&self.line);
+                    self.last = Some(std::mem::take(&mut self.line));
+                }
+            } else if self.line.len() > self.cap {
+                self.summary(out);
+                out.append(&mut self.line);
+                self.last = None;
+                self.overflow = true;
+            }
+        }
+    }
+
+    /// If there are repeats not yet summarised, append a summary of them to `out`. Later repeats
+    /// of the same line are counted afresh.
+    pub fn summary(&mut self, out: &mut Vec<u8>) {
+        if self.repeats > 0 {
+            let s = format!(
+                "-- previous line repeated {} time{} --\n",
+                self.repeats,
+                if self.repeats == 1 { "" } else { "s" }
+            );
+            out.extend_from_slice(s.as_bytes());
+            self.repeats = 0;
+        }
+    }
+
+    /// Summarise any repeats, and forget the previous line, so that the next line is output in
+    /// full even if it is the same (e.g. because it will be in a different file).
+    pub fn forget(&mut self, out: &mut Vec<u8>) {
+        self.summary(out);
+        self.last = None;
+    }
+
+    /// At the end of input: summarise any repeats, and append any incomplete line to `out`.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        self.forget(out);
+        out.append(&mut self.line);
+        self.overflow = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Squeezer;
+
+    fn squeeze(s: &mut Squeezer, input: &[&[u8]]) -> String {
+        let mut out = Vec::new();
+        for i in input {
+            s.feed(i, &mut out);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_squeeze() {
+        let mut s = Squeezer::new(100);
+        assert_eq!(
+            squeeze(&mut s, &[b"a\na\na\nb\n", b"b", b"\nb\nc\nc"]),
+            concat!(
+                "a\n",
+                "-- previous line repeated 2 times --\n",
+                "b\n",
+                "-- previous line repeated 2 times --\n",
+                "c\n"
+            )
+        );
+        // An incomplete line isn't a repeat until it's complete.
+        assert_eq!(s.repeats(), 0);
+        assert_eq!(squeeze(&mut s, &[b"\nc\n"]), "");
+        assert_eq!(s.repeats(), 2);
+        let mut out = Vec::new();
+        s.summary(&mut out);
+        assert_eq!(out, b"-- previous line repeated 2 times --\n");
+        assert_eq!(squeeze(&mut s, &[b"c\n"]), "");
+        assert_eq!(s.repeats(), 1);
+        let mut out = Vec::new();
+        s.forget(&mut out);
+        assert_eq!(out, b"-- previous line repeated 1 time --\n");
+        // After forgetting, a repeat is output in full.
+        assert_eq!(squeeze(&mut s, &[b"c\nc\nd"]), "c\n");
+        let mut out = Vec::new();
+        s.finish(&mut out);
+        assert_eq!(out, b"-- previous line repeated 1 time --\nd");
+    }
+
+    #[test]
+    fn test_squeeze_cap() {
+        let mut s = Squeezer::new(4);
+        // Long lines are passed through as they arrive, and never squeezed.
+        assert_eq!(squeeze(&mut s, &[b"abc", b"de"]), "abcde");
+        assert_eq!(squeeze(&mut s, &[b"f\nabcdef\n"]), "f\nabcdef\n");
+        assert_eq!(squeeze(&mut s, &[b"abc\nabc\n"]), "abc\n");
+        assert_eq!(s.repeats(), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! The subcommands (e.g. `rotee ls`), which work on an existing series rather than writing one,
+//! and what they share: finding and opening the files of a series.
+
+pub mod cat;
+pub mod decrypt;
+pub mod locate;
+pub mod ls;
+pub mod pack;
+pub mod prune;
+pub mod replay_timing;
+pub mod selftest;
+pub mod tail;
+
+use crate::{commit_journal_path, outfile_path, prefix_dir, Flock};
+use std::{
+    error::Error,
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+/// How many times `rotee cat` tries to open the files of a series that a rotation is changing.
+pub const CAT_ATTEMPTS: usize = 100;
+
+/// Open the files of the series under `prefix`, indexed by their number, with `None` for any
+/// missing below the highest. A running rotee may rotate the files while they are being opened,
+/// so they are opened again until the files opened are those at their paths once all are open,
+/// and, if any are missing (as they are mid-rotation), the same as were opened last time (or until
+/// too many attempts have been made). Nor are they taken while a commit (see `--staging-prefix`)
+/// is changing the series. With `--shared`, the series' lock is held meanwhile.
+pub fn open_series_files(prefix: &str) -> Result<Vec<Option<File>>, Box<dyn Error>> {
+    let lock = open_existing_lock(prefix)?;
+    let _held = lock.as_ref().map(Flock::exclusive).transpose()?;
+    let mut files = Vec::new();
+    let mut last_ids = None;
+    for _ in 0..CAT_ATTEMPTS {
+        let mut max = None;
+        for rest in series_names(prefix)? {
+            if let Some(n) = rest
+                .strip_suffix(b".age")
+                .filter(|n| series_number(n).is_some())
+            {
+                return Err(format!(
+                    "{}{} is encrypted: decrypt it with rotee decrypt",
+                    prefix,
+                    String::from_utf8_lossy(n)
+                )
+                .into());
+            }
+            max = max.max(series_number(&rest));
+        }
+        let Some(max) = max else {
+            return Err(format!("there are no files under {}", prefix).into());
+        };
+        files = (0..=max)
+            .map(|i| match File::open(outfile_path(prefix, i)) {
+                Ok(f) => Ok(Some(f)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let ids = files
+            .iter()
+            .map(|f| {
+                f.as_ref()
+                    .map(|f| f.metadata().map(|m| (m.dev(), m.ino())))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let unchanged = (0..=max + 1).all(|i| {
+            match (
+                ids.get(i).copied().flatten(),
+                fs::metadata(outfile_path(prefix, i)),
+            ) {
+                (Some(id), Ok(p)) => id == (p.dev(), p.ino()),
+                (None, Err(e)) => e.kind() == io::ErrorKind::NotFound,
+                _ => false,
+            }
+        });
+        let committing = commit_journal_path(prefix).exists();
+        if unchanged
+            && !committing
+            && (ids.iter().all(Option::is_some) || last_ids.as_ref() == Some(&ids))
+        {
+            break;
+        }
+        last_ids = Some(ids);
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(files)
+}
+
+/// The lock file of the series under `prefix`, if there is one (its writers use `--shared`).
+pub fn open_existing_lock(prefix: &str) -> Result<Option<File>, io::Error> {
+    match File::open(format!("{}lock", prefix)) {
+        Ok(f) => Ok(Some(f)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// What follows `prefix` in the names of the files in its directory that start with it.
+pub fn series_names(prefix: &str) -> Result<Vec<Vec<u8>>, io::Error> {
+    let base = Path::new(prefix)
+        .file_name()
+        .map_or(&b""[..], |n| n.as_bytes());
+    let mut names = Vec::new();
+    for e in fs::read_dir(prefix_dir(prefix))? {
+        if let Some(rest) = e?.file_name().as_bytes().strip_prefix(base) {
+            names.push(rest.to_vec());
+        }
+    }
+    Ok(names)
+}
+
+/// If `s` is a number as rotee names files (without leading zeros), that number.
+pub fn series_number(s: &[u8]) -> Option<usize> {
+    let n = std::str::from_utf8(s).ok()?.parse::<usize>().ok()?;
+    (n.to_string().as_bytes() == s).then_some(n)
+}
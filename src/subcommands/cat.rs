@@ -0,0 +1,177 @@
+//! `rotee cat`: write the files of a series to stdout, oldest first.
+
+use super::open_series_files;
+use crate::outfile_path;
+use rotee::diag;
+use std::{
+    error::Error,
+    io::{self, Write},
+};
+
+const CAT_USAGE: &str = "usage: rotee cat [-p PREFIX] [--newest N | --oldest N]";
+
+/// `rotee cat [-p PREFIX] [--newest N | --oldest N]`: write the files of the series under `PREFIX`
+/// (by default `rotee.`), or only the newest or oldest `N` of them, to stdout, oldest first.
+pub fn cat(args: &[String]) -> i32 {
+    match cat_series(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            diag::error(&e);
+            1
+        }
+    }
+}
+
+fn cat_series(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut prefix = String::from("rotee.");
+    let (mut newest, mut oldest) = (None, None);
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        let mut value = || args.next().ok_or(CAT_USAGE);
+        match a.as_str() {
+            "-p" | "--file-prefix" => prefix = value()?.clone(),
+            "--newest" => newest = Some(value()?.parse::<usize>().map_err(|_| CAT_USAGE)?),
+            "--oldest" => oldest = Some(value()?.parse::<usize>().map_err(|_| CAT_USAGE)?),
+            _ => return Err(CAT_USAGE.into()),
+        }
+    }
+    let mut files = open_series_files(&prefix)?;
+    let range = match (newest, oldest) {
+        (None, None) => 0..files.len(),
+        (Some(n), None) => 0..n.min(files.len()),
+        (None, Some(n)) => files.len().saturating_sub(n)..files.len(),
+        (Some(_), Some(_)) => return Err(CAT_USAGE.into()),
+    };
+    let missing = range
+        .clone()
+        .filter(|&i| files[i].is_none())
+        .map(|i| outfile_path(&prefix, i).display().to_string())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!("missing from the series: {}", missing.join(", ")).into());
+    }
+    let mut out = io::stdout().lock();
+    for f in files[range].iter_mut().rev() {
+        match io::copy(f.as_mut().unwrap(), &mut out) {
+            // The reader has gone (e.g. `rotee cat | head`).
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            r => r?,
+        };
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use std::{
+        env, fs,
+        io::Write,
+        path::PathBuf,
+        process::{Command, Stdio},
+        thread,
+        time::Duration,
+    };
+    use tempfile::TempDir;
+
+    /// `rotee cat` reassembles what was captured, however the files were written, and even while
+    /// they are being rotated.
+    #[test]
+    fn test_cat() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let input = (0..1000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let cat = |dir: &TempDir, args: &[&str]| {
+            Command::new(&p)
+                .current_dir(dir.path())
+                .arg("cat")
+                .args(args)
+                .output()
+                .unwrap()
+        };
+
+        for (args, prefix) in [
+            (&["-s", "100"][..], "rotee."),
+            (&["-s", "100", "--buffer-files"], "rotee."),
+            (&["-s", "64", "-m", "64", "-l"], "rotee."),
+            (&["-s", "100", "-p", "a/b/log-"], "a/b/log-"),
+            (&["-s", "100", "--shared"], "rotee."),
+        ] {
+            let dir = TempDir::new().unwrap();
+            let mut child = Command::new(&p)
+                .current_dir(dir.path())
+                .stdin(Stdio::piped())
+                .args(["-e", "-n", "1000"])
+                .args(args)
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(&input).unwrap();
+            assert!(child.wait().unwrap().success());
+            let out = cat(&dir, &["-p", prefix]);
+            assert!(out.status.success(), "{:?}", args);
+            assert_eq!(out.stdout, input, "{:?}", args);
+        }
+
+        let dir = TempDir::new().unwrap();
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "-s", "100", "-n", "3"])
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&input).unwrap();
+        assert!(child.wait().unwrap().success());
+        // Only the newest files are left.
+        let tail = |n: usize| &input[input.len() - n..];
+        let all = fs::metadata(dir.path().join("rotee.0")).unwrap().len() as usize + 200;
+        assert_eq!(cat(&dir, &[]).stdout, tail(all));
+        assert_eq!(cat(&dir, &["--oldest", "2"]).stdout, &tail(all)[..200]);
+        assert_eq!(cat(&dir, &["--newest", "2"]).stdout, tail(all - 100));
+        fs::remove_file(dir.path().join("rotee.1")).unwrap();
+        let out = cat(&dir, &[]);
+        assert!(!out.status.success());
+        assert!(out.stdout.is_empty());
+        assert_eq!(
+            String::from_utf8(out.stderr).unwrap(),
+            "error: missing from the series: rotee.1\n"
+        );
+        assert_eq!(cat(&dir, &["--oldest", "1"]).stdout, &tail(all)[..100]);
+
+        // While the files are being rotated, what's read is always everything written so far.
+        let dir = TempDir::new().unwrap();
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "-s", "10", "-n", "1000"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let input = input[..2000].to_vec();
+        let writer = thread::spawn(move || {
+            for c in input.chunks(7) {
+                stdin.write_all(c).unwrap();
+                thread::sleep(Duration::from_micros(100));
+            }
+            input
+        });
+        let mut reads = Vec::new();
+        while !writer.is_finished() {
+            let out = cat(&dir, &[]);
+            if out.status.success() {
+                reads.push(out.stdout);
+            }
+        }
+        let input = writer.join().unwrap();
+        assert!(child.wait().unwrap().success());
+        assert!(!reads.is_empty());
+        for r in reads {
+            assert!(input.starts_with(&r));
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! `rotee decrypt`: decrypt a sealed file of a series written with `--encrypt-recipient`.
+
+use rotee::diag;
+use std::process::Command;
+
+/// `rotee decrypt IDENTITY FILE`: decrypt the sealed file `FILE` to stdout with the age identity
+/// file `IDENTITY`, by running `age`.
+pub fn decrypt(args: &[String]) -> i32 {
+    let [identity, file] = args else {
+        diag::emit(format_args!("usage: rotee decrypt <identity file> <file>"));
+        return 1;
+    };
+    match Command::new("age")
+        .args(["--decrypt", "--identity", identity, file])
+        .status()
+    {
+        Ok(s) => s.code().unwrap_or(1),
+        Err(e) => {
+            diag::error(&format_args!("can't run age: {}", e));
+            1
+        }
+    }
+}
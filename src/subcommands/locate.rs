@@ -0,0 +1,236 @@
+//! `rotee locate`: find the file of a series that holds a given byte of the stream, or that was
+//! being written at a given time.
+
+use super::{ls::series_members, open_existing_lock};
+use crate::Flock;
+use log::warn;
+use rotee::{
+    clock::{parse_timestamp, utc_timestamp},
+    diag,
+    size::parse_size,
+};
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Read, Seek, Write},
+};
+
+const LOCATE_USAGE: &str =
+    "usage: rotee locate [-p PREFIX] (--offset N [--extract BYTES] | --time TIMESTAMP)";
+
+/// `rotee locate [-p PREFIX] (--offset N [--extract BYTES] | --time TIMESTAMP)`: print the file of
+/// the series under `PREFIX` (by default `rotee.`) that holds byte `N` of the captured stream, and
+/// where in that file it is, or the file that was being written at `TIMESTAMP` (RFC 3339). With
+/// `--extract`, write `BYTES` bytes of the stream, starting at `N`, to stdout instead.
+pub fn locate(args: &[String]) -> i32 {
+    match locate_in_series(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            diag::error(&e);
+            1
+        }
+    }
+}
+
+fn locate_in_series(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut prefix = String::from("rotee.");
+    let (mut offset, mut time, mut extract) = (None, None, None);
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        let mut value = || args.next().ok_or(LOCATE_USAGE);
+        match a.as_str() {
+            "-p" | "--file-prefix" => prefix = value()?.clone(),
+            "--offset" => match parse_size(value()?) {
+                Some(n) => offset = Some(n as u64),
+                None => return Err(LOCATE_USAGE.into()),
+            },
+            "--time" => {
+                let v = value()?;
+                match parse_timestamp(v) {
+                    Some(t) => time = Some(t),
+                    None => return Err(format!("invalid timestamp '{}'", v).into()),
+                }
+            }
+            "--extract" => match parse_size(value()?) {
+                Some(n) => extract = Some(n as u64),
+                None => return Err(LOCATE_USAGE.into()),
+            },
+            _ => return Err(LOCATE_USAGE.into()),
+        }
+    }
+
+    let lock = open_existing_lock(&prefix)?;
+    let _held = lock.as_ref().map(Flock::exclusive).transpose()?;
+    let members = series_members(&prefix)?;
+    let present = members.iter().filter(|m| m.meta.is_some());
+    match (offset, time, extract) {
+        (Some(n), None, _) => {
+            warn!(
+                "there's no index of the series, so offsets are counted from the start of its oldest remaining file, and are out by the size of any files that have been removed"
+            );
+            // Where each file ends in the stream, up to the first encrypted file, whose size
+            // isn't that of what it holds.
+            let mut ends = Vec::new();
+            let mut end = 0;
+            let mut encrypted = None;
+            for m in present {
+                if m.encrypted {
+                    encrypted = Some(m);
+                    break;
+                }
+                end += m.meta.unwrap().0;
+                ends.push((end, m));
+            }
+            let i = ends.partition_point(|&(end, _)| end <= n);
+            let Some(&(end, m)) = ends.get(i) else {
+                return Err(match encrypted {
+                    Some(m) => format!(
+                        "{} is encrypted, so offsets from {} on can't be located: decrypt it with rotee decrypt",
+                        m.path.display(),
+                        end
+                    ),
+                    None => format!(
+                        "offset {} is beyond the end of the series ({} bytes)",
+                        n, end
+                    ),
+                }
+                .into());
+            };
+            let local = n - (end - m.meta.unwrap().0);
+            let Some(len) = extract else {
+                println!("{} {}", m.path.display(), local);
+                return Ok(());
+            };
+            let mut out = io::stdout().lock();
+            let (mut left, mut from) = (len, local);
+            for &(_, m) in &ends[i..] {
+                if left == 0 {
+                    break;
+                }
+                let mut f = File::open(&m.path)?;
+                f.seek(io::SeekFrom::Start(from))?;
+                match io::copy(&mut f.take(left), &mut out) {
+                    // The reader has gone (e.g. `rotee locate ... | head`).
+                    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                    r => left -= r?,
+                }
+                from = 0;
+            }
+            out.flush()?;
+            Ok(())
+        }
+        (None, Some(t), None) => {
+            // Each file was written from the last write to the one before it (or, for the
+            // oldest, some time before that) to its modification time.
+            let present = present.collect::<Vec<_>>();
+            let i = present.partition_point(|m| m.meta.unwrap().1 < t);
+            match present.get(i) {
+                Some(m) if m.encrypted => println!("{} (encrypted)", m.path.display()),
+                Some(m) => println!("{}", m.path.display()),
+                None => {
+                    return Err(format!(
+                        "{} is after the last write to the series",
+                        utc_timestamp(t, false)
+                    )
+                    .into())
+                }
+            }
+            Ok(())
+        }
+        _ => Err(LOCATE_USAGE.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use std::{
+        env,
+        fs::{self, File},
+        io::Write,
+        path::PathBuf,
+        process::Command,
+        time::{Duration, UNIX_EPOCH},
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_locate() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let t = UNIX_EPOCH + Duration::from_secs(1_717_243_200); // 2024-06-01T12:00:00Z.
+        for (i, contents) in ["ccccc", "bbbbbbbbbb", "", "aaaaaaaaaa"].iter().enumerate() {
+            let f = File::create(dir.path().join(format!("log.{}", i))).unwrap();
+            (&f).write_all(contents.as_bytes()).unwrap();
+            f.set_modified(t + Duration::from_secs(60 * (3 - i as u64)))
+                .unwrap();
+        }
+        let locate = |args: &[&str]| {
+            let out = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["locate", "-p", "log."])
+                .args(args)
+                .output()
+                .unwrap();
+            (
+                out.status.success(),
+                String::from_utf8(out.stdout).unwrap(),
+                String::from_utf8(out.stderr).unwrap(),
+            )
+        };
+
+        // Each file boundary, and either side of it. The empty file holds no offsets.
+        for (n, at) in [
+            ("0", "log.3 0\n"),
+            ("9", "log.3 9\n"),
+            ("10", "log.1 0\n"),
+            ("19", "log.1 9\n"),
+            ("20", "log.0 0\n"),
+            ("24", "log.0 4\n"),
+        ] {
+            let (ok, stdout, stderr) = locate(&["--offset", n]);
+            assert!(ok, "{}", stderr);
+            assert_eq!(stdout, at);
+            assert!(stderr.starts_with("warning: there's no index of the series"));
+        }
+        let (ok, _, stderr) = locate(&["--offset", "25"]);
+        assert!(!ok);
+        assert!(stderr.ends_with("error: offset 25 is beyond the end of the series (25 bytes)\n"));
+
+        assert_eq!(locate(&["--offset", "8", "--extract", "4"]).1, "aabb");
+        assert_eq!(locate(&["--offset", "22", "--extract", "100"]).1, "ccc");
+        assert_eq!(locate(&["--offset", "0", "--extract", "0"]).1, "");
+
+        for (ts, at) in [
+            ("2024-06-01T11:00:00Z", "log.3\n"),
+            ("2024-06-01T12:00:00Z", "log.3\n"),
+            ("2024-06-01T12:00:01Z", "log.2\n"),
+            ("2024-06-01T14:01:30+02:00", "log.1\n"),
+            ("2024-06-01T12:03:00Z", "log.0\n"),
+        ] {
+            assert_eq!(
+                locate(&["--time", ts]),
+                (true, at.to_owned(), String::new())
+            );
+        }
+        assert_eq!(
+            locate(&["--time", "2024-06-01T12:03:01Z"]).2,
+            "error: 2024-06-01T12:03:01Z is after the last write to the series\n"
+        );
+
+        // An encrypted file's size isn't that of what it holds.
+        fs::rename(dir.path().join("log.3"), dir.path().join("log.3.age")).unwrap();
+        let (ok, _, stderr) = locate(&["--offset", "20"]);
+        assert!(!ok);
+        assert!(stderr.ends_with(
+            "error: log.3.age is encrypted, so offsets from 0 on can't be located: decrypt it with rotee decrypt\n"
+        ));
+        assert_eq!(
+            locate(&["--time", "2024-06-01T12:00:00Z"]).1,
+            "log.3.age (encrypted)\n"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! `rotee ls`: list the files of a series.
+
+use super::{series_names, series_number};
+use crate::outfile_path;
+use rotee::{clock::utc_timestamp, diag, json};
+use std::{
+    error::Error,
+    ffi::OsStr,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    time::SystemTime,
+};
+
+const LS_USAGE: &str = "usage: rotee ls [-p PREFIX] [--json] [--reverse]";
+
+/// A member of a series, as listed by `rotee ls`.
+pub struct Member {
+    pub index: usize,
+    pub path: PathBuf,
+    /// The file's size and modification time, or `None` if it is missing (i.e. there's a gap in
+    /// the numbering).
+    pub meta: Option<(u64, SystemTime)>,
+    pub encrypted: bool,
+}
+
+/// `rotee ls [-p PREFIX] [--json] [--reverse]`: list the files of the series under `PREFIX` (by
+/// default `rotee.`), oldest first (or with `--reverse`, newest first), with their sizes and
+/// modification times, flagging gaps in the numbering.
+pub fn ls(args: &[String]) -> i32 {
+    match ls_series(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            diag::error(&e);
+            1
+        }
+    }
+}
+
+fn ls_series(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut prefix = String::from("rotee.");
+    let (mut json, mut reverse) = (false, false);
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "-p" | "--file-prefix" => prefix = args.next().ok_or(LS_USAGE)?.clone(),
+            "--json" => json = true,
+            "--reverse" => reverse = true,
+            _ => return Err(LS_USAGE.into()),
+        }
+    }
+    let mut members = series_members(&prefix)?;
+    if reverse {
+        members.reverse();
+    }
+
+    let out = if json {
+        ls_json(&members)
+    } else {
+        ls_text(&members)
+    };
+    match io::stdout().lock().write_all(out.as_bytes()) {
+        // The reader has gone (e.g. `rotee ls | head`).
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        r => Ok(r?),
+    }
+}
+
+/// The members of the series under `prefix`, oldest (highest numbered) first, including any
+/// missing below the highest.
+pub fn series_members(prefix: &str) -> Result<Vec<Member>, Box<dyn Error>> {
+    let mut members = Vec::new();
+    for n in series_names(prefix)? {
+        let (index, encrypted) = match series_number(&n) {
+            Some(i) => (i, false),
+            None => match n.strip_suffix(b".age").and_then(series_number) {
+                Some(i) => (i, true),
+                None => continue,
+            },
+        };
+        let path = PathBuf::from(OsStr::from_bytes(&[prefix.as_bytes(), &n].concat()));
+        let m = match path.metadata() {
+            Ok(m) => m,
+            // Removed since the directory was read.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        members.push(Member {
+            index,
+            path,
+            meta: Some((m.len(), m.modified()?)),
+            encrypted,
+        });
+    }
+    let Some(max) = members.iter().map(|m| m.index).max() else {
+        return Err(format!("there are no files under {}", prefix).into());
+    };
+    for index in 0..max {
+        if !members.iter().any(|m| m.index == index) {
+            members.push(Member {
+                index,
+                path: outfile_path(prefix, index),
+                meta: None,
+                encrypted: false,
+            });
+        }
+    }
+    members.sort_by(|a, b| b.index.cmp(&a.index).then(a.path.cmp(&b.path)));
+    Ok(members)
+}
+
+/// List `members` one per line, followed by a line of totals.
+fn ls_text(members: &[Member]) -> String {
+    let index_width = members
+        .iter()
+        .map(|m| m.index.to_string().len())
+        .max()
+        .unwrap_or(1);
+    let size_width = members
+        .iter()
+        .filter_map(|m| m.meta.map(|(size, _)| size.to_string().len()))
+        .max()
+        .unwrap_or(1);
+    let mut out = String::new();
+    for m in members {
+        let (size, mtime) = match m.meta {
+            Some((size, mtime)) => (size.to_string(), utc_timestamp(mtime, false)),
+            None => (String::from("-"), String::from("-")),
+        };
+        let note = match (&m.meta, m.index, m.encrypted) {
+            (None, _, _) => " (missing)",
+            (_, _, true) => " (encrypted)",
+            (_, 0, _) => " (active)",
+            _ => "",
+        };
+        out.push_str(&format!(
+            "{:>iw$}  {:>sw$}  {:<20}  {}{}\n",
+            m.index,
+            size,
+            mtime,
+            m.path.display(),
+            note,
+            iw = index_width,
+            sw = size_width
+        ));
+    }
+    let present = members.iter().filter(|m| m.meta.is_some());
+    out.push_str(&format!(
+        "total: {} files, {} bytes",
+        present.clone().count(),
+        present.map(|m| m.meta.unwrap().0).sum::<u64>()
+    ));
+    match members.iter().filter(|m| m.meta.is_none()).count() {
+        0 => out.push('\n'),
+        n => out.push_str(&format!(", {} missing\n", n)),
+    }
+    out
+}
+
+/// List `members` as a JSON array of objects, one per line.
+fn ls_json(members: &[Member]) -> String {
+    let mut out = Vec::new();
+    out.push(b'[');
+    for (i, m) in members.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        out.extend_from_slice(format!("\n{{\"index\":{},\"path\":", m.index).as_bytes());
+        json::escape(&m.path.to_string_lossy(), &mut out);
+        match m.meta {
+            Some((size, mtime)) => {
+                out.extend_from_slice(format!(",\"size\":{},\"mtime\":", size).as_bytes());
+                json::escape(&utc_timestamp(mtime, false), &mut out);
+            }
+            None => out.extend_from_slice(b",\"missing\":true"),
+        }
+        out.extend_from_slice(
+            format!(
+                ",\"encrypted\":{},\"active\":{}}}",
+                m.encrypted,
+                m.index == 0 && m.meta.is_some()
+            )
+            .as_bytes(),
+        );
+    }
+    out.extend_from_slice(b"\n]\n");
+    String::from_utf8(out).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use std::{
+        env,
+        fs::File,
+        path::PathBuf,
+        process::Command,
+        time::{Duration, UNIX_EPOCH},
+    };
+    use tempfile::TempDir;
+
+    /// `rotee ls` lists the files of a series in order, flagging gaps in the numbering.
+    #[test]
+    fn test_ls() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let t = UNIX_EPOCH + Duration::from_secs(1_717_243_200); // 2024-06-01T12:00:00Z.
+        for (name, len) in [
+            ("log.0", 5),
+            ("log.1", 100),
+            ("log.2.age", 150),
+            ("log.4", 1000),
+            ("log.lock", 0),
+            ("other.3", 0),
+        ] {
+            let f = File::create(dir.path().join(name)).unwrap();
+            f.set_len(len).unwrap();
+            f.set_modified(t + Duration::from_secs(60 * (10 - len.min(10))))
+                .unwrap();
+        }
+        let ls = |args: &[&str]| {
+            let out = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["ls", "-p", "log."])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(out.status.success());
+            String::from_utf8(out.stdout).unwrap()
+        };
+
+        assert_eq!(
+            ls(&[]),
+            "4  1000  2024-06-01T12:00:00Z  log.4\n\
+             3     -  -                     log.3 (missing)\n\
+             2   150  2024-06-01T12:00:00Z  log.2.age (encrypted)\n\
+             1   100  2024-06-01T12:00:00Z  log.1\n\
+             0     5  2024-06-01T12:05:00Z  log.0 (active)\n\
+             total: 4 files, 1255 bytes, 1 missing\n"
+        );
+        assert_eq!(
+            ls(&["--reverse"]).lines().next().unwrap(),
+            "0     5  2024-06-01T12:05:00Z  log.0 (active)"
+        );
+        let json = ls(&["--json"]);
+        assert_eq!(
+            json,
+            "[\n\
+             {\"index\":4,\"path\":\"log.4\",\"size\":1000,\"mtime\":\"2024-06-01T12:00:00Z\",\"encrypted\":false,\"active\":false},\n\
+             {\"index\":3,\"path\":\"log.3\",\"missing\":true,\"encrypted\":false,\"active\":false},\n\
+             {\"index\":2,\"path\":\"log.2.age\",\"size\":150,\"mtime\":\"2024-06-01T12:00:00Z\",\"encrypted\":true,\"active\":false},\n\
+             {\"index\":1,\"path\":\"log.1\",\"size\":100,\"mtime\":\"2024-06-01T12:00:00Z\",\"encrypted\":false,\"active\":false},\n\
+             {\"index\":0,\"path\":\"log.0\",\"size\":5,\"mtime\":\"2024-06-01T12:05:00Z\",\"encrypted\":false,\"active\":true}\n\
+             ]\n"
+        );
+        serde_json::from_str::<serde_json::Value>(&json).unwrap();
+
+        let out = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["ls", "-p", "none."])
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        assert_eq!(
+            String::from_utf8(out.stderr).unwrap(),
+            "error: there are no files under none.\n"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! `rotee pack`: renumber the rotated files of a series so that there are no gaps in their
+//! numbering.
+
+use super::{open_existing_lock, series_names, series_number};
+use crate::{prefix_dir, Flock};
+use rotee::{diag, temp};
+use std::{
+    error::Error,
+    fs::{self, rename, File},
+    io::{self, Write},
+};
+
+const PACK_USAGE: &str = "usage: rotee pack [-p PREFIX] [--dry-run] [--sync]";
+
+/// `rotee pack [-p PREFIX] [--dry-run] [--sync]`: renumber the rotated files of the series under
+/// `PREFIX` (by default `rotee.`) so that there are no gaps in their numbering, and remove stale
+/// temporary files from its directory, printing each change (or with `--dry-run`, only printing
+/// them). With `--sync`, make the changes durable.
+pub fn pack(args: &[String]) -> i32 {
+    match pack_series(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            diag::error(&e);
+            1
+        }
+    }
+}
+
+fn pack_series(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut prefix = String::from("rotee.");
+    let (mut dry_run, mut sync) = (false, false);
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "-p" | "--file-prefix" => prefix = args.next().ok_or(PACK_USAGE)?.clone(),
+            "--dry-run" => dry_run = true,
+            "--sync" => sync = true,
+            _ => return Err(PACK_USAGE.into()),
+        }
+    }
+
+    let lock = open_existing_lock(&prefix)?;
+    let _held = lock.as_ref().map(Flock::exclusive).transpose()?;
+    // The rotated files (never the active file, 0), plain or encrypted, by index.
+    let mut files = series_names(&prefix)?
+        .iter()
+        .filter_map(|n| {
+            let (i, suffix) = match series_number(n) {
+                Some(i) => (i, ""),
+                None => (series_number(n.strip_suffix(b".age")?)?, ".age"),
+            };
+            (i > 0).then_some((i, suffix))
+        })
+        .collect::<Vec<_>>();
+    files.sort_unstable();
+    if let Some(w) = files.windows(2).find(|w| w[0].0 == w[1].0) {
+        return Err(format!(
+            "both {}{} and {}{}.age exist: remove one of them",
+            prefix, w[0].0, prefix, w[0].0
+        )
+        .into());
+    }
+
+    let mut out = io::stdout().lock();
+    // Each file is moved down to the lowest free number, lowest first, so no rename ever replaces
+    // a file: if we're interrupted, nothing is lost, and packing again finishes the job.
+    for (to, &(from, suffix)) in (1..).zip(&files) {
+        if to == from {
+            continue;
+        }
+        let from = format!("{}{}{}", prefix, from, suffix);
+        let to = format!("{}{}{}", prefix, to, suffix);
+        writeln!(out, "rename {} {}", from, to)?;
+        if dry_run {
+            continue;
+        }
+        if fs::symlink_metadata(&to).is_ok() {
+            return Err(format!("can't rename {} to {}: it already exists", from, to).into());
+        }
+        rename(&from, &to)?;
+    }
+    for p in temp::find_stale(prefix_dir(&prefix))? {
+        writeln!(out, "remove {}", p.display())?;
+        if dry_run {
+            continue;
+        }
+        match fs::remove_file(&p) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    out.flush()?;
+    if sync && !dry_run {
+        File::open(prefix_dir(&prefix))?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use std::{env, fs, path::PathBuf, process::Command};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pack() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let pack = |args: &[&str]| {
+            let out = Command::new(&p)
+                .current_dir(dir.path())
+                .args(["pack", "-p", "log."])
+                .args(args)
+                .output()
+                .unwrap();
+            (
+                out.status.success(),
+                String::from_utf8(out.stdout).unwrap(),
+                String::from_utf8(out.stderr).unwrap(),
+            )
+        };
+        let layout = || {
+            let mut names = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| {
+                    let e = e.unwrap();
+                    let name = e.file_name().into_string().unwrap();
+                    let contents = fs::read_to_string(e.path()).unwrap();
+                    format!("{}={}", name, contents)
+                })
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        // Gaps left by a crash, an encrypted file, and a stale temporary file (no process has a
+        // pid this large).
+        for name in [
+            "log.0",
+            "log.2",
+            "log.5",
+            "log.6.age",
+            ".log.3.tmp.4194305.0",
+            "other.3",
+        ] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+        let before = layout();
+        let actions = "rename log.2 log.1\n\
+                       rename log.5 log.2\n\
+                       rename log.6.age log.3.age\n\
+                       remove ./.log.3.tmp.4194305.0\n";
+        assert_eq!(
+            pack(&["--dry-run"]),
+            (true, actions.to_owned(), String::new())
+        );
+        assert_eq!(layout(), before);
+        assert_eq!(pack(&["--sync"]), (true, actions.to_owned(), String::new()));
+        assert_eq!(
+            layout(),
+            [
+                "log.0=log.0",
+                "log.1=log.2",
+                "log.2=log.5",
+                "log.3.age=log.6.age",
+                "other.3=other.3"
+            ]
+        );
+        // A packed series is left as it is.
+        assert_eq!(pack(&[]), (true, String::new(), String::new()));
+
+        // Files missing from the bottom of the series, with the active file missing too.
+        fs::remove_file(dir.path().join("log.0")).unwrap();
+        fs::rename(dir.path().join("log.1"), dir.path().join("log.7")).unwrap();
+        fs::rename(dir.path().join("log.2"), dir.path().join("log.4")).unwrap();
+        assert_eq!(
+            pack(&[]).1,
+            "rename log.3.age log.1.age\nrename log.4 log.2\nrename log.7 log.3\n"
+        );
+        assert_eq!(
+            layout(),
+            [
+                "log.1.age=log.6.age",
+                "log.2=log.5",
+                "log.3=log.2",
+                "other.3=other.3"
+            ]
+        );
+
+        // Which of a plain and an encrypted file with the same number to keep is up to the user.
+        fs::write(dir.path().join("log.2.age"), "").unwrap();
+        let (ok, stdout, stderr) = pack(&[]);
+        assert!(!ok && stdout.is_empty());
+        assert_eq!(
+            stderr,
+            "error: both log.2 and log.2.age exist: remove one of them\n"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! `rotee prune`: remove the rotated files of a series that a writer with given retention options
+//! would.
+
+use super::{open_existing_lock, series_names, series_number};
+use crate::{parse_duration, Flock};
+use rotee::{diag, thin::Thinning};
+use std::{
+    error::Error, ffi::OsStr, fs, io, os::unix::ffi::OsStrExt, path::PathBuf, time::SystemTime,
+};
+
+const PRUNE_USAGE: &str =
+    "usage: rotee prune [-p PREFIX] [-n NUM | --thin SPEC] [--max-age DURATION] [--dry-run]";
+
+/// `rotee prune [-p PREFIX] [-n NUM | --thin SPEC] [--max-age DURATION] [--dry-run]`: remove the
+/// rotated files of the series under `PREFIX` (by default `rotee.`) that a writer with the given
+/// retention options would, oldest first, or with `--dry-run`, list them. Exits with 0 if any
+/// files were (or would be) removed, 1 if none were, and 2 on error.
+pub fn prune(args: &[String]) -> i32 {
+    match prune_series(args) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            diag::error(&e);
+            2
+        }
+    }
+}
+
+fn prune_series(args: &[String]) -> Result<bool, Box<dyn Error>> {
+    let mut prefix = String::from("rotee.");
+    let (mut num_files, mut max_age, mut thinning, mut dry_run) = (None, None, None, false);
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        let mut value = || args.next().ok_or(PRUNE_USAGE);
+        match a.as_str() {
+            "-p" | "--file-prefix" => prefix = value()?.clone(),
+            "-n" | "--num-files" => match value()?.parse::<usize>() {
+                Ok(0) => return Err("number of files (-n) must be non-zero".into()),
+                Ok(n) => num_files = Some(n),
+                Err(_) => return Err(PRUNE_USAGE.into()),
+            },
+            "--max-age" => match parse_duration(value()?)? {
+                d if d.is_zero() => return Err("--max-age must be non-zero".into()),
+                d => max_age = Some(d),
+            },
+            "--thin" => thinning = Some(Thinning::parse(value()?)?),
+            "--dry-run" => dry_run = true,
+            _ => return Err(PRUNE_USAGE.into()),
+        }
+    }
+    if num_files.is_some() && thinning.is_some() {
+        return Err(PRUNE_USAGE.into());
+    }
+    if num_files.is_none() && max_age.is_none() && thinning.is_none() {
+        return Err("nothing to prune by: give -n, --max-age, or --thin".into());
+    }
+
+    let lock = open_existing_lock(&prefix)?;
+    let _held = lock.as_ref().map(Flock::exclusive).transpose()?;
+    // The rotated files (never the active file, 0), plain or encrypted, by index.
+    let mut files = series_names(&prefix)?
+        .iter()
+        .filter_map(|n| {
+            let i = series_number(n).or_else(|| series_number(n.strip_suffix(b".age")?))?;
+            (i > 0).then(|| {
+                (
+                    i,
+                    PathBuf::from(OsStr::from_bytes(&[prefix.as_bytes(), n].concat())),
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    files.sort_unstable();
+
+    // As the writer applies them: `-n`, then `--max-age`, then `--thin` on what's left.
+    let mut remove = files
+        .iter()
+        .filter(|(i, _)| num_files.is_some_and(|n| *i >= n))
+        .map(|&(i, _)| i)
+        .collect::<Vec<_>>();
+    if let Some(max_age) = max_age {
+        let now = SystemTime::now();
+        for (i, p) in &files {
+            let modified = p.metadata()?.modified()?;
+            if now.duration_since(modified).is_ok_and(|age| age > max_age) {
+                remove.push(*i);
+            }
+        }
+    }
+    if let Some(t) = &thinning {
+        let mut present = files
+            .iter()
+            .map(|&(i, _)| i)
+            .filter(|i| !remove.contains(i))
+            .collect::<Vec<_>>();
+        present.dedup();
+        remove.extend(t.to_remove(&present));
+    }
+
+    let mut removed = false;
+    for (_, p) in files.iter().rev().filter(|(i, _)| remove.contains(i)) {
+        removed = true;
+        if dry_run {
+            println!("{}", p.display());
+            continue;
+        }
+        match fs::remove_file(p) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use std::{
+        env,
+        fs::{self, File},
+        path::PathBuf,
+        process::Command,
+        time::{Duration, SystemTime},
+    };
+    use tempfile::TempDir;
+
+    /// `rotee prune` removes the rotated files that each retention policy would, but never the
+    /// active file.
+    #[test]
+    fn test_prune() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let old = SystemTime::now() - Duration::from_secs(3 * 60 * 60);
+        // Seed a series of 10 files, of which 0, 3, and 4 are old, and 5 is encrypted.
+        let seed = || {
+            let dir = TempDir::new().unwrap();
+            for i in 0..10 {
+                let name = match i {
+                    5 => String::from("rotee.5.age"),
+                    _ => format!("rotee.{}", i),
+                };
+                let f = File::create(dir.path().join(name)).unwrap();
+                if [0, 3, 4].contains(&i) {
+                    f.set_modified(old).unwrap();
+                }
+            }
+            dir
+        };
+        let left = |dir: &TempDir| {
+            let mut names = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let prune = |dir: &TempDir, args: &[&str]| {
+            Command::new(&p)
+                .current_dir(dir.path())
+                .arg("prune")
+                .args(args)
+                .output()
+                .unwrap()
+        };
+        let names = |ns: &[usize]| {
+            ns.iter()
+                .map(|&i| match i {
+                    5 => String::from("rotee.5.age"),
+                    _ => format!("rotee.{}", i),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (args, kept) in [
+            (&["-n", "5"][..], &[0, 1, 2, 3, 4][..]),
+            (&["--max-age", "1h"], &[0, 1, 2, 5, 6, 7, 8, 9]),
+            (&["-n", "8", "--max-age", "1h"], &[0, 1, 2, 5, 6, 7]),
+            // Tiers of every file up to 2, and one in every 4 up to 10.
+            (&["--thin", "2:1,10:4"], &[0, 1, 2, 5, 9]),
+            (&["--thin", "2:1,10:4", "--max-age", "1h"], &[0, 1, 2, 5, 9]),
+        ] {
+            let dir = seed();
+            let all = left(&dir);
+            let out = prune(&dir, &[&["--dry-run"], args].concat());
+            assert!(out.status.success(), "{:?}", args);
+            assert_eq!(left(&dir), all, "{:?}", args);
+            // The files that would be removed are listed oldest first.
+            let removed = (0..10)
+                .rev()
+                .filter(|i| !kept.contains(i))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                String::from_utf8(out.stdout).unwrap(),
+                names(&removed)
+                    .iter()
+                    .map(|n| format!("{}\n", n))
+                    .collect::<String>(),
+                "{:?}",
+                args
+            );
+            assert!(prune(&dir, args).status.success(), "{:?}", args);
+            assert_eq!(left(&dir), names(kept), "{:?}", args);
+            // There's nothing more to remove.
+            assert_eq!(prune(&dir, args).status.code(), Some(1), "{:?}", args);
+        }
+
+        let dir = seed();
+        let out = prune(&dir, &[]);
+        assert_eq!(out.status.code(), Some(2));
+        assert_eq!(
+            String::from_utf8(out.stderr).unwrap(),
+            "error: nothing to prune by: give -n, --max-age, or --thin\n"
+        );
+        assert_eq!(
+            prune(&dir, &["-n", "2", "--thin", "2:1"]).status.code(),
+            Some(2)
+        );
+        assert_eq!(left(&dir).len(), 10);
+    }
+}
@@ -0,0 +1,79 @@
+//! `rotee replay-timing`: replay the data written to a series at the pace it was written.
+
+use rotee::{clock::SystemClock, diag, timing};
+use std::{
+    fs::File,
+    io::{self, BufReader},
+};
+
+/// `rotee replay-timing TIMING DATA`: write `DATA` to stdout, paced by the timing file `TIMING`.
+pub fn replay_timing(args: &[String]) -> i32 {
+    let [timing, data] = args else {
+        diag::emit(format_args!(
+            "usage: rotee replay-timing <timing file> <data file>"
+        ));
+        return 1;
+    };
+    let res = File::open(timing)
+        .and_then(|t| Ok((t, File::open(data)?)))
+        .map_err(|e| e.into())
+        .and_then(|(t, d)| timing::replay(&SystemClock, BufReader::new(t), d, io::stdout()));
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            diag::error(&e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use rotee::timing::parse_entry;
+    use std::{env, fs, path::PathBuf, process::Command};
+    use tempfile::TempDir;
+
+    /// Each timing file accounts for exactly the bytes of its output file, and replaying them
+    /// reproduces the output.
+    #[test]
+    fn test_timing_file() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let script = "i=0; while [ $i -lt 20 ]; do echo line$i; sleep 0.01; i=$((i+1)); done";
+        let status = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["-e", "-s", "30", "-n", "100", "--timing-file", "timing."])
+            .args(["--", "sh", "-c", script])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut files = 0;
+        for i in 0..100 {
+            let Ok(data) = fs::read(dir.path().join(format!("rotee.{}", i))) else {
+                assert!(!dir.path().join(format!("timing.{}", i)).exists());
+                break;
+            };
+            let t = fs::read_to_string(dir.path().join(format!("timing.{}", i))).unwrap();
+            let total = t.lines().map(|l| parse_entry(l).unwrap().1).sum::<usize>();
+            assert_eq!(total, data.len());
+
+            let out = Command::new(&p)
+                .current_dir(dir.path())
+                .args([
+                    "replay-timing",
+                    &format!("timing.{}", i),
+                    &format!("rotee.{}", i),
+                ])
+                .output()
+                .unwrap();
+            assert!(out.status.success());
+            assert_eq!(out.stdout, data);
+            files += 1;
+        }
+        assert!(files > 3);
+    }
+}
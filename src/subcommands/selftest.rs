@@ -0,0 +1,234 @@
+//! `rotee selftest`: check that rotee works on a filesystem.
+
+use super::open_series_files;
+use crate::exit_code;
+use rotee::{diag, verify::check_series};
+use std::{
+    env,
+    error::Error,
+    fs::{self, rename, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+const SELFTEST_USAGE: &str = "usage: rotee selftest [DIR]";
+
+/// The file size (`-s`) of `rotee selftest`'s captures: small, so that they rotate often.
+const SELFTEST_FILE_SIZE: usize = 1000;
+
+/// How much `rotee selftest` captures: enough for many rotations, and not a multiple of the file
+/// size, so that the active file is left partly full.
+const SELFTEST_INPUT: usize = 25 * SELFTEST_FILE_SIZE + SELFTEST_FILE_SIZE / 2;
+
+/// `rotee selftest [DIR]`: check that rotee works on the filesystem of `DIR` (by default, the
+/// temporary directory), before trusting it there. In a scratch directory under `DIR`, check what
+/// the filesystem supports, then capture a pseudo-random stream with several `-n`, checking that
+/// the series left reassembles into the end of the stream, that its files are the expected sizes,
+/// and that the newest file exists. Prints PASS, FAIL, or SKIP for each check, and returns 1 if
+/// any failed.
+pub fn selftest(args: &[String]) -> i32 {
+    let dir = match args {
+        [] => env::temp_dir(),
+        [d] if !d.starts_with('-') => PathBuf::from(d),
+        _ => {
+            diag::error(&SELFTEST_USAGE);
+            return 1;
+        }
+    };
+    let scratch = match tempfile::Builder::new()
+        .prefix("rotee-selftest.")
+        .tempdir_in(&dir)
+    {
+        Ok(d) => d,
+        Err(e) => {
+            diag::error(&format_args!(
+                "can't create a directory in {}: {}",
+                dir.display(),
+                e
+            ));
+            return 1;
+        }
+    };
+    let mut failed = false;
+    let mut report = |check: &str, res: Result<(), Box<dyn Error>>| match res {
+        Ok(()) => println!("PASS {}", check),
+        Err(e) => {
+            println!("FAIL {}: {}", check, e);
+            failed = true;
+        }
+    };
+
+    report(
+        "rename replaces an existing file",
+        selftest_rename(scratch.path()),
+    );
+    report(
+        "fsync of a file and its directory",
+        selftest_fsync(scratch.path()),
+    );
+    let input = selftest_input(SELFTEST_INPUT);
+    for num_files in [2, 3, 10] {
+        let prefix = format!("{}/n{}.", scratch.path().display(), num_files);
+        report(
+            &format!("capture with -n {}", num_files),
+            selftest_capture(&prefix, num_files, &input, None)
+                .and_then(|()| selftest_series(&prefix, num_files, &input)),
+        );
+    }
+    #[cfg(feature = "fault-injection")]
+    {
+        // Crash during the third rotation, once the files have been renamed but before the new
+        // active file has been created, then recover.
+        let prefix = format!("{}/crash.", scratch.path().display());
+        let acked = &input[..3 * SELFTEST_FILE_SIZE];
+        report(
+            "recovery from a crash mid-rotation",
+            selftest_capture(&prefix, 10, &input, Some("rotate-before-create:3"))
+                .and_then(|()| selftest_capture(&prefix, 10, &[], None))
+                .and_then(|()| selftest_series(&prefix, 10, acked)),
+        );
+    }
+    #[cfg(not(feature = "fault-injection"))]
+    println!("SKIP recovery from a crash mid-rotation: rotee was built without fault injection");
+
+    match failed {
+        true => 1,
+        false => 0,
+    }
+}
+
+/// `len` bytes of a pseudo-random (xorshift64) stream.
+fn selftest_input(len: usize) -> Vec<u8> {
+    let mut x = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// Does renaming a file in `dir` over another replace it, as rotation relies on?
+fn selftest_rename(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let (from, to) = (dir.join("rename.from"), dir.join("rename.to"));
+    fs::write(&from, "new")?;
+    fs::write(&to, "old")?;
+    rename(&from, &to)?;
+    if from.exists() || fs::read(&to)? != b"new" {
+        return Err("the file renamed over wasn't replaced".into());
+    }
+    Ok(())
+}
+
+/// Can a file in `dir`, and `dir` itself, be synced (as `--fsync` and `--buffer-files-sync` do)?
+fn selftest_fsync(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let mut f = File::create(dir.join("fsync"))?;
+    f.write_all(b"x")?;
+    f.sync_data().map_err(|e| format!("fdatasync: {}", e))?;
+    f.sync_all().map_err(|e| format!("fsync: {}", e))?;
+    File::open(dir)?
+        .sync_all()
+        .map_err(|e| format!("fsync of the directory: {}", e))?;
+    Ok(())
+}
+
+/// Capture `input` into the series under `prefix` with this rotee binary, with `-n num_files`. If
+/// `fail_at` is given, rotee is expected to crash there (see `ROTEE_FAIL_AT`).
+fn selftest_capture(
+    prefix: &str,
+    num_files: usize,
+    input: &[u8],
+    fail_at: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args(["-e", "-s", &SELFTEST_FILE_SIZE.to_string()])
+        .args(["-n", &num_files.to_string(), "-p", prefix])
+        // Recover what a crashed capture left in the newest file.
+        .arg("--rotate-at-start")
+        // The captures are checked against rotee's defaults, so no configuration applies.
+        .env("ROTEE_NO_CONFIG", "1")
+        .env_remove("ROTEE_DEFAULT_ARGS")
+        .env_remove("ROTEE_FAIL_AT")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    if let Some(f) = fail_at {
+        cmd.env("ROTEE_FAIL_AT", f);
+    }
+    let mut child = cmd.spawn()?;
+    // With `fail_at`, rotee may crash before reading everything.
+    let res = child.stdin.take().unwrap().write_all(input);
+    let out = child.wait_with_output()?;
+    match (fail_at, out.status.success()) {
+        (None, true) => Ok(res?),
+        (Some(f), true) => Err(format!("rotee didn't crash at {}", f).into()),
+        (Some(_), false) => Ok(()),
+        (None, false) => Err(format!(
+            "rotee exited with status {}: {}",
+            exit_code(out.status),
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        )
+        .into()),
+    }
+}
+
+/// Check the series under `prefix`, captured with `-n num_files` from `input` (see
+/// `check_series()`).
+fn selftest_series(prefix: &str, num_files: usize, input: &[u8]) -> Result<(), Box<dyn Error>> {
+    let files = open_series_files(prefix)?
+        .into_iter()
+        .map(|f| {
+            f.map(|mut f| {
+                let mut buf = Vec::new();
+                f.read_to_end(&mut buf).map(|_| buf)
+            })
+            .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(check_series(
+        prefix,
+        &files,
+        SELFTEST_FILE_SIZE,
+        num_files,
+        input,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use std::{env, fs, path::PathBuf, process::Command};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_selftest() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let dir = TempDir::new().unwrap();
+        let out = Command::new(&p)
+            .args(["selftest", dir.path().to_str().unwrap()])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(out.status.success(), "{}", stdout);
+        assert!(stdout.lines().all(|l| !l.starts_with("FAIL")));
+        assert!(stdout.contains("PASS capture with -n 10\n"));
+        // The scratch directory is removed afterwards.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let out = Command::new(&p)
+            .args(["selftest", "--bogus"])
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        assert_eq!(
+            String::from_utf8(out.stderr).unwrap(),
+            "error: usage: rotee selftest [DIR]\n"
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! `rotee tail`: write the end of a series to stdout, optionally following it as it grows.
+
+use super::{open_series_files, series_names, series_number, CAT_ATTEMPTS};
+use crate::outfile_path;
+use log::warn;
+use rotee::diag;
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    os::unix::fs::{FileExt, MetadataExt},
+    thread,
+    time::Duration,
+};
+
+const TAIL_USAGE: &str = "usage: rotee tail [-p PREFIX] [-n LINES | -c BYTES] [-f]";
+
+/// How often `rotee tail -f` looks for more output, once it has read everything written so far.
+const TAIL_POLL: Duration = Duration::from_millis(100);
+
+/// How much of the end of a series `rotee tail` starts with.
+#[derive(Clone, Copy)]
+enum TailFrom {
+    Lines(usize),
+    Bytes(u64),
+}
+
+/// `rotee tail [-p PREFIX] [-n LINES | -c BYTES] [-f]`: write the last `LINES` lines (by default
+/// 10) or `BYTES` bytes of the series under `PREFIX` (by default `rotee.`) to stdout, reading as
+/// many of its files as that takes. With `-f`, then follow the series' active file across
+/// rotations.
+pub fn tail(args: &[String]) -> i32 {
+    match tail_series(args) {
+        Ok(()) => 0,
+        // The reader has gone (e.g. `rotee tail -f | head`).
+        Err(e)
+            if e.downcast_ref::<io::Error>().map(io::Error::kind)
+                == Some(io::ErrorKind::BrokenPipe) =>
+        {
+            0
+        }
+        Err(e) => {
+            diag::error(&e);
+            1
+        }
+    }
+}
+
+fn tail_series(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut prefix = String::from("rotee.");
+    let (mut lines, mut bytes, mut follow) = (None, None, false);
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        let mut value = || args.next().ok_or(TAIL_USAGE);
+        match a.as_str() {
+            "-p" | "--file-prefix" => prefix = value()?.clone(),
+            "-n" | "--lines" => lines = Some(value()?.parse::<usize>().map_err(|_| TAIL_USAGE)?),
+            "-c" | "--bytes" => bytes = Some(value()?.parse::<u64>().map_err(|_| TAIL_USAGE)?),
+            "-f" | "--follow" => follow = true,
+            _ => return Err(TAIL_USAGE.into()),
+        }
+    }
+    let from = match (lines, bytes) {
+        (None, None) => TailFrom::Lines(10),
+        (Some(n), None) => TailFrom::Lines(n),
+        (None, Some(n)) => TailFrom::Bytes(n),
+        (Some(_), Some(_)) => return Err(TAIL_USAGE.into()),
+    };
+
+    // The newest files, up to the first missing one: what's older than a gap doesn't lead into
+    // what follows. The active file's length is fixed now, so that following it carries on from
+    // exactly where the backfill stopped.
+    let mut run = open_series_files(&prefix)?
+        .into_iter()
+        .skip_while(Option::is_none)
+        .map_while(|f| f)
+        .map(|f| Ok((f.metadata()?.len(), f)))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    if run.is_empty() {
+        return Err(format!("there are no files under {}", prefix).into());
+    }
+    let (first, start) = match from {
+        TailFrom::Lines(n) => tail_lines_start(&run, n)?,
+        TailFrom::Bytes(n) => tail_bytes_start(&run, n),
+    };
+    let mut out = io::stdout().lock();
+    for (i, (len, f)) in run.iter_mut().enumerate().take(first + 1).rev() {
+        let from = if i == first { start } else { 0 };
+        f.seek(io::SeekFrom::Start(from))?;
+        io::copy(&mut Read::by_ref(f).take(*len - from), &mut out)?;
+    }
+    out.flush()?;
+    if follow {
+        follow_series(&prefix, run.swap_remove(0).1, &mut out)?;
+    }
+    Ok(())
+}
+
+/// Where the last `n` lines of `run` (files and their lengths, newest first) start: the index of
+/// a file and an offset in it. A final newline doesn't start another line.
+fn tail_lines_start(run: &[(u64, File)], n: usize) -> Result<(usize, u64), io::Error> {
+    if n == 0 {
+        return Ok((0, run[0].0));
+    }
+    let mut buf = vec![0; 64 * 1024];
+    let (mut newlines, mut last) = (0, true);
+    for (i, (len, f)) in run.iter().enumerate() {
+        let mut end = *len;
+        while end > 0 {
+            let from = end.saturating_sub(buf.len() as u64);
+            let chunk = &mut buf[..usize::try_from(end - from).unwrap()];
+            f.read_exact_at(chunk, from)?;
+            for (j, &b) in chunk.iter().enumerate().rev() {
+                if b == b'\n' && !last {
+                    newlines += 1;
+                    if newlines == n {
+                        return Ok((i, from + j as u64 + 1));
+                    }
+                }
+                last = false;
+            }
+            end = from;
+        }
+    }
+    Ok((run.len() - 1, 0))
+}
+
+/// Where the last `n` bytes of `run` (files and their lengths, newest first) start: the index of a
+/// file and an offset in it.
+fn tail_bytes_start(run: &[(u64, File)], mut n: u64) -> (usize, u64) {
+    for (i, (len, _)) in run.iter().enumerate() {
+        if n <= *len {
+            return (i, len - n);
+        }
+        n -= len;
+    }
+    (run.len() - 1, 0)
+}
+
+/// Write what is written to `cur` (one of the series under `prefix`) to `out` as it is written,
+/// moving on to the file that follows `cur` in the series once `cur` has been rotated and read to
+/// its end. Only returns if there's an error.
+fn follow_series(prefix: &str, mut cur: File, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    loop {
+        io::copy(&mut cur, out)?;
+        out.flush()?;
+        let m = cur.metadata()?;
+        match tail_successor(prefix, (m.dev(), m.ino()))? {
+            Some(next) => {
+                // `cur` has been rotated, so nothing more is written to it: read what's left.
+                io::copy(&mut cur, out)?;
+                cur = next;
+            }
+            None => thread::sleep(TAIL_POLL),
+        }
+    }
+}
+
+/// The (device, inode) pair of output file `i` of the series under `prefix`, if it exists.
+fn series_file_id(prefix: &str, i: usize) -> Option<(u64, u64)> {
+    fs::metadata(outfile_path(prefix, i))
+        .ok()
+        .map(|m| (m.dev(), m.ino()))
+}
+
+/// If the file of the series under `prefix` identified by `id` has been rotated, the file that
+/// followed it; otherwise (or if that file hasn't been created yet) `None`. If the file has been
+/// removed (because it became too old while we were still reading it), there's no telling which
+/// file followed it, so, with a warning, the oldest file left.
+fn tail_successor(prefix: &str, id: (u64, u64)) -> Result<Option<File>, io::Error> {
+    if series_file_id(prefix, 0) == Some(id) {
+        return Ok(None);
+    }
+    for _ in 0..CAT_ATTEMPTS {
+        let max = series_names(prefix)?
+            .iter()
+            .filter_map(|n| series_number(n))
+            .max()
+            .unwrap_or(0);
+        // Rotation only moves a file to a higher number, so, looking from the lowest up, we can't
+        // miss it: it's either found or has gone.
+        let Some(k) = (0..=max + 1).find(|&k| series_file_id(prefix, k) == Some(id)) else {
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        };
+        if k == 0 {
+            return Ok(None);
+        }
+        for j in (0..k).rev() {
+            match File::open(outfile_path(prefix, j)) {
+                // Files are renamed from the highest number down, so if our file is still at `k`,
+                // `f` can't have been moved to `j` since: it's the file that followed ours.
+                Ok(f) if series_file_id(prefix, k) == Some(id) => return Ok(Some(f)),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        // Either the file that follows ours has yet to be created, or ours has been rotated again.
+        if series_file_id(prefix, k) == Some(id) {
+            return Ok(None);
+        }
+    }
+    warn!("the file being read was removed before the file that followed it was found: some output may be missing");
+    let mut names = series_names(prefix)?
+        .iter()
+        .filter_map(|n| series_number(n))
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    for i in names.into_iter().rev() {
+        match File::open(outfile_path(prefix, i)) {
+            Ok(f) => return Ok(Some(f)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::CARGO_PROFILE;
+    use std::{
+        env, fs,
+        io::{Read, Write},
+        path::PathBuf,
+        process::{Command, Stdio},
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+    use tempfile::TempDir;
+
+    /// `rotee tail` starts with the end of a series, however many files that spans, and, with
+    /// `-f`, follows it across rotations without dropping or repeating anything.
+    #[test]
+    fn test_tail() {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = [&md, "target", CARGO_PROFILE, "rotee"]
+            .iter()
+            .collect::<PathBuf>();
+        let input = (0..2000)
+            .map(|i| format!("line {:04}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let dir = TempDir::new().unwrap();
+        let tail = |args: &[&str]| {
+            Command::new(&p)
+                .current_dir(dir.path())
+                .arg("tail")
+                .args(args)
+                .output()
+                .unwrap()
+                .stdout
+        };
+        let mut child = Command::new(&p)
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .args(["-e", "-s", "100", "-n", "1000"])
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let (before, after) = input.split_at(1000);
+        stdin.write_all(before).unwrap();
+        let written = || {
+            (0..1000)
+                .filter_map(|i| fs::metadata(dir.path().join(format!("rotee.{}", i))).ok())
+                .map(|m| m.len())
+                .sum::<u64>()
+        };
+        while written() < 1000 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Each line is 10 bytes.
+        assert_eq!(tail(&[]), &before[900..]);
+        assert_eq!(tail(&["-n", "2"]), &before[980..]);
+        assert_eq!(tail(&["-n", "0"]), b"");
+        assert_eq!(tail(&["-n", "5000"]), before);
+        assert_eq!(tail(&["-c", "250"]), &before[750..]);
+        assert_eq!(tail(&["-c", "5000"]), before);
+
+        let mut follow = Command::new(&p)
+            .current_dir(dir.path())
+            .args(["tail", "-c", "250", "-f"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = follow.stdout.take().unwrap();
+        // Nothing more is written until the tail has started.
+        let mut got = vec![0; 250];
+        stdout.read_exact(&mut got).unwrap();
+        assert_eq!(got, &before[750..]);
+        let expect = after.to_vec();
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            let mut got = vec![0; expect.len()];
+            stdout.read_exact(&mut got).unwrap();
+            tx.send(()).unwrap();
+            (got, expect)
+        });
+        // Write in bursts that each rotate several times, between which the tail looks for more.
+        for c in after.chunks(300) {
+            stdin.write_all(c).unwrap();
+            thread::sleep(Duration::from_millis(30));
+        }
+        drop(stdin);
+        assert!(child.wait().unwrap().success());
+        let res = rx.recv_timeout(Duration::from_secs(10));
+        follow.kill().unwrap();
+        follow.wait().unwrap();
+        assert!(res.is_ok(), "the tail didn't keep up");
+        let (got, expect) = reader.join().unwrap();
+        assert_eq!(String::from_utf8(got), String::from_utf8(expect));
+    }
+}
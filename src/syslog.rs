@@ -0,0 +1,198 @@
+//! Forwarding lines of input to syslog (`--syslog`).
+
+use libc::c_int;
+use log::warn;
+use std::{
+    io,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// The socket that the local syslog daemon reads.
+pub const DEV_LOG: &str = "/dev/log";
+
+const FACILITIES: &[(&str, c_int)] = &[
+    ("kern", libc::LOG_KERN),
+    ("user", libc::LOG_USER),
+    ("mail", libc::LOG_MAIL),
+    ("daemon", libc::LOG_DAEMON),
+    ("auth", libc::LOG_AUTH),
+    ("syslog", libc::LOG_SYSLOG),
+    ("lpr", libc::LOG_LPR),
+    ("news", libc::LOG_NEWS),
+    ("uucp", libc::LOG_UUCP),
+    ("cron", libc::LOG_CRON),
+    ("authpriv", libc::LOG_AUTHPRIV),
+    ("ftp", libc::LOG_FTP),
+    ("local0", libc::LOG_LOCAL0),
+    ("local1", libc::LOG_LOCAL1),
+    ("local2", libc::LOG_LOCAL2),
+    ("local3", libc::LOG_LOCAL3),
+    ("local4", libc::LOG_LOCAL4),
+    ("local5", libc::LOG_LOCAL5),
+    ("local6", libc::LOG_LOCAL6),
+    ("local7", libc::LOG_LOCAL7),
+];
+
+const PRIORITIES: &[(&str, c_int)] = &[
+    ("emerg", libc::LOG_EMERG),
+    ("alert", libc::LOG_ALERT),
+    ("crit", libc::LOG_CRIT),
+    ("err", libc::LOG_ERR),
+    ("warning", libc::LOG_WARNING),
+    ("notice", libc::LOG_NOTICE),
+    ("info", libc::LOG_INFO),
+    ("debug", libc::LOG_DEBUG),
+];
+
+/// Parse a `FACILITY.PRIORITY` pair, as in `syslog.conf` (e.g. `local0.info`), into the `PRI`
+/// value of a syslog message.
+pub fn parse_spec(s: &str) -> Result<c_int, String> {
+    let lookup = |table: &[(&str, c_int)], name: &str| {
+        table
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+    };
+    let (fac, pri) = s.split_once('.').ok_or_else(|| {
+        format!(
+            "invalid syslog selector '{}' (expected FACILITY.PRIORITY)",
+            s
+        )
+    })?;
+    let fac =
+        lookup(FACILITIES, fac).ok_or_else(|| format!("unknown syslog facility '{}'", fac))?;
+    let pri =
+        lookup(PRIORITIES, pri).ok_or_else(|| format!("unknown syslog priority '{}'", pri))?;
+    Ok(fac | pri)
+}
+
+/// Reassembles lines from chunks of input, sending each complete line to syslog as a message of
+/// its own. Syslog not being available is never an error: the first message that can't be sent
+/// is reported on stderr, and messages are dropped until the socket can be (re)connected.
+pub struct Syslog {
+    path: PathBuf,
+    sock: Option<UnixDatagram>,
+    /// The `<PRI>TAG[PID]: ` header of every message.
+    header: Vec<u8>,
+    /// The longest message body sent: the rest of a longer line is dropped.
+    cap: usize,
+    /// The (first `cap` bytes of the) incomplete line read so far.
+    line: Vec<u8>,
+    /// Has a failure been reported since the last message was sent?
+    warned: bool,
+}
+
+impl Syslog {
+    /// Send lines to the syslog socket at `path`, with the `PRI` value `pri` and the tag `tag`.
+    pub fn new(path: &Path, pri: c_int, tag: &str, cap: usize) -> Self {
+        Self {
+            path: path.to_owned(),
+            sock: None,
+            header: format!("<{}>{}[{}]: ", pri, tag, process::id()).into_bytes(),
+            cap,
+            line: Vec::new(),
+            warned: false,
+        }
+    }
+
+    /// Feed `bytes`, sending any lines they complete.
+    pub fn feed(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let (part, eol) = match bytes.iter().position(|b| *b == b'\n') {
+                Some(i) => (&bytes[..i], true),
+                None => (bytes, false),
+            };
+            bytes = &bytes[(part.len() + usize::from(eol))..];
+            let room = self.cap.saturating_sub(self.line.len());
+            self.line.extend_from_slice(&part[..part.len().min(room)]);
+            if eol {
+                self.send();
+            }
+        }
+    }
+
+    /// Send any incomplete line, once input has ended.
+    pub fn finish(&mut self) {
+        if !self.line.is_empty() {
+            self.send();
+        }
+    }
+
+    fn send(&mut self) {
+        let mut msg = self.header.clone();
+        msg.extend_from_slice(self.line.strip_suffix(b"\r").unwrap_or(&self.line));
+        self.line.clear();
+        match self.send_msg(&msg) {
+            Ok(()) => self.warned = false,
+            Err(e) => {
+                self.sock = None;
+                if !self.warned {
+                    warn!(
+                        "can't write to syslog ({}): {}: dropping lines until it's available",
+                        self.path.display(),
+                        e
+                    );
+                    self.warned = true;
+                }
+            }
+        }
+    }
+
+    fn send_msg(&mut self, msg: &[u8]) -> Result<(), io::Error> {
+        let sock = match &mut self.sock {
+            Some(s) => s,
+            None => {
+                let s = UnixDatagram::unbound()?;
+                s.connect(&self.path)?;
+                // A syslog daemon that has stopped reading mustn't be able to stall rotee.
+                s.set_nonblocking(true)?;
+                self.sock.insert(s)
+            }
+        };
+        sock.send(msg).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_spec, Syslog};
+    use std::{os::unix::net::UnixDatagram, process};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec("user.info"), Ok(libc::LOG_USER | libc::LOG_INFO));
+        assert_eq!(
+            parse_spec("LOCAL3.err"),
+            Ok(libc::LOG_LOCAL3 | libc::LOG_ERR)
+        );
+        assert!(parse_spec("user").is_err());
+        assert!(parse_spec("nope.info").is_err());
+        assert!(parse_spec("user.nope").is_err());
+    }
+
+    #[test]
+    fn test_syslog() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("log");
+        let mut s = Syslog::new(&path, libc::LOG_USER | libc::LOG_INFO, "rotee", 5);
+
+        // Nothing is listening yet: the line is dropped.
+        s.feed(b"lost\n");
+
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        s.feed(b"ab");
+        s.feed(b"c\r\ndefghij\nk");
+        s.finish();
+        let mut buf = [0; 64];
+        let h = format!("<14>rotee[{}]: ", process::id());
+        for expected in ["abc", "defgh", "k"] {
+            let n = daemon.recv(&mut buf).unwrap();
+            assert_eq!(buf[..n], *format!("{}{}", h, expected).as_bytes());
+        }
+        daemon.set_nonblocking(true).unwrap();
+        assert!(daemon.recv(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Temporary files for atomic replacements: a file is written under a temporary name alongside
+//! the file it is to replace, and then renamed over it.
+//!
+//! The temporary file for `DIR/NAME` is `DIR/.NAME.tmp.PID.N`, where `PID` is the id of the process
+//! that made it, and `N` counts the temporary files that process has made, so that neither two
+//! processes nor two replacements by one process ever share a name. A process that dies part way
+//! through a replacement leaves its temporary file behind: `remove_stale()` removes those.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of temporary files this process has made.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A temporary file, to be renamed over the file it replaces with `persist()`. If it is dropped
+/// instead (e.g. because writing it failed), it is removed, unless it is being kept.
+pub struct TempFile {
+    path: PathBuf,
+    /// Keep the file if it isn't persisted (`--keep-temps`)?
+    keep: bool,
+}
+
+impl TempFile {
+    /// Name a temporary file for replacing `target`. The file itself isn't created: the caller
+    /// creates it at `path()`, in whatever way suits.
+    pub fn new(target: &Path, keep: bool) -> Self {
+        let mut name = OsString::from(".");
+        name.push(target.file_name().unwrap_or(OsStr::new("")));
+        name.push(format!(
+            ".tmp.{}.{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Self {
+            path: target.with_file_name(name),
+            keep,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename the temporary file over `target`.
+    pub fn persist(mut self, target: &Path) -> Result<(), io::Error> {
+        fs::rename(&self.path, target)?;
+        // There's nothing left to remove.
+        self.keep = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// If `name` is that of a temporary file, the id of the process that made it.
+fn temp_pid(name: &OsStr) -> Option<u32> {
+    let name = name.as_bytes().strip_prefix(b".")?;
+    let (rest, n) = rsplit_once(name, b'.')?;
+    let (rest, pid) = rsplit_once(rest, b'.')?;
+    let target = rest.strip_suffix(b".tmp")?;
+    if target.is_empty() || !all_digits(n) || !all_digits(pid) {
+        return None;
+    }
+    std::str::from_utf8(pid).ok()?.parse().ok()
+}
+
+fn rsplit_once(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().rposition(|&b| b == sep)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+fn all_digits(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(u8::is_ascii_digit)
+}
+
+/// The temporary files in `dir` left by processes that have gone (e.g. that were killed part way
+/// through a replacement). A directory that doesn't exist has none.
+pub fn find_stale(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut stale = Vec::new();
+    for e in entries {
+        let e = e?;
+        let Some(pid) = temp_pid(&e.file_name()) else {
+            continue;
+        };
+        if pid != process::id() && !running(pid) {
+            stale.push(e.path());
+        }
+    }
+    Ok(stale)
+}
+
+/// Remove the stale temporary files in `dir` (see `find_stale()`), returning their paths.
+pub fn remove_stale(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut removed = Vec::new();
+    for p in find_stale(dir)? {
+        match fs::remove_file(&p) {
+            Ok(()) => removed.push(p),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Is process `pid` running? A process that exists, but that we can't signal, is.
+fn running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{remove_stale, temp_pid, TempFile};
+    use std::{ffi::OsStr, fs, process};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_names() {
+        let t = TempFile::new(&std::path::PathBuf::from("a/rotee.1.age"), false);
+        let name = t.path().file_name().unwrap();
+        assert!(name
+            .to_str()
+            .unwrap()
+            .starts_with(&format!(".rotee.1.age.tmp.{}.", process::id())));
+        assert_eq!(temp_pid(name), Some(process::id()));
+        // Each temporary file has a name of its own.
+        assert_ne!(
+            TempFile::new(t.path(), false).path(),
+            TempFile::new(t.path(), false).path()
+        );
+        for n in [
+            ".rotee.0.tmp.12.x",
+            ".rotee.0.tmp..3",
+            "rotee.0.tmp.12.3",
+            ".tmp.12.3",
+            ".rotee.0.12.3",
+            "rotee.0",
+        ] {
+            assert_eq!(temp_pid(OsStr::new(n)), None, "{}", n);
+        }
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().join("rotee.0");
+        let t = TempFile::new(&target, false);
+        fs::write(t.path(), "x").unwrap();
+        t.persist(&target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "x");
+        // An unpersisted file is removed, unless it's being kept.
+        let t = TempFile::new(&target, false);
+        let p = t.path().to_owned();
+        fs::write(&p, "y").unwrap();
+        drop(t);
+        assert!(!p.exists());
+        let t = TempFile::new(&target, true);
+        let p = t.path().to_owned();
+        fs::write(&p, "y").unwrap();
+        drop(t);
+        assert!(p.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_remove_stale() {
+        let dir = TempDir::new().unwrap();
+        // No process has a pid this large, so this one's temporary file is stale.
+        let stale = dir.path().join(".rotee.0.tmp.4194305.0");
+        let ours = dir.path().join(format!(".rotee.0.tmp.{}.0", process::id()));
+        let init = dir.path().join(".rotee.0.tmp.1.0");
+        let other = dir.path().join("rotee.0.tmp");
+        for p in [&stale, &ours, &init, &other] {
+            fs::write(p, "").unwrap();
+        }
+        assert_eq!(remove_stale(dir.path()).unwrap(), vec![stale.clone()]);
+        assert!(!stale.exists() && ours.exists() && init.exists() && other.exists());
+        assert!(remove_stale(&dir.path().join("nope")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! Thinning retention (`--thin`): keeping every rotated file while it is recent, and ever sparser
+//! samples of older ones.
+
+/// A thinning specification: a list of tiers, each of which is a `(limit, step)` pair, sorted by
+/// `limit`. The files at indices up to and including the first tier's `limit` are sampled every
+/// `step` rotations, and so on; files beyond the last tier's `limit` aren't kept.
+///
+/// Files are named by index, and every file moves up one index at each rotation, so the gaps
+/// between files never change. Thinning thus works on gaps: a file is kept if the next older kept
+/// file is at least `step` rotations older. Once kept, a file stays kept until it moves into a
+/// coarser tier, where it is judged again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Thinning {
+    tiers: Vec<(usize, usize)>,
+}
+
+impl Thinning {
+    /// Parse a specification such as `10:1,100:10,1000:100`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid thinning specification '{}'", s);
+        let mut tiers: Vec<(usize, usize)> = Vec::new();
+        for t in s.split(',') {
+            let (limit, step) = t.split_once(':').ok_or_else(invalid)?;
+            let limit = limit.parse::<usize>().map_err(|_| invalid())?;
+            let step = step.parse::<usize>().map_err(|_| invalid())?;
+            if step == 0 || limit == 0 {
+                return Err(format!(
+                    "thinning specification '{}' has a zero limit or step",
+                    s
+                ));
+            }
+            if tiers.last().is_some_and(|&(l, st)| l >= limit || st > step) {
+                return Err(format!(
+                    "the limits of thinning specification '{}' must increase, and its steps must \
+                     not decrease",
+                    s
+                ));
+            }
+            tiers.push((limit, step));
+        }
+        // Otherwise, the newest rotated file could be removed as soon as it was rotated.
+        if tiers[0].1 != 1 {
+            return Err(format!(
+                "the first tier of thinning specification '{}' must have a step of 1",
+                s
+            ));
+        }
+        Ok(Self { tiers })
+    }
+
+    /// The highest index at which a file can be kept.
+    pub fn limit(&self) -> usize {
+        self.tiers.last().unwrap().0
+    }
+
+    /// The sampling step of the tier that index `i` is in, or `None` if it is beyond the last tier.
+    fn step(&self, i: usize) -> Option<usize> {
+        self.tiers.iter().find(|(l, _)| i <= *l).map(|&(_, s)| s)
+    }
+
+    /// Given the indices of the rotated files that exist (in ascending order, and not including
+    /// the active file, 0), return those of the files to remove.
+    pub fn to_remove(&self, present: &[usize]) -> Vec<usize> {
+        let mut remove = Vec::new();
+        let mut older_kept = None::<usize>;
+        for &i in present.iter().rev() {
+            let keep = match self.step(i) {
+                None => false,
+                Some(s) => older_kept.is_none_or(|o| o - i >= s),
+            };
+            if keep {
+                older_kept = Some(i);
+            } else {
+                remove.push(i);
+            }
+        }
+        remove
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Thinning;
+
+    #[test]
+    fn test_parse() {
+        let t = Thinning::parse("10:1,100:10,1000:100").unwrap();
+        assert_eq!(t.tiers, vec![(10, 1), (100, 10), (1000, 100)]);
+        assert_eq!(t.limit(), 1000);
+        assert!(Thinning::parse("").is_err());
+        assert!(Thinning::parse("10").is_err());
+        assert!(Thinning::parse("10:0").is_err());
+        assert!(Thinning::parse("0:1").is_err());
+        assert!(Thinning::parse("10:1,10:2").is_err());
+        assert!(Thinning::parse("10:2").is_err());
+        assert!(Thinning::parse("10:1,20:4,30:2").is_err());
+        assert!(Thinning::parse("10:1,x:2").is_err());
+    }
+
+    #[test]
+    fn test_to_remove() {
+        let t = Thinning::parse("2:1,10:4").unwrap();
+        assert_eq!(t.to_remove(&[]), Vec::<usize>::new());
+        assert_eq!(t.to_remove(&[1, 2, 3, 4, 5, 11]), vec![11, 4, 3]);
+        assert_eq!(t.to_remove(&[1, 2, 3, 7, 10]), vec![7]);
+    }
+
+    /// Simulate thousands of rotations, checking that: recent files are all kept; each tier's
+    /// samples are neither too close together nor too far apart; and a kept file is only ever
+    /// removed as it moves into a coarser tier.
+    #[test]
+    fn test_simulated_rotations() {
+        for spec in [
+            "10:1,100:10,1000:100",
+            "3:1,20:4,50:7",
+            "1:1,64:8",
+            "4:1,9:1",
+        ] {
+            let t = Thinning::parse(spec).unwrap();
+            // `files[i]` is the rotation that created the file at index `i`, if there is one. As
+            // with `-n`, the file at `limit()` is dropped when the files are rotated.
+            let mut files: Vec<Option<usize>> = vec![None; t.limit() + 1];
+            for rotation in 0..5000 {
+                files.pop();
+                files.insert(1, Some(rotation));
+                let present = (1..files.len())
+                    .filter(|&i| files[i].is_some())
+                    .collect::<Vec<_>>();
+                for i in t.to_remove(&present) {
+                    // A file is only removed on entering a tier.
+                    assert!(i > 1 && t.step(i) != t.step(i - 1));
+                    files[i] = None;
+                }
+
+                let kept = (1..files.len())
+                    .filter(|&i| files[i].is_some())
+                    .collect::<Vec<_>>();
+                for w in kept.windows(2) {
+                    let (newer, older) = (w[0], w[1]);
+                    let step = t.step(older).unwrap();
+                    if t.step(newer) == Some(step) {
+                        assert!(older - newer >= step);
+                    }
+                    assert!(older - newer < 2 * step);
+                }
+                // Every file in the first tier is kept.
+                let n = usize::min(t.tiers[0].0, rotation + 1);
+                assert!((1..=n).all(|i| files[i].is_some()));
+            }
+        }
+    }
+}
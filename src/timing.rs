@@ -0,0 +1,84 @@
+//! Timing files in the format written by `script -t` and read by `scriptreplay`: each line is
+//! `DELAY BYTES`, where `DELAY` is the time (in seconds) since the previous chunk of output was
+//! written and `BYTES` is the length of the chunk.
+
+use crate::clock::Clock;
+use std::{
+    error::Error,
+    io::{self, BufRead, Read, Write},
+    time::Duration,
+};
+
+/// The timing file line for a chunk of `len` bytes written `delay` after the previous chunk.
+pub fn entry(delay: Duration, len: usize) -> String {
+    format!("{}.{:06} {}\n", delay.as_secs(), delay.subsec_micros(), len)
+}
+
+/// Parse a timing file line (without its newline) into its delay and length.
+pub fn parse_entry(line: &str) -> Result<(Duration, usize), String> {
+    let err = || format!("invalid timing line '{}'", line);
+    let (delay, len) = line.trim().split_once(' ').ok_or_else(err)?;
+    let delay = delay
+        .parse()
+        .ok()
+        .and_then(|d| Duration::try_from_secs_f64(d).ok())
+        .ok_or_else(err)?;
+    Ok((delay, len.trim().parse().map_err(|_| err())?))
+}
+
+/// Write `data` to `out` paced by `timing`: each chunk is written once its delay (since the
+/// previous chunk) has elapsed on `clock`.
+pub fn replay<R: BufRead, D: Read, W: Write>(
+    clock: &dyn Clock,
+    timing: R,
+    mut data: D,
+    mut out: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut due = clock.now();
+    for line in timing.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (delay, len) = parse_entry(&line)?;
+        due += delay;
+        clock.sleep_until(due);
+        let len = u64::try_from(len)?;
+        if io::copy(&mut data.by_ref().take(len), &mut out)? < len {
+            return Err("data ended before the timing file".into());
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry, parse_entry, replay};
+    use crate::clock::{Clock, FakeClock};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_entry() {
+        let e = entry(Duration::from_micros(1_500_002), 42);
+        assert_eq!(e, "1.500002 42\n");
+        assert_eq!(
+            parse_entry(e.trim_end()).unwrap(),
+            (Duration::from_micros(1_500_002), 42)
+        );
+        assert!(parse_entry("1.5").is_err());
+        assert!(parse_entry("x 1").is_err());
+        assert!(parse_entry("-1 1").is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let clock = FakeClock::new(UNIX_EPOCH);
+        let mut out = Vec::new();
+        replay(&clock, &b"0.5 2\n1.0 3\n"[..], &b"abcde"[..], &mut out).unwrap();
+        assert_eq!(out, b"abcde");
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_millis(1500));
+
+        assert!(replay(&clock, &b"0 6\n"[..], &b"abcde"[..], &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Validating input as UTF-8 (`--utf8 replace|reject`).
+
+use std::str;
+
+/// What to do with invalid UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Invalid {
+    /// Replace each invalid sequence with U+FFFD, as `String::from_utf8_lossy()` does.
+    Replace,
+    /// Fail, giving the offset of the first invalid sequence.
+    Reject,
+}
+
+/// The UTF-8 encoding of U+FFFD (the replacement character).
+const REPLACEMENT: &[u8] = "\u{FFFD}".as_bytes();
+
+/// Validates a stream as UTF-8, however the stream is split into chunks: a sequence that is
+/// incomplete at the end of a chunk is held back until the next chunk shows whether it is valid.
+pub struct Utf8Filter {
+    invalid: Invalid,
+    /// The start of a sequence, incomplete so far, held back from the last chunk.
+    held: Vec<u8>,
+    /// The offset in the stream of the first byte of `held` (or of the next chunk if nothing is
+    /// held).
+    offset: u64,
+}
+
+impl Utf8Filter {
+    pub fn new(invalid: Invalid) -> Self {
+        Self {
+            invalid,
+            held: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Feed `bytes`, appending what should be output to `out`. With `Invalid::Reject`, an invalid
+    /// sequence is an error giving its offset in the stream; what came before it is appended to
+    /// `out`.
+    pub fn feed(&mut self, bytes: &[u8], out: &mut Vec<u8>) -> Result<(), u64> {
+        let joined;
+        let mut rest = if self.held.is_empty() {
+            bytes
+        } else {
+            joined = [&std::mem::take(&mut self.held), bytes].concat();
+            &joined[..]
+        };
+        loop {
+            let e = match str::from_utf8(rest) {
+                Ok(_) => {
+                    out.extend_from_slice(rest);
+                    self.offset += rest.len() as u64;
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            let valid = e.valid_up_to();
+            out.extend_from_slice(&rest[..valid]);
+            self.offset += valid as u64;
+            rest = &rest[valid..];
+            match e.error_len() {
+                // The sequence may be completed by the next chunk.
+                None => {
+                    self.held = rest.to_vec();
+                    return Ok(());
+                }
+                Some(n) => {
+                    self.invalid(out)?;
+                    self.offset += n as u64;
+                    rest = &rest[n..];
+                }
+            }
+        }
+    }
+
+    /// Once input has ended, deal with any incomplete sequence held back, appending what should be
+    /// output to `out`.
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), u64> {
+        if self.held.is_empty() {
+            return Ok(());
+        }
+        self.invalid(out)?;
+        self.offset += std::mem::take(&mut self.held).len() as u64;
+        Ok(())
+    }
+
+    /// Deal with an invalid sequence at `self.offset`.
+    fn invalid(&self, out: &mut Vec<u8>) -> Result<(), u64> {
+        match self.invalid {
+            Invalid::Replace => {
+                out.extend_from_slice(REPLACEMENT);
+                Ok(())
+            }
+            Invalid::Reject => Err(self.offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Invalid, Utf8Filter};
+    use proptest::prelude::*;
+
+    /// Feed `input` split into chunks of `chunk` bytes.
+    fn filter(invalid: Invalid, input: &[u8], chunk: usize) -> Result<Vec<u8>, u64> {
+        let mut f = Utf8Filter::new(invalid);
+        let mut out = Vec::new();
+        for c in input.chunks(chunk) {
+            f.feed(c, &mut out)?;
+        }
+        f.finish(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_filter() {
+        for (input, replaced, rejected) in [
+            (&b"a\xc3\xa9b\xe2\x82\xac"[..], "a\u{e9}b\u{20ac}", None),
+            // Truncated: a 3-byte sequence cut after 2, and a 4-byte one at the end cut after 3.
+            (b"a\xe2\x82b\xf0\x9f\x98", "a\u{FFFD}b\u{FFFD}", Some(1)),
+            // Overlong encodings of '/' and NUL.
+            (
+                b"ab\xc0\xaf\xe0\x80\x80",
+                "ab\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}",
+                Some(2),
+            ),
+            // A surrogate, and a continuation byte with nothing to continue.
+            (
+                b"\xed\xa0\x80x\x80",
+                "\u{FFFD}\u{FFFD}\u{FFFD}x\u{FFFD}",
+                Some(0),
+            ),
+            (b"\xff", "\u{FFFD}", Some(0)),
+            (b"", "", None),
+        ] {
+            for chunk in 1..=input.len().max(1) {
+                assert_eq!(
+                    filter(Invalid::Replace, input, chunk),
+                    Ok(replaced.as_bytes().to_vec())
+                );
+                assert_eq!(
+                    filter(Invalid::Reject, input, chunk).err(),
+                    rejected,
+                    "{:?} in chunks of {}",
+                    input,
+                    chunk
+                );
+            }
+        }
+    }
+
+    proptest! {
+        /// However input is split, replacement matches `String::from_utf8_lossy()`, and rejection
+        /// gives the offset of the first invalid sequence.
+        #[test]
+        fn prop_filter(
+            input in prop::collection::vec(any::<u8>(), 0..200),
+            chunk in 1usize..20,
+        ) {
+            // Mostly the bytes of multi-byte sequences, so that plenty are split between chunks.
+            let input = input
+                .iter()
+                .map(|b| b"a\xc3\xa9\xe2\x82\xac\xf0\x9f\x98\x80\xc0\xff\xed\xa0"[usize::from(b % 14)])
+                .collect::<Vec<_>>();
+            let lossy = String::from_utf8_lossy(&input).into_owned().into_bytes();
+            prop_assert_eq!(filter(Invalid::Replace, &input, chunk).unwrap(), lossy);
+            let first = std::str::from_utf8(&input).err().map(|e| e.valid_up_to() as u64);
+            prop_assert_eq!(filter(Invalid::Reject, &input, chunk).err(), first);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Checking that a series of output files holds what was captured into it, as `rotee selftest`
+//! and the tests do.
+
+/// Check `files`, the contents of the series under `prefix` from the newest file (index 0) up,
+/// with `None` for a missing file, captured with `-s file_size -n num_files` from `input`:
+///
+///   * the newest file exists, and no file below the highest is missing;
+///   * no more than `num_files` files are left;
+///   * every rotated file is full, and the newest file isn't;
+///   * the files, oldest first, reassemble into the end of `input`, keeping as much of it as they
+///     can hold.
+///
+/// Missing files above the highest one left are ignored, so `files` can cover more indices than
+/// the series has.
+pub fn check_series(
+    prefix: &str,
+    files: &[Option<Vec<u8>>],
+    file_size: usize,
+    num_files: usize,
+    input: &[u8],
+) -> Result<(), String> {
+    let len = files.iter().rposition(Option::is_some).map_or(0, |i| i + 1);
+    if let Some(i) = (0..len.max(1)).find(|&i| !matches!(files.get(i), Some(Some(_)))) {
+        return Err(format!("{}{} is missing", prefix, i));
+    }
+    if len > num_files {
+        return Err(format!(
+            "{} files are left, rather than at most {}",
+            len, num_files
+        ));
+    }
+    let mut got = Vec::new();
+    for (i, f) in files[..len].iter().enumerate().rev() {
+        let f = f.as_ref().unwrap();
+        if (i > 0 && f.len() != file_size) || (i == 0 && f.len() >= file_size) {
+            return Err(format!(
+                "{}{} has {} bytes, rather than {}{}",
+                prefix,
+                i,
+                f.len(),
+                if i == 0 { "fewer than " } else { "" },
+                file_size
+            ));
+        }
+        got.extend_from_slice(f);
+    }
+    let active = input.len() % file_size;
+    let kept = input.len().min(active + (num_files - 1) * file_size);
+    if got != input[input.len() - kept..] {
+        return Err(format!(
+            "the files reassemble into {} bytes that aren't the last {} bytes of the input",
+            got.len(),
+            kept
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_series;
+
+    #[test]
+    fn test_check_series() {
+        let f = |s: &str| Some(s.as_bytes().to_vec());
+        let input = b"abcdefghij";
+        assert_eq!(
+            check_series("p.", &[f("ij"), f("efgh"), f("abcd")], 4, 3, input),
+            Ok(())
+        );
+        // The oldest data has been rotated out of existence.
+        assert_eq!(
+            check_series("p.", &[f("ij"), f("efgh")], 4, 2, input),
+            Ok(())
+        );
+        // Missing files above the highest are ignored.
+        assert_eq!(
+            check_series("p.", &[f("ij"), f("efgh"), f("abcd"), None], 4, 3, input),
+            Ok(())
+        );
+        // Input that fills its last file leaves the newest file empty.
+        assert_eq!(
+            check_series("p.", &[f(""), f("abcd")], 4, 3, b"abcd"),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_series("p.", &[None, f("efgh"), f("abcd")], 4, 3, input),
+            Err("p.0 is missing".to_owned())
+        );
+        assert_eq!(
+            check_series("p.", &[], 4, 3, b""),
+            Err("p.0 is missing".to_owned())
+        );
+        assert_eq!(
+            check_series("p.", &[f("ij"), None, f("abcd")], 4, 3, input),
+            Err("p.1 is missing".to_owned())
+        );
+        assert_eq!(
+            check_series("p.", &[f("ij"), f("efgh"), f("abcd")], 4, 2, input),
+            Err("3 files are left, rather than at most 2".to_owned())
+        );
+        assert_eq!(
+            check_series("p.", &[f("hij"), f("defg"), f("abc")], 4, 3, input),
+            Err("p.2 has 3 bytes, rather than 4".to_owned())
+        );
+        assert_eq!(
+            check_series(
+                "p.",
+                &[f("ijkl"), f("efgh"), f("abcd")],
+                4,
+                3,
+                b"abcdefghijkl"
+            ),
+            Err("p.0 has 4 bytes, rather than fewer than 4".to_owned())
+        );
+        assert_eq!(
+            check_series("p.", &[f("ij"), f("abcd"), f("efgh")], 4, 3, input),
+            Err(
+                "the files reassemble into 10 bytes that aren't the last 10 bytes of the input"
+                    .to_owned()
+            )
+        );
+    }
+}
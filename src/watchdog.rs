@@ -0,0 +1,170 @@
+//! Watching for writes that stall (`--write-timeout`), e.g. on a stuck NFS mount.
+//!
+//! A write blocked in the kernel can't be interrupted from another thread, so the watchdog can only
+//! report the stall, or try to exit. Whether exiting works depends on where the write is blocked:
+//! a process can't exit while a thread is in an uninterruptible sleep (Linux's `D` state), as it
+//! is during a write to a `hard` NFS mount whose server has gone.
+
+use crate::diag;
+use log::warn;
+use std::{
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// What to do about a write that takes longer than the timeout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stall {
+    /// Warn, and warn again once the write completes.
+    Warn,
+    /// Exit with an error.
+    Abort,
+}
+
+struct State {
+    /// The write in progress, if any: its number, when it started, and the file it writes to.
+    write: Option<(u64, Instant, PathBuf)>,
+    /// The number of writes started so far.
+    started: u64,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever `state` changes.
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// A thread that watches for writes that take longer than `timeout`.
+pub struct Watchdog {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn spawn(timeout: Duration, stall: Stall) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                write: None,
+                started: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let s = Arc::clone(&shared);
+        let thread = thread::spawn(move || watch(&s, timeout, stall));
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Watch a write to `path`, until the returned guard is dropped.
+    pub fn watch(&self, path: &Path) -> Watch<'_> {
+        let mut st = self.shared.lock();
+        st.started += 1;
+        st.write = Some((st.started, Instant::now(), path.to_owned()));
+        self.shared.changed.notify_all();
+        Watch { dog: self }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+/// A write being watched, which is finished when this is dropped.
+pub struct Watch<'a> {
+    dog: &'a Watchdog,
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        self.dog.shared.lock().write = None;
+        self.dog.shared.changed.notify_all();
+    }
+}
+
+/// Is write number `n` still in progress?
+fn writing(st: &State, n: u64) -> bool {
+    st.write.as_ref().is_some_and(|w| w.0 == n) && !st.closed
+}
+
+fn watch(shared: &Shared, timeout: Duration, stall: Stall) {
+    let mut st = shared.lock();
+    loop {
+        while st.write.is_none() && !st.closed {
+            st = shared.changed.wait(st).unwrap();
+        }
+        let Some((n, start, path)) = st.write.clone().filter(|_| !st.closed) else {
+            return;
+        };
+        while writing(&st, n) && start.elapsed() < timeout {
+            st = shared
+                .changed
+                .wait_timeout(st, timeout.saturating_sub(start.elapsed()))
+                .unwrap()
+                .0;
+        }
+        if !writing(&st, n) {
+            continue;
+        }
+        if stall == Stall::Abort {
+            // Not held back by a rotation in progress, which may be what's stuck.
+            diag::error(&format_args!(
+                "writing to {} has taken more than {:?}: giving up",
+                path.display(),
+                timeout
+            ));
+            process::exit(1);
+        }
+        warn!(
+            "writing to {} has taken more than {:?}",
+            path.display(),
+            timeout
+        );
+        while writing(&st, n) {
+            st = shared.changed.wait(st).unwrap();
+        }
+        warn!(
+            "writing to {} completed after {:.1}s",
+            path.display(),
+            start.elapsed().as_secs_f64()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stall, Watchdog};
+    use std::{path::Path, thread, time::Duration};
+
+    #[test]
+    fn test_watch() {
+        // Writes that finish in time, and those that don't, are both seen through.
+        let dog = Watchdog::spawn(Duration::from_millis(20), Stall::Warn);
+        for _ in 0..3 {
+            let _w = dog.watch(Path::new("quick"));
+        }
+        {
+            let _w = dog.watch(Path::new("slow"));
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _w = dog.watch(Path::new("quick"));
+    }
+}
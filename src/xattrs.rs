@@ -0,0 +1,73 @@
+//! Stamping rotated files with extended attributes describing them (`--xattrs`).
+
+use crate::clock::utc_timestamp;
+use std::{ffi::CString, fs::File, io, os::unix::io::AsRawFd, time::SystemTime};
+
+/// What is known about a file of the series once it is rotated.
+pub struct FileMeta {
+    /// The file's number amongst those started by this run (as in `--header-line`).
+    pub sequence: u64,
+    /// The offset, in everything written to the series by this run, of the file's first byte.
+    pub first_offset: u64,
+    /// When the file was started.
+    pub start: SystemTime,
+    /// The CRC-32 of the file's contents.
+    pub checksum: u32,
+}
+
+impl FileMeta {
+    /// The extended attributes to set, and their values.
+    pub fn attrs(&self) -> [(&'static str, String); 4] {
+        [
+            ("user.rotee.sequence", self.sequence.to_string()),
+            ("user.rotee.first_offset", self.first_offset.to_string()),
+            ("user.rotee.start_ts", utc_timestamp(self.start, true)),
+            ("user.rotee.checksum", format!("{:08x}", self.checksum)),
+        ]
+    }
+}
+
+/// Set the extended attributes of `meta` on `f`. Returns `Ok(false)` if the file system doesn't
+/// support (user) extended attributes.
+pub fn stamp(f: &File, meta: &FileMeta) -> Result<bool, io::Error> {
+    for (name, value) in meta.attrs() {
+        let name = CString::new(name).unwrap();
+        let rc = unsafe {
+            libc::fsetxattr(
+                f.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if rc == -1 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOTSUP) => Ok(false),
+                _ => Err(e),
+            };
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileMeta;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_attrs() {
+        let meta = FileMeta {
+            sequence: 3,
+            first_offset: 1024,
+            start: UNIX_EPOCH + Duration::from_millis(1_717_243_200_123),
+            checksum: 0xcbf43926,
+        };
+        assert_eq!(
+            meta.attrs().map(|(_, v)| v),
+            ["3", "1024", "2024-06-01T12:00:00.123Z", "cbf43926"].map(String::from)
+        );
+    }
+}